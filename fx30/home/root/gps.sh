@@ -33,12 +33,14 @@ PATH=$PATH:/legato/systems/current/bin/
 # Timeout code
 TIMEOUT=50
 timeout_monitor() {
-  sleep $TIMEOUT
+  # sleep must not hold stdout, the buoy reads until the script exits
+  sleep $TIMEOUT > /dev/null
   echo "GPS FAILED: timed out"
   kill $1
 }
 timeout_monitor $$ &
 TIMEOUT_MONITOR_PID=$!
+trap 'kill $TIMEOUT_MONITOR_PID 2> /dev/null' EXIT
 
 
 # Run a command
@@ -66,11 +68,23 @@ runcmd() {
 #     TTFF start = 3188 msec
 runcmd "gnss fix" "msec"
 
+# The output is parsed by the buoy, see Position::parse_gnss() in src/position.rs
+#
 # Expect:
 #     Latitude(positive->north) : -36.843292
 #     Longitude(positive->east) : 174.756864
 #     hAccuracy                 : 10.0m
-echo $(gnss get loc2d)
+#     Position state: 3D Fix
+#     Date(YYYY-MM-DD) 2020-08-30
+#     Time(HH:MM:SS:ms) 02:35:17:000
+#     Satellites in View: 12
+#     Satellites Tracked: 9
+#     Satellites Used: 7
+gnss get loc2d
+gnss get posState
+gnss get date
+gnss get time
+gnss get satStat
 
 # Expect:
 #     Success!
@@ -79,5 +93,3 @@ runcmd "gnss stop" "Success!" > /dev/null
 # Expect:
 #     Success!
 runcmd "gnss disable" "Success!" > /dev/null
-
-kill $TIMEOUT_MONITOR_PID
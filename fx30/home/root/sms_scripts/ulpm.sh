@@ -29,6 +29,9 @@ ULPM_SECONDS=$1
 source /etc/run.env
 PATH=$PATH:/legato/systems/current/bin/
 
+# Tell the next session why this one ended, unless the buoy already did
+(cd /home/root && ./buoy end-session command)
+
 # This command blocks the shutdown
 # FIXME: devMode needs to be removed entirely. See: https://docs.legato.io/latest/basicTargetDevMode.html
 app stop devMode
@@ -63,6 +63,9 @@ if [ -z "${VERSION_CODE}" ]; then
 fi
 echo "Starting upgrade, using: ${URL}" >> ${STD_LOG}
 
+# Tell the next session why this one ended, unless the buoy already did
+(cd /home/root && ./buoy end-session command)
+
 # Stop the buoy process - this will disconnect the network
 app stop TaringaBoot
 echo "app stop TaringaBoot: DONE" >> ${STD_LOG}
@@ -0,0 +1,339 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Battery state estimator.  The battery voltage is sampled often and each
+/// sample goes through a median filter (removes spikes, e.g. the load dip while
+/// the modem transmits) followed by an exponential moving average.  The
+/// filtered voltage is used to estimate the state of charge and the
+/// charge/discharge trend.
+///
+/// The state of charge comes from a resting voltage table, so it's only a rough
+/// guide while the battery is being charged or heavily loaded.
+///
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use buoy_code::errors::GiftError;
+
+use crate::platform::Platform;
+use crate::supervisor::{lock, Supervisor};
+use crate::voltage::{get_voltage, Calibration};
+
+// (volts, state of charge %) for a 12 V lead-acid battery at rest
+const LEAD_ACID_SOC: &[(f32, f32)] = &[
+  (10.50, 0.0),
+  (11.51, 10.0),
+  (11.66, 20.0),
+  (11.81, 30.0),
+  (11.96, 40.0),
+  (12.10, 50.0),
+  (12.24, 60.0),
+  (12.37, 70.0),
+  (12.50, 80.0),
+  (12.62, 90.0),
+  (12.73, 100.0),
+];
+
+// (volts, state of charge %) for a 4S (12.8 V) LiFePO4 battery at rest
+const LIFEPO4_SOC: &[(f32, f32)] = &[
+  (10.00, 0.0),
+  (12.00, 9.0),
+  (12.50, 14.0),
+  (12.80, 17.0),
+  (12.90, 20.0),
+  (13.00, 30.0),
+  (13.10, 40.0),
+  (13.20, 70.0),
+  (13.30, 90.0),
+  (13.40, 99.0),
+  (13.60, 100.0),
+];
+
+// The trend needs at least this much history before it's reported
+const MIN_TREND_SPAN: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Chemistry {
+  LeadAcid,
+  Lifepo4,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct BatteryConfig {
+  pub chemistry: Chemistry,
+  pub sample_interval_secs: u64, // How often to sample the voltage
+  pub median_window: usize,      // Number of samples in the median filter
+  pub ema_alpha: f32,            // Weight of a new sample in the moving average (0..1]
+  pub trend_window_secs: u64,    // Period the charge/discharge trend is calculated over
+}
+
+impl Default for BatteryConfig {
+  fn default() -> Self {
+    BatteryConfig {
+      chemistry: Chemistry::LeadAcid,
+      sample_interval_secs: 10,
+      median_window: 5,
+      ema_alpha: 0.1,
+      trend_window_secs: 60 * 60,
+    }
+  }
+}
+
+impl BatteryConfig {
+  pub fn validate(&self) -> Result<(), GiftError> {
+    if self.sample_interval_secs == 0 || self.median_window == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "battery: sample_interval_secs and median_window must be above 0",
+      )));
+    }
+    if self.ema_alpha <= 0.0 || self.ema_alpha > 1.0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "battery: ema_alpha must be in (0, 1]",
+      )));
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatteryState {
+  pub voltage: f32,       // The filtered voltage
+  pub raw_voltage: f32,   // The last voltage sample
+  pub soc: f32,           // Estimated state of charge, in %
+  pub trend: Option<f32>, // Voltage trend in V/hour, positive when charging
+  pub samples: usize,     // The number of samples taken
+}
+
+impl BatteryState {
+  /// The filter has enough samples to be trusted.
+  pub fn is_settled(&self, config: &BatteryConfig) -> bool {
+    self.samples >= config.median_window
+  }
+}
+
+///
+/// Interpolate the state of charge from the voltage table.
+///
+pub fn state_of_charge(chemistry: Chemistry, volts: f32) -> f32 {
+  let table = match chemistry {
+    Chemistry::LeadAcid => LEAD_ACID_SOC,
+    Chemistry::Lifepo4 => LIFEPO4_SOC,
+  };
+
+  if volts <= table[0].0 {
+    return table[0].1;
+  }
+  for w in table.windows(2) {
+    let (v0, s0) = w[0];
+    let (v1, s1) = w[1];
+    if volts <= v1 {
+      return s0 + (volts - v0) * (s1 - s0) / (v1 - v0);
+    }
+  }
+  table[table.len() - 1].1
+}
+
+pub struct BatteryFilter {
+  config: BatteryConfig,
+  window: VecDeque<f32>, // The most recent samples for the median filter
+  ema: Option<f32>,      // The moving average
+  history: VecDeque<(Instant, f32)>, // The moving average over the trend window
+  state: BatteryState,
+}
+
+impl BatteryFilter {
+  pub fn new(config: &BatteryConfig) -> Self {
+    BatteryFilter {
+      config: config.clone(),
+      window: VecDeque::new(),
+      ema: None,
+      history: VecDeque::new(),
+      state: BatteryState::default(),
+    }
+  }
+
+  fn median(&self) -> f32 {
+    let mut sorted: Vec<f32> = self.window.iter().cloned().collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+      (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+      sorted[mid]
+    }
+  }
+
+  ///
+  /// Least squares slope of the moving average over the trend window, in V/hour.
+  ///
+  fn trend(&self) -> Option<f32> {
+    let (first, _) = *self.history.front()?;
+    let (last, _) = *self.history.back()?;
+    if last.duration_since(first) < MIN_TREND_SPAN {
+      return None;
+    }
+
+    let n = self.history.len() as f64;
+    let points: Vec<(f64, f64)> = self
+      .history
+      .iter()
+      .map(|&(t, v)| {
+        let hours = t.duration_since(first).as_secs_f64() / 3600.0;
+        (hours, f64::from(v))
+      })
+      .collect();
+    let sum_x: f64 = points.iter().map(|p| p.0).sum();
+    let sum_y: f64 = points.iter().map(|p| p.1).sum();
+    let sum_xx: f64 = points.iter().map(|p| p.0 * p.0).sum();
+    let sum_xy: f64 = points.iter().map(|p| p.0 * p.1).sum();
+    let denominator = n * sum_xx - sum_x * sum_x;
+    if denominator.abs() < std::f64::EPSILON {
+      return None;
+    }
+
+    Some(((n * sum_xy - sum_x * sum_y) / denominator) as f32)
+  }
+
+  ///
+  /// Add a voltage sample taken at `now`, returns the new state.
+  ///
+  pub fn add(&mut self, volts: f32, now: Instant) -> &BatteryState {
+    self.window.push_back(volts);
+    while self.window.len() > self.config.median_window {
+      self.window.pop_front();
+    }
+
+    let median = self.median();
+    let ema = match self.ema {
+      Some(ema) => ema + self.config.ema_alpha * (median - ema),
+      None => median,
+    };
+    self.ema = Some(ema);
+
+    let trend_window = Duration::from_secs(self.config.trend_window_secs);
+    self.history.push_back((now, ema));
+    while let Some(&(t, _)) = self.history.front() {
+      if now.duration_since(t) > trend_window {
+        self.history.pop_front();
+      } else {
+        break;
+      }
+    }
+
+    self.state.voltage = ema;
+    self.state.raw_voltage = volts;
+    self.state.soc = state_of_charge(self.config.chemistry, ema);
+    self.state.trend = self.trend();
+    self.state.samples += 1;
+
+    &self.state
+  }
+}
+
+///
+/// Sample the battery voltage every `sample_interval_secs` and keep the
+/// returned battery state up to date.
+///
+/// This spawns a supervised task.
+///
+pub fn battery_monitor(
+  config: &BatteryConfig,
+  cal: &Calibration,
+  platform: &Arc<dyn Platform>,
+  supervisor: &Supervisor,
+) -> Arc<Mutex<BatteryState>> {
+  let state = Arc::new(Mutex::new(BatteryState::default()));
+  let thread_state = Arc::clone(&state);
+  let mut filter = BatteryFilter::new(config);
+  let interval = Duration::from_secs(config.sample_interval_secs);
+  let cal = cal.clone();
+  let platform = Arc::clone(platform);
+
+  supervisor.spawn("battery", move || loop {
+    match get_voltage(platform.as_ref(), &cal) {
+      Ok(volts) => {
+        let new_state = filter.add(volts, Instant::now());
+        debug!("battery_monitor(): {:?}", new_state);
+        *lock(&thread_state) = new_state.clone();
+      }
+      Err(e) => error!("battery_monitor(): error reading the voltage: {:?}", e),
+    }
+    thread::sleep(interval);
+  });
+
+  state
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::battery::*;
+
+  #[test]
+  fn test_state_of_charge() {
+    assert_eq!(0.0, state_of_charge(Chemistry::LeadAcid, 9.0));
+    assert_eq!(50.0, state_of_charge(Chemistry::LeadAcid, 12.10));
+    assert_eq!(100.0, state_of_charge(Chemistry::LeadAcid, 14.2));
+    assert!((state_of_charge(Chemistry::LeadAcid, 12.17) - 55.0).abs() < 0.01);
+    assert_eq!(30.0, state_of_charge(Chemistry::Lifepo4, 13.0));
+    assert!((state_of_charge(Chemistry::Lifepo4, 13.15) - 55.0).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_filter_removes_spikes() {
+    let config = BatteryConfig::default();
+    let mut filter = BatteryFilter::new(&config);
+    let now = Instant::now();
+
+    for i in 0..20 {
+      // The modem pulls the voltage down on every fourth sample
+      let v = if i % 4 == 3 { 10.2 } else { 12.4 };
+      filter.add(v, now + Duration::from_secs(i * 10));
+    }
+
+    let state = filter.add(12.4, now + Duration::from_secs(200));
+    assert!((state.voltage - 12.4).abs() < 0.001);
+    assert!(state.is_settled(&config));
+    assert_eq!(None, state.trend);
+  }
+
+  #[test]
+  fn test_trend() {
+    let config = BatteryConfig::default();
+    let mut filter = BatteryFilter::new(&config);
+    let now = Instant::now();
+
+    // Discharging at 0.1 V/hour, sampled every minute for two hours
+    let mut state = BatteryState::default();
+    for i in 0..120 {
+      let v = 12.6 - 0.1 * i as f32 / 60.0;
+      state = filter.add(v, now + Duration::from_secs(i * 60)).clone();
+    }
+
+    let trend = state.trend.unwrap();
+    assert!((trend + 0.1).abs() < 0.01, "trend: {}", trend);
+  }
+}
@@ -0,0 +1,116 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The `buoy calibrate` subcommand.  The operator sets a bench power supply to
+/// a few voltages across the battery range and enters the voltage measured with
+/// a multimeter.  For each voltage the raw `mpp_05` ADC value is read and, once
+/// done, the fit is written to the calibration file.  buoy.toml is left alone.
+///
+/// Usage:
+///    buoy calibrate [--piecewise]
+///
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use buoy_code::errors::GiftError;
+
+use crate::platform::{Adc, Platform};
+use crate::voltage::{fit_linear, fit_piecewise};
+
+// The ADC is noisy, so we average a few readings for each point
+const SAMPLES_PER_POINT: usize = 10;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+fn read_raw_average(platform: &dyn Platform) -> Result<f32, GiftError> {
+  let mut sum = 0.0;
+  for _ in 0..SAMPLES_PER_POINT {
+    sum += platform.read_adc(Adc::Battery)?;
+    thread::sleep(SAMPLE_INTERVAL);
+  }
+  Ok(sum / SAMPLES_PER_POINT as f32)
+}
+
+pub fn calibrate(
+  calibration_path: &Path,
+  piecewise: bool,
+  platform: &dyn Platform,
+) -> Result<(), GiftError> {
+  let mut points = Vec::new();
+  let stdin = io::stdin();
+
+  println!(
+    "Calibrating the battery voltage ADC ({:?}).",
+    calibration_path
+  );
+  println!("Enter the reference voltage measured with a multimeter, or 'done' to finish.");
+
+  loop {
+    print!("volts> ");
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    if stdin.lock().read_line(&mut line)? == 0 {
+      break;
+    }
+    let line = line.trim();
+    if line == "done" {
+      break;
+    }
+    let volts = match line.parse::<f32>() {
+      Ok(v) if v.is_finite() => v,
+      _ => {
+        println!("'{}' is not a voltage", line);
+        continue;
+      }
+    };
+
+    let raw = read_raw_average(platform)?;
+    if !raw.is_finite() {
+      println!("  the ADC read {}, try again", raw);
+      continue;
+    }
+    println!("  {:.3} V => raw {:.1}", volts, raw);
+    points.push((raw, volts));
+  }
+
+  let cal = if piecewise {
+    fit_piecewise(&points)?
+  } else {
+    fit_linear(&points)?
+  };
+
+  println!("Fit: {:?}", cal);
+  for &(raw, volts) in &points {
+    println!(
+      "  raw {:.1}: reference {:.3} V, fit {:.3} V",
+      raw,
+      volts,
+      cal.apply(raw)
+    );
+  }
+
+  cal.save(calibration_path)?;
+  println!("Saved to {:?}", calibration_path);
+
+  Ok(())
+}
@@ -0,0 +1,296 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Disciplines the buoy clock.  The system clock on the FX30 can be unset
+/// (1970) for a while after boot, so timestamps are worked out from a UTC
+/// reference and the monotonic clock:
+///
+///    utc = reference.utc + (instant - reference.instant) * (1 + drift)
+///
+/// The GNSS time is the best reference.  The server's Date header is only
+/// used when there has been no GNSS time for a day.  The drift is measured
+/// between two GNSS references at least an hour apart, the older one is kept
+/// as the drift anchor while the fixes in between become the reference.
+///
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use buoy_code::clock::{format_date, is_valid_date, system_source, ClockStatus, TimeSource};
+
+// A server reference does not replace a GNSS reference younger than this
+const GNSS_PREFERRED: Duration = Duration::from_secs(24 * 60 * 60);
+
+// The shortest time between GNSS references to measure the drift
+const MIN_DRIFT_SPAN: Duration = Duration::from_secs(60 * 60);
+
+// Drifts larger than this are measurement errors, a crystal is within 200 ppm
+const MAX_DRIFT_PPM: f64 = 200.0;
+
+#[derive(Clone, Debug)]
+struct Reference {
+  utc: DateTime<Utc>, // The UTC time at `instant`
+  instant: Instant,
+  source: TimeSource,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ClockDiscipline {
+  reference: Option<Reference>,
+  drift_anchor: Option<Reference>, // The GNSS reference the drift is measured from
+  drift_ppm: Option<f64>,
+}
+
+impl ClockDiscipline {
+  ///
+  /// Add a UTC time from `source`, seen at `instant`.  Returns true if it's
+  /// now the reference.
+  ///
+  pub fn add_reference(
+    &mut self,
+    utc: DateTime<Utc>,
+    source: TimeSource,
+    instant: Instant,
+  ) -> bool {
+    if !is_valid_date(&utc) {
+      warn!("ClockDiscipline: ignoring {} time {}", source, utc);
+      return false;
+    }
+
+    if let Some(old) = &self.reference {
+      let span = if instant > old.instant {
+        instant.duration_since(old.instant)
+      } else {
+        return false; // Older than what we have
+      };
+
+      if source == TimeSource::Server && old.source == TimeSource::Gnss && span < GNSS_PREFERRED {
+        return false;
+      }
+    }
+
+    if source == TimeSource::Gnss {
+      self.measure_drift(utc, instant);
+    }
+
+    debug!("ClockDiscipline: {} time {} is the reference", source, utc);
+    self.reference = Some(Reference {
+      utc,
+      instant,
+      source,
+    });
+    true
+  }
+
+  ///
+  /// Measure the drift from the anchor to a GNSS time.  The anchor is only
+  /// moved once there's a measurement, so frequent fixes still reach
+  /// MIN_DRIFT_SPAN.
+  ///
+  fn measure_drift(&mut self, utc: DateTime<Utc>, instant: Instant) {
+    if let Some(anchor) = &self.drift_anchor {
+      if instant <= anchor.instant {
+        return;
+      }
+      let span = instant.duration_since(anchor.instant);
+      if span < MIN_DRIFT_SPAN {
+        return;
+      }
+
+      let mono_ms = span.as_millis() as f64;
+      let utc_ms = (utc - anchor.utc).num_milliseconds() as f64;
+      let drift_ppm = (utc_ms - mono_ms) / mono_ms * 1e6;
+      if drift_ppm.abs() <= MAX_DRIFT_PPM {
+        self.drift_ppm = Some(drift_ppm);
+      } else {
+        warn!("ClockDiscipline: ignoring a drift of {:.0} ppm", drift_ppm);
+      }
+    }
+
+    self.drift_anchor = Some(Reference {
+      utc,
+      instant,
+      source: TimeSource::Gnss,
+    });
+  }
+
+  /// The UTC time at `instant`, if there is a reference.
+  pub fn utc_at(&self, instant: Instant) -> Option<DateTime<Utc>> {
+    let reference = self.reference.as_ref()?;
+    let scale = 1.0 + self.drift_ppm.unwrap_or(0.0) / 1e6;
+    let elapsed_ms = if instant >= reference.instant {
+      instant.duration_since(reference.instant).as_millis() as f64
+    } else {
+      -(reference.instant.duration_since(instant).as_millis() as f64)
+    };
+    Some(reference.utc + chrono::Duration::milliseconds((elapsed_ms * scale).round() as i64))
+  }
+
+  ///
+  /// The timestamp for `instant`, in the same format as `date_now()`.  Without
+  /// a reference this is `system`, the system clock time read at `instant`.
+  ///
+  pub fn stamp(&self, instant: Instant, system: &str) -> (String, TimeSource) {
+    match (&self.reference, self.utc_at(instant)) {
+      (Some(reference), Some(utc)) => (format_date(&utc), reference.source),
+      _ => (String::from(system), system_source(system)),
+    }
+  }
+
+  /// The state of the clock, compared to the system clock `system` at `now`.
+  pub fn status(&self, now: Instant, system: DateTime<Utc>) -> Option<ClockStatus> {
+    let reference = self.reference.as_ref()?;
+    Some(ClockStatus {
+      source: reference.source,
+      offset_ms: (self.utc_at(now)? - system).num_milliseconds(),
+      drift_ppm: self.drift_ppm,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::clock_discipline::*;
+  use buoy_code::clock::parse_date;
+
+  const HOUR: Duration = Duration::from_secs(60 * 60);
+
+  fn utc(date: &str) -> DateTime<Utc> {
+    parse_date(date).unwrap()
+  }
+
+  #[test]
+  fn test_unsynced() {
+    let mut clock = ClockDiscipline::default();
+    let now = Instant::now();
+    assert_eq!(
+      (String::from("19700101T000142.000Z"), TimeSource::Unsynced),
+      clock.stamp(now, "19700101T000142.000Z")
+    );
+    assert_eq!(
+      (String::from("20200830T023517.000Z"), TimeSource::System),
+      clock.stamp(now, "20200830T023517.000Z")
+    );
+    assert!(clock.status(now, Utc::now()).is_none());
+
+    assert!(!clock.add_reference(utc("19700101T000142.000Z"), TimeSource::Gnss, now));
+    assert!(clock.utc_at(now).is_none());
+  }
+
+  #[test]
+  fn test_stamp() {
+    let mut clock = ClockDiscipline::default();
+    let start = Instant::now();
+    let boot = utc("19700101T000142.000Z");
+    let fix = start + Duration::from_secs(60);
+    assert!(clock.add_reference(utc("20200830T023517.000Z"), TimeSource::Gnss, fix));
+
+    // A recording started before the first fix
+    assert_eq!(
+      (String::from("20200830T023417.000Z"), TimeSource::Gnss),
+      clock.stamp(start, "19700101T000142.000Z")
+    );
+
+    let status = clock.status(fix, boot).unwrap();
+    assert_eq!(TimeSource::Gnss, status.source);
+    assert_eq!(
+      (utc("20200830T023517.000Z") - boot).num_milliseconds(),
+      status.offset_ms
+    );
+    assert_eq!(None, status.drift_ppm);
+  }
+
+  #[test]
+  fn test_drift() {
+    let mut clock = ClockDiscipline::default();
+    let start = Instant::now();
+    assert!(clock.add_reference(utc("20200830T000000.000Z"), TimeSource::Gnss, start));
+
+    // Too soon to measure the drift
+    assert!(clock.add_reference(
+      utc("20200830T000100.000Z"),
+      TimeSource::Gnss,
+      start + Duration::from_secs(60)
+    ));
+    assert_eq!(None, clock.status(start, Utc::now()).unwrap().drift_ppm);
+
+    // The monotonic clock runs 10 ppm slow: 36ms over an hour
+    let later = start + 2 * HOUR;
+    assert!(clock.add_reference(utc("20200830T020000.072Z"), TimeSource::Gnss, later));
+    let drift = clock.status(later, Utc::now()).unwrap().drift_ppm.unwrap();
+    assert!((drift - 10.0).abs() < 0.01);
+    assert_eq!("20200830T030000.108Z", clock.stamp(later + HOUR, "").0);
+
+    // A wild drift is ignored
+    let wild = later + 2 * HOUR;
+    assert!(clock.add_reference(utc("20200830T040100.000Z"), TimeSource::Gnss, wild));
+    let drift = clock.status(wild, Utc::now()).unwrap().drift_ppm.unwrap();
+    assert!((drift - 10.0).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_drift_frequent_fixes() {
+    let mut clock = ClockDiscipline::default();
+    let start = Instant::now();
+    let quarter = Duration::from_secs(15 * 60);
+
+    // A fix every 15 minutes, with the monotonic clock 10 ppm slow: 9ms a fix
+    for (i, date) in [
+      "20200830T000000.000Z",
+      "20200830T001500.009Z",
+      "20200830T003000.018Z",
+      "20200830T004500.027Z",
+    ]
+    .iter()
+    .enumerate()
+    {
+      assert!(clock.add_reference(utc(date), TimeSource::Gnss, start + quarter * i as u32));
+      assert_eq!(None, clock.status(start, Utc::now()).unwrap().drift_ppm);
+    }
+
+    // An hour after the first fix
+    let hour = start + quarter * 4;
+    assert!(clock.add_reference(utc("20200830T010000.036Z"), TimeSource::Gnss, hour));
+    let drift = clock.status(hour, Utc::now()).unwrap().drift_ppm.unwrap();
+    assert!((drift - 10.0).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_server_reference() {
+    let mut clock = ClockDiscipline::default();
+    let start = Instant::now();
+    assert!(clock.add_reference(utc("20200830T000000.000Z"), TimeSource::Server, start));
+    assert!(clock.add_reference(
+      utc("20200830T000100.000Z"),
+      TimeSource::Gnss,
+      start + Duration::from_secs(60)
+    ));
+
+    // GNSS is preferred for a day
+    let later = start + 2 * HOUR;
+    assert!(!clock.add_reference(utc("20200830T020000.000Z"), TimeSource::Server, later));
+    assert_eq!(TimeSource::Gnss, clock.stamp(later, "").1);
+
+    let much_later = start + 25 * HOUR;
+    assert!(clock.add_reference(utc("20200831T010000.000Z"), TimeSource::Server, much_later));
+    assert_eq!(TimeSource::Server, clock.stamp(much_later, "").1);
+  }
+}
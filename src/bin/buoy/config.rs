@@ -0,0 +1,95 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The per-device configuration.  This is read from buoy_code::BUOY_CONFIG_PATH,
+/// which is not part of the firmware package, so an upgrade does not overwrite
+/// it.  Any missing values use the defaults, see `fx30/home/root/buoy.example.toml`.
+///
+/// `buoy calibrate` writes the voltage calibration to its own file,
+/// buoy_code::BUOY_CALIBRATION_PATH, so the operator's buoy.toml is never
+/// rewritten.
+///
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use buoy_code::errors::GiftError;
+
+use crate::battery::BatteryConfig;
+use crate::geofence::GeofenceConfig;
+use crate::gps::GpsConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::light::LightConfig;
+use crate::platform::sim::SimScript;
+use crate::power::PowerConfig;
+use crate::supervisor::SupervisorConfig;
+use crate::voltage::Calibration;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BuoyConfig {
+  pub voltage: Calibration,   // The ADC to battery voltage calibration
+  pub battery: BatteryConfig, // The battery state estimator
+  pub power: PowerConfig,     // The power policy states
+  pub gps: GpsConfig,         // GPS fixes
+  pub geofence: Option<GeofenceConfig>, // The anchor-drag alarm, off if not set
+  pub light: LightConfig,     // The navigation light character
+  pub supervisor: SupervisorConfig, // Restarting failed tasks
+  pub heartbeat: HeartbeatConfig, // The health heartbeat
+  pub sim: SimScript,         // The simulator, when not built for the FX30
+}
+
+impl BuoyConfig {
+  ///
+  /// Load the config, if the file does not exist then the defaults are used.
+  /// The calibration in `calibration_path`, if there is one, replaces the
+  /// `[voltage]` table.
+  ///
+  pub fn load(path: &Path, calibration_path: &Path) -> Result<BuoyConfig, GiftError> {
+    let mut config: BuoyConfig = if path.exists() {
+      toml::from_str(&fs::read_to_string(path)?)?
+    } else {
+      info!("No config file {:?}, using the defaults", path);
+      BuoyConfig::default()
+    };
+
+    if let Some(cal) = Calibration::load(calibration_path)? {
+      config.voltage = cal;
+    }
+    config.validate()?;
+    Ok(config)
+  }
+
+  pub fn validate(&self) -> Result<(), GiftError> {
+    self.voltage.validate()?;
+    self.battery.validate()?;
+    self.power.validate()?;
+    self.gps.validate()?;
+    if let Some(geofence) = &self.geofence {
+      geofence.validate()?;
+    }
+    self.light.validate()?;
+    self.supervisor.validate()?;
+    self.heartbeat.validate()?;
+    self.sim.validate()
+  }
+}
@@ -18,243 +18,809 @@
 ///
 ///****************************************************************************
 
-#[cfg(feature = "fx30")]
-use std::fs;
-#[cfg(feature = "fx30")]
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use std::process::Command;
-#[cfg(feature = "fx30")]
-use std::process::Stdio;
+///
+/// The controller is an event-driven state machine.  It handles the actions
+/// sent by the other tasks (recordings, server responses, drift alerts), and
+/// its own timers:
+///
+///    power check - every `power.check_interval_secs`, may start a sleep
+///    no data     - upload without a recording after FX30_NO_DATA_WAIT
+///    stopping    - stop waiting for the last recording before a sleep
+///
+/// The time comes from a `Clock`, and the uploads go through a `Transport`,
+/// so the tests run days of operation on the simulator in milliseconds.
+///
+use std::path::Path;
 use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-
-use time::now as utc_time_now;
-use url::Url;
+use std::time::{Duration, Instant};
 
-use crate::data_send::Transmit;
+use crate::battery::BatteryState;
+use crate::clock_discipline::ClockDiscipline;
+use crate::config::BuoyConfig;
+use crate::data_send::{modem_snapshot, Uplink};
+use crate::geofence::Geofence;
+use crate::gnss::{create_source, CliSource};
+use crate::gps::{read_gps_thread, LastFix};
+use crate::heartbeat::{heartbeat_task, HeartbeatSources};
+use crate::light::{LightMonitor, LightSchedule};
+use crate::platform::{Clock, Platform, SystemClock};
+use crate::power::PowerPolicy;
 use crate::sensor_reader;
-use crate::voltage;
+use crate::shutdown::Shutdown;
+use crate::state_store::StateStore;
+use crate::sun;
+use crate::supervisor::{lock, Supervisor};
+use crate::transport::{QuicTransport, Transport};
+use buoy_code::clock::{format_date, TimeSource};
 use buoy_code::commands::handle_fx30_command;
 use buoy_code::errors::GiftError;
+use buoy_code::link_stats::LinkStats;
+use buoy_code::session::{SavedFix, Session, SessionEnd};
 use buoy_code::ControllerAction::{self, *};
+use buoy_code::{BuoyData, BUOY_SPOOL_PATH, FX30_NO_DATA_WAIT};
 
-#[cfg(feature = "fx30")]
-fn read_gps(last_gps: Arc<Mutex<String>>) -> Result<(), GiftError> {
-  // Start the actual connection
-  let mut gps_cmd = Command::new(buoy_code::GPS_SCRIPT)
-    .stdout(Stdio::piped())
-    .spawn()
-    .map_err(GiftError::Io)?;
-  {
-    let stdout = gps_cmd.stdout.as_mut().ok_or(GiftError::GPSIssue)?;
-    let stdout_lines = BufReader::new(stdout).lines();
-
-    for line in stdout_lines {
-      let ln = line.unwrap();
-      let mut gps = last_gps.lock().unwrap();
-      *gps = ln;
-      debug!("read_gps(): GPS => {}", *gps);
-      // Any data means we have the result
-      break;
-    }
+///
+/// It's lighter than civil twilight at the last GPS position, or at the
+/// deployment position if there has not been a fix.  Without a position it's
+/// treated as night, so the light is on.
+///
+fn is_daylight(
+  last_fix: &Mutex<LastFix>,
+  deployment: Option<(f64, f64)>,
+  discipline: &Mutex<ClockDiscipline>,
+  clock: &dyn Clock,
+) -> bool {
+  let location = lock(last_fix).location().or(deployment);
+  let now = lock(discipline)
+    .utc_at(clock.now())
+    .unwrap_or_else(|| clock.utc());
+  match location {
+    Some((latitude, longitude)) => sun::is_daylight(&now, latitude, longitude),
+    None => false,
   }
-
-  // Although the process exited, we still need to close it
-  gps_cmd.wait()?;
-
-  Ok(())
 }
 
 ///
-/// Read the GPS every buoy_code::GPS_ACQUISITION_PERIOD seconds.
+/// Flash the buoy navigation light with the light character in the config,
+/// see light.rs.  The light is only on between civil dusk and dawn.  Each
+/// switch is checked by the light monitor.
 ///
-/// This spawns a thread.
+/// This spawns a supervised task.
 ///
-#[cfg(feature = "fx30")]
-fn read_gps_thread(last_gps: Arc<Mutex<String>>) {
-  thread::spawn(move || loop {
-    thread::sleep(buoy_code::GPS_ACQUISITION_PERIOD);
-    read_gps(Arc::clone(&last_gps)).unwrap();
-  });
-}
+fn blink_buoy_light(
+  supervisor: &Supervisor,
+  platform: Arc<dyn Platform>,
+  config: &BuoyConfig,
+  last_fix: Arc<Mutex<LastFix>>,
+  discipline: Arc<Mutex<ClockDiscipline>>,
+  clock: Arc<dyn Clock>,
+  power: Arc<Mutex<PowerPolicy>>,
+  monitor: Arc<Mutex<LightMonitor>>,
+) -> Result<(), GiftError> {
+  let mut schedule = LightSchedule::new(config.light.sequence()?, Instant::now());
+  let deployment = config.geofence.as_ref().map(|g| (g.latitude, g.longitude));
+  info!(
+    "blink_buoy_light(): light character {}",
+    config.light.character
+  );
 
-fn is_nz_daylight() -> bool {
-  let utc_time = utc_time_now();
-  utc_time.tm_hour > 19 || utc_time.tm_hour < 5
-}
+  supervisor.spawn("light", move || {
+    let mut cycle = None;
+    let mut daylight = None;
+    let mut light_off = false;
+    loop {
+      let change = schedule.next(Instant::now());
 
-///
-/// Blink the buoy navigation light for a short period, then wait for a longer
-/// period.
-///
-/// Maritime NZ says 5 quick flashes every 20 seconds for a scientific buoy.
-/// That also looks like what DTA were doing. Flash duration ~500ms  should be ok.
-///
-///
-/// This spawns a thread.
-///
-#[cfg(feature = "fx30")]
-fn blink_buoy_light() {
-  thread::spawn(move || loop {
-    thread::sleep(buoy_code::BUOY_NAV_LIGHT_LONG_INT);
-    let is_daylight = is_nz_daylight();
-    for _ in 0..buoy_code::BUOY_NAV_LIGHT_NUM_SHORT {
-      // Turn ON - but only outside of bright daylight hours (7pm.. 5am UTC)
-      if !is_daylight {
-        match fs::write(buoy_code::BUOY_NAV_LIGHT_GPIO, b"1") {
-          Err(_) => (),
-          Ok(()) => (),
+      // Only check once a period, so a group of flashes is not cut short
+      if cycle != Some(change.cycle) {
+        cycle = Some(change.cycle);
+        let is_day = is_daylight(&last_fix, deployment, &discipline, clock.as_ref());
+        if daylight != Some(is_day) {
+          info!("blink_buoy_light(): daylight is {}", is_day);
+          daylight = Some(is_day);
         }
+        light_off = is_day || !lock(&power).features().nav_light;
+        lock(&monitor).set_active(!light_off);
       }
-      thread::sleep(buoy_code::BUOY_NAV_LIGHT_BLINK_ON);
 
-      // OFF
-      match fs::write(buoy_code::BUOY_NAV_LIGHT_GPIO, b"0") {
-        Err(_) => (),
-        Ok(()) => (),
+      let now = Instant::now();
+      if change.at > now {
+        thread::sleep(change.at - now);
       }
-      thread::sleep(buoy_code::BUOY_NAV_LIGHT_BLINK_OFF);
+
+      lock(&monitor).switch(platform.as_ref(), change.on && !light_off);
     }
   });
+  Ok(())
 }
 
-fn run_ulpm(sleeptime: usize) {
-  let output = Command::new(buoy_code::ULPM_SCRIPT)
-    .arg(sleeptime.to_string())
-    .output()
-    .expect("failed to execute process");
-  error!(
-    "run_ulpm done: stderr: {}",
-    String::from_utf8_lossy(&output.stderr)
-  );
+///
+/// What the controller runs against, the FX30 or the simulator.
+///
+#[derive(Clone)]
+pub struct Env {
+  pub clock: Arc<dyn Clock>,
+  pub platform: Arc<dyn Platform>,
+  pub transport: Arc<dyn Transport>,
+  pub battery: Arc<Mutex<BatteryState>>,
+  pub supervisor: Supervisor,  // For the task restart counts
+  pub shutdown: Arc<Shutdown>, // Stops the recording before a sleep
+  pub store: Arc<StateStore>,  // The state kept over reboots and sleeps
 }
 
 ///
-/// Power management - Check the voltage every 5 minutes.  The power management (PM)
-/// states are as a follows:
-///  FX30_PM_POWER_MEDIUM_THRESH - Any voltage above this value requires no PM.
-///  FX30_PM_POWER_LOW_THRESH - Any voltage between this and *_MEDIUM_* requires ULPM .
-///
-/// This spawns a thread.
+/// The state the controller shares with the other tasks.
 ///
-fn power_management() {
-  thread::spawn(move || loop {
-    thread::sleep(buoy_code::FX30_PM_TIME_AWAKE);
-
-    let battery_voltage = voltage::get_voltage().unwrap();
-    if battery_voltage < buoy_code::FX30_PM_POWER_LOW_THRESH {
-      thread::sleep(Duration::from_secs(60));
-      run_ulpm(buoy_code::FX30_PM_POWER_LOW_SLEEP_TIME_SEC);
-    } else if battery_voltage < buoy_code::FX30_PM_POWER_MEDIUM_THRESH {
-      thread::sleep(Duration::from_secs(60));
-      run_ulpm(buoy_code::FX30_PM_POWER_MEDIUM_SLEEP_TIME_SEC);
+#[derive(Clone)]
+pub struct Shared {
+  pub last_fix: Arc<Mutex<LastFix>>,
+  pub clock: Arc<Mutex<ClockDiscipline>>,
+  pub light: Arc<Mutex<LightMonitor>>,
+  pub power: Arc<Mutex<PowerPolicy>>,
+  pub last_recording: Arc<Mutex<Option<Instant>>>, // When the last recording arrived
+  pub session: Arc<Mutex<Option<Session>>>, // Reported with the uploads until one gets through
+}
+
+impl Shared {
+  pub fn new(config: &BuoyConfig, now: Instant) -> Self {
+    Shared {
+      last_fix: Arc::new(Mutex::new(LastFix::default())),
+      clock: Arc::new(Mutex::new(ClockDiscipline::default())),
+      light: Arc::new(Mutex::new(LightMonitor::new(&config.light))),
+      power: Arc::new(Mutex::new(PowerPolicy::new(&config.power, now))),
+      last_recording: Arc::new(Mutex::new(None)),
+      session: Arc::new(Mutex::new(None)),
     }
-  });
+  }
 }
 
-fn transmit_data(
-  url: Url,
-  ca_path: PathBuf,
-  action_tx: Sender<ControllerAction>,
-  last_gps: &std::sync::Arc<std::sync::Mutex<std::string::String>>,
-  mut data: buoy_code::BuoyData,
-) {
-  // Collect the data from the hydrophone buffer
-
-  // Get the last gps position
-  let gps = last_gps.lock().unwrap();
-  let t_gps = (*gps).clone();
-
-  thread::spawn(move || {
-    // Connect to the server
-    let result = Transmit::new(url, ca_path, &action_tx);
-    let mut conn = match result {
-      Ok(conn) => conn,
-      Err(e) => {
-        error!("Transmit::new() failed: {:?}", e);
-        return;
-      }
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+  Running,
+  Stopping { sleep: Duration, until: Instant }, // Waiting for the last recording before a sleep
+}
+
+pub struct Controller {
+  config: BuoyConfig,
+  env: Env,
+  shared: Shared,
+  state: State,
+  last_data: Instant,        // When we last uploaded
+  next_power_check: Instant, // When the battery state is next checked
+}
+
+impl Controller {
+  ///
+  /// Create the controller, it carries on from the state saved before the
+  /// last reboot or sleep, and starts a new session.
+  ///
+  pub fn new(config: &BuoyConfig, env: Env, shared: Shared) -> Self {
+    let now = env.clock.now();
+    let mut controller = Controller {
+      config: config.clone(),
+      env,
+      shared,
+      state: State::Running,
+      last_data: now,
+      next_power_check: now + Duration::from_secs(config.power.check_interval_secs),
     };
+    controller.restore_state();
+    controller
+  }
+
+  fn restore_state(&mut self) {
+    let state = self.env.store.state();
+    if let Some(power_state) = state.power_state {
+      info!("restore_state(): starting in power state {}", power_state);
+      let mut power = lock(&self.shared.power);
+      power.restore(power_state, state.power_transitions, self.env.clock.now());
+    }
+    self.env.transport.restore_link_stats(state.link_stats);
 
-    data.gps = t_gps;
+    // They are reported from memory now
+    let restored = self.env.store.update(|state| {
+      state.power_transitions.clear();
+      state.link_stats = LinkStats::default();
+    });
+    if let Err(e) = restored {
+      error!("restore_state(): {:?}", e);
+    }
+
+    match self.env.store.start() {
+      Ok(session) => {
+        info!(
+          "restore_state(): boot {}, the last session ended with {}",
+          session.boots, session.previous_end
+        );
+        *lock(&self.shared.session) = Some(session);
+      }
+      Err(e) => error!("restore_state(): could not start the session: {:?}", e),
+    }
+  }
+
+  ///
+  /// Save the power state and the last fix, only the values that changed are
+  /// written.
+  ///
+  fn save_state(&self, now: Instant) {
+    let power_state = lock(&self.shared.power).state();
+    let last_fix = lock(&self.shared.last_fix)
+      .position(&self.config.gps, now)
+      .map(|position| SavedFix {
+        latitude: position.latitude,
+        longitude: position.longitude,
+        fix_time: position.fix_time,
+      });
+
+    let saved = self.env.store.update(|state| {
+      state.power_state = Some(power_state);
+      if last_fix.is_some() {
+        state.last_fix = last_fix;
+      }
+    });
+    if let Err(e) = saved {
+      error!("save_state(): {:?}", e);
+    }
+  }
+
+  fn flush_timeout(&self) -> Duration {
+    Duration::from_secs(self.config.power.flush_timeout_secs)
+  }
+
+  /// When the next timer is due, if there is no action before then.
+  pub fn deadline(&self) -> Instant {
+    let deadline = self
+      .next_power_check
+      .min(self.last_data + FX30_NO_DATA_WAIT);
+    match self.state {
+      State::Running => deadline,
+      State::Stopping { until, .. } => deadline.min(until),
+    }
+  }
+
+  ///
+  /// Handle an action, then run the timers that are due.  None when there
+  /// was no action before the deadline.
+  ///
+  pub fn handle(&mut self, action: Option<ControllerAction>) -> Result<(), GiftError> {
+    if let Some(action) = action {
+      self.handle_action(action)?;
+    }
+    self.run_timers()
+  }
+
+  fn handle_action(&mut self, action: ControllerAction) -> Result<(), GiftError> {
+    match action {
+      CtrlBuoyData(data) => {
+        if !data.hydrophone.is_empty() {
+          *lock(&self.shared.last_recording) = Some(self.env.clock.now());
+        }
+        self.transmit(data);
+      }
+      CtrlServerCmd(action) => {
+        let command = action.to_string();
+        if let Err(e) = self
+          .env
+          .store
+          .update(|state| state.last_command = Some(command))
+        {
+          error!("controller(): could not save the command: {:?}", e);
+        }
+        handle_fx30_command(action)?
+      }
+      CtrlServerTime(date, received) => {
+        lock(&self.shared.clock).add_reference(date, TimeSource::Server, received);
+      }
+      CtrlDriftAlert(alert) => {
+        warn!("controller(): sending drift alert: {}", alert.encode());
+        let mut data = self.empty_data()?;
+        data.drift_alert = Some(alert);
+        self.transmit(data);
+      }
+      CtrlRecordingStopped => {
+        if let State::Stopping { sleep, .. } = self.state {
+          self.sleep(sleep);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn run_timers(&mut self) -> Result<(), GiftError> {
+    let now = self.env.clock.now();
+    if now >= self.next_power_check {
+      self.next_power_check = now + Duration::from_secs(self.config.power.check_interval_secs);
+      self.check_power(now);
+      self.save_state(now);
+    }
+
+    if let State::Stopping { sleep, until } = self.state {
+      if now >= until {
+        warn!("controller(): the recording did not stop, sleeping anyway");
+        self.sleep(sleep);
+      }
+    }
+
+    // A sleep moves the clock on
+    if self.env.clock.now() >= self.last_data + FX30_NO_DATA_WAIT {
+      debug!("Timed out waiting for hydrophone data");
+      let data = self.empty_data()?;
+      self.transmit(data);
+    }
+    Ok(())
+  }
 
-    // Send the data to the cloud
-    match conn.send(&data) {
-      Ok(_) => (),
-      Err(e) => {
-        // Errors are handled in `conn.send()`
-        // TODO: should count the number of errors here
-        error!("conn.send() failed: {:?}", e)
+  ///
+  /// Feed the filtered battery voltage to the power policy, and start going
+  /// to sleep when the policy says so.  See power.rs for the states.
+  ///
+  fn check_power(&mut self, now: Instant) {
+    let battery = lock(&self.env.battery).clone();
+    if !battery.is_settled(&self.config.battery) {
+      info!("check_power(): waiting for the battery filter to settle");
+      return;
+    }
+
+    let system = format_date(&self.env.clock.utc());
+    let sleep = {
+      let mut power = lock(&self.shared.power);
+      if let Some(transition) = power.update(battery.voltage, now, &system) {
+        warn!(
+          "check_power(): {} -> {} (battery {:.2} V, {:.0}%, trend {:?} V/h)",
+          transition.from, transition.to, battery.voltage, battery.soc, battery.trend
+        );
       }
+      power.sleep_due(now)
     };
-  });
+
+    if let (Some(sleep), State::Running) = (sleep, self.state) {
+      info!("check_power(): going to sleep for {:?}", sleep);
+      self.env.shutdown.stop_recording();
+      self.state = State::Stopping {
+        sleep,
+        until: now + self.flush_timeout(),
+      };
+    }
+  }
+
+  ///
+  /// Go to sleep once the last recording has been uploaded, or we gave up
+  /// waiting for it, see shutdown.rs.
+  ///
+  fn sleep(&mut self, sleep: Duration) {
+    self.env.transport.flush(self.flush_timeout());
+
+    // Tell the server when to expect us
+    if let Err(e) = self.env.transport.send_sleep_notice(sleep) {
+      error!("sleep(): the sleep notice was not sent: {:?}", e);
+    }
+
+    // Keep what would be lost when the FX30 is switched off
+    let clock = lock(&self.shared.clock).clone();
+    let (power_state, power_transitions) = {
+      let power = lock(&self.shared.power);
+      (power.state(), power.transitions(&clock))
+    };
+    let link_stats = self.env.transport.link_stats();
+    let saved = self
+      .env
+      .store
+      .update(|state| {
+        state.power_state = Some(power_state);
+        state.power_transitions = power_transitions;
+        state.link_stats = link_stats;
+      })
+      .and_then(|_| self.env.store.end_session(SessionEnd::Ulpm));
+    if let Err(e) = saved {
+      error!("sleep(): could not save the state: {:?}", e);
+    }
+
+    info!("sleep(): entering ULPM for {:?}", sleep);
+    if let Err(e) = self.env.platform.enter_ulpm(sleep) {
+      error!("sleep(): ULPM failed: {:?}", e);
+    }
+
+    // ULPM did not switch us off, e.g. the simulator, so carry on from memory
+    let resumed = self
+      .env
+      .store
+      .update(|state| {
+        state.power_transitions.clear();
+        state.link_stats = LinkStats::default();
+      })
+      .and_then(|_| self.env.store.resume());
+    if let Err(e) = resumed {
+      error!("sleep(): could not save the state: {:?}", e);
+    }
+    let now = self.env.clock.now();
+    lock(&self.shared.power).woke(now);
+    self.last_data = now;
+    self.next_power_check = now + Duration::from_secs(self.config.power.check_interval_secs);
+    self.state = State::Running;
+    self.env.shutdown.resume();
+  }
+
+  /// Buoy data without a recording.
+  fn empty_data(&self) -> Result<BuoyData, GiftError> {
+    let start = (format_date(&self.env.clock.utc()), self.env.clock.now());
+    sensor_reader::create_buoy_data(None, Some(start), &self.env.battery)
+  }
+
+  ///
+  /// Complete the buoy data and hand it to the transport.
+  ///
+  fn transmit(&mut self, mut data: BuoyData) {
+    let now = self.env.clock.now();
+    self.last_data = now;
+
+    // Check what the power policy allows, drift alerts are always sent
+    let features = lock(&self.shared.power).features();
+    if !features.uploads && data.drift_alert.is_none() {
+      info!("transmit(): uploads are disabled by the power policy");
+      return;
+    }
+    if !features.recording {
+      data.hydrophone.clear();
+    }
+    data.session = lock(&self.shared.session).clone();
+
+    // Correct the start time, the system clock may not have been set
+    let clock = lock(&self.shared.clock).clone();
+    let (start_time, source) = clock.stamp(data.start_instant, &data.start_time);
+    data.start_time = start_time;
+    data.start_time_source = source;
+    data.clock = clock.status(now, self.env.clock.utc());
+    data.light = Some(lock(&self.shared.light).status());
+    data.restarts = self.env.supervisor.restarts();
+    data.position = lock(&self.shared.last_fix).position(&self.config.gps, now);
+    {
+      let mut power = lock(&self.shared.power);
+      data.power_state = power.state();
+      data.power_transitions = power.take_transitions(&clock);
+    }
+
+    // The modem state before connecting, it may explain a failure
+    data.modem = modem_snapshot(self.env.platform.as_ref());
+
+    self.env.transport.upload(data);
+  }
+}
+
+///
+/// Wait for actions, and run the timers when they are due.
+///
+pub fn run(
+  controller: &mut Controller,
+  action_rx: &Receiver<ControllerAction>,
+) -> Result<(), GiftError> {
+  loop {
+    let now = controller.env.clock.now();
+    let deadline = controller.deadline();
+    let timeout = if deadline > now {
+      deadline - now
+    } else {
+      Duration::from_secs(0)
+    };
+
+    match action_rx.recv_timeout(timeout) {
+      Ok(action) => controller.handle(Some(action))?,
+      Err(RecvTimeoutError::Timeout) => controller.handle(None)?,
+      Err(e) => error!("controller(): Waiting for recv: {:?}", e),
+    }
+  }
 }
 
 ///
-/// The main loop
+/// Start the tasks around the controller, and run it.
 ///
+#[allow(clippy::too_many_arguments)]
 pub fn controller(
-  url: &Url,
-  ca_path: &PathBuf,
-  action_tx: Sender<ControllerAction>,
+  config: &BuoyConfig,
+  platform: &Arc<dyn Platform>,
+  battery: &Arc<Mutex<BatteryState>>,
+  supervisor: &Supervisor,
+  shutdown: &Arc<Shutdown>,
+  store: &Arc<StateStore>,
+  uplink: Uplink,
   action_rx: Receiver<ControllerAction>,
 ) -> Result<(), GiftError> {
-  let last_gps = Arc::new(Mutex::new(String::new()));
+  let shared = Shared::new(config, Instant::now());
+  let action_tx = Sender::clone(&uplink.action_tx);
+  let transport: Arc<dyn Transport> = Arc::new(QuicTransport::new(
+    uplink,
+    &shared.power,
+    &shared.session,
+    Path::new(BUOY_SPOOL_PATH),
+    store,
+  ));
+  let env = Env {
+    clock: Arc::new(SystemClock),
+    platform: Arc::clone(platform),
+    transport: Arc::clone(&transport),
+    battery: Arc::clone(battery),
+    supervisor: supervisor.clone(),
+    shutdown: Arc::clone(shutdown),
+    store: Arc::clone(store),
+  };
+  let mut controller = Controller::new(config, env, shared.clone());
 
-  // Stuff for the fx30 only
-  #[cfg(feature = "fx30")]
-  {
-    // Tell the GPS every once in a while to do a capture
-    read_gps_thread(Arc::clone(&last_gps));
+  // A missing GPS receiver shouldn't stop the buoy, the FX30 has its own GNSS
+  let gnss = create_source(&config.gps.source, platform).unwrap_or_else(|e| {
+    error!(
+      "controller(): GPS source {:?} failed, using the platform GNSS: {:?}",
+      config.gps.source, e
+    );
+    Box::new(CliSource::new(platform))
+  });
 
-    // Create a thread the blinks the light every so many seconds
-    blink_buoy_light();
-  }
+  // Tell the GPS every once in a while to do a capture
+  read_gps_thread(
+    supervisor,
+    gnss,
+    Arc::clone(&shared.last_fix),
+    Arc::clone(&shared.clock),
+    Arc::clone(&shared.power),
+    config.geofence.as_ref().map(Geofence::new),
+    action_tx,
+  );
 
-  // Create the Power management thread
-  power_management();
+  // Create a thread the blinks the light every so many seconds
+  blink_buoy_light(
+    supervisor,
+    Arc::clone(platform),
+    config,
+    Arc::clone(&shared.last_fix),
+    Arc::clone(&shared.clock),
+    Arc::clone(&controller.env.clock),
+    Arc::clone(&shared.power),
+    Arc::clone(&shared.light),
+  )?;
 
-  // The main loop
-  loop {
-    //
-    // Wait
-    //
+  // Tell the server we are alive, whatever the hydrophone is doing
+  heartbeat_task(
+    supervisor,
+    config,
+    HeartbeatSources {
+      platform: Arc::clone(platform),
+      last_fix: shared.last_fix,
+      light: shared.light,
+      power: shared.power,
+      last_recording: shared.last_recording,
+      transport,
+    },
+  );
 
-    thread::sleep(buoy_code::FX30_SEND_INTERVAL);
+  run(&mut controller, &action_rx)
+}
 
-    //
-    // Gather all necessary data
-    //
+#[cfg(test)]
+mod tests {
+  use crate::controller::*;
+  use crate::platform::sim::{SimPlatform, SimScript};
+  use crate::voltage::Calibration;
+  use buoy_code::heartbeat::Heartbeat;
+  use buoy_code::link_stats::LinkStats;
+  use buoy_code::modem::ModemStatus;
+  use buoy_code::power_state::PowerState;
+  use buoy_code::task_restarts::TaskRestarts;
 
-    loop {
-      let action = action_rx.recv_timeout(buoy_code::FX30_NO_DATA_WAIT);
-
-      // Handle the action
-      match action {
-        Ok(CtrlBuoyData(data)) => transmit_data(
-          url.clone(),
-          ca_path.clone(),
-          Sender::clone(&action_tx),
-          &last_gps,
-          data,
-        ),
-        Ok(CtrlServerCmd(action)) => handle_fx30_command(action)?,
-        Err(RecvTimeoutError::Timeout) => {
-          debug!("Timed out waiting for hydrophone data");
-          let data = sensor_reader::create_buoy_data(None, None)?;
-          transmit_data(
-            url.clone(),
-            ca_path.clone(),
-            Sender::clone(&action_tx),
-            &last_gps,
-            data,
-          )
+  #[derive(Default)]
+  struct FakeTransport {
+    uploads: Mutex<Vec<BuoyData>>,
+    sleep_notices: Mutex<Vec<Duration>>,
+    session: Arc<Mutex<Option<Session>>>,
+    failing: Mutex<bool>, // The uploads don't get through
+  }
+
+  impl Transport for FakeTransport {
+    fn upload(&self, data: BuoyData) {
+      if !*self.failing.lock().unwrap() {
+        let mut session = self.session.lock().unwrap();
+        if data.session.is_some() && *session == data.session {
+          *session = None;
         }
-        Err(e) => error!("controller(): Waiting for recv: {:?}", e),
       }
+      self.uploads.lock().unwrap().push(data);
+    }
+
+    fn in_flight(&self) -> usize {
+      0
+    }
+
+    fn flush(&self, _timeout: Duration) {}
+
+    fn send_heartbeat(
+      &self,
+      _heartbeat: &Heartbeat,
+      _restarts: &TaskRestarts,
+      _modem: Option<&ModemStatus>,
+    ) -> Result<(), GiftError> {
+      Ok(())
+    }
+
+    fn send_sleep_notice(&self, sleep: Duration) -> Result<(), GiftError> {
+      self.sleep_notices.lock().unwrap().push(sleep);
+      Ok(())
+    }
+
+    fn link_stats(&self) -> LinkStats {
+      LinkStats::default()
+    }
+
+    fn restore_link_stats(&self, _stats: LinkStats) {}
+  }
+
+  /// A controller on the simulator, with a settled battery at `voltage`.
+  fn simulation(voltage: f32) -> (Controller, Arc<SimPlatform>, Arc<FakeTransport>) {
+    let config = BuoyConfig::default();
+    let sim = Arc::new(SimPlatform::manual(
+      &SimScript::default(),
+      &Calibration::default(),
+    ));
+    let platform: Arc<dyn Platform> = sim.clone();
+    let shared = Shared::new(&config, Clock::now(sim.as_ref()));
+    let transport = Arc::new(FakeTransport {
+      session: Arc::clone(&shared.session),
+      ..FakeTransport::default()
+    });
+    let battery = BatteryState {
+      voltage,
+      samples: config.battery.median_window,
+      ..BatteryState::default()
+    };
+    let store = Arc::new(StateStore::memory());
+    let env = Env {
+      clock: sim.clone(),
+      platform: Arc::clone(&platform),
+      transport: transport.clone(),
+      battery: Arc::new(Mutex::new(battery)),
+      supervisor: Supervisor::new(&config.supervisor, &platform, &store),
+      shutdown: Arc::new(Shutdown::default()),
+      store,
+    };
+    (Controller::new(&config, env, shared), sim, transport)
+  }
+
+  /// Jump from deadline to deadline until `secs` of simulated time.
+  fn run_until(controller: &mut Controller, sim: &SimPlatform, secs: f64) {
+    while sim.now() < secs {
+      let now = Clock::now(sim);
+      let deadline = controller.deadline();
+      if deadline > now {
+        sim.advance(deadline - now);
+      }
+      controller.handle(None).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_no_data() {
+    let (mut controller, sim, transport) = simulation(12.6);
+
+    let mut data =
+      sensor_reader::create_buoy_data(Some(vec![1, 2, 3]), None, &controller.env.battery).unwrap();
+    data.start_instant = Clock::now(sim.as_ref());
+    controller.handle(Some(CtrlBuoyData(data))).unwrap();
+    assert!(controller.shared.last_recording.lock().unwrap().is_some());
+
+    // A day without the hydrophone, an upload every FX30_NO_DATA_WAIT
+    run_until(&mut controller, &sim, 24.0 * 3600.0);
+    let uploads = transport.uploads.lock().unwrap();
+    assert_eq!(1 + 48, uploads.len());
+    assert_eq!(vec![1, 2, 3], uploads[0].hydrophone);
+    assert!(uploads[1..].iter().all(|u| u.hydrophone.is_empty()));
+    assert!(sim.ulpm_log().is_empty());
+  }
+
+  #[test]
+  fn test_daylight() {
+    let (controller, sim, _) = simulation(12.6);
+    let last_fix = Mutex::new(LastFix::default());
+    let deployment = Some((-36.84, 174.76));
+
+    // Day and night follow the simulated clock
+    let mut days = Vec::new();
+    for _ in 0..24 {
+      let is_day = is_daylight(
+        &last_fix,
+        deployment,
+        &controller.shared.clock,
+        controller.env.clock.as_ref(),
+      );
+      assert_eq!(
+        sun::is_daylight(&Clock::utc(sim.as_ref()), -36.84, 174.76),
+        is_day
+      );
+      days.push(is_day);
+      sim.advance(Duration::from_secs(60 * 60));
     }
+    assert!(days.contains(&true) && days.contains(&false));
+
+    assert!(!is_daylight(
+      &last_fix,
+      None,
+      &controller.shared.clock,
+      controller.env.clock.as_ref()
+    ));
+  }
+
+  #[test]
+  fn test_sleep() {
+    let (mut controller, sim, transport) = simulation(11.9);
+    let config = BuoyConfig::default().power;
+
+    // Conserve at the first check, then sleep after being awake awake_secs
+    run_until(&mut controller, &sim, 600.0);
+    assert_eq!(
+      PowerState::Conserve,
+      controller.shared.power.lock().unwrap().state()
+    );
+    assert!(controller.env.shutdown.is_stopping());
+    assert!(sim.ulpm_log().is_empty());
+
+    controller.handle(Some(CtrlRecordingStopped)).unwrap();
+    let sleep = Duration::from_secs(config.conserve.sleep_secs);
+    assert_eq!(vec![(600.0, sleep)], sim.ulpm_log());
+    assert_eq!(vec![sleep], *transport.sleep_notices.lock().unwrap());
+    assert!(!controller.env.shutdown.is_stopping());
+    assert_eq!(
+      SessionEnd::Running,
+      controller.env.store.state().session_end
+    );
+
+    // The recording does not stop this time, so it sleeps after flush_timeout_secs
+    let stopping = sim.now() + config.conserve.awake_secs as f64;
+    let asleep = stopping + config.flush_timeout_secs as f64;
+    run_until(&mut controller, &sim, asleep + 1.0);
+    assert_eq!(vec![(600.0, sleep), (asleep, sleep)], sim.ulpm_log());
+  }
+
+  #[test]
+  fn test_restore_state() {
+    let (mut controller, _, transport) = simulation(11.2);
+    let session = controller.shared.session.lock().unwrap().clone().unwrap();
+    assert_eq!((1, SessionEnd::New), (session.boots, session.previous_end));
+
+    // Went to sleep in low power
+    controller
+      .env
+      .store
+      .update(|state| {
+        state.power_state = Some(PowerState::Low);
+        state
+          .link_stats
+          .record_failure(buoy_code::link_stats::FAIL_CONNECT);
+      })
+      .unwrap();
+    controller.env.store.end_session(SessionEnd::Ulpm).unwrap();
+    controller.restore_state();
+    assert_eq!(
+      PowerState::Low,
+      controller.shared.power.lock().unwrap().state()
+    );
+    assert!(controller.env.store.state().link_stats.is_empty());
+    let session = controller.shared.session.lock().unwrap().clone().unwrap();
+    assert_eq!((2, SessionEnd::Ulpm), (session.boots, session.previous_end));
+
+    // Then crashed, the session is sent until an upload gets through
+    controller.restore_state();
+    *transport.failing.lock().unwrap() = true;
+    let data = controller.empty_data().unwrap();
+    controller.transmit(data);
+    *transport.failing.lock().unwrap() = false;
+    let data = controller.empty_data().unwrap();
+    controller.transmit(data);
+    let data = controller.empty_data().unwrap();
+    controller.transmit(data);
+    let uploads = transport.uploads.lock().unwrap();
+    assert_eq!(uploads[0].session, uploads[1].session);
+    let session = uploads[1].session.clone().unwrap();
+    assert_eq!(
+      (3, SessionEnd::Crash),
+      (session.boots, session.previous_end)
+    );
+    assert!(uploads[2].session.is_none());
   }
 }
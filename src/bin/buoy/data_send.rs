@@ -20,19 +20,72 @@
 use std::fs;
 use std::net::ToSocketAddrs;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use futures::Future;
 use quinn_proto;
+use slog::{Drain, Level, Logger, Never, OwnedKVList, Record};
 use tokio::prelude::*;
 use tokio::runtime::current_thread::Runtime;
 use url::Url;
 
+use buoy_code::clock::{HEADER_CLOCK, HEADER_START_TIME_SOURCE};
+use buoy_code::commands::FX30Command;
+use buoy_code::drift_alert::HEADER_DRIFT_ALERT;
 use buoy_code::errors::GiftError;
+use buoy_code::heartbeat::{Heartbeat, HEADER_HEARTBEAT};
+use buoy_code::light_status::HEADER_LIGHT_STATUS;
+use buoy_code::link_stats::{self, LinkStats, HEADER_LINK_STATS};
+use buoy_code::modem::{ModemStatus, HEADER_MODEM};
+use buoy_code::power_state::{encode_transitions, HEADER_POWER_TRANSITIONS};
+use buoy_code::session::HEADER_SESSION;
+use buoy_code::task_restarts::{TaskRestarts, HEADER_TASK_RESTARTS};
 use buoy_code::BuoyData;
 use buoy_code::ControllerAction;
-use buoy_code::{END_BOUNDARY, SW_VERSION};
+
+use crate::platform::Platform;
+use crate::supervisor::lock;
+use buoy_code::{
+  BUOY_ID, END_BOUNDARY, HEADER_BATTERY_SOC, HEADER_BATTERY_TREND, HEADER_SLEEP, SW_VERSION,
+};
+
+///
+/// A snapshot of the modem state, None if the modem reported nothing.
+///
+pub fn modem_snapshot(platform: &dyn Platform) -> Option<ModemStatus> {
+  match platform.modem_info() {
+    Ok(output) => Some(ModemStatus::parse(&output)).filter(|s| *s != ModemStatus::default()),
+    Err(e) => {
+      error!("modem_snapshot(): {:?}", e);
+      None
+    }
+  }
+}
+
+///
+/// Where and how to connect to the server.
+///
+#[derive(Clone)]
+pub struct Uplink {
+  pub url: Url,
+  pub ca_path: PathBuf,
+  pub action_tx: Sender<ControllerAction>, // Server responses go to the controller
+  pub link_stats: Arc<Mutex<LinkStats>>,
+}
+
+impl Uplink {
+  pub fn connect(&self) -> Result<Transmit<'_>, GiftError> {
+    Transmit::new(
+      self.url.clone(),
+      self.ca_path.clone(),
+      &self.action_tx,
+      Arc::clone(&self.link_stats),
+    )
+  }
+}
 
 pub struct Transmit<'a> {
   remote: std::net::SocketAddr,
@@ -40,6 +93,92 @@ pub struct Transmit<'a> {
   // endpoint: quinn::Endpoint,
   host: String,
   action_tx: &'a Sender<ControllerAction>,
+  link_stats: Arc<Mutex<LinkStats>>,
+  retransmits: Arc<AtomicU64>, // Of the last upload
+}
+
+// How quinn 0.4 traces loss, it has no connection statistics
+const TRACE_LOST: &str = "packets lost: ";
+const TRACE_HANDSHAKE_LOST: &str = "retransmitting handshake packets";
+
+///
+/// The number of packets a quinn trace message says are being retransmitted,
+/// e.g. "packets lost: [3, 4]" is 2.
+///
+fn retransmits_traced(msg: &str) -> u64 {
+  if msg.starts_with(TRACE_LOST) {
+    msg[TRACE_LOST.len()..]
+      .split(',')
+      .filter(|pn| {
+        !pn
+          .trim_matches(|c| c == '[' || c == ']' || c == ' ')
+          .is_empty()
+      })
+      .count() as u64
+  } else if msg == TRACE_HANDSHAKE_LOST {
+    1
+  } else {
+    0
+  }
+}
+
+///
+/// A quinn logger that counts the retransmitted packets.
+///
+struct RetransmitCounter(Arc<AtomicU64>);
+
+impl Drain for RetransmitCounter {
+  type Ok = ();
+  type Err = Never;
+
+  fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), Never> {
+    if record.level() == Level::Trace && record.module().starts_with("quinn_proto::connection") {
+      let count = retransmits_traced(&record.msg().to_string());
+      self.0.fetch_add(count, Ordering::Relaxed);
+    }
+    Ok(())
+  }
+}
+
+///
+/// The reason an upload failed, the stage is one of the `link_stats::FAIL_*`
+/// values.
+///
+#[derive(Debug, Fail)]
+#[fail(display = "{} failed: {}", stage, msg)]
+struct UploadFailure {
+  stage: &'static str,
+  msg: String,
+}
+
+fn upload_failure<E: std::fmt::Display>(stage: &'static str) -> impl Fn(E) -> failure::Error {
+  move |e| {
+    UploadFailure {
+      stage,
+      msg: e.to_string(),
+    }
+    .into()
+  }
+}
+
+///
+/// Convert the reason a connection was lost to a close code, e.g. "app.0".
+/// Returns None if we closed the connection ourselves.
+///
+fn close_code(err: &quinn::ConnectionError) -> Option<String> {
+  match err {
+    quinn::ConnectionError::ApplicationClosed { reason } => {
+      Some(format!("app.{}", reason.error_code))
+    }
+    quinn::ConnectionError::ConnectionClosed { reason } => {
+      Some(format!("transport.{}", reason.error_code))
+    }
+    quinn::ConnectionError::TimedOut => Some(String::from("timeout")),
+    quinn::ConnectionError::Reset => Some(String::from("reset")),
+    quinn::ConnectionError::VersionMismatch => Some(String::from("version")),
+    quinn::ConnectionError::TransportError(_) => Some(String::from("transport")),
+    quinn::ConnectionError::LocallyClosed => None,
+  }
 }
 
 impl<'a> Transmit<'a> {
@@ -47,6 +186,7 @@ impl<'a> Transmit<'a> {
     url: Url,
     ca_path: PathBuf,
     action_tx: &'a Sender<ControllerAction>,
+    link_stats: Arc<Mutex<LinkStats>>,
   ) -> Result<Self, GiftError> {
     let remote = url
       .with_default_port(|_| Ok(4433))?
@@ -72,21 +212,118 @@ impl<'a> Transmit<'a> {
           .ok_or_else(|| format_err!("URL missing host"))?,
       ),
       action_tx,
+      link_stats,
+      retransmits: Arc::new(AtomicU64::new(0)),
     })
   }
 
+  ///
+  /// Send the buoy data to the server.  The link statistics collected so far
+  /// are sent with the data, and the statistics of this upload are recorded for
+  /// the next one.
+  ///
   pub fn send(&mut self, buoy: &BuoyData) -> Result<(), GiftError> {
     info!("Sending request");
 
+    let report = lock(&self.link_stats).take();
+    let start = Instant::now();
+
+    self.retransmits.store(0, Ordering::Relaxed);
+    let result = build_http_post(buoy, &report).and_then(|request| self.upload(request));
+    let retransmits = self.retransmits.load(Ordering::Relaxed);
+    lock(&self.link_stats).record_retransmits(retransmits);
+
+    match result {
+      Ok((handshake, rtt)) => {
+        let total = start.elapsed();
+        let seconds = duration_secs(&total);
+        let kb = buoy.hydrophone.len() as f32 / 1024.0;
+        info!(
+          "uploaded: {:0.1} kB at {:0.2} kB/s (handshake: {} ms, rtt: {} ms)",
+          kb,
+          kb / seconds,
+          handshake.as_millis(),
+          rtt.as_millis()
+        );
+        let mut stats = lock(&self.link_stats);
+        stats.record_upload(buoy.hydrophone.len(), handshake, rtt, total);
+        Ok(())
+      }
+      Err(e) => {
+        let stage = match e {
+          GiftError::StdFailure(ref f) => f
+            .downcast_ref::<UploadFailure>()
+            .map_or(link_stats::FAIL_OTHER, |u| u.stage),
+          GiftError::QuinnEndpoint(_) | GiftError::QuinnConnect(_) => link_stats::FAIL_CONNECT,
+          GiftError::HttpStatus(_) => link_stats::FAIL_SERVER,
+          _ => link_stats::FAIL_OTHER,
+        };
+        let mut stats = lock(&self.link_stats);
+        stats.merge(report);
+        stats.record_failure(stage);
+        Err(e)
+      }
+    }
+  }
+
+  ///
+  /// Send a heartbeat to the server, it's not counted in the link statistics.
+  ///
+  pub fn send_heartbeat(
+    &mut self,
+    heartbeat: &Heartbeat,
+    restarts: &TaskRestarts,
+    modem: Option<&ModemStatus>,
+  ) -> Result<(), GiftError> {
+    let (handshake, rtt) = self.upload(build_heartbeat_post(heartbeat, restarts, modem))?;
+    debug!(
+      "heartbeat sent (handshake: {} ms, rtt: {} ms)",
+      handshake.as_millis(),
+      rtt.as_millis()
+    );
+    Ok(())
+  }
+
+  ///
+  /// Send a request that was spooled before going to sleep, see spool.rs.
+  ///
+  pub fn send_request(&mut self, request: Vec<u8>) -> Result<(), GiftError> {
+    let (handshake, rtt) = self.upload(request)?;
+    info!(
+      "spooled upload sent (handshake: {} ms, rtt: {} ms)",
+      handshake.as_millis(),
+      rtt.as_millis()
+    );
+    Ok(())
+  }
+
+  ///
+  /// Tell the server we are going to sleep, so it knows when to expect us.
+  ///
+  pub fn send_sleep_notice(&mut self, sleep: Duration) -> Result<(), GiftError> {
+    self.upload(build_sleep_post(sleep))?;
+    Ok(())
+  }
+
+  ///
+  /// Do the upload, returns the handshake time and the time it took to get the
+  /// response once the request was sent.
+  ///
+  fn upload(&mut self, request: Vec<u8>) -> Result<(Duration, Duration), GiftError> {
     //
     // Build the runtime used to send the data
     //
-    let request = build_http_post(buoy)?;
     let action_tx = Sender::clone(&self.action_tx);
+    let driver_stats = Arc::clone(&self.link_stats);
     let start = Instant::now();
 
+    self.retransmits.store(0, Ordering::Relaxed);
     let mut endpoint = quinn::Endpoint::builder();
     endpoint.default_client_config(self.client_config.clone());
+    endpoint.logger(Logger::root(
+      RetransmitCounter(Arc::clone(&self.retransmits)),
+      slog::o!(),
+    ));
     let (endpoint_driver, endpoint, _) = endpoint.bind("0.0.0.0:0")?;
 
     let mut runtime = Runtime::new()?;
@@ -96,46 +333,64 @@ impl<'a> Transmit<'a> {
     // The send-data thread
     //
 
-    runtime.block_on(
+    let times = runtime.block_on(
       endpoint
         .connect(&self.remote, &self.host)?
         .timeout(buoy_code::FX30_CONNECT_TIMEOUT)
-        .map_err(|e| format_err!("failed to connect: {}", e))
+        .map_err(|e| {
+          if e.is_elapsed() {
+            upload_failure(link_stats::FAIL_TIMEOUT)(e)
+          } else {
+            upload_failure(link_stats::FAIL_CONNECT)(e)
+          }
+        })
         .and_then(move |new_conn| {
-          tokio_current_thread::spawn(
-            new_conn
-              .driver
-              .map_err(|e| eprintln!("connection lost: {}", e)),
-          );
+          let handshake = start.elapsed();
+          tokio_current_thread::spawn(new_conn.driver.map_err(move |e| {
+            eprintln!("connection lost: {}", e);
+            if let Some(code) = close_code(&e) {
+              lock(&driver_stats).record_close_code(&code);
+            }
+          }));
           let conn = new_conn.connection;
           let stream = conn.open_bi();
           stream
-            .map_err(|e| format_err!("failed to open stream: {}", e))
+            .map_err(upload_failure(link_stats::FAIL_STREAM))
             .and_then(move |(send, recv)| {
               // Send the request
               tokio::io::write_all(send, request.to_owned())
                 .timeout(buoy_code::FX30_UPLOAD_SEND_TIMEOUT)
-                .map_err(|e| format_err!("failed to send request: {}", e))
-                .and_then(|(send, _)| {
-                  send
-                    .finish()
-                    .map_err(|e| format_err!("failed to shutdown stream: {}", e))
+                .map_err(|e| {
+                  if e.is_elapsed() {
+                    upload_failure(link_stats::FAIL_TIMEOUT)(e)
+                  } else {
+                    upload_failure(link_stats::FAIL_SEND)(e)
+                  }
                 })
+                .and_then(|(send, _)| send.finish().map_err(upload_failure(link_stats::FAIL_SEND)))
                 .and_then(move |_| {
+                  let sent = Instant::now();
                   recv
                     .read_to_end(usize::max_value())
                     .timeout(buoy_code::FX30_UPLOAD_RESP_TIMEOUT)
-                    .map_err(|e| format_err!("failed to read response: {}", e))
-                    .map(move |resp| {
-                      buoy_code::commands::handle_server_response(action_tx, &resp).unwrap();
+                    .map_err(|e| {
+                      if e.is_elapsed() {
+                        upload_failure(link_stats::FAIL_TIMEOUT)(e)
+                      } else {
+                        upload_failure(link_stats::FAIL_RESPONSE)(e)
+                      }
+                    })
+                    .and_then(move |resp| {
+                      let rtt = sent.elapsed();
+                      buoy_code::commands::handle_server_response(action_tx, &resp)
+                        .map(|command| (rtt, command))
+                        .map_err(|e| upload_failure(link_stats::FAIL_RESPONSE)(format!("{:?}", e)))
                     })
                 })
             })
-            .map(move |_| {
-              let seconds = duration_secs(&start.elapsed());
-              let kb = buoy.hydrophone.len() as f32 / 1024.0;
-              info!("uploaded: {:0.1} kB at {:0.2} kB/s", kb, kb / seconds);
+            .map(move |(rtt, command)| {
               conn.close(0u32.into(), b"done");
+              (handshake, rtt, command)
             })
         }),
     )?;
@@ -151,34 +406,81 @@ impl<'a> Transmit<'a> {
     // Let the connection to finish closing gracefully
     runtime.run().unwrap(); // FIXME: Need to handle this
 
-    Ok(())
+    // The request went through but the server did not accept it, the command
+    // tells the caller whether to retry, drop or alert
+    match times {
+      (handshake, rtt, FX30Command::Normal) => Ok((handshake, rtt)),
+      (_, _, command) => Err(GiftError::HttpStatus(command)),
+    }
   }
 }
 
-fn build_http_post(buoy: &BuoyData) -> Result<Vec<u8>, GiftError> {
-  let header = format!(
+pub fn build_http_post(buoy: &BuoyData, report: &LinkStats) -> Result<Vec<u8>, GiftError> {
+  let mut header = format!(
     "POST /id/{} HTTP/1.1\r\n\
      Host: /id/{}\r\n\
      Content-Type: multipart/form-data\r\n\
      Battery-Voltage: {}\r\n\
+     {}: {:.0}\r\n\
+     Power-State: {}\r\n\
      Dropped-Blocks: {}\r\n\
-     GPS: {}\r\n\
      Start-Time: {}\r\n\
+     {}: {}\r\n\
      Uptime: {}\r\n\
      sw-version: {}\r\n\
-     length: {}{}",
+     length: {}\r\n",
     buoy.id,
     buoy.id,
     buoy.voltage,
+    HEADER_BATTERY_SOC,
+    buoy.battery_soc,
+    buoy.power_state,
     buoy.dropped_blocks,
-    buoy.gps,
     buoy.start_time,
+    HEADER_START_TIME_SOURCE,
+    buoy.start_time_source,
     buoy.uptime,
     SW_VERSION,
     buoy.hydrophone.len(),
-    buoy_code::HTTP_HEADER_END
-  )
-  .into_bytes();
+  );
+  if let Some(position) = &buoy.position {
+    header.push_str(&position.to_headers());
+  }
+  if let Some(clock) = &buoy.clock {
+    header.push_str(&format!("{}: {}\r\n", HEADER_CLOCK, clock.encode()));
+  }
+  if let Some(alert) = &buoy.drift_alert {
+    header.push_str(&format!("{}: {}\r\n", HEADER_DRIFT_ALERT, alert.encode()));
+  }
+  if let Some(modem) = &buoy.modem {
+    header.push_str(&format!("{}: {}\r\n", HEADER_MODEM, modem.encode()));
+  }
+  if let Some(light) = &buoy.light {
+    header.push_str(&format!("{}: {}\r\n", HEADER_LIGHT_STATUS, light.encode()));
+  }
+  if let Some(session) = &buoy.session {
+    header.push_str(&format!("{}: {}\r\n", HEADER_SESSION, session.encode()));
+  }
+  header.push_str(&format!(
+    "{}: {}\r\n",
+    HEADER_TASK_RESTARTS,
+    buoy.restarts.encode()
+  ));
+  if let Some(trend) = buoy.battery_trend {
+    header.push_str(&format!("{}: {:.3}\r\n", HEADER_BATTERY_TREND, trend));
+  }
+  if !buoy.power_transitions.is_empty() {
+    header.push_str(&format!(
+      "{}: {}\r\n",
+      HEADER_POWER_TRANSITIONS,
+      encode_transitions(&buoy.power_transitions)
+    ));
+  }
+  if !report.is_empty() {
+    header.push_str(&format!("{}: {}\r\n", HEADER_LINK_STATS, report.encode()));
+  }
+  header.push_str("\r\n");
+  let header = header.into_bytes();
   let end = END_BOUNDARY.as_bytes();
   let mut post = Vec::new();
 
@@ -189,6 +491,67 @@ fn build_http_post(buoy: &BuoyData) -> Result<Vec<u8>, GiftError> {
   Ok(post)
 }
 
+fn build_heartbeat_post(
+  heartbeat: &Heartbeat,
+  restarts: &TaskRestarts,
+  modem: Option<&ModemStatus>,
+) -> Vec<u8> {
+  let mut header = format!(
+    "POST /id/{}/heartbeat HTTP/1.1\r\n\
+     Host: /id/{}\r\n\
+     {}: {}\r\n\
+     {}: {}\r\n\
+     sw-version: {}\r\n",
+    BUOY_ID,
+    BUOY_ID,
+    HEADER_HEARTBEAT,
+    heartbeat.encode(),
+    HEADER_TASK_RESTARTS,
+    restarts.encode(),
+    SW_VERSION,
+  );
+  if let Some(modem) = modem {
+    header.push_str(&format!("{}: {}\r\n", HEADER_MODEM, modem.encode()));
+  }
+  header.push_str("\r\n");
+
+  let mut post = header.into_bytes();
+  post.extend_from_slice(END_BOUNDARY.as_bytes());
+  post
+}
+
+fn build_sleep_post(sleep: Duration) -> Vec<u8> {
+  let header = format!(
+    "POST /id/{}/sleep HTTP/1.1\r\n\
+     Host: /id/{}\r\n\
+     {}: {}\r\n\
+     sw-version: {}\r\n\r\n",
+    BUOY_ID,
+    BUOY_ID,
+    HEADER_SLEEP,
+    sleep.as_secs(),
+    SW_VERSION,
+  );
+
+  let mut post = header.into_bytes();
+  post.extend_from_slice(END_BOUNDARY.as_bytes());
+  post
+}
+
 fn duration_secs(x: &Duration) -> f32 {
   x.as_secs() as f32 + x.subsec_nanos() as f32 * 1e-9
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::data_send::*;
+
+  #[test]
+  fn test_retransmits_traced() {
+    assert_eq!(2, retransmits_traced("packets lost: [3, 4]"));
+    assert_eq!(1, retransmits_traced("packets lost: [17]"));
+    assert_eq!(0, retransmits_traced("packets lost: []"));
+    assert_eq!(1, retransmits_traced(TRACE_HANDSHAKE_LOST));
+    assert_eq!(0, retransmits_traced("sending 1200 bytes in 1 datagrams"));
+  }
+}
@@ -0,0 +1,251 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Anchor-drag alarm.  The buoy has a deployment position and a watch circle
+/// around it that it should stay in while moored.  When `confirm_fixes`
+/// consecutive GPS fixes are outside the circle the alarm is raised: the GPS
+/// is read every `fast_period_secs` and a drift alert is uploaded straight
+/// away, then every `report_period_secs` while the buoy is still outside.
+///
+/// The fix accuracy is taken off the distance, so a poor fix near the edge of
+/// the circle does not raise the alarm.
+///
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use buoy_code::drift_alert::DriftAlert;
+use buoy_code::errors::GiftError;
+use buoy_code::position::{FixType, Position};
+
+fn default_confirm_fixes() -> u32 {
+  2
+}
+
+fn default_fast_period_secs() -> u64 {
+  60
+}
+
+fn default_report_period_secs() -> u64 {
+  5 * 60
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct GeofenceConfig {
+  pub latitude: f64,  // The deployment position
+  pub longitude: f64, // The deployment position
+  pub radius_m: f64,  // The watch circle radius
+  #[serde(default = "default_confirm_fixes")]
+  pub confirm_fixes: u32, // Consecutive fixes outside (or back inside) to change the alarm
+  #[serde(default = "default_fast_period_secs")]
+  pub fast_period_secs: u64, // How often to read the GPS while the alarm is raised
+  #[serde(default = "default_report_period_secs")]
+  pub report_period_secs: u64, // How often to send a drift alert while the alarm is raised
+}
+
+impl GeofenceConfig {
+  pub fn validate(&self) -> Result<(), GiftError> {
+    if self.latitude.abs() > 90.0 || self.longitude.abs() > 180.0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "geofence: invalid deployment position",
+      )));
+    }
+    if self.radius_m <= 0.0 || self.confirm_fixes == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "geofence: radius_m and confirm_fixes must be above 0",
+      )));
+    }
+    if self.fast_period_secs == 0 || self.report_period_secs == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "geofence: fast_period_secs and report_period_secs must be above 0",
+      )));
+    }
+    Ok(())
+  }
+}
+
+pub struct Geofence {
+  config: GeofenceConfig,
+  outside: u32,                 // Consecutive fixes outside
+  inside: u32,                  // Consecutive fixes inside
+  alarm: bool,                  // The alarm is raised
+  last_report: Option<Instant>, // When the last drift alert was sent
+}
+
+impl Geofence {
+  pub fn new(config: &GeofenceConfig) -> Self {
+    Geofence {
+      config: config.clone(),
+      outside: 0,
+      inside: 0,
+      alarm: false,
+      last_report: None,
+    }
+  }
+
+  pub fn is_alarm(&self) -> bool {
+    self.alarm
+  }
+
+  /// How long to wait before the next GPS fix, if the alarm is raised.
+  pub fn fast_period(&self) -> Option<Duration> {
+    if self.alarm {
+      Some(Duration::from_secs(self.config.fast_period_secs))
+    } else {
+      None
+    }
+  }
+
+  ///
+  /// Check a new fix, returns a drift alert if one should be sent now.
+  ///
+  pub fn update(&mut self, position: &Position, now: Instant) -> Option<DriftAlert> {
+    if position.stale || position.fix_type == FixType::NoFix {
+      return None;
+    }
+
+    let distance = position.distance_to(self.config.latitude, self.config.longitude);
+    let accuracy = f64::from(position.h_accuracy.unwrap_or(0.0));
+    if distance - accuracy > self.config.radius_m {
+      self.outside += 1;
+      self.inside = 0;
+    } else {
+      self.inside += 1;
+      self.outside = 0;
+    }
+
+    if !self.alarm && self.outside >= self.config.confirm_fixes {
+      warn!(
+        "Geofence: {:.0} m from the deployment position, raising the drift alarm",
+        distance
+      );
+      self.alarm = true;
+      self.last_report = None;
+    } else if self.alarm && self.inside >= self.config.confirm_fixes {
+      info!("Geofence: back inside the watch circle, clearing the drift alarm");
+      self.alarm = false;
+    }
+
+    if !self.alarm || self.outside == 0 {
+      return None;
+    }
+    let report_period = Duration::from_secs(self.config.report_period_secs);
+    if let Some(last_report) = self.last_report {
+      if now.duration_since(last_report) < report_period {
+        return None;
+      }
+    }
+
+    self.last_report = Some(now);
+    Some(DriftAlert {
+      distance_m: distance,
+      radius_m: self.config.radius_m,
+      fixes_outside: self.outside,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::geofence::*;
+  use buoy_code::clock::TimeSource;
+
+  const LAT: f64 = -36.843292;
+  const LON: f64 = 174.756864;
+
+  fn config() -> GeofenceConfig {
+    GeofenceConfig {
+      latitude: LAT,
+      longitude: LON,
+      radius_m: 100.0,
+      confirm_fixes: default_confirm_fixes(),
+      fast_period_secs: default_fast_period_secs(),
+      report_period_secs: default_report_period_secs(),
+    }
+  }
+
+  // A position `metres` north of the deployment position
+  fn north(metres: f64, h_accuracy: f32) -> Position {
+    Position {
+      latitude: LAT + metres / 111_195.0,
+      longitude: LON,
+      h_accuracy: Some(h_accuracy),
+      fix_time: String::from("20200830T023517.000Z"),
+      time_source: TimeSource::Gnss,
+      fix_type: FixType::Fix3d,
+      satellites: Some(7),
+      stale: false,
+    }
+  }
+
+  #[test]
+  fn test_drift_alarm() {
+    let mut geofence = Geofence::new(&config());
+    let start = Instant::now();
+    let at = |mins: u64| start + Duration::from_secs(mins * 60);
+
+    // Inside, and a poor fix just outside the circle
+    assert_eq!(None, geofence.update(&north(50.0, 5.0), at(0)));
+    assert_eq!(None, geofence.update(&north(120.0, 30.0), at(1)));
+    assert!(!geofence.is_alarm());
+    assert_eq!(None, geofence.fast_period());
+
+    // One fix outside is not enough
+    assert_eq!(None, geofence.update(&north(150.0, 5.0), at(2)));
+    assert!(!geofence.is_alarm());
+
+    // The second one raises the alarm and sends an alert straight away
+    let alert = geofence.update(&north(200.0, 5.0), at(3)).unwrap();
+    assert!((alert.distance_m - 200.0).abs() < 1.0);
+    assert_eq!(2, alert.fixes_outside);
+    assert_eq!(Some(Duration::from_secs(60)), geofence.fast_period());
+
+    // Then every report period
+    assert_eq!(None, geofence.update(&north(250.0, 5.0), at(4)));
+    assert!(geofence.update(&north(500.0, 5.0), at(8)).is_some());
+    assert_eq!(None, geofence.update(&north(500.0, 5.0), at(9)));
+
+    // Stale fixes are ignored
+    let mut stale = north(50.0, 5.0);
+    stale.stale = true;
+    assert_eq!(None, geofence.update(&stale, at(10)));
+    assert!(geofence.is_alarm());
+
+    // Towed back home
+    assert_eq!(None, geofence.update(&north(10.0, 5.0), at(60)));
+    assert!(geofence.is_alarm());
+    assert_eq!(None, geofence.update(&north(10.0, 5.0), at(61)));
+    assert!(!geofence.is_alarm());
+  }
+
+  #[test]
+  fn test_validate() {
+    assert!(config().validate().is_ok());
+
+    let mut bad = config();
+    bad.radius_m = 0.0;
+    assert!(bad.validate().is_err());
+
+    let mut bad = config();
+    bad.latitude = 91.0;
+    assert!(bad.validate().is_err());
+  }
+}
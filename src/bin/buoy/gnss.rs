@@ -0,0 +1,262 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Where the GPS fixes come from.  Either:
+///
+///  - gnss: The FX30's GNSS, using the Legato `gnss` tool (gps.sh)
+///  - nmea_serial: An external receiver sending NMEA 0183 on a serial port
+///  - nmea_file: A recorded NMEA file, replayed a fix at a time
+///
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serialport::prelude::*;
+use serialport::ClearBuffer;
+
+use buoy_code::date_now;
+use buoy_code::errors::GiftError;
+use buoy_code::nmea::{parse_sentence, NmeaFix};
+use buoy_code::position::Position;
+
+use crate::platform::Platform;
+
+// How long to wait for a fix from a receiver, the same as gps.sh
+const NMEA_FIX_TIMEOUT: Duration = Duration::from_secs(50);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GnssSourceConfig {
+  Gnss,
+  NmeaSerial { path: PathBuf, baud: u32 },
+  NmeaFile { path: PathBuf, repeat: bool },
+}
+
+impl Default for GnssSourceConfig {
+  fn default() -> Self {
+    GnssSourceConfig::Gnss
+  }
+}
+
+pub trait GnssSource: Send {
+  /// Get a new fix.
+  fn read_fix(&mut self) -> Result<Position, GiftError>;
+
+  /// The fix time is now, a replayed fix can't set the clock.
+  fn is_realtime(&self) -> bool {
+    true
+  }
+}
+
+///
+/// The FX30's GNSS, through the platform.
+///
+pub struct CliSource {
+  platform: Arc<dyn Platform>,
+}
+
+impl CliSource {
+  pub fn new(platform: &Arc<dyn Platform>) -> Self {
+    CliSource {
+      platform: Arc::clone(platform),
+    }
+  }
+}
+
+impl GnssSource for CliSource {
+  fn read_fix(&mut self) -> Result<Position, GiftError> {
+    let output = self.platform.read_gnss()?;
+    Position::parse_gnss(&output, &date_now())
+  }
+}
+
+enum NmeaInput {
+  Serial(Box<dyn SerialPort>),
+  File {
+    path: PathBuf,
+    repeat: bool,
+    reader: BufReader<File>,
+  },
+  Reader(Box<dyn BufRead + Send>),
+}
+
+///
+/// Reads NMEA sentences until there's a fix.
+///
+pub struct NmeaSource {
+  input: NmeaInput,
+  timeout: Duration,
+}
+
+impl NmeaSource {
+  pub fn serial(path: &PathBuf, baud: u32) -> Result<Self, GiftError> {
+    let settings = SerialPortSettings {
+      baud_rate: baud,
+      data_bits: DataBits::Eight,
+      flow_control: FlowControl::None,
+      parity: Parity::None,
+      stop_bits: StopBits::One,
+      timeout: Duration::from_secs(1),
+    };
+    info!("Reading NMEA from {:?} at {} baud", path, baud);
+    let port = serialport::open_with_settings(path, &settings)?;
+
+    Ok(NmeaSource {
+      input: NmeaInput::Serial(port),
+      timeout: NMEA_FIX_TIMEOUT,
+    })
+  }
+
+  /// Replay a file, from the start again at the end if `repeat` is set.
+  pub fn file(path: &PathBuf, repeat: bool) -> Result<Self, GiftError> {
+    Ok(NmeaSource {
+      input: NmeaInput::File {
+        path: path.clone(),
+        repeat,
+        reader: BufReader::new(File::open(path)?),
+      },
+      timeout: NMEA_FIX_TIMEOUT,
+    })
+  }
+
+  pub fn from_reader(reader: Box<dyn BufRead + Send>) -> Self {
+    NmeaSource {
+      input: NmeaInput::Reader(reader),
+      timeout: NMEA_FIX_TIMEOUT,
+    }
+  }
+
+  /// Read sentences from `reader` until the fix is complete.
+  fn read_from(
+    reader: &mut dyn BufRead,
+    fix: &mut NmeaFix,
+    deadline: Instant,
+  ) -> Result<bool, GiftError> {
+    let mut line = String::new();
+    while Instant::now() < deadline {
+      line.clear();
+      match reader.read_line(&mut line) {
+        Ok(0) => return Ok(false), // End of the file
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue, // Not UTF-8, noise
+        Err(e) => return Err(GiftError::Io(e)),
+      }
+
+      match parse_sentence(&line) {
+        Ok(sentence) => fix.add(sentence),
+        Err(_) => debug!("NmeaSource: ignoring '{}'", line.trim()),
+      }
+      if fix.is_complete() {
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+}
+
+impl GnssSource for NmeaSource {
+  fn read_fix(&mut self) -> Result<Position, GiftError> {
+    let mut fix = NmeaFix::default();
+    let deadline = Instant::now() + self.timeout;
+
+    let complete = match &mut self.input {
+      NmeaInput::Serial(port) => {
+        // The receiver sends a fix every second, throw away the old ones
+        port.clear(ClearBuffer::Input)?;
+        let mut reader = BufReader::new(port);
+        Self::read_from(&mut reader, &mut fix, deadline)?
+      }
+      NmeaInput::File {
+        path,
+        repeat,
+        reader,
+      } => {
+        let mut complete = Self::read_from(reader, &mut fix, deadline)?;
+        if !complete && *repeat {
+          *reader = BufReader::new(File::open(&path)?);
+          fix = NmeaFix::default();
+          complete = Self::read_from(reader, &mut fix, deadline)?;
+        }
+        complete
+      }
+      NmeaInput::Reader(reader) => Self::read_from(reader, &mut fix, deadline)?,
+    };
+
+    if !complete {
+      warn!("NmeaSource: no complete fix");
+    }
+    fix.position(&date_now()).ok_or(GiftError::GPSIssue)
+  }
+
+  fn is_realtime(&self) -> bool {
+    match self.input {
+      NmeaInput::Serial(_) => true,
+      NmeaInput::File { .. } | NmeaInput::Reader(_) => false,
+    }
+  }
+}
+
+pub fn create_source(
+  config: &GnssSourceConfig,
+  platform: &Arc<dyn Platform>,
+) -> Result<Box<dyn GnssSource>, GiftError> {
+  Ok(match config {
+    GnssSourceConfig::Gnss => Box::new(CliSource::new(platform)),
+    GnssSourceConfig::NmeaSerial { path, baud } => Box::new(NmeaSource::serial(path, *baud)?),
+    GnssSourceConfig::NmeaFile { path, repeat } => Box::new(NmeaSource::file(path, *repeat)?),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::gnss::*;
+  use std::io::Cursor;
+
+  const REPLAY: &str = "\
+$GNGGA,023518.00,,,,,0,00,99.99,,,,,,*75
+$GNRMC,023518.00,V,,,,,,,300820,,,N*67
+41184,E,1,07,1.20,21.3,M,28.1,M,,*65
+$GNGSA,A,3,10,12,24,25,32,,,,,,,,2.31,1.20,1.97*12
+$GNGGA,023517.00,3650.59752,S,17445.41184,E,1,07,1.20,21.3,M,28.1,M,,*65
+$GNRMC,023517.00,A,3650.59752,S,17445.41184,E,0.052,,300820,,,A*78
+$GNGGA,023518.00,3650.60752,S,17445.41184,E,1,07,1.20,21.3,M,28.1,M,,*60
+";
+
+  #[test]
+  fn test_nmea_replay() {
+    let mut source = NmeaSource::from_reader(Box::new(Cursor::new(REPLAY)));
+
+    let pos = source.read_fix().unwrap();
+    assert!((pos.latitude + 36.843292).abs() < 1e-6);
+    assert_eq!("20200830T023517.000Z", pos.fix_time);
+
+    // The last GGA has no RMC, so the time comes from the buoy
+    let pos = source.read_fix().unwrap();
+    assert!((pos.latitude + 36.843459).abs() < 1e-6);
+    assert_ne!("20200830T023518.000Z", pos.fix_time);
+
+    assert!(source.read_fix().is_err());
+  }
+}
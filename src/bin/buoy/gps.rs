@@ -0,0 +1,207 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Reads the GPS position.  The last fix is kept with the time it was read, so
+/// a fix that is too old is marked stale when it's sent to the server.  Each
+/// fix is checked against the geofence, if there is one.  The GNSS time
+/// disciplines the buoy clock.
+///
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use buoy_code::clock::{parse_date, TimeSource};
+use buoy_code::errors::GiftError;
+use buoy_code::position::Position;
+use buoy_code::ControllerAction;
+
+use crate::clock_discipline::ClockDiscipline;
+use crate::geofence::Geofence;
+use crate::gnss::{GnssSource, GnssSourceConfig};
+use crate::power::PowerPolicy;
+use crate::supervisor::{lock, Supervisor};
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct GpsConfig {
+  pub max_age_secs: u64,        // A fix older than this is sent as stale
+  pub source: GnssSourceConfig, // Where the fixes come from
+}
+
+impl Default for GpsConfig {
+  fn default() -> Self {
+    GpsConfig {
+      // Two acquisition periods, so one failed fix does not make it stale
+      max_age_secs: 2 * buoy_code::GPS_ACQUISITION_PERIOD.as_secs(),
+      source: GnssSourceConfig::default(),
+    }
+  }
+}
+
+impl GpsConfig {
+  pub fn validate(&self) -> Result<(), GiftError> {
+    if self.max_age_secs == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "gps: max_age_secs must be above 0",
+      )));
+    }
+    Ok(())
+  }
+}
+
+///
+/// The last GPS fix, and when it was read.
+///
+#[derive(Clone, Debug, Default)]
+pub struct LastFix {
+  fix: Option<(Position, Instant)>,
+}
+
+impl LastFix {
+  pub fn update(&mut self, position: Position, now: Instant) {
+    self.fix = Some((position, now));
+  }
+
+  /// Where the last fix was, however old it is.
+  pub fn location(&self) -> Option<(f64, f64)> {
+    self
+      .fix
+      .as_ref()
+      .map(|(position, _)| (position.latitude, position.longitude))
+  }
+
+  /// The last position, marked stale if it's older than `max_age_secs`.
+  pub fn position(&self, config: &GpsConfig, now: Instant) -> Option<Position> {
+    self.fix.as_ref().map(|(position, read)| {
+      let mut position = position.clone();
+      position.stale = now.duration_since(*read) > Duration::from_secs(config.max_age_secs);
+      position
+    })
+  }
+}
+
+///
+/// Read a fix.  A live GNSS time sets the clock, otherwise the fix time is
+/// taken from the clock.
+///
+pub fn read_gps(
+  source: &mut dyn GnssSource,
+  last_fix: &Mutex<LastFix>,
+  clock: &Mutex<ClockDiscipline>,
+) -> Result<Position, GiftError> {
+  let mut position = source.read_fix()?;
+  let now = Instant::now();
+  debug!("read_gps(): GPS => {:?}", position);
+
+  let mut clock = lock(clock);
+  if position.time_source != TimeSource::Gnss {
+    let (fix_time, time_source) = clock.stamp(now, &position.fix_time);
+    position.fix_time = fix_time;
+    position.time_source = time_source;
+  } else if source.is_realtime() {
+    if let Some(utc) = parse_date(&position.fix_time) {
+      clock.add_reference(utc, TimeSource::Gnss, now);
+    }
+  }
+
+  lock(last_fix).update(position.clone(), now);
+
+  Ok(position)
+}
+
+///
+/// Read the GPS every buoy_code::GPS_ACQUISITION_PERIOD seconds, or faster
+/// while the drift alarm is raised.  Drift alerts are sent to the controller.
+///
+/// This spawns a supervised task.
+///
+pub fn read_gps_thread(
+  supervisor: &Supervisor,
+  mut source: Box<dyn GnssSource>,
+  last_fix: Arc<Mutex<LastFix>>,
+  clock: Arc<Mutex<ClockDiscipline>>,
+  power: Arc<Mutex<PowerPolicy>>,
+  mut geofence: Option<Geofence>,
+  action_tx: Sender<ControllerAction>,
+) {
+  supervisor.spawn("gps", move || loop {
+    let fast_period = geofence.as_ref().and_then(Geofence::fast_period);
+    thread::sleep(fast_period.unwrap_or(buoy_code::GPS_ACQUISITION_PERIOD));
+
+    // A drifting buoy needs the GPS, whatever the power state
+    if !lock(&power).features().gps && fast_period.is_none() {
+      continue;
+    }
+
+    let position = match read_gps(source.as_mut(), &last_fix, &clock) {
+      Ok(position) => position,
+      Err(e) => {
+        error!("read_gps(): {:?}", e);
+        continue;
+      }
+    };
+
+    if let Some(geofence) = geofence.as_mut() {
+      if let Some(alert) = geofence.update(&position, Instant::now()) {
+        action_tx
+          .send(ControllerAction::CtrlDriftAlert(alert))
+          .unwrap_or_else(|e| error!("read_gps_thread(): {:?}", e));
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::gnss::CliSource;
+  use crate::gps::*;
+  use crate::platform::sim::{SimPlatform, SimScript};
+  use crate::platform::Platform;
+  use crate::voltage::Calibration;
+
+  #[test]
+  fn test_stale_fix() {
+    let config = GpsConfig::default();
+    let sim: Arc<dyn Platform> = Arc::new(SimPlatform::manual(
+      &SimScript::default(),
+      &Calibration::default(),
+    ));
+    let mut source = CliSource::new(&sim);
+    let last_fix = Mutex::new(LastFix::default());
+    let clock = Mutex::new(ClockDiscipline::default());
+    let now = Instant::now();
+    assert_eq!(None, last_fix.lock().unwrap().position(&config, now));
+
+    read_gps(&mut source, &last_fix, &clock).unwrap();
+    let last_fix = last_fix.lock().unwrap();
+    let position = last_fix.position(&config, Instant::now()).unwrap();
+    assert_eq!(-36.843292, position.latitude);
+    assert!(!position.stale);
+    assert_eq!(TimeSource::Gnss, position.time_source);
+    assert!(clock.lock().unwrap().utc_at(now).is_some());
+
+    let later = Instant::now() + Duration::from_secs(config.max_age_secs + 1);
+    assert!(last_fix.position(&config, later).unwrap().stale);
+  }
+}
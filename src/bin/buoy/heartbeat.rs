@@ -0,0 +1,304 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Send a health heartbeat to the server every `interval_secs`, whether or not
+/// the hydrophone is sending data.  See buoy_code::heartbeat for what's in it.
+///
+/// The health of each subsystem:
+///
+///    hydrophone - failed if there was no recording for FX30_NO_DATA_WAIT
+///    gps        - failed without a fix, degraded if the fix is stale
+///    light      - failed while the light monitor reports a fault
+///
+/// Any subsystem switched off by the power policy is `off`, and so is the
+/// light during the day.  A snapshot of the modem state is sent with it.
+///
+use std::fs;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use buoy_code::errors::GiftError;
+use buoy_code::heartbeat::{Health, Heartbeat};
+use buoy_code::light_status::LightStatus;
+use buoy_code::position::{FixType, Position};
+
+use crate::config::BuoyConfig;
+use crate::data_send::modem_snapshot;
+use crate::gps::{GpsConfig, LastFix};
+use crate::light::LightMonitor;
+use crate::platform::Platform;
+use crate::power::PowerPolicy;
+use crate::supervisor::{lock, Supervisor};
+use crate::transport::Transport;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+  pub interval_secs: u64, // How often the heartbeat is sent
+  pub flash_path: String, // Where the flash usage is measured
+}
+
+impl Default for HeartbeatConfig {
+  fn default() -> Self {
+    HeartbeatConfig {
+      interval_secs: 5 * 60,
+      flash_path: String::from("/home/root"),
+    }
+  }
+}
+
+impl HeartbeatConfig {
+  pub fn validate(&self) -> Result<(), GiftError> {
+    if self.interval_secs == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "heartbeat: interval_secs must be above 0",
+      )));
+    }
+    Ok(())
+  }
+}
+
+/// The available memory in kB from /proc/meminfo.
+pub fn parse_meminfo(meminfo: &str) -> Option<u64> {
+  let value = |name: &str| {
+    meminfo
+      .lines()
+      .find(|line| line.starts_with(name))
+      .and_then(|line| line.split_whitespace().nth(1))
+      .and_then(|kb| kb.parse().ok())
+  };
+  // Older kernels don't have MemAvailable
+  value("MemAvailable:").or_else(|| value("MemFree:"))
+}
+
+/// The 1 minute load average from /proc/loadavg.
+pub fn parse_loadavg(loadavg: &str) -> Option<f32> {
+  loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// The percentage of the file system used from the output of `df -k`.
+pub fn parse_df(df: &str) -> Option<f32> {
+  // Skip the column names, a long file system name may wrap the line
+  let fields: Vec<&str> = df.lines().skip(1).flat_map(str::split_whitespace).collect();
+  let used: f32 = fields.get(2)?.parse().ok()?;
+  let available: f32 = fields.get(3)?.parse().ok()?;
+  if used + available > 0.0 {
+    Some(100.0 * used / (used + available))
+  } else {
+    None
+  }
+}
+
+/// Free memory, flash usage and load average, None if they can't be read.
+fn system_stats(flash_path: &str) -> (Option<u64>, Option<f32>, Option<f32>) {
+  let mem_free_kb = fs::read_to_string("/proc/meminfo")
+    .ok()
+    .and_then(|s| parse_meminfo(&s));
+  let flash_used_pct = Command::new("df")
+    .args(&["-k", flash_path])
+    .output()
+    .ok()
+    .and_then(|out| parse_df(&String::from_utf8_lossy(&out.stdout)));
+  let load_avg = fs::read_to_string("/proc/loadavg")
+    .ok()
+    .and_then(|s| parse_loadavg(&s));
+  (mem_free_kb, flash_used_pct, load_avg)
+}
+
+pub fn hydrophone_health(
+  enabled: bool,
+  last_recording: Option<Instant>,
+  started: Instant,
+  now: Instant,
+) -> Health {
+  if !enabled {
+    return Health::Off;
+  }
+  let since = last_recording.unwrap_or(started);
+  if now.duration_since(since) > buoy_code::FX30_NO_DATA_WAIT {
+    Health::Failed
+  } else {
+    Health::Ok
+  }
+}
+
+pub fn gps_health(enabled: bool, position: Option<&Position>) -> Health {
+  match position {
+    _ if !enabled => Health::Off,
+    None => Health::Failed,
+    Some(p) if p.fix_type == FixType::NoFix => Health::Failed,
+    Some(p) if p.stale => Health::Degraded,
+    Some(_) => Health::Ok,
+  }
+}
+
+pub fn light_health(status: &LightStatus) -> Health {
+  if status.fault.is_some() {
+    Health::Failed
+  } else if !status.active {
+    Health::Off
+  } else {
+    Health::Ok
+  }
+}
+
+///
+/// Where the heartbeat gets the state of each subsystem from.
+///
+#[derive(Clone)]
+pub struct HeartbeatSources {
+  pub platform: Arc<dyn Platform>,
+  pub last_fix: Arc<Mutex<LastFix>>,
+  pub light: Arc<Mutex<LightMonitor>>,
+  pub power: Arc<Mutex<PowerPolicy>>,
+  pub last_recording: Arc<Mutex<Option<Instant>>>, // When the last recording arrived
+  pub transport: Arc<dyn Transport>,               // The uploads in progress
+}
+
+impl HeartbeatSources {
+  pub fn heartbeat(
+    &self,
+    config: &HeartbeatConfig,
+    gps: &GpsConfig,
+    started: Instant,
+  ) -> Heartbeat {
+    let now = Instant::now();
+    let features = lock(&self.power).features();
+    let position = lock(&self.last_fix).position(gps, now);
+    let (mem_free_kb, flash_used_pct, load_avg) = system_stats(&config.flash_path);
+
+    Heartbeat {
+      hydrophone: hydrophone_health(
+        features.recording,
+        *lock(&self.last_recording),
+        started,
+        now,
+      ),
+      gps: gps_health(features.gps, position.as_ref()),
+      light: light_health(&lock(&self.light).status()),
+      power_state: lock(&self.power).state(),
+      upload_queue: self.transport.in_flight() as u32,
+      mem_free_kb,
+      flash_used_pct,
+      load_avg,
+      uptime_secs: now.duration_since(started).as_secs(),
+    }
+  }
+}
+
+///
+/// Send the heartbeat every `interval_secs`.  Like the uploads, it's not sent
+/// when the power policy does not allow uploads.
+///
+/// This spawns a supervised task.
+///
+pub fn heartbeat_task(supervisor: &Supervisor, config: &BuoyConfig, sources: HeartbeatSources) {
+  let gps = config.gps.clone();
+  let config = config.heartbeat.clone();
+  let interval = Duration::from_secs(config.interval_secs);
+  let started = Instant::now();
+  let restarts = supervisor.clone();
+
+  supervisor.spawn("heartbeat", move || loop {
+    thread::sleep(interval);
+
+    if !lock(&sources.power).features().uploads {
+      debug!("heartbeat_task(): uploads are disabled by the power policy");
+      continue;
+    }
+
+    let heartbeat = sources.heartbeat(&config, &gps, started);
+    let modem = modem_snapshot(sources.platform.as_ref());
+    info!("heartbeat_task(): {}", heartbeat.encode());
+    let result = sources
+      .transport
+      .send_heartbeat(&heartbeat, &restarts.restarts(), modem.as_ref());
+    if let Err(e) = result {
+      error!("heartbeat_task(): sending the heartbeat failed: {:?}", e);
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::heartbeat::*;
+
+  #[test]
+  fn test_parse_system_stats() {
+    let meminfo = "MemTotal:         253472 kB\nMemFree:           10240 kB\n\
+                   MemAvailable:      20480 kB\nBuffers:            1024 kB\n";
+    assert_eq!(Some(20480), parse_meminfo(meminfo));
+    assert_eq!(Some(10240), parse_meminfo("MemFree:   10240 kB\n"));
+    assert_eq!(None, parse_meminfo(""));
+
+    assert_eq!(Some(0.35), parse_loadavg("0.35 0.20 0.10 1/84 1234\n"));
+    assert_eq!(None, parse_loadavg(""));
+
+    let df = "Filesystem           1K-blocks      Used Available Use% Mounted on\n\
+              /dev/ubi0_0             40000     25000     15000  63% /home/root\n";
+    assert_eq!(Some(62.5), parse_df(df));
+    let df = "Filesystem           1K-blocks      Used Available Use% Mounted on\n\
+              /dev/a-very-long-file-system-name\n\
+                                      40000     10000     30000  25% /\n";
+    assert_eq!(Some(25.0), parse_df(df));
+    assert_eq!(None, parse_df("df: /nowhere: No such file or directory\n"));
+  }
+
+  #[test]
+  fn test_health() {
+    let start = Instant::now();
+    let later = start + buoy_code::FX30_NO_DATA_WAIT + Duration::from_secs(1);
+    assert_eq!(Health::Ok, hydrophone_health(true, None, start, start));
+    assert_eq!(Health::Failed, hydrophone_health(true, None, start, later));
+    assert_eq!(
+      Health::Ok,
+      hydrophone_health(true, Some(later), start, later)
+    );
+    assert_eq!(Health::Off, hydrophone_health(false, None, start, later));
+
+    let mut position = Position {
+      latitude: -36.843_292,
+      longitude: 174.756_864,
+      h_accuracy: Some(10.0),
+      fix_time: String::from("20200830T023517.000Z"),
+      time_source: buoy_code::clock::TimeSource::Gnss,
+      fix_type: FixType::Fix3d,
+      satellites: Some(7),
+      stale: false,
+    };
+    assert_eq!(Health::Ok, gps_health(true, Some(&position)));
+    position.stale = true;
+    assert_eq!(Health::Degraded, gps_health(true, Some(&position)));
+    assert_eq!(Health::Failed, gps_health(true, None));
+    assert_eq!(Health::Off, gps_health(false, None));
+
+    let mut status = LightStatus::default();
+    assert_eq!(Health::Off, light_health(&status));
+    status.active = true;
+    assert_eq!(Health::Ok, light_health(&status));
+    status.fault = Some(buoy_code::light_status::LightFault::Readback);
+    assert_eq!(Health::Failed, light_health(&status));
+  }
+}
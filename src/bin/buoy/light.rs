@@ -0,0 +1,645 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The navigation light character, written the way it is on a chart, e.g.
+/// "Fl(5)Y.20s" is a group of five yellow flashes every 20 seconds, and
+/// "Q(6)+LFl.15s" is six quick flashes and a long flash every 15 seconds.
+///
+/// The phases are:
+///
+///    F     Fixed, always on
+///    Fl    Flashing, `flash_ms` on and `eclipse_ms` off
+///    LFl   Long flash, 2 seconds on
+///    Q     Quick, 60 flashes a minute
+///    VQ    Very quick, 120 flashes a minute
+///    Iso   Isophase, on and off for half of the period each
+///    Oc    Occulting, mostly on with `eclipse_ms` eclipses
+///
+/// A group like "Fl(2+1)" has an extra eclipse between the groups.  The rest
+/// of the period is dark, or light for occulting.
+///
+/// The light is switched at fixed times from the start of the first period,
+/// so the period stays exact however late the thread wakes up.
+///
+/// Every switch is read back from the GPIO and, if there is a current sense
+/// ADC, the lamp current is checked at the end of each flash.  The failures
+/// are counted and the light is faulty after `fault_after` failures in a row.
+///
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use buoy_code::errors::GiftError;
+use buoy_code::light_status::{LightFault, LightStatus};
+
+use crate::platform::{Adc, Gpio, Platform};
+
+const LONG_FLASH: Duration = Duration::from_millis(2000);
+const QUICK_ON: Duration = Duration::from_millis(300);
+const QUICK_OFF: Duration = Duration::from_millis(700);
+const VERY_QUICK_ON: Duration = Duration::from_millis(150);
+const VERY_QUICK_OFF: Duration = Duration::from_millis(350);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CurrentSense {
+  pub min_raw: f32, // The lowest raw ADC value while the lamp is lit
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct LightConfig {
+  pub character: String, // The light character, e.g. "Fl(5)Y.20s"
+  pub flash_ms: u64,     // How long a flash is
+  pub eclipse_ms: u64,   // How long the light is off between flashes
+  pub fault_after: u32,  // Failures in a row before the light is faulty
+  pub current_sense: Option<CurrentSense>, // Check the lamp current, off if not set
+}
+
+impl Default for LightConfig {
+  fn default() -> Self {
+    // Maritime NZ says 5 quick flashes every 20 seconds for a scientific buoy
+    LightConfig {
+      character: String::from(buoy_code::BUOY_NAV_LIGHT_CHARACTER),
+      flash_ms: 500,
+      eclipse_ms: 1000,
+      fault_after: 3,
+      current_sense: None,
+    }
+  }
+}
+
+impl LightConfig {
+  /// The on/off steps for one period.
+  pub fn sequence(&self) -> Result<Vec<(bool, Duration)>, GiftError> {
+    let character: LightCharacter = self.character.parse()?;
+    character.sequence(
+      Duration::from_millis(self.flash_ms),
+      Duration::from_millis(self.eclipse_ms),
+    )
+  }
+
+  pub fn validate(&self) -> Result<(), GiftError> {
+    if self.flash_ms == 0 || self.eclipse_ms == 0 || self.fault_after == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "light: flash_ms, eclipse_ms and fault_after must be above 0",
+      )));
+    }
+    self.sequence().map(|_| ())
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+  Fixed,
+  Flash,
+  LongFlash,
+  Quick,
+  VeryQuick,
+  Isophase,
+  Occulting,
+}
+
+impl FromStr for Phase {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "F" => Ok(Phase::Fixed),
+      "Fl" => Ok(Phase::Flash),
+      "LFl" => Ok(Phase::LongFlash),
+      "Q" => Ok(Phase::Quick),
+      "VQ" => Ok(Phase::VeryQuick),
+      "Iso" => Ok(Phase::Isophase),
+      "Oc" => Ok(Phase::Occulting),
+      _ => Err(invalid(s, "unknown phase")),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightCharacter {
+  pub phases: Vec<(Phase, Vec<u32>)>, // Each phase, with the flashes in each group
+  pub colour: String,                 // e.g. "Y", only for information
+  pub period: Option<Duration>,       // None to repeat without a pause
+}
+
+fn invalid(character: &str, reason: &str) -> GiftError {
+  GiftError::InvalidConfig(format!("light: '{}' {}", character, reason))
+}
+
+fn config_error(reason: &str) -> GiftError {
+  GiftError::InvalidConfig(format!("light: {}", reason))
+}
+
+impl FromStr for LightCharacter {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    lazy_static! {
+      static ref RE: Regex = Regex::new(concat!(
+        r"^(?P<a>Fl|F|LFl|VQ|Q|Iso|Oc)(?:\((?P<ag>\d+(?:\+\d+)*)\))?",
+        r"(?:\+(?P<b>Fl|LFl|VQ|Q)(?:\((?P<bg>\d+(?:\+\d+)*)\))?)?",
+        r"\s*(?P<colour>(?:W|R|G|Y|Bu)*)",
+        r"(?:[.\s]\s*(?P<period>\d+(?:\.\d+)?)s)?$"
+      ))
+      .unwrap();
+    }
+
+    let caps = RE
+      .captures(s.trim())
+      .ok_or_else(|| invalid(s, "is not a light character"))?;
+
+    let groups = |name: &str| -> Result<Vec<u32>, GiftError> {
+      match caps.name(name) {
+        None => Ok(vec![1]),
+        Some(m) => m
+          .as_str()
+          .split('+')
+          .map(|n| match n.parse() {
+            Ok(0) | Err(_) => Err(invalid(s, "has an invalid group")),
+            Ok(n) => Ok(n),
+          })
+          .collect(),
+      }
+    };
+
+    let mut phases = vec![(caps["a"].parse()?, groups("ag")?)];
+    if let Some(b) = caps.name("b") {
+      phases.push((b.as_str().parse()?, groups("bg")?));
+    }
+
+    let period = match caps.name("period") {
+      Some(p) => {
+        let secs: f64 = p.as_str().parse()?;
+        Some(Duration::from_millis((secs * 1000.0).round() as u64))
+      }
+      None => None,
+    };
+
+    Ok(LightCharacter {
+      phases,
+      colour: String::from(&caps["colour"]),
+      period,
+    })
+  }
+}
+
+impl LightCharacter {
+  ///
+  /// The on/off steps for one period.  Consecutive steps are always
+  /// different, and they add up to the period.
+  ///
+  pub fn sequence(
+    &self,
+    flash: Duration,
+    eclipse: Duration,
+  ) -> Result<Vec<(bool, Duration)>, GiftError> {
+    let mut steps: Vec<(bool, Duration)> = Vec::new();
+
+    match self.phases[0] {
+      (Phase::Fixed, _) | (Phase::Isophase, _) if self.phases.len() > 1 => {
+        return Err(config_error("fixed and isophase can't be combined"));
+      }
+      (Phase::Fixed, _) => {
+        steps.push((true, self.period.unwrap_or(Duration::from_secs(1))));
+      }
+      (Phase::Isophase, _) => {
+        let period = self
+          .period
+          .ok_or_else(|| config_error("isophase needs a period"))?;
+        steps.push((true, period / 2));
+        steps.push((false, period - period / 2));
+      }
+      _ => {
+        for (phase, groups) in &self.phases {
+          let (lit, on, off) = match phase {
+            Phase::Flash => (true, flash, eclipse),
+            Phase::LongFlash => (true, LONG_FLASH, eclipse),
+            Phase::Quick => (true, QUICK_ON, QUICK_OFF),
+            Phase::VeryQuick => (true, VERY_QUICK_ON, VERY_QUICK_OFF),
+            Phase::Occulting => (false, eclipse, eclipse),
+            Phase::Fixed | Phase::Isophase => {
+              return Err(config_error("fixed and isophase can't be combined"));
+            }
+          };
+          for (i, &count) in groups.iter().enumerate() {
+            if i > 0 {
+              steps.last_mut().unwrap().1 += eclipse;
+            }
+            for _ in 0..count {
+              steps.push((lit, on));
+              steps.push((!lit, off));
+            }
+          }
+        }
+      }
+    }
+
+    // The rest of the period
+    let total: Duration = steps.iter().map(|&(_, d)| d).sum();
+    if let Some(period) = self.period {
+      if total > period {
+        return Err(config_error(&format!(
+          "the flashes take {:?}, longer than the {:?} period",
+          total, period
+        )));
+      }
+      steps.last_mut().unwrap().1 += period - total;
+    }
+    if steps.iter().any(|&(_, d)| d == Duration::from_secs(0)) {
+      return Err(config_error("the period must be above 0"));
+    }
+
+    // Join steps that are the same, e.g. occulting
+    let mut joined: Vec<(bool, Duration)> = Vec::new();
+    for (on, duration) in steps {
+      match joined.last_mut() {
+        Some(last) if last.0 == on => last.1 += duration,
+        _ => joined.push((on, duration)),
+      }
+    }
+    Ok(joined)
+  }
+}
+
+///
+/// When to switch the light.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+  pub at: Instant,
+  pub on: bool,
+  pub cycle: u64, // Which period this is in, counting from 0
+}
+
+///
+/// Works out when to switch the light, from the time the first period
+/// started.
+///
+pub struct LightSchedule {
+  steps: Vec<(bool, Duration)>,
+  period: Duration,
+  start: Instant, // The start of the current period
+  cycle: u64,
+  index: usize,     // The next step
+  offset: Duration, // When the next step starts, from `start`
+}
+
+impl LightSchedule {
+  pub fn new(steps: Vec<(bool, Duration)>, start: Instant) -> Self {
+    LightSchedule {
+      period: steps.iter().map(|&(_, d)| d).sum(),
+      steps,
+      start,
+      cycle: 0,
+      index: 0,
+      offset: Duration::from_secs(0),
+    }
+  }
+
+  fn next_period(&mut self) {
+    self.start += self.period;
+    self.cycle += 1;
+    self.index = 0;
+    self.offset = Duration::from_secs(0);
+  }
+
+  ///
+  /// The next change.  Steps that ended before `now` are skipped, so after a
+  /// long sleep the light carries on where it should be.
+  ///
+  pub fn next(&mut self, now: Instant) -> Change {
+    loop {
+      if self.index == self.steps.len() {
+        self.next_period();
+      }
+      if self.index == 0 && now >= self.start + self.period {
+        // Skip the whole periods
+        let behind = now.duration_since(self.start).as_millis() / self.period.as_millis();
+        self.start += self.period * (behind as u32 - 1);
+        self.cycle += behind as u64 - 1;
+        self.next_period();
+        continue;
+      }
+
+      let (on, duration) = self.steps[self.index];
+      let at = self.start + self.offset;
+      self.index += 1;
+      self.offset += duration;
+      if at + duration > now {
+        return Change {
+          at,
+          on,
+          cycle: self.cycle,
+        };
+      }
+    }
+  }
+}
+
+///
+/// Switches the light and checks that it worked.
+///
+pub struct LightMonitor {
+  current_sense: Option<CurrentSense>,
+  fault_after: u32,
+  status: LightStatus,
+  lit: bool,       // The light should be on
+  switch_ok: bool, // The last switch was read back
+  failures_in_a_row: u32,
+}
+
+impl LightMonitor {
+  pub fn new(config: &LightConfig) -> Self {
+    LightMonitor {
+      current_sense: config.current_sense.clone(),
+      fault_after: config.fault_after,
+      status: LightStatus::default(),
+      lit: false,
+      switch_ok: true,
+      failures_in_a_row: 0,
+    }
+  }
+
+  pub fn status(&self) -> LightStatus {
+    self.status.clone()
+  }
+
+  /// The light is meant to be flashing, i.e. it's night.
+  pub fn set_active(&mut self, active: bool) {
+    self.status.active = active;
+  }
+
+  fn failure(&mut self, fault: LightFault) {
+    match fault {
+      LightFault::Write => self.status.write_failures += 1,
+      LightFault::Readback => self.status.readback_failures += 1,
+      LightFault::NoCurrent => self.status.current_failures += 1,
+    }
+    self.failures_in_a_row += 1;
+    if self.failures_in_a_row >= self.fault_after {
+      if self.status.fault.is_none() {
+        error!("LightMonitor: the navigation light is faulty: {}", fault);
+      }
+      self.status.fault = Some(fault);
+    }
+  }
+
+  fn good_flash(&mut self) {
+    self.status.flashes += 1;
+    self.failures_in_a_row = 0;
+    if let Some(fault) = self.status.fault.take() {
+      warn!(
+        "LightMonitor: the navigation light is working again after {}",
+        fault
+      );
+    }
+  }
+
+  /// The lamp current, at the end of a flash.
+  fn lamp_lit(&self, platform: &dyn Platform) -> bool {
+    match &self.current_sense {
+      None => true,
+      Some(sense) => match platform.read_adc(Adc::LampCurrent) {
+        Ok(raw) => raw >= sense.min_raw,
+        Err(e) => {
+          error!("LightMonitor: reading the lamp current: {:?}", e);
+          false
+        }
+      },
+    }
+  }
+
+  ///
+  /// Switch the light on or off.  The end of a flash is checked first, while
+  /// the lamp is still lit.
+  ///
+  pub fn switch(&mut self, platform: &dyn Platform, on: bool) {
+    if self.lit {
+      if !self.lamp_lit(platform) {
+        self.failure(LightFault::NoCurrent);
+      } else if self.switch_ok {
+        self.good_flash();
+      }
+    }
+
+    self.switch_ok = match platform
+      .set_gpio(Gpio::NavLight, on)
+      .and_then(|_| platform.get_gpio(Gpio::NavLight))
+    {
+      Ok(state) if state == on => true,
+      Ok(_) => {
+        self.failure(LightFault::Readback);
+        false
+      }
+      Err(e) => {
+        debug!("LightMonitor: switching the light: {:?}", e);
+        self.failure(LightFault::Write);
+        false
+      }
+    };
+    self.lit = on;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::light::*;
+
+  fn millis(character: &str) -> Vec<(bool, u64)> {
+    LightConfig {
+      character: String::from(character),
+      ..LightConfig::default()
+    }
+    .sequence()
+    .unwrap()
+    .iter()
+    .map(|&(on, d)| (on, d.as_millis() as u64))
+    .collect()
+  }
+
+  #[test]
+  fn test_parse() {
+    let c: LightCharacter = "Fl(5)Y.20s".parse().unwrap();
+    assert_eq!(vec![(Phase::Flash, vec![5])], c.phases);
+    assert_eq!("Y", c.colour);
+    assert_eq!(Some(Duration::from_secs(20)), c.period);
+
+    let c: LightCharacter = "Q(6)+LFl.15s".parse().unwrap();
+    assert_eq!(
+      vec![(Phase::Quick, vec![6]), (Phase::LongFlash, vec![1])],
+      c.phases
+    );
+    assert_eq!("", c.colour);
+
+    let c: LightCharacter = "Fl(2+1)G 7.5s".parse().unwrap();
+    assert_eq!(vec![(Phase::Flash, vec![2, 1])], c.phases);
+    assert_eq!(Some(Duration::from_millis(7500)), c.period);
+
+    let c: LightCharacter = "VQ".parse().unwrap();
+    assert_eq!(None, c.period);
+
+    for bad in &["", "Fl(0).5s", "Fl(5)X.20s", "Strobe.5s", "Fl(5).20"] {
+      assert!(bad.parse::<LightCharacter>().is_err(), "{}", bad);
+    }
+  }
+
+  #[test]
+  fn test_sequence() {
+    assert_eq!(
+      vec![
+        (true, 500),
+        (false, 1000),
+        (true, 500),
+        (false, 1000),
+        (true, 500),
+        (false, 1000),
+        (true, 500),
+        (false, 1000),
+        (true, 500),
+        (false, 13500),
+      ],
+      millis("Fl(5)Y.20s")
+    );
+
+    // Six quick flashes in 6 seconds, a 2 second long flash then dark
+    let south = millis("Q(6)+LFl.15s");
+    assert_eq!(14, south.len());
+    assert_eq!((true, 300), south[0]);
+    assert_eq!((false, 700), south[11]);
+    assert_eq!((true, 2000), south[12]);
+    assert_eq!((false, 7000), south[13]);
+    assert_eq!(15_000, south.iter().map(|s| s.1).sum::<u64>());
+
+    assert_eq!(
+      vec![
+        (true, 500),
+        (false, 1000),
+        (true, 500),
+        (false, 2000),
+        (true, 500),
+        (false, 3000)
+      ],
+      millis("Fl(2+1).7.5s")
+    );
+    assert_eq!(vec![(true, 2000), (false, 2000)], millis("Iso.4s"));
+    assert_eq!(
+      vec![(false, 1000), (true, 1000), (false, 1000), (true, 5000)],
+      millis("Oc(2)R.8s")
+    );
+    assert_eq!(vec![(true, 150), (false, 350)], millis("VQ"));
+    assert_eq!(vec![(true, 1000)], millis("F"));
+
+    for bad in &["Fl(5).5s", "F+Fl.5s", "Iso", "F.0s"] {
+      let config = LightConfig {
+        character: String::from(*bad),
+        ..LightConfig::default()
+      };
+      assert!(config.validate().is_err(), "{}", bad);
+    }
+    assert!(LightConfig::default().validate().is_ok());
+  }
+
+  #[test]
+  fn test_monitor() {
+    use crate::platform::sim::{SimPlatform, SimScript};
+    use crate::voltage::Calibration;
+
+    let config = LightConfig {
+      current_sense: Some(CurrentSense { min_raw: 1000.0 }),
+      ..LightConfig::default()
+    };
+    let flash = |monitor: &mut LightMonitor, sim: &SimPlatform, times: usize| {
+      for _ in 0..times {
+        monitor.switch(sim, true);
+        monitor.switch(sim, false);
+      }
+    };
+
+    let sim = SimPlatform::manual(&SimScript::default(), &Calibration::default());
+    let mut monitor = LightMonitor::new(&config);
+    flash(&mut monitor, &sim, 5);
+    assert_eq!(5, monitor.status().flashes);
+    assert_eq!(None, monitor.status().fault);
+
+    // A dead lamp
+    let dead = SimPlatform::manual(
+      &SimScript {
+        lamp_raw: 0.0,
+        ..SimScript::default()
+      },
+      &Calibration::default(),
+    );
+    flash(&mut monitor, &dead, 2);
+    assert_eq!(None, monitor.status().fault);
+    flash(&mut monitor, &dead, 1);
+    let status = monitor.status();
+    assert_eq!(Some(LightFault::NoCurrent), status.fault);
+    assert_eq!(3, status.current_failures);
+    assert_eq!(5, status.flashes);
+
+    // Fixed
+    flash(&mut monitor, &sim, 1);
+    assert_eq!(None, monitor.status().fault);
+    assert_eq!(6, monitor.status().flashes);
+
+    // Without current sense only the GPIO is checked
+    let mut monitor = LightMonitor::new(&LightConfig::default());
+    flash(&mut monitor, &dead, 3);
+    assert_eq!(3, monitor.status().flashes);
+  }
+
+  #[test]
+  fn test_schedule() {
+    let steps = LightConfig::default().sequence().unwrap();
+    let start = Instant::now();
+    let mut schedule = LightSchedule::new(steps, start);
+
+    // Two periods, each starts exactly 20 seconds after the last
+    let changes: Vec<Change> = (0..20).map(|_| schedule.next(start)).collect();
+    assert_eq!(start, changes[0].at);
+    assert!(changes[0].on);
+    assert_eq!(start + Duration::from_millis(500), changes[1].at);
+    assert!(!changes[1].on);
+    assert_eq!(start + Duration::from_secs(20), changes[10].at);
+    assert_eq!(1, changes[10].cycle);
+    assert_eq!(start + Duration::from_millis(26_500), changes[19].at);
+
+    // Woken up late in the third flash of period 2
+    let late = start + Duration::from_millis(43_200);
+    let change = schedule.next(late);
+    assert_eq!(start + Duration::from_secs(43), change.at);
+    assert!(change.on);
+    assert_eq!(2, change.cycle);
+
+    // After sleeping for an hour
+    let later = start + Duration::from_millis(3_600_100);
+    let change = schedule.next(later);
+    assert_eq!(start + Duration::from_secs(3600), change.at);
+    assert_eq!(180, change.cycle);
+    assert_eq!(
+      start + Duration::from_millis(3_600_500),
+      schedule.next(later).at
+    );
+  }
+}
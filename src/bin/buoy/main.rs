@@ -28,37 +28,112 @@ extern crate env_logger;
 extern crate futures;
 extern crate quinn;
 extern crate rustls;
-extern crate time;
+extern crate slog;
 extern crate tokio;
 extern crate url;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::thread;
+use std::sync::{Arc, Mutex};
 
+pub mod battery;
+pub mod calibrate;
+pub mod clock_discipline;
+pub mod config;
 pub mod controller;
 pub mod data_send;
+pub mod geofence;
+pub mod gnss;
+pub mod gps;
+pub mod heartbeat;
+pub mod light;
+pub mod platform;
+pub mod power;
 pub mod sensor_reader;
+pub mod shutdown;
+pub mod spool;
+pub mod state_store;
+pub mod sun;
+pub mod supervisor;
+pub mod transport;
 pub mod voltage;
 
 use url::Url;
 
 use buoy_code::errors::GiftError;
-use buoy_code::{CA_CERT_PATH, HOME_SERVER_URL, SW_VERSION};
+use buoy_code::link_stats::LinkStats;
+use buoy_code::session::SessionEnd;
+use buoy_code::{
+  BUOY_CALIBRATION_PATH, BUOY_CONFIG_PATH, BUOY_STATE_PATH, CA_CERT_PATH, HOME_SERVER_URL,
+  SW_VERSION,
+};
 
+use crate::battery::battery_monitor;
+use crate::calibrate::calibrate;
+use crate::config::BuoyConfig;
 use crate::controller::controller;
+use crate::data_send::Uplink;
+use crate::platform::Platform;
 use crate::sensor_reader::sensor_reader;
+use crate::shutdown::Shutdown;
+use crate::state_store::StateStore;
+use crate::supervisor::Supervisor;
 
-#[allow(unreachable_code)]
+///
+/// Exit on an error the supervisor can't recover from, boot.sh reboots the
+/// FX30.
+///
 pub fn handle_error(err: GiftError) {
   error!("ERROR: {:?}", err);
-  panic!("Help");
   ::std::process::exit(1);
 }
 
+///
+/// The FX30 hardware, or the simulator when built for another platform.
+///
+#[cfg(feature = "fx30")]
+fn create_platform(_config: &BuoyConfig) -> Arc<dyn Platform> {
+  Arc::new(platform::fx30::Fx30Platform::new())
+}
+
+#[cfg(not(feature = "fx30"))]
+fn create_platform(config: &BuoyConfig) -> Arc<dyn Platform> {
+  info!("Not an FX30, using the simulator");
+  Arc::new(platform::sim::SimPlatform::realtime(
+    &config.sim,
+    &config.voltage,
+  ))
+}
+
 fn main() {
   // Set up the logger
   env_logger::init();
+
+  let calibration_path = Path::new(BUOY_CALIBRATION_PATH);
+  let config = BuoyConfig::load(Path::new(BUOY_CONFIG_PATH), calibration_path)
+    .map_err(handle_error)
+    .unwrap();
+  let platform = create_platform(&config);
+
+  let args: Vec<String> = std::env::args().collect();
+  if args.len() > 1 && args[1] == "calibrate" {
+    let piecewise = args.iter().any(|a| a == "--piecewise");
+    calibrate(calibration_path, piecewise, platform.as_ref())
+      .map_err(handle_error)
+      .unwrap();
+    return;
+  }
+
+  // Run by the SMS scripts, so the next session knows why this one ended
+  if args.len() > 2 && args[1] == "end-session" {
+    args[2]
+      .parse::<SessionEnd>()
+      .and_then(|end| StateStore::end_session_at(Path::new(BUOY_STATE_PATH), end))
+      .map_err(handle_error)
+      .unwrap();
+    return;
+  }
+
   println!(
     "Starting buoy runtime.\n\tVersion:{}\n\tRemote: {}",
     SW_VERSION, HOME_SERVER_URL
@@ -68,18 +143,55 @@ fn main() {
   let (action_tx1, action_rx) = mpsc::channel();
   let action_tx2 = mpsc::Sender::clone(&action_tx1);
 
+  // What is kept over reboots and sleeps, the buoy runs without it if the
+  // flash fails
+  let store = Arc::new(
+    StateStore::open(Path::new(BUOY_STATE_PATH)).unwrap_or_else(|e| {
+      error!("Could not open the state store: {:?}", e);
+      StateStore::memory()
+    }),
+  );
+
+  // Owns all the long-running tasks, and restarts them when they fail
+  let supervisor = Supervisor::new(&config.supervisor, &platform, &store);
+
+  // Keep track of the battery state
+  let battery = battery_monitor(&config.battery, &config.voltage, &platform, &supervisor);
+
+  // Stops the recording before going to sleep
+  let shutdown = Arc::new(Shutdown::default());
+
   // Get the hydrophone data
   let serial_port = PathBuf::from(buoy_code::SERIAL_PATH);
-  thread::spawn(move || {
-    sensor_reader(&action_tx1, &serial_port, buoy_code::SERIAL_BAUD)
-      .map_err(handle_error)
-      .unwrap();
+  let sensor_battery = Arc::clone(&battery);
+  let sensor_shutdown = Arc::clone(&shutdown);
+  supervisor.spawn("sensor", move || {
+    sensor_reader(
+      &action_tx1,
+      &serial_port,
+      buoy_code::SERIAL_BAUD,
+      &sensor_battery,
+      &sensor_shutdown,
+    )
   });
 
   // Main controller
-  let url = Url::parse(HOME_SERVER_URL).unwrap();
-  let ca = PathBuf::from(CA_CERT_PATH);
-  controller(&url, &ca, action_tx2, action_rx)
-    .map_err(handle_error)
-    .unwrap();
+  let uplink = Uplink {
+    url: Url::parse(HOME_SERVER_URL).unwrap(),
+    ca_path: PathBuf::from(CA_CERT_PATH),
+    action_tx: action_tx2,
+    link_stats: Arc::new(Mutex::new(LinkStats::default())),
+  };
+  controller(
+    &config,
+    &platform,
+    &battery,
+    &supervisor,
+    &shutdown,
+    &store,
+    uplink,
+    action_rx,
+  )
+  .map_err(handle_error)
+  .unwrap();
 }
@@ -0,0 +1,178 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The FX30 platform.
+///
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use buoy_code::errors::GiftError;
+
+use crate::platform::{Adc, Gpio, Platform};
+use crate::voltage::parse_raw;
+
+pub struct Fx30Platform;
+
+impl Fx30Platform {
+  pub fn new() -> Self {
+    Fx30Platform
+  }
+}
+
+impl Default for Fx30Platform {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn adc_path(adc: Adc) -> &'static str {
+  match adc {
+    Adc::Battery => buoy_code::GPIO_ADC_PATH,
+    Adc::LampCurrent => buoy_code::LAMP_CURRENT_ADC_PATH,
+  }
+}
+
+fn gpio_path(gpio: Gpio) -> &'static str {
+  match gpio {
+    Gpio::NavLight => buoy_code::BUOY_NAV_LIGHT_GPIO,
+  }
+}
+
+///
+/// Run a command and return stdout, stderr is logged if it fails.
+///
+fn run_command(cmd: &str, args: &[&str]) -> Result<String, GiftError> {
+  let output = Command::new(cmd).args(args).output()?;
+  if !output.status.success() {
+    error!(
+      "{} {:?} failed: {}",
+      cmd,
+      args,
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+impl Platform for Fx30Platform {
+  fn read_adc(&self, adc: Adc) -> Result<f32, GiftError> {
+    parse_raw(&fs::read_to_string(adc_path(adc))?)
+  }
+
+  fn set_gpio(&self, gpio: Gpio, on: bool) -> Result<(), GiftError> {
+    fs::write(gpio_path(gpio), if on { b"1" } else { b"0" })?;
+    Ok(())
+  }
+
+  fn get_gpio(&self, gpio: Gpio) -> Result<bool, GiftError> {
+    Ok(fs::read_to_string(gpio_path(gpio))?.trim() == "1")
+  }
+
+  fn read_gnss(&self) -> Result<String, GiftError> {
+    // gps.sh has its own timeout, and prints "GPS FAILED" if there's no fix
+    let mut gps_cmd = Command::new(buoy_code::GPS_SCRIPT)
+      .stdout(Stdio::piped())
+      .spawn()
+      .map_err(GiftError::Io)?;
+    let stdout = gps_cmd.stdout.take().ok_or(GiftError::GPSIssue)?;
+
+    // Don't wait for EOF, anything the script left running in the background
+    // may still hold stdout open.  Stop once the script itself has exited.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+      for line in BufReader::new(stdout).lines() {
+        let ln = match line {
+          Ok(ln) => ln,
+          Err(_) => break,
+        };
+        if tx.send(ln).is_err() {
+          break;
+        }
+      }
+    });
+
+    let deadline = Instant::now() + buoy_code::GPS_SCRIPT_TIMEOUT;
+    let mut output = String::new();
+    let mut exited = false;
+    loop {
+      match rx.recv_timeout(Duration::from_millis(100)) {
+        Ok(ln) => {
+          output.push_str(&ln);
+          output.push('\n');
+          continue;
+        }
+        Err(RecvTimeoutError::Disconnected) => break,
+        // The script has exited and there's nothing left in the pipe
+        Err(RecvTimeoutError::Timeout) if exited => break,
+        Err(RecvTimeoutError::Timeout) => (),
+      }
+
+      if gps_cmd.try_wait()?.is_some() {
+        exited = true;
+      } else if Instant::now() > deadline {
+        error!("{} timed out, killing it", buoy_code::GPS_SCRIPT);
+        gps_cmd.kill()?;
+        break;
+      }
+    }
+
+    // Although the process exited, we still need to close it
+    gps_cmd.wait()?;
+
+    Ok(output)
+  }
+
+  fn enter_ulpm(&self, sleep: Duration) -> Result<(), GiftError> {
+    let output = Command::new(buoy_code::ULPM_SCRIPT)
+      .arg(sleep.as_secs().to_string())
+      .output()?;
+    error!(
+      "run_ulpm done: stderr: {}",
+      String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+  }
+
+  fn reboot(&self) -> Result<(), GiftError> {
+    run_command("reboot", &[])?;
+    Ok(())
+  }
+
+  fn modem_info(&self) -> Result<String, GiftError> {
+    // `cm` is slow, so the data session state is read from the interface
+    // rather than running `cm data info` as well
+    let radio = run_command("cm", &["radio"])?;
+    let connected = match fs::read_to_string(buoy_code::MODEM_DATA_OPERSTATE) {
+      // rmnet reports "unknown" when it's up
+      Ok(state) => state.trim() != "down",
+      Err(_) => false,
+    };
+    Ok(format!(
+      "{}\nConnected: {}\n",
+      radio,
+      if connected { "yes" } else { "no" }
+    ))
+  }
+}
@@ -0,0 +1,98 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The hardware abstraction layer.  All access to the FX30 hardware goes
+/// through the `Platform` trait, so the rest of the buoy code can run on a
+/// Linux box against the simulator.
+///
+///  - fx30.rs: The FX30 implementation (sysfs, gps.sh, ulpm.sh and cm)
+///  - sim.rs: A scriptable simulator with voltage curves and GPS tracks
+///
+/// The time comes from the `Clock` trait, so the simulator can also run the
+/// controller through days of operation in a test.
+///
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use buoy_code::errors::GiftError;
+
+pub mod fx30;
+pub mod sim;
+
+/// The ADC inputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Adc {
+  Battery,     // The battery voltage divider
+  LampCurrent, // The navigation lamp current sense, if fitted
+}
+
+/// The GPIO outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Gpio {
+  NavLight, // The navigation light
+}
+
+pub trait Platform: Send + Sync {
+  /// Read the raw value of an ADC input.
+  fn read_adc(&self, adc: Adc) -> Result<f32, GiftError>;
+
+  /// Set a GPIO output.
+  fn set_gpio(&self, gpio: Gpio, on: bool) -> Result<(), GiftError>;
+
+  /// Read back the state of a GPIO.
+  fn get_gpio(&self, gpio: Gpio) -> Result<bool, GiftError>;
+
+  /// Get a GNSS fix, returns the output of the `gnss get` commands, see
+  /// `Position::parse_gnss()`.
+  fn read_gnss(&self) -> Result<String, GiftError>;
+
+  /// Power off the device and wake it up again after `sleep`.
+  fn enter_ulpm(&self, sleep: Duration) -> Result<(), GiftError>;
+
+  /// Reboot the device.
+  fn reboot(&self) -> Result<(), GiftError>;
+
+  /// The output of `cm radio`, with a `Connected: yes|no` line for the data
+  /// session.
+  fn modem_info(&self) -> Result<String, GiftError>;
+}
+
+pub trait Clock: Send + Sync {
+  /// The monotonic time.
+  fn now(&self) -> Instant;
+
+  /// The system time, it may not have been set, see clock_discipline.rs.
+  fn utc(&self) -> DateTime<Utc>;
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn utc(&self) -> DateTime<Utc> {
+    Utc::now()
+  }
+}
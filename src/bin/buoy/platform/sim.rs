@@ -0,0 +1,424 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// A scriptable simulator of the FX30.  The battery voltage and the GPS
+/// position follow curves of (seconds, value) points, with linear
+/// interpolation between the points.  The curves can repeat, e.g. every day
+/// to simulate solar charging.
+///
+/// The simulated clock either follows the real clock (sped up by `speed`),
+/// or is moved forward by hand with `advance()` for tests.  Time spent in ULPM
+/// is skipped.  The simulator is also the `Clock`, so the controller sees the
+/// simulated time.
+///
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use buoy_code::errors::GiftError;
+
+use crate::platform::{Adc, Clock, Gpio, Platform};
+use crate::voltage::Calibration;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct SimScript {
+  pub speed: f64,                  // Simulated seconds per real second
+  pub period_secs: Option<f64>,    // Repeat the curves with this period
+  pub voltage: Vec<(f64, f32)>,    // (seconds, volts) battery voltage curve
+  pub voltage_noise: f32,          // Random noise added to each reading, in volts
+  pub track: Vec<(f64, f64, f64)>, // (seconds, latitude, longitude) GPS track
+  pub gnss_accuracy: f32,          // The reported horizontal accuracy, in metres
+  pub satellites: u32,             // The number of satellites used
+  pub lamp_raw: f32, // The lamp current ADC value while the light is on, 0 for a dead lamp
+  pub rssi: Vec<(f64, f32)>, // (seconds, dBm) modem signal curve, below -110 is no coverage
+  pub modem_info: String, // What `modem_info()` returns, instead of the rssi curve
+}
+
+impl Default for SimScript {
+  fn default() -> Self {
+    SimScript {
+      speed: 1.0,
+      period_secs: None,
+      voltage: vec![(0.0, 12.6)],
+      voltage_noise: 0.0,
+      track: vec![(0.0, -36.843_292, 174.756_864)],
+      gnss_accuracy: 10.0,
+      satellites: 7,
+      lamp_raw: 2000.0,
+      rssi: vec![(0.0, -70.0)],
+      modem_info: String::new(),
+    }
+  }
+}
+
+impl SimScript {
+  pub fn validate(&self) -> Result<(), GiftError> {
+    if self.voltage.is_empty() || self.track.is_empty() || self.rssi.is_empty() {
+      return Err(GiftError::InvalidConfig(String::from(
+        "sim: voltage, track and rssi need at least one point",
+      )));
+    }
+    if self.voltage.windows(2).any(|w| w[0].0 >= w[1].0)
+      || self.track.windows(2).any(|w| w[0].0 >= w[1].0)
+      || self.rssi.windows(2).any(|w| w[0].0 >= w[1].0)
+    {
+      return Err(GiftError::InvalidConfig(String::from(
+        "sim: voltage, track and rssi points must be sorted by time",
+      )));
+    }
+    if self.speed <= 0.0 || self.period_secs.map_or(false, |p| p <= 0.0) {
+      return Err(GiftError::InvalidConfig(String::from(
+        "sim: speed and period_secs must be above 0",
+      )));
+    }
+    Ok(())
+  }
+
+  /// The time within the curves.
+  fn curve_time(&self, secs: f64) -> f64 {
+    match self.period_secs {
+      Some(period) => secs % period,
+      None => secs,
+    }
+  }
+
+  pub fn voltage_at(&self, secs: f64) -> f32 {
+    let t = self.curve_time(secs);
+    let (i0, i1, frac) = segment(self.voltage.iter().map(|p| p.0), t);
+    let (v0, v1) = (self.voltage[i0].1, self.voltage[i1].1);
+    v0 + (v1 - v0) * frac as f32
+  }
+
+  pub fn rssi_at(&self, secs: f64) -> f32 {
+    let t = self.curve_time(secs);
+    let (i0, i1, frac) = segment(self.rssi.iter().map(|p| p.0), t);
+    let (r0, r1) = (self.rssi[i0].1, self.rssi[i1].1);
+    r0 + (r1 - r0) * frac as f32
+  }
+
+  /// The (latitude, longitude) at `secs`.
+  pub fn position_at(&self, secs: f64) -> (f64, f64) {
+    let t = self.curve_time(secs);
+    let (i0, i1, frac) = segment(self.track.iter().map(|p| p.0), t);
+    let (_, lat0, lon0) = self.track[i0];
+    let (_, lat1, lon1) = self.track[i1];
+    (lat0 + (lat1 - lat0) * frac, lon0 + (lon1 - lon0) * frac)
+  }
+}
+
+///
+/// Find the two points either side of `t`, and how far `t` is between them.
+/// Before the first point or after the last, that point is used.
+///
+fn segment(times: impl Iterator<Item = f64>, t: f64) -> (usize, usize, f64) {
+  let times: Vec<f64> = times.collect();
+  match times.iter().position(|&x| t < x) {
+    Some(0) => (0, 0, 0.0),
+    Some(i) => (i - 1, i, (t - times[i - 1]) / (times[i] - times[i - 1])),
+    None => (times.len() - 1, times.len() - 1, 0.0),
+  }
+}
+
+// Below this there is no coverage
+const NO_COVERAGE_DBM: f32 = -110.0;
+
+enum SimClock {
+  Realtime(Instant), // Follows the real clock from this instant
+  Manual(f64),       // Moved forward with `advance()`
+}
+
+struct SimState {
+  clock: SimClock,
+  skipped: f64,                     // Seconds skipped in ULPM
+  seed: u64,                        // For the voltage noise
+  gpio: HashMap<Gpio, bool>,        // The current GPIO values
+  gpio_log: Vec<(f64, Gpio, bool)>, // Every GPIO write
+  ulpm_log: Vec<(f64, Duration)>,   // Every ULPM request
+  reboot_log: Vec<f64>,             // Every reboot request
+}
+
+pub struct SimPlatform {
+  script: SimScript,
+  cal: Calibration, // Used to turn the voltage back into a raw ADC value
+  started: (Instant, DateTime<Utc>), // The start of the simulated time
+  state: Mutex<SimState>,
+}
+
+impl SimPlatform {
+  fn new(script: &SimScript, cal: &Calibration, clock: SimClock) -> Self {
+    SimPlatform {
+      script: script.clone(),
+      cal: cal.clone(),
+      started: (Instant::now(), Utc::now()),
+      state: Mutex::new(SimState {
+        clock,
+        skipped: 0.0,
+        seed: 0x2545_f491_4f6c_dd1d,
+        gpio: HashMap::new(),
+        gpio_log: Vec::new(),
+        ulpm_log: Vec::new(),
+        reboot_log: Vec::new(),
+      }),
+    }
+  }
+
+  /// A simulator that follows the real clock.
+  pub fn realtime(script: &SimScript, cal: &Calibration) -> Self {
+    Self::new(script, cal, SimClock::Realtime(Instant::now()))
+  }
+
+  /// A simulator where time only moves with `advance()`.
+  pub fn manual(script: &SimScript, cal: &Calibration) -> Self {
+    Self::new(script, cal, SimClock::Manual(0.0))
+  }
+
+  /// Move the manual clock forward.
+  pub fn advance(&self, duration: Duration) {
+    if let SimClock::Manual(ref mut secs) = self.state.lock().unwrap().clock {
+      *secs += duration.as_secs_f64();
+    }
+  }
+
+  fn now_secs(state: &SimState, speed: f64) -> f64 {
+    let secs = match state.clock {
+      SimClock::Realtime(start) => start.elapsed().as_secs_f64() * speed,
+      SimClock::Manual(secs) => secs,
+    };
+    secs + state.skipped
+  }
+
+  /// The simulated time, in seconds since the start.
+  pub fn now(&self) -> f64 {
+    Self::now_secs(&self.state.lock().unwrap(), self.script.speed)
+  }
+
+  /// Every GPIO write as (seconds, gpio, value).
+  pub fn gpio_log(&self) -> Vec<(f64, Gpio, bool)> {
+    self.state.lock().unwrap().gpio_log.clone()
+  }
+
+  /// Every ULPM request as (seconds, sleep time).
+  pub fn ulpm_log(&self) -> Vec<(f64, Duration)> {
+    self.state.lock().unwrap().ulpm_log.clone()
+  }
+
+  /// Every reboot request, in seconds.
+  pub fn reboot_log(&self) -> Vec<f64> {
+    self.state.lock().unwrap().reboot_log.clone()
+  }
+
+  /// Uniform noise in [-voltage_noise, voltage_noise], xorshift so runs repeat.
+  fn noise(&self, state: &mut SimState) -> f32 {
+    state.seed ^= state.seed << 13;
+    state.seed ^= state.seed >> 7;
+    state.seed ^= state.seed << 17;
+    let unit = (state.seed >> 11) as f64 / (1u64 << 53) as f64;
+    self.script.voltage_noise * (2.0 * unit as f32 - 1.0)
+  }
+}
+
+impl Platform for SimPlatform {
+  fn read_adc(&self, adc: Adc) -> Result<f32, GiftError> {
+    let mut state = self.state.lock().unwrap();
+    match adc {
+      Adc::Battery => {
+        let now = Self::now_secs(&state, self.script.speed);
+        let volts = self.script.voltage_at(now) + self.noise(&mut state);
+        Ok(self.cal.invert(volts))
+      }
+      Adc::LampCurrent => match state.gpio.get(&Gpio::NavLight) {
+        Some(true) => Ok(self.script.lamp_raw),
+        _ => Ok(0.0),
+      },
+    }
+  }
+
+  fn set_gpio(&self, gpio: Gpio, on: bool) -> Result<(), GiftError> {
+    let mut state = self.state.lock().unwrap();
+    let now = Self::now_secs(&state, self.script.speed);
+    state.gpio.insert(gpio, on);
+    state.gpio_log.push((now, gpio, on));
+    Ok(())
+  }
+
+  fn get_gpio(&self, gpio: Gpio) -> Result<bool, GiftError> {
+    let state = self.state.lock().unwrap();
+    Ok(state.gpio.get(&gpio).cloned().unwrap_or(false))
+  }
+
+  fn read_gnss(&self) -> Result<String, GiftError> {
+    let (lat, lon) = self.script.position_at(self.now());
+    let now = self.utc();
+
+    // The same as gps.sh
+    Ok(format!(
+      "Latitude(positive->north) : {:.6}\n\
+       Longitude(positive->east) : {:.6}\n\
+       hAccuracy                 : {:.1}m\n\
+       Position state: 3D Fix\n\
+       Date(YYYY-MM-DD) {}\n\
+       Time(HH:MM:SS:ms) {}\n\
+       Satellites Used: {}\n",
+      lat,
+      lon,
+      self.script.gnss_accuracy,
+      now.format("%Y-%m-%d"),
+      now.format("%H:%M:%S:%3f"),
+      self.script.satellites,
+    ))
+  }
+
+  fn enter_ulpm(&self, sleep: Duration) -> Result<(), GiftError> {
+    let mut state = self.state.lock().unwrap();
+    let now = Self::now_secs(&state, self.script.speed);
+    info!("sim: ULPM for {:?} at {:.0} s", sleep, now);
+    state.ulpm_log.push((now, sleep));
+    state.skipped += sleep.as_secs_f64();
+    Ok(())
+  }
+
+  fn reboot(&self) -> Result<(), GiftError> {
+    let mut state = self.state.lock().unwrap();
+    let now = Self::now_secs(&state, self.script.speed);
+    warn!("sim: reboot at {:.0} s", now);
+    state.reboot_log.push(now);
+    Ok(())
+  }
+
+  fn modem_info(&self) -> Result<String, GiftError> {
+    if !self.script.modem_info.is_empty() {
+      return Ok(self.script.modem_info.clone());
+    }
+
+    // The same as the FX30's `cm radio` and data session state
+    let rssi = self.script.rssi_at(self.now());
+    let (status, connected) = if rssi > NO_COVERAGE_DBM {
+      ("Registered, home network (LE_MRC_REG_HOME)", "yes")
+    } else {
+      ("Not registered, searching (LE_MRC_REG_SEARCHING)", "no")
+    };
+    Ok(format!(
+      "Power:                         ON\n\
+       Current Network Operator:      Simulated\n\
+       Current RAT:                   LTE network (LE_MRC_RAT_LTE)\n\
+       Status:                        {}\n\
+       Cell ID:                       0x1\n\
+       RSSI:                          {:.0} dBm\n\
+       Connected:                     {}\n",
+      status, rssi, connected
+    ))
+  }
+}
+
+impl Clock for SimPlatform {
+  fn now(&self) -> Instant {
+    self.started.0 + Duration::from_secs_f64(SimPlatform::now(self))
+  }
+
+  fn utc(&self) -> DateTime<Utc> {
+    let millis = (SimPlatform::now(self) * 1000.0) as i64;
+    self.started.1 + chrono::Duration::milliseconds(millis)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::platform::sim::*;
+
+  #[test]
+  fn test_curves() {
+    let script = SimScript {
+      period_secs: Some(200.0),
+      voltage: vec![(0.0, 12.0), (100.0, 13.0), (150.0, 12.0)],
+      track: vec![(0.0, -36.0, 174.0), (100.0, -37.0, 175.0)],
+      ..SimScript::default()
+    };
+    assert!(script.validate().is_ok());
+
+    assert_eq!(12.0, script.voltage_at(0.0));
+    assert_eq!(12.5, script.voltage_at(50.0));
+    assert_eq!(12.5, script.voltage_at(125.0));
+    assert_eq!(12.0, script.voltage_at(175.0));
+    assert_eq!(12.5, script.voltage_at(250.0));
+    assert_eq!((-36.5, 174.5), script.position_at(50.0));
+    assert_eq!((-37.0, 175.0), script.position_at(150.0));
+  }
+
+  #[test]
+  fn test_sim_platform() {
+    let script = SimScript {
+      voltage: vec![(0.0, 12.0), (3600.0, 11.0)],
+      voltage_noise: 0.05,
+      ..SimScript::default()
+    };
+    let cal = Calibration::default();
+    let sim = SimPlatform::manual(&script, &cal);
+
+    let volts = cal.apply(sim.read_adc(Adc::Battery).unwrap());
+    assert!((volts - 12.0).abs() <= 0.051, "volts: {}", volts);
+
+    sim.advance(Duration::from_secs(1800));
+    let volts = cal.apply(sim.read_adc(Adc::Battery).unwrap());
+    assert!((volts - 11.5).abs() <= 0.051, "volts: {}", volts);
+
+    let (instant, utc) = (Clock::now(&sim), sim.utc());
+    sim.enter_ulpm(Duration::from_secs(1800)).unwrap();
+    assert_eq!(3600.0, sim.now());
+    assert_eq!(Duration::from_secs(1800), Clock::now(&sim) - instant);
+    assert_eq!(1800, (sim.utc() - utc).num_seconds());
+    assert_eq!(vec![(1800.0, Duration::from_secs(1800))], sim.ulpm_log());
+
+    sim.set_gpio(Gpio::NavLight, true).unwrap();
+    assert!(sim.get_gpio(Gpio::NavLight).unwrap());
+    assert_eq!(vec![(3600.0, Gpio::NavLight, true)], sim.gpio_log());
+
+    assert!(sim
+      .read_gnss()
+      .unwrap()
+      .starts_with("Latitude(positive->north) : -36.843292"));
+  }
+
+  #[test]
+  fn test_modem_coverage() {
+    use buoy_code::modem::ModemStatus;
+
+    let script = SimScript {
+      rssi: vec![(0.0, -70.0), (100.0, -120.0)],
+      ..SimScript::default()
+    };
+    let sim = SimPlatform::manual(&script, &Calibration::default());
+
+    let modem = ModemStatus::parse(&sim.modem_info().unwrap());
+    assert_eq!(Some(-70), modem.rssi_dbm);
+    assert_eq!(Some(String::from("home")), modem.registration);
+    assert_eq!(Some(true), modem.data_connected);
+
+    sim.advance(Duration::from_secs(100));
+    let modem = ModemStatus::parse(&sim.modem_info().unwrap());
+    assert_eq!(Some(-120), modem.rssi_dbm);
+    assert_eq!(Some(String::from("searching")), modem.registration);
+    assert_eq!(Some(false), modem.data_connected);
+  }
+}
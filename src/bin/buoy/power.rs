@@ -0,0 +1,442 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The power policy state machine.  The states, from best to worst, are:
+///
+///    Normal -> Conserve -> Low -> Critical
+///
+/// Each state, except Normal, is entered when the filtered battery voltage
+/// drops below `enter_below` and left (to the next better state) when the
+/// voltage rises above `exit_above`.  `exit_above` must be higher than
+/// `enter_below` so the buoy does not bounce between states.
+///
+/// Each state defines which features are allowed and whether the buoy should
+/// sleep in ULPM after being awake for `awake_secs`.
+///
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use buoy_code::errors::GiftError;
+use buoy_code::power_state::{PowerState, Transition, ALL_STATES};
+
+use crate::clock_discipline::ClockDiscipline;
+
+///
+/// What the buoy is allowed to do.
+///
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Features {
+  pub recording: bool, // Upload the hydrophone recordings
+  pub uploads: bool,   // Connect to the server at all
+  pub gps: bool,       // Get GPS fixes
+  pub nav_light: bool, // Flash the navigation light
+}
+
+impl Features {
+  const ALL: Features = Features {
+    recording: true,
+    uploads: true,
+    gps: true,
+    nav_light: true,
+  };
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct StatePolicy {
+  pub enter_below: f32,   // Enter this state when the voltage drops below this
+  pub exit_above: f32,    // Leave this state when the voltage rises above this
+  pub awake_secs: u64,    // How long to stay awake before sleeping
+  pub sleep_secs: u64,    // How long to sleep in ULPM, 0 to stay awake
+  pub features: Features, // What's allowed in this state
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct PowerConfig {
+  pub check_interval_secs: u64, // How often the battery state is checked
+  pub flush_timeout_secs: u64,  // How long to wait for uploads before sleeping, see shutdown.rs
+  pub normal: Features,
+  pub conserve: StatePolicy,
+  pub low: StatePolicy,
+  pub critical: StatePolicy,
+}
+
+impl Default for PowerConfig {
+  fn default() -> Self {
+    PowerConfig {
+      check_interval_secs: 60,
+      flush_timeout_secs: 2 * 60,
+      normal: Features::ALL,
+      conserve: StatePolicy {
+        enter_below: 12.0,
+        exit_above: 12.3,
+        awake_secs: 9 * 60,
+        sleep_secs: 30 * 60,
+        features: Features::ALL,
+      },
+      low: StatePolicy {
+        enter_below: 11.0,
+        exit_above: 11.4,
+        awake_secs: 9 * 60,
+        sleep_secs: 3 * 60 * 60,
+        features: Features {
+          recording: false,
+          ..Features::ALL
+        },
+      },
+      critical: StatePolicy {
+        enter_below: 10.5,
+        exit_above: 11.0,
+        awake_secs: 5 * 60,
+        sleep_secs: 6 * 60 * 60,
+        features: Features {
+          recording: false,
+          uploads: true,
+          gps: false,
+          nav_light: true,
+        },
+      },
+    }
+  }
+}
+
+impl PowerConfig {
+  /// The policy for a state, Normal has none.
+  pub fn policy(&self, state: PowerState) -> Option<&StatePolicy> {
+    match state {
+      PowerState::Normal => None,
+      PowerState::Conserve => Some(&self.conserve),
+      PowerState::Low => Some(&self.low),
+      PowerState::Critical => Some(&self.critical),
+    }
+  }
+
+  pub fn features(&self, state: PowerState) -> Features {
+    self.policy(state).map_or(self.normal, |p| p.features)
+  }
+
+  pub fn validate(&self) -> Result<(), GiftError> {
+    let policies = [&self.conserve, &self.low, &self.critical];
+    if self.check_interval_secs == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "power: check_interval_secs must be above 0",
+      )));
+    }
+    if policies.iter().any(|p| p.exit_above <= p.enter_below) {
+      return Err(GiftError::InvalidConfig(String::from(
+        "power: exit_above must be higher than enter_below",
+      )));
+    }
+    if policies
+      .windows(2)
+      .any(|w| w[1].enter_below >= w[0].enter_below)
+    {
+      return Err(GiftError::InvalidConfig(String::from(
+        "power: enter_below must decrease from conserve to low to critical",
+      )));
+    }
+    Ok(())
+  }
+}
+
+pub struct PowerPolicy {
+  config: PowerConfig,
+  state: PowerState,
+  awake_since: Instant, // When we woke up, or entered the current state
+  // Transitions not yet reported to the server, and when they happened if
+  // their time still needs correcting
+  pending: Vec<(Transition, Option<Instant>)>,
+}
+
+impl PowerPolicy {
+  pub fn new(config: &PowerConfig, now: Instant) -> Self {
+    PowerPolicy {
+      config: config.clone(),
+      state: PowerState::Normal,
+      awake_since: now,
+      pending: Vec::new(),
+    }
+  }
+
+  pub fn state(&self) -> PowerState {
+    self.state
+  }
+
+  pub fn features(&self) -> Features {
+    self.config.features(self.state)
+  }
+
+  fn next_state(&self, voltage: f32) -> PowerState {
+    // Dropping: go to the worst state we are below
+    if let Some(&worse) = ALL_STATES
+      .iter()
+      .rev()
+      .filter(|&&s| s > self.state)
+      .find(|&&s| voltage < self.config.policy(s).unwrap().enter_below)
+    {
+      return worse;
+    }
+
+    // Rising: climb one state at a time while we are above the exit voltage
+    let mut state = self.state;
+    while let Some(policy) = self.config.policy(state) {
+      if voltage > policy.exit_above {
+        state = ALL_STATES[state as usize - 1];
+      } else {
+        break;
+      }
+    }
+    state
+  }
+
+  ///
+  /// Update the state with the filtered battery voltage, returns the
+  /// transition if the state changed.  `system` is the system clock time at
+  /// `now`, the transition time is corrected when it's reported, the same as
+  /// the recording start times.
+  ///
+  pub fn update(&mut self, voltage: f32, now: Instant, system: &str) -> Option<Transition> {
+    let next = self.next_state(voltage);
+    if next == self.state {
+      return None;
+    }
+
+    let transition = Transition {
+      from: self.state,
+      to: next,
+      voltage,
+      time: String::from(system),
+    };
+    self.state = next;
+    self.awake_since = now;
+    self.pending.push((transition.clone(), Some(now)));
+
+    Some(transition)
+  }
+
+  ///
+  /// How long we should sleep for, if it's time to go to sleep.
+  ///
+  pub fn sleep_due(&self, now: Instant) -> Option<Duration> {
+    let policy = self.config.policy(self.state)?;
+    if policy.sleep_secs > 0
+      && now.duration_since(self.awake_since) >= Duration::from_secs(policy.awake_secs)
+    {
+      Some(Duration::from_secs(policy.sleep_secs))
+    } else {
+      None
+    }
+  }
+
+  /// We have woken up from a sleep, or been told not to sleep yet.
+  pub fn woke(&mut self, now: Instant) {
+    self.awake_since = now;
+  }
+
+  /// The transitions not yet reported to the server, with their times from
+  /// `clock`.
+  pub fn transitions(&self, clock: &ClockDiscipline) -> Vec<Transition> {
+    self
+      .pending
+      .iter()
+      .map(|(transition, instant)| match instant {
+        Some(instant) => Transition {
+          time: clock.stamp(*instant, &transition.time).0,
+          ..transition.clone()
+        },
+        None => transition.clone(),
+      })
+      .collect()
+  }
+
+  ///
+  /// Carry on in the state we were in before a sleep.  The voltage updates
+  /// move us out of it as usual.
+  ///
+  pub fn restore(&mut self, state: PowerState, transitions: Vec<Transition>, now: Instant) {
+    self.state = state;
+    self.awake_since = now;
+    self.restore_transitions(transitions);
+  }
+
+  /// Take the transitions that need to be reported to the server.
+  pub fn take_transitions(&mut self, clock: &ClockDiscipline) -> Vec<Transition> {
+    let transitions = self.transitions(clock);
+    self.pending.clear();
+    transitions
+  }
+
+  /// Put back transitions that could not be reported.
+  pub fn restore_transitions(&mut self, transitions: Vec<Transition>) {
+    let mut restored: Vec<_> = transitions.into_iter().map(|t| (t, None)).collect();
+    restored.append(&mut self.pending);
+    self.pending = restored;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::power::*;
+  use buoy_code::clock::{parse_date, TimeSource};
+
+  const SYSTEM: &str = "20200830T023517.000Z";
+
+  fn run(policy: &mut PowerPolicy, voltages: &[f32]) -> Vec<PowerState> {
+    let now = Instant::now();
+    voltages
+      .iter()
+      .map(|&v| {
+        policy.update(v, now, SYSTEM);
+        policy.state()
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_hysteresis() {
+    let mut policy = PowerPolicy::new(&PowerConfig::default(), Instant::now());
+
+    use PowerState::*;
+    assert_eq!(
+      vec![Normal, Conserve, Conserve, Conserve, Normal],
+      run(&mut policy, &[12.1, 11.9, 12.1, 12.3, 12.31])
+    );
+  }
+
+  #[test]
+  fn test_drop_and_recover() {
+    let mut policy = PowerPolicy::new(&PowerConfig::default(), Instant::now());
+
+    use PowerState::*;
+    // A sudden drop goes straight to the worst state, recovery is one state at a time
+    assert_eq!(
+      vec![Critical, Critical, Low, Conserve, Conserve, Normal],
+      run(&mut policy, &[10.4, 10.9, 11.2, 11.5, 12.2, 12.4])
+    );
+
+    let clock = ClockDiscipline::default();
+    let transitions = policy.take_transitions(&clock);
+    assert_eq!(4, transitions.len());
+    assert_eq!(PowerState::Critical, transitions[0].to);
+    assert_eq!(SYSTEM, transitions[0].time);
+    assert!(policy.take_transitions(&clock).is_empty());
+  }
+
+  #[test]
+  fn test_unsynced_time() {
+    let start = Instant::now();
+    let mut policy = PowerPolicy::new(&PowerConfig::default(), start);
+    let mut clock = ClockDiscipline::default();
+
+    // The clock is set after the transition
+    policy.update(11.9, start, "19700101T000142.000Z");
+    assert_eq!("19700101T000142.000Z", policy.transitions(&clock)[0].time);
+    let fix = parse_date("20200830T023527.000Z").unwrap();
+    clock.add_reference(fix, TimeSource::Gnss, start + Duration::from_secs(10));
+    assert_eq!("20200830T023517.000Z", policy.transitions(&clock)[0].time);
+
+    // Put back after a failed upload, they keep their corrected time
+    let transitions = policy.take_transitions(&clock);
+    policy.restore_transitions(transitions);
+    assert_eq!(
+      "20200830T023517.000Z",
+      policy.take_transitions(&ClockDiscipline::default())[0].time
+    );
+  }
+
+  #[test]
+  fn test_sleep_due() {
+    let config = PowerConfig::default();
+    let start = Instant::now();
+    let mut policy = PowerPolicy::new(&config, start);
+
+    assert_eq!(None, policy.sleep_due(start + Duration::from_secs(3600)));
+
+    policy.update(11.9, start, SYSTEM);
+    assert_eq!(None, policy.sleep_due(start + Duration::from_secs(60)));
+    assert_eq!(
+      Some(Duration::from_secs(config.conserve.sleep_secs)),
+      policy.sleep_due(start + Duration::from_secs(config.conserve.awake_secs))
+    );
+    assert_eq!(PowerState::Conserve, policy.state());
+    assert_eq!(config.conserve.features, policy.features());
+
+    // Waking starts the awake time again
+    let woke = start + Duration::from_secs(config.conserve.awake_secs + config.conserve.sleep_secs);
+    policy.woke(woke);
+    assert_eq!(None, policy.sleep_due(woke + Duration::from_secs(60)));
+
+    // After a restart we carry on where we were
+    let mut restored = PowerPolicy::new(&config, woke);
+    let clock = ClockDiscipline::default();
+    restored.restore(policy.state(), policy.take_transitions(&clock), woke);
+    assert_eq!(PowerState::Conserve, restored.state());
+    assert_eq!(1, restored.transitions(&clock).len());
+    assert_eq!(None, restored.update(11.9, woke, SYSTEM));
+  }
+
+  #[test]
+  fn test_simulated_day() {
+    use crate::battery::{BatteryConfig, BatteryFilter};
+    use crate::platform::sim::{SimPlatform, SimScript};
+    use crate::voltage::{get_voltage, Calibration};
+
+    // Discharge overnight, then the solar panel charges the battery
+    let script = SimScript {
+      voltage: vec![(0.0, 12.4), (12.0 * 3600.0, 10.9), (18.0 * 3600.0, 12.6)],
+      voltage_noise: 0.1,
+      ..SimScript::default()
+    };
+    let cal = Calibration::default();
+    let sim = SimPlatform::manual(&script, &cal);
+    let mut filter = BatteryFilter::new(&BatteryConfig::default());
+    let start = Instant::now();
+    let mut policy = PowerPolicy::new(&PowerConfig::default(), start);
+
+    let mut states = vec![PowerState::Normal];
+    for i in 0..(24 * 60) {
+      let now = start + Duration::from_secs(i * 60);
+      let volts = get_voltage(&sim, &cal).unwrap();
+      let voltage = filter.add(volts, now).voltage;
+      if policy.update(voltage, now, SYSTEM).is_some() {
+        states.push(policy.state());
+      }
+      sim.advance(Duration::from_secs(60));
+    }
+
+    use PowerState::*;
+    assert_eq!(vec![Normal, Conserve, Low, Conserve, Normal], states);
+  }
+
+  #[test]
+  fn test_validate() {
+    let mut config = PowerConfig::default();
+    assert!(config.validate().is_ok());
+
+    config.low.exit_above = config.low.enter_below;
+    assert!(config.validate().is_err());
+
+    let mut config = PowerConfig::default();
+    config.critical.enter_below = config.low.enter_below + 0.1;
+    assert!(config.validate().is_err());
+  }
+}
@@ -23,14 +23,21 @@ use std::io;
 use std::io::prelude::*;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::time;
 
 use serialport::prelude::*;
+use serialport::ClearBuffer;
 
-use crate::voltage::get_voltage;
+use crate::battery::BatteryState;
+use crate::shutdown::Shutdown;
+use crate::supervisor::lock;
 
+use buoy_code::clock::system_source;
 use buoy_code::date_now;
 use buoy_code::errors::GiftError;
+use buoy_code::power_state::PowerState;
+use buoy_code::task_restarts::TaskRestarts;
 use buoy_code::BuoyData;
 use buoy_code::ControllerAction;
 
@@ -126,10 +133,17 @@ fn get_os_uptime() -> i64 {
   }
 }
 
+///
+/// `start` is the system time and instant the recording started, the
+/// controller corrects the time with the disciplined clock.
+///
 pub fn create_buoy_data(
   hydrophone: Option<Vec<u8>>,
-  start_time: Option<String>,
+  start: Option<(String, time::Instant)>,
+  battery: &Arc<Mutex<BatteryState>>,
 ) -> Result<BuoyData, GiftError> {
+  let battery = lock(battery).clone();
+  let (start_time, start_instant) = start.unwrap_or_else(|| (date_now(), time::Instant::now()));
   Ok(BuoyData {
     id: buoy_code::BUOY_ID,
     hydrophone: if hydrophone.is_none() {
@@ -137,21 +151,31 @@ pub fn create_buoy_data(
     } else {
       hydrophone.unwrap()
     },
-    voltage: get_voltage()?,
+    voltage: battery.voltage,
+    battery_soc: battery.soc,
+    battery_trend: battery.trend,
     dropped_blocks: 0,
-    gps: String::from(""),
-    start_time: if start_time.is_none() {
-      date_now()
-    } else {
-      start_time.unwrap()
-    },
+    position: None,
+    drift_alert: None,
+    light: None,
+    restarts: TaskRestarts::default(),
+    modem: None,
+    session: None,
+    start_time_source: system_source(&start_time),
+    start_time,
+    start_instant,
+    clock: None,
     uptime: get_os_uptime(),
+    power_state: PowerState::Normal,
+    power_transitions: Vec::new(),
   })
 }
 
 fn read_loop(
   mut port: Box<dyn SerialPort>,
   data_tx: &Sender<ControllerAction>,
+  battery: &Arc<Mutex<BatteryState>>,
+  shutdown: &Shutdown,
 ) -> Result<(), GiftError> {
   let mut serial_buf: Vec<u8> = vec![0; buoy_code::SERIAL_BUF_SIZE];
   let mut send_buf = Vec::new();
@@ -162,6 +186,31 @@ fn read_loop(
   let header_start: [u8; 4] = [b'S', b'T', 0x00, 0x01];
 
   loop {
+    // Going to sleep: send what we have up to the last full frame, and wait
+    if shutdown.is_stopping() {
+      let (buf, _partial_frame) = clean_x3_data(&send_buf, &header_start);
+      if !buf.is_empty() {
+        info!(
+          "Collected hydrophone data before sleeping: {} - {} bytes",
+          start_time,
+          buf.len()
+        );
+        data_tx.send(ControllerAction::CtrlBuoyData(create_buoy_data(
+          Some(buf),
+          Some((start_time, rec_time)),
+          battery,
+        )?))?;
+      }
+      data_tx.send(ControllerAction::CtrlRecordingStopped)?;
+      shutdown.wait_resumed();
+
+      // Start a new recording, anything the hydrophone sent meanwhile is stale
+      port.clear(ClearBuffer::Input)?;
+      send_buf.clear();
+      rec_time = time::Instant::now();
+      start_time = date_now();
+    }
+
     // Read data from UART
     match port.read(serial_buf.as_mut_slice()) {
       Ok(bytes_read) => {
@@ -185,7 +234,8 @@ fn read_loop(
       );
       data_tx.send(ControllerAction::CtrlBuoyData(create_buoy_data(
         Some(buf),
-        Some(start_time),
+        Some((start_time, rec_time)),
+        battery,
       )?))?;
 
       // Restart the collection timers
@@ -199,6 +249,8 @@ pub fn sensor_reader(
   data_tx: &Sender<ControllerAction>,
   port_name: &PathBuf,
   port_baud: u32,
+  battery: &Arc<Mutex<BatteryState>>,
+  shutdown: &Shutdown,
 ) -> Result<(), GiftError> {
   let settings = SerialPortSettings {
     baud_rate: port_baud,
@@ -214,16 +266,9 @@ pub fn sensor_reader(
     &port_name, port_baud
   );
 
-  match serialport::open_with_settings(&port_name, &settings) {
-    Ok(port) => read_loop(port, data_tx),
-    Err(e) => {
-      error!(
-        "sensor_reader(): An error occurred reading serial port: {:?}",
-        GiftError::Serialport(e)
-      );
-      Ok(())
-    }
-  }
+  // The supervisor restarts us if the port can't be opened
+  let port = serialport::open_with_settings(&port_name, &settings)?;
+  read_loop(port, data_tx, battery, shutdown)
 }
 
 #[cfg(test)]
@@ -0,0 +1,74 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Going to sleep in ULPM switches the FX30 off, so everything has to be
+/// wrapped up first.  When the power policy says it's time to sleep the
+/// controller:
+///
+///   1. Asks the sensor reader to stop at the end of the last full x3 frame,
+///      the last recording is sent and then the reader waits.
+///   2. Waits up to `flush_timeout_secs` for the uploads in progress, the
+///      ones that don't finish are spooled to flash, see spool.rs.
+///   3. Tells the server how long we are going to sleep for.
+///   4. Saves the state that would otherwise be lost, see state_store.rs.
+///   5. Enters ULPM.
+///
+/// The saved state is restored, and the spool sent, when the buoy starts.
+///
+use std::sync::{Condvar, Mutex};
+
+use crate::supervisor::lock;
+
+///
+/// Tells the sensor reader to stop recording before going to sleep.
+///
+#[derive(Default)]
+pub struct Shutdown {
+  stopping: Mutex<bool>,
+  changed: Condvar,
+}
+
+impl Shutdown {
+  pub fn stop_recording(&self) {
+    *lock(&self.stopping) = true;
+    self.changed.notify_all();
+  }
+
+  pub fn is_stopping(&self) -> bool {
+    *lock(&self.stopping)
+  }
+
+  /// We are awake again, recording can start.
+  pub fn resume(&self) {
+    *lock(&self.stopping) = false;
+    self.changed.notify_all();
+  }
+
+  pub fn wait_resumed(&self) {
+    let mut stopping = lock(&self.stopping);
+    while *stopping {
+      stopping = self
+        .changed
+        .wait(stopping)
+        .unwrap_or_else(|e| e.into_inner());
+    }
+  }
+}
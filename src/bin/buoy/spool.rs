@@ -0,0 +1,353 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Uploads that could not be sent before going to sleep are spooled to flash,
+/// one file per upload holding the HTTP request, and sent when the buoy wakes:
+///
+///    spool/00000000000000000042.post
+///
+/// The file names are a sequence number, one more than the highest in the
+/// spool, so they are sent in the order they were recorded.  A file is only
+/// removed once the server has the upload, or has rejected it for good.
+///
+/// The server may fail on one request every time it's sent, so the attempts
+/// are counted in a file next to it, and after `SPOOL_MAX_ATTEMPTS` it is
+/// set aside as `.failed` for someone to look at.  A busy or full server
+/// (503) fails them all, so the rest are left for the next pass uncounted.
+///
+/// An upload that is still running when it's spooled keeps going, if it gets
+/// through after all its spool file is removed again.
+///
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use buoy_code::commands::FX30Command;
+use buoy_code::errors::GiftError;
+use buoy_code::BuoyData;
+
+use crate::data_send::Uplink;
+use crate::supervisor::lock;
+
+const SPOOL_EXTENSION: &str = "post";
+const ATTEMPTS_EXTENSION: &str = "attempts";
+const FAILED_EXTENSION: &str = "failed";
+const SPOOL_MAX_ATTEMPTS: u32 = 5;
+
+/// The sequence number of a spool file.
+fn sequence(path: &Path) -> Option<u64> {
+  path.file_stem()?.to_str()?.parse().ok()
+}
+
+pub struct Spool {
+  dir: PathBuf,
+  sending: Mutex<()>,           // Only one upload thread sends the spool
+  next_seq: Mutex<Option<u64>>, // Read from the spool on the first save
+}
+
+impl Spool {
+  pub fn new(dir: &Path) -> Self {
+    Spool {
+      dir: dir.to_path_buf(),
+      sending: Mutex::new(()),
+      next_seq: Mutex::new(None),
+    }
+  }
+
+  ///
+  /// Save a request.  It's written to a temporary file first so a power
+  /// failure does not leave half a request to be sent.
+  ///
+  pub fn save(&self, request: &[u8]) -> Result<PathBuf, GiftError> {
+    fs::create_dir_all(&self.dir)?;
+    let mut next_seq = lock(&self.next_seq);
+    let seq = match *next_seq {
+      Some(seq) => seq,
+      None => self
+        .pending()?
+        .iter()
+        .filter_map(|path| sequence(path))
+        .max()
+        .map_or(0, |seq| seq + 1),
+    };
+
+    let path = self.dir.join(format!("{:020}.{}", seq, SPOOL_EXTENSION));
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, request)?;
+    fs::rename(&tmp_path, &path)?;
+    *next_seq = Some(seq + 1);
+    Ok(path)
+  }
+
+  /// Remove a spooled request, it may already have been sent.
+  pub fn remove(&self, path: &Path) -> Result<(), GiftError> {
+    remove_if_exists(&path.with_extension(ATTEMPTS_EXTENSION))?;
+    remove_if_exists(path)
+  }
+
+  ///
+  /// Count a failed attempt at sending a spooled request, it's set aside
+  /// after `SPOOL_MAX_ATTEMPTS`.  Returns the number of attempts.
+  ///
+  fn count_attempt(&self, path: &Path) -> Result<u32, GiftError> {
+    let attempts_path = path.with_extension(ATTEMPTS_EXTENSION);
+    let attempts = fs::read_to_string(&attempts_path)
+      .ok()
+      .and_then(|s| s.trim().parse::<u32>().ok())
+      .unwrap_or(0)
+      + 1;
+    if attempts >= SPOOL_MAX_ATTEMPTS {
+      fs::rename(path, path.with_extension(FAILED_EXTENSION))?;
+      remove_if_exists(&attempts_path)?;
+    } else {
+      fs::write(&attempts_path, attempts.to_string())?;
+    }
+    Ok(attempts)
+  }
+
+  /// The spooled requests, oldest first.
+  pub fn pending(&self) -> Result<Vec<PathBuf>, GiftError> {
+    if !self.dir.exists() {
+      return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(&self.dir)? {
+      let path = entry?.path();
+      if path.extension().map_or(false, |e| e == SPOOL_EXTENSION) {
+        paths.push(path);
+      }
+    }
+    paths.sort_by_key(|path| (sequence(path), path.clone()));
+    Ok(paths)
+  }
+
+  ///
+  /// Send the spooled requests to the server.  It stops at the first failure,
+  /// the rest are sent next time.  Nothing is done if another thread is
+  /// already sending them.
+  ///
+  pub fn send(&self, uplink: &Uplink) -> Result<(), GiftError> {
+    let _sending = match self.sending.try_lock() {
+      Ok(guard) => guard,
+      Err(_) => return Ok(()),
+    };
+    let pending = self.pending()?;
+    if pending.is_empty() {
+      return Ok(());
+    }
+    info!("Spool::send(): sending {} spooled uploads", pending.len());
+
+    let mut conn = uplink.connect()?;
+    for path in pending {
+      match conn.send_request(fs::read(&path)?) {
+        Ok(()) => (),
+        // The server will never accept it, don't keep it around
+        Err(GiftError::HttpStatus(FX30Command::Drop(e)))
+        | Err(GiftError::HttpStatus(FX30Command::Alert(e))) => {
+          error!("Spool::send(): {:?} was rejected: {}", path, e);
+        }
+        // The server fails on this one, try the rest
+        Err(GiftError::HttpStatus(FX30Command::Retry(ref e))) if e.status != 503 => {
+          let attempts = self.count_attempt(&path)?;
+          error!(
+            "Spool::send(): {:?} failed, attempt {} of {}: {}",
+            path, attempts, SPOOL_MAX_ATTEMPTS, e
+          );
+          continue;
+        }
+        Err(e) => return Err(e),
+      }
+      self.remove(&path)?;
+    }
+    Ok(())
+  }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), GiftError> {
+  match fs::remove_file(path) {
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    result => Ok(result?),
+  }
+}
+
+#[derive(Default)]
+struct Uploads {
+  next_id: u64,
+  running: BTreeMap<u64, Arc<BuoyData>>,
+  spooled: BTreeMap<u64, PathBuf>, // Running uploads that were spooled too
+}
+
+///
+/// The uploads in progress, so the ones that don't finish before going to
+/// sleep can be spooled.
+///
+#[derive(Default)]
+pub struct InFlight {
+  uploads: Mutex<Uploads>,
+}
+
+impl InFlight {
+  /// Add an upload, returns the id to pass to `done()`.
+  pub fn add(&self, data: Arc<BuoyData>) -> u64 {
+    let mut uploads = lock(&self.uploads);
+    let id = uploads.next_id;
+    uploads.next_id += 1;
+    uploads.running.insert(id, data);
+    id
+  }
+
+  /// The upload thread has finished, returns the spool file if it was spooled.
+  pub fn done(&self, id: u64) -> Option<PathBuf> {
+    let mut uploads = lock(&self.uploads);
+    uploads.running.remove(&id);
+    uploads.spooled.remove(&id)
+  }
+
+  /// The uploads that are running and not spooled.
+  pub fn len(&self) -> usize {
+    let uploads = lock(&self.uploads);
+    uploads.running.len() - uploads.spooled.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  ///
+  /// Spool the unfinished uploads, oldest first.  They keep running, `done()`
+  /// returns the spool file so it can be removed if the upload gets through.
+  ///
+  pub fn spool<F>(&self, mut save: F)
+  where
+    F: FnMut(&BuoyData) -> Result<PathBuf, GiftError>,
+  {
+    // Locked while saving, so an upload can't finish without seeing its file
+    let mut uploads = lock(&self.uploads);
+    let unspooled: Vec<(u64, Arc<BuoyData>)> = uploads
+      .running
+      .iter()
+      .filter(|(id, _)| !uploads.spooled.contains_key(id))
+      .map(|(id, data)| (*id, Arc::clone(data)))
+      .collect();
+    for (id, data) in unspooled {
+      match save(&data) {
+        Ok(path) => {
+          info!(
+            "InFlight::spool(): spooled {} to {:?}",
+            data.start_time, path
+          );
+          uploads.spooled.insert(id, path);
+        }
+        Err(e) => error!(
+          "InFlight::spool(): could not spool {}: {:?}",
+          data.start_time, e
+        ),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::spool::*;
+
+  #[test]
+  fn test_spool() {
+    let dir = std::env::temp_dir().join(format!("buoy-spool-{}", std::process::id()));
+    let spool = Spool::new(&dir);
+    assert!(spool.pending().unwrap().is_empty());
+
+    let first = spool.save(b"first").unwrap();
+    let second = spool.save(b"second").unwrap();
+    fs::write(dir.join("partial.tmp"), b"partial").unwrap();
+    assert_eq!(dir.join("00000000000000000000.post"), first);
+    assert_eq!(dir.join("00000000000000000001.post"), second);
+    assert_eq!(
+      spool.pending().unwrap(),
+      vec![first.clone(), second.clone()]
+    );
+    assert_eq!(fs::read(&first).unwrap(), b"first");
+
+    spool.remove(&first).unwrap();
+    spool.remove(&first).unwrap();
+    assert_eq!(spool.pending().unwrap(), vec![second.clone()]);
+
+    // Files from before the sequence numbers, named by the time
+    let old = dir.join("1598922000123456789.post");
+    fs::write(&old, b"old").unwrap();
+    let spool = Spool::new(&dir);
+    let next = spool.save(b"next").unwrap();
+    assert_eq!(dir.join("01598922000123456790.post"), next);
+    assert_eq!(
+      spool.pending().unwrap(),
+      vec![second.clone(), old, next.clone()]
+    );
+
+    // The server keeps failing on one
+    for attempts in 1..SPOOL_MAX_ATTEMPTS {
+      assert_eq!(attempts, spool.count_attempt(&second).unwrap());
+      assert!(spool.pending().unwrap().contains(&second));
+    }
+    assert_eq!(SPOOL_MAX_ATTEMPTS, spool.count_attempt(&second).unwrap());
+    assert!(!spool.pending().unwrap().contains(&second));
+    assert_eq!(
+      fs::read(second.with_extension(FAILED_EXTENSION)).unwrap(),
+      b"second"
+    );
+    assert!(!second.with_extension(ATTEMPTS_EXTENSION).exists());
+
+    spool.count_attempt(&next).unwrap();
+    spool.remove(&next).unwrap();
+    assert!(!next.with_extension(ATTEMPTS_EXTENSION).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_in_flight() {
+    use crate::battery::BatteryState;
+    use crate::sensor_reader::create_buoy_data;
+
+    let battery = Arc::new(Mutex::new(BatteryState::default()));
+    let data = Arc::new(create_buoy_data(None, None, &battery).unwrap());
+    let in_flight = InFlight::default();
+    let first = in_flight.add(Arc::clone(&data));
+    let second = in_flight.add(data);
+    assert_eq!(2, in_flight.len());
+
+    assert_eq!(None, in_flight.done(first));
+    let mut saved = 0;
+    in_flight.spool(|_| {
+      saved += 1;
+      Ok(PathBuf::from("spool/00000000000000000000.post"))
+    });
+    assert_eq!(1, saved);
+    assert!(in_flight.is_empty());
+
+    // Already spooled
+    in_flight.spool(|_| panic!("spooled twice"));
+    assert_eq!(
+      Some(PathBuf::from("spool/00000000000000000000.post")),
+      in_flight.done(second)
+    );
+  }
+}
@@ -0,0 +1,550 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// What the buoy keeps on flash over reboots and sleeps: the upload
+/// counters, the last GPS fix, the power state, the last server command, and
+/// why the last session ended.  It's loaded when the buoy starts and reported
+/// in the `Session` header of the first upload.
+///
+/// The state is kept in two files:
+///
+///    buoy.state          - a snapshot, replaced via a temporary file
+///    buoy.state.journal  - the changes since the snapshot, one per line
+///
+/// A journal line is `{checksum} {Key}: {value}`, the checksum is the FNV-1a
+/// hash of the rest of the line, so a line cut short when the power is lost
+/// is ignored.  The values are not deltas, so replaying a line twice does no
+/// harm, e.g. when the power is lost between writing the snapshot and
+/// clearing the journal.  The journal is folded into the snapshot every
+/// `JOURNAL_MAX_LINES` lines.
+///
+/// The session ends when the buoy goes to sleep (`ulpm`), the supervisor
+/// reboots (`watchdog`), or an SMS script runs `buoy end-session command`.
+/// A session that is still `running` at the next start crashed.  The SMS
+/// scripts run while the buoy is running, so `end-session` only appends a
+/// journal line, and the buoy replays the journal before it compacts it.
+///
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use buoy_code::errors::GiftError;
+use buoy_code::link_stats::{LinkStats, HEADER_LINK_STATS};
+use buoy_code::power_state::{
+  decode_transitions, encode_transitions, PowerState, Transition, HEADER_POWER_TRANSITIONS,
+};
+use buoy_code::session::{SavedFix, Session, SessionEnd};
+
+use crate::supervisor::lock;
+
+const JOURNAL_MAX_LINES: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuoyState {
+  pub boots: u32,                         // How many times the buoy program has started
+  pub session_end: SessionEnd,            // Why the last session ended, or `Running`
+  pub uploads: u64,                       // Successful uploads
+  pub failures: u64,                      // Failed uploads
+  pub bytes: u64,                         // Bytes sent by the successful uploads
+  pub last_fix: Option<SavedFix>,         // The last GPS fix
+  pub last_command: Option<String>,       // The last command from the server
+  pub power_state: Option<PowerState>,    // The last power state
+  pub power_transitions: Vec<Transition>, // Transitions not reported before a sleep
+  pub link_stats: LinkStats,              // Link statistics not reported before a sleep
+}
+
+impl Default for BuoyState {
+  fn default() -> Self {
+    BuoyState {
+      boots: 0,
+      session_end: SessionEnd::New,
+      uploads: 0,
+      failures: 0,
+      bytes: 0,
+      last_fix: None,
+      last_command: None,
+      power_state: None,
+      power_transitions: Vec::new(),
+      link_stats: LinkStats::default(),
+    }
+  }
+}
+
+impl BuoyState {
+  /// Each value as a `(key, value)` pair, an empty value is None.
+  fn fields(&self) -> Vec<(&'static str, String)> {
+    vec![
+      ("Boots", self.boots.to_string()),
+      ("Session-End", self.session_end.to_string()),
+      ("Uploads", self.uploads.to_string()),
+      ("Failures", self.failures.to_string()),
+      ("Bytes", self.bytes.to_string()),
+      (
+        "Last-Fix",
+        self
+          .last_fix
+          .as_ref()
+          .map_or_else(String::new, SavedFix::encode),
+      ),
+      (
+        "Last-Command",
+        self.last_command.clone().unwrap_or_default(),
+      ),
+      (
+        "Power-State",
+        self.power_state.map_or_else(String::new, |s| s.to_string()),
+      ),
+      (
+        HEADER_POWER_TRANSITIONS,
+        encode_transitions(&self.power_transitions),
+      ),
+      (HEADER_LINK_STATS, self.link_stats.encode()),
+    ]
+  }
+
+  fn set(&mut self, key: &str, value: &str) -> Result<(), GiftError> {
+    let some = Some(value).filter(|v| !v.is_empty());
+    match key {
+      "Boots" => self.boots = value.parse()?,
+      "Session-End" => self.session_end = value.parse()?,
+      "Uploads" => self.uploads = value.parse()?,
+      "Failures" => self.failures = value.parse()?,
+      "Bytes" => self.bytes = value.parse()?,
+      "Last-Fix" => self.last_fix = some.map(SavedFix::decode).transpose()?,
+      "Last-Command" => self.last_command = some.map(String::from),
+      "Power-State" => self.power_state = some.map(str::parse).transpose()?,
+      HEADER_POWER_TRANSITIONS => self.power_transitions = decode_transitions(value)?,
+      HEADER_LINK_STATS => self.link_stats = LinkStats::decode(value)?,
+      _ => debug!("BuoyState::set(): ignoring '{}'", key),
+    }
+    Ok(())
+  }
+
+  /// The snapshot, one `Key: value` line per value.
+  pub fn encode(&self) -> String {
+    self
+      .fields()
+      .iter()
+      .map(|(key, value)| format!("{}: {}\n", key, value))
+      .collect()
+  }
+
+  pub fn decode(s: &str) -> Result<BuoyState, GiftError> {
+    let mut state = BuoyState::default();
+    for line in s.lines().filter(|l| !l.trim().is_empty()) {
+      let (key, value) = split_line(line)?;
+      state.set(key, value)?;
+    }
+    Ok(state)
+  }
+
+  /// The report for the first upload of a session.
+  pub fn session(&self, previous_end: SessionEnd) -> Session {
+    Session {
+      boots: self.boots,
+      previous_end,
+      uploads: self.uploads,
+      failures: self.failures,
+      bytes: self.bytes,
+      last_command: self.last_command.clone(),
+      last_fix: self.last_fix.clone(),
+    }
+  }
+}
+
+fn split_line(line: &str) -> Result<(&str, &str), GiftError> {
+  let mut kv = line.splitn(2, ':');
+  let key = kv.next().ok_or(GiftError::ParseTelemetry)?.trim();
+  let value = kv.next().ok_or(GiftError::ParseTelemetry)?.trim();
+  Ok((key, value))
+}
+
+/// 32 bit FNV-1a, to spot journal lines that were not completely written.
+fn checksum(s: &str) -> u32 {
+  s.bytes().fold(0x811c_9dc5, |hash, b| {
+    (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+  })
+}
+
+fn journal_line(key: &str, value: &str) -> String {
+  let entry = format!("{}: {}", key, value);
+  format!("{:08x} {}\n", checksum(&entry), entry)
+}
+
+///
+/// Apply the journal to the state, up to the first line that is not
+/// complete or can't be read.  Returns the number of lines applied.
+///
+fn replay(state: &mut BuoyState, journal: &str) -> usize {
+  let mut applied = 0;
+  for line in journal.split_terminator('\n') {
+    if !journal_entry_valid(line) {
+      warn!("replay(): ignoring the journal from '{}'", line);
+      break;
+    }
+    let entry = &line[9..];
+    if let Err(e) = split_line(entry).and_then(|(key, value)| state.set(key, value)) {
+      error!("replay(): ignoring the journal from '{}': {:?}", line, e);
+      break;
+    }
+    applied += 1;
+  }
+  applied
+}
+
+/// A file cut short by a power failure may end half way through a character.
+fn read_lossy(path: &Path) -> Result<String, GiftError> {
+  Ok(String::from_utf8_lossy(&fs::read(path)?).into_owned())
+}
+
+/// The snapshot with the journal applied, and the number of journal lines.
+fn load(path: &Path) -> Result<(BuoyState, usize), GiftError> {
+  let mut state = if path.exists() {
+    BuoyState::decode(&read_lossy(path)?).unwrap_or_else(|e| {
+      error!("load(): invalid state in {:?}: {:?}", path, e);
+      BuoyState::default()
+    })
+  } else {
+    BuoyState::default()
+  };
+
+  let journal_path = journal_path(path);
+  let journal_lines = if journal_path.exists() {
+    replay(&mut state, &read_lossy(&journal_path)?)
+  } else {
+    0
+  };
+  Ok((state, journal_lines))
+}
+
+fn append_journal(path: &Path, lines: &str) -> Result<(), GiftError> {
+  let mut journal = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(journal_path(path))?;
+  journal.write_all(lines.as_bytes())?;
+  journal.sync_data()?;
+  Ok(())
+}
+
+fn journal_entry_valid(line: &str) -> bool {
+  if line.len() < 9 || !line.is_char_boundary(9) || &line[8..9] != " " {
+    return false;
+  }
+  u32::from_str_radix(&line[..8], 16).ok() == Some(checksum(&line[9..]))
+}
+
+struct Stored {
+  state: BuoyState,
+  journal_lines: usize, // Lines in the journal since the last snapshot
+}
+
+pub struct StateStore {
+  path: Option<PathBuf>, // The snapshot path, None keeps the state in memory
+  stored: Mutex<Stored>,
+}
+
+impl StateStore {
+  ///
+  /// Load the snapshot and the journal.  A snapshot that can't be read is
+  /// logged and the buoy starts again from nothing, the state is not worth
+  /// stopping for.
+  ///
+  pub fn open(path: &Path) -> Result<StateStore, GiftError> {
+    let (state, journal_lines) = load(path)?;
+    let store = StateStore {
+      path: Some(PathBuf::from(path)),
+      stored: Mutex::new(Stored {
+        state,
+        journal_lines,
+      }),
+    };
+
+    // Start from a clean journal, the last line may be incomplete
+    {
+      let mut stored = lock(&store.stored);
+      store.compact(&mut stored)?;
+    }
+    Ok(store)
+  }
+
+  ///
+  /// Record why the session is ending from another process, while the buoy
+  /// may be running.  Only a journal line is appended, the snapshot and the
+  /// rest of the journal are the running buoy's.
+  ///
+  pub fn end_session_at(path: &Path, end: SessionEnd) -> Result<(), GiftError> {
+    let (state, _) = load(path)?;
+    if state.session_end != SessionEnd::Running {
+      return Ok(());
+    }
+    append_journal(path, &journal_line("Session-End", &end.to_string()))
+  }
+
+  /// A store that is not saved, for the tests and when the flash fails.
+  pub fn memory() -> StateStore {
+    StateStore {
+      path: None,
+      stored: Mutex::new(Stored {
+        state: BuoyState::default(),
+        journal_lines: 0,
+      }),
+    }
+  }
+
+  pub fn state(&self) -> BuoyState {
+    lock(&self.stored).state.clone()
+  }
+
+  ///
+  /// Change the state, the values that changed are appended to the journal
+  /// before this returns.
+  ///
+  pub fn update<F>(&self, f: F) -> Result<(), GiftError>
+  where
+    F: FnOnce(&mut BuoyState),
+  {
+    let mut stored = lock(&self.stored);
+    let before = stored.state.fields();
+    f(&mut stored.state);
+
+    let path = match &self.path {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+    let changes: String = stored
+      .state
+      .fields()
+      .iter()
+      .zip(before.iter())
+      .filter(|(after, before)| after != before)
+      .map(|((key, value), _)| journal_line(key, value))
+      .collect();
+    if changes.is_empty() {
+      return Ok(());
+    }
+
+    append_journal(path, &changes)?;
+    stored.journal_lines += changes.lines().count();
+    if stored.journal_lines >= JOURNAL_MAX_LINES {
+      self.compact(&mut stored)?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Start a new session.  Returns the report for the first upload, with why
+  /// the previous session ended.
+  ///
+  pub fn start(&self) -> Result<Session, GiftError> {
+    let previous_end = match self.state().session_end {
+      SessionEnd::Running => SessionEnd::Crash,
+      end => end,
+    };
+    self.update(|state| {
+      state.boots += 1;
+      state.session_end = SessionEnd::Running;
+    })?;
+    Ok(self.state().session(previous_end))
+  }
+
+  /// Record why the session is ending, unless it already has been.
+  pub fn end_session(&self, end: SessionEnd) -> Result<(), GiftError> {
+    // It may have been ended by an SMS script
+    self.reload_journal(&mut lock(&self.stored))?;
+    self.update(|state| {
+      if state.session_end == SessionEnd::Running {
+        state.session_end = end;
+      }
+    })
+  }
+
+  /// The session carries on, e.g. ULPM did not switch the FX30 off.
+  pub fn resume(&self) -> Result<(), GiftError> {
+    self.update(|state| state.session_end = SessionEnd::Running)
+  }
+
+  ///
+  /// Apply the journal again, to pick up the lines other processes appended.
+  /// Our own lines are already in the state, and replaying them does no harm.
+  ///
+  fn reload_journal(&self, stored: &mut Stored) -> Result<(), GiftError> {
+    let journal_path = match &self.path {
+      Some(path) => journal_path(path),
+      None => return Ok(()),
+    };
+    if journal_path.exists() {
+      stored.journal_lines = replay(&mut stored.state, &read_lossy(&journal_path)?);
+    }
+    Ok(())
+  }
+
+  /// Write a new snapshot and clear the journal.
+  fn compact(&self, stored: &mut Stored) -> Result<(), GiftError> {
+    let path = match &self.path {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+    self.reload_journal(stored)?;
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(stored.state.encode().as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    File::create(journal_path(path))?.sync_all()?;
+    stored.journal_lines = 0;
+    Ok(())
+  }
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+  PathBuf::from(format!("{}.journal", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::state_store::*;
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("buoy-{}-{}.state", name, std::process::id()))
+  }
+
+  #[test]
+  fn test_encode_decode() {
+    let mut state = BuoyState::default();
+    assert_eq!(state, BuoyState::decode(&state.encode()).unwrap());
+
+    state.boots = 3;
+    state.session_end = SessionEnd::Watchdog;
+    state.uploads = 120;
+    state.bytes = 4_915_200;
+    state.last_fix = Some(SavedFix {
+      latitude: -36.843292,
+      longitude: 174.765121,
+      fix_time: String::from("20200901T013000.000Z"),
+    });
+    state.last_command = Some(String::from("normal"));
+    state.power_state = Some(PowerState::Low);
+    state.power_transitions = vec![Transition {
+      from: PowerState::Conserve,
+      to: PowerState::Low,
+      voltage: 10.9,
+      time: String::from("20200901T013000.000Z"),
+    }];
+    state
+      .link_stats
+      .record_failure(buoy_code::link_stats::FAIL_CONNECT);
+    assert_eq!(state, BuoyState::decode(&state.encode()).unwrap());
+    assert!(BuoyState::decode("Boots: many\n").is_err());
+  }
+
+  #[test]
+  fn test_journal() {
+    let path = temp_path("journal");
+    let store = StateStore::open(&path).unwrap();
+    let session = store.start().unwrap();
+    assert_eq!((1, SessionEnd::New), (session.boots, session.previous_end));
+    store.update(|s| s.uploads += 1).unwrap();
+    store.update(|s| s.failures += 1).unwrap();
+
+    // Only the changes are journaled
+    let journal = fs::read_to_string(journal_path(&path)).unwrap();
+    assert_eq!(4, journal.lines().count());
+    assert!(journal.ends_with(&journal_line("Failures", "1")));
+
+    // Lose the power half way through a line, then start again
+    let mut file = OpenOptions::new()
+      .append(true)
+      .open(journal_path(&path))
+      .unwrap();
+    file
+      .write_all(journal_line("Uploads", "1000").split_at(12).0.as_bytes())
+      .unwrap();
+    let store = StateStore::open(&path).unwrap();
+    let session = store.start().unwrap();
+    assert_eq!(
+      (2, SessionEnd::Crash, 1, 1),
+      (
+        session.boots,
+        session.previous_end,
+        session.uploads,
+        session.failures
+      )
+    );
+
+    // The journal is folded into the snapshot, after starting it had the boots
+    for _ in 0..JOURNAL_MAX_LINES {
+      store.update(|s| s.bytes += 1).unwrap();
+    }
+    let journal = fs::read_to_string(journal_path(&path)).unwrap();
+    assert_eq!(1, journal.lines().count());
+    store.end_session(SessionEnd::Ulpm).unwrap();
+    store.end_session(SessionEnd::Command).unwrap();
+
+    let state = StateStore::open(&path).unwrap().state();
+    assert_eq!(JOURNAL_MAX_LINES as u64, state.bytes);
+    assert_eq!(SessionEnd::Ulpm, state.session_end);
+
+    // Not UTF-8, from a line cut short
+    let mut file = OpenOptions::new()
+      .append(true)
+      .open(journal_path(&path))
+      .unwrap();
+    file.write_all(b"\xe2\x82").unwrap();
+    assert_eq!(state, StateStore::open(&path).unwrap().state());
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(journal_path(&path)).unwrap();
+  }
+
+  #[test]
+  fn test_end_session_at() {
+    let path = temp_path("end-session");
+    let store = StateStore::open(&path).unwrap();
+    store.start().unwrap();
+
+    // An SMS script ends the session while the buoy is running
+    StateStore::end_session_at(&path, SessionEnd::Command).unwrap();
+    let journal = fs::read_to_string(journal_path(&path)).unwrap();
+    assert!(journal.ends_with(&journal_line("Session-End", "command")));
+
+    // The buoy's compaction keeps it
+    for _ in 0..JOURNAL_MAX_LINES {
+      store.update(|s| s.uploads += 1).unwrap();
+    }
+    assert_eq!(SessionEnd::Command, store.state().session_end);
+    store.end_session(SessionEnd::Ulpm).unwrap();
+
+    let state = StateStore::open(&path).unwrap().state();
+    assert_eq!(JOURNAL_MAX_LINES as u64, state.uploads);
+    assert_eq!(SessionEnd::Command, state.session_end);
+
+    // Only a running session is ended
+    StateStore::end_session_at(&path, SessionEnd::Watchdog).unwrap();
+    let state = StateStore::open(&path).unwrap().state();
+    assert_eq!(SessionEnd::Command, state.session_end);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(journal_path(&path)).unwrap();
+  }
+}
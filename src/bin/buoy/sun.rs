@@ -0,0 +1,200 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Where the sun is, so the navigation light is only on between civil dusk
+/// and civil dawn, when the sun is more than 6 degrees below the horizon.
+///
+/// This uses the NOAA solar calculator equations, which are good to about a
+/// minute, see https://gml.noaa.gov/grad/solcalc/calcdetails.html
+///
+use chrono::{DateTime, Timelike, Utc};
+
+// The sun elevation at civil dawn and dusk, in degrees
+pub const CIVIL_TWILIGHT: f64 = -6.0;
+
+fn julian_century(utc: &DateTime<Utc>) -> f64 {
+  let unix_days =
+    (utc.timestamp() as f64 + f64::from(utc.timestamp_subsec_millis()) / 1000.0) / 86400.0;
+  let julian_day = unix_days + 2_440_587.5;
+  (julian_day - 2_451_545.0) / 36525.0
+}
+
+///
+/// The sun declination in radians, and the equation of time in minutes.
+///
+fn solar_position(utc: &DateTime<Utc>) -> (f64, f64) {
+  let t = julian_century(utc);
+
+  let mean_long = (280.466_46 + t * (36_000.769_83 + t * 0.000_303_2)).rem_euclid(360.0);
+  let mean_anomaly = (357.529_11 + t * (35_999.050_29 - 0.000_153_7 * t)).to_radians();
+  let eccentricity = 0.016_708_634 - t * (0.000_042_037 + 0.000_000_126_7 * t);
+
+  let centre = mean_anomaly.sin() * (1.914_602 - t * (0.004_817 + 0.000_014 * t))
+    + (2.0 * mean_anomaly).sin() * (0.019_993 - 0.000_101 * t)
+    + (3.0 * mean_anomaly).sin() * 0.000_289;
+  let omega = (125.04 - 1934.136 * t).to_radians();
+  let apparent_long = (mean_long + centre - 0.005_69 - 0.004_78 * omega.sin()).to_radians();
+
+  let mean_obliquity =
+    23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.000_59 - t * 0.001_813))) / 60.0) / 60.0;
+  let obliquity = (mean_obliquity + 0.002_56 * omega.cos()).to_radians();
+
+  let declination = (obliquity.sin() * apparent_long.sin()).asin();
+
+  let l0 = mean_long.to_radians();
+  let y = (obliquity / 2.0).tan().powi(2);
+  let equation_of_time = 4.0
+    * (y * (2.0 * l0).sin() - 2.0 * eccentricity * mean_anomaly.sin()
+      + 4.0 * eccentricity * y * mean_anomaly.sin() * (2.0 * l0).cos()
+      - 0.5 * y * y * (4.0 * l0).sin()
+      - 1.25 * eccentricity * eccentricity * (2.0 * mean_anomaly).sin())
+    .to_degrees();
+
+  (declination, equation_of_time)
+}
+
+///
+/// The sun elevation above the horizon in degrees, ignoring refraction.
+///
+pub fn elevation(utc: &DateTime<Utc>, latitude: f64, longitude: f64) -> f64 {
+  let (declination, equation_of_time) = solar_position(utc);
+
+  let minutes = f64::from(utc.num_seconds_from_midnight()) / 60.0;
+  let solar_time = minutes + equation_of_time + 4.0 * longitude;
+  let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+
+  let latitude = latitude.to_radians();
+  let cos_zenith =
+    latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+  90.0 - cos_zenith.max(-1.0).min(1.0).acos().to_degrees()
+}
+
+/// It's lighter than civil twilight.
+pub fn is_daylight(utc: &DateTime<Utc>, latitude: f64, longitude: f64) -> bool {
+  elevation(utc, latitude, longitude) > CIVIL_TWILIGHT
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::sun::*;
+  use chrono::{Date, Duration, TimeZone};
+
+  ///
+  /// The dawn and dusk times for a day.
+  ///
+  #[derive(Clone, Debug, PartialEq)]
+  enum Twilight {
+    Times {
+      dawn: DateTime<Utc>,
+      dusk: DateTime<Utc>,
+    },
+    PolarDay,   // It never gets dark
+    PolarNight, // It never gets light
+  }
+
+  ///
+  /// The civil dawn and dusk for a UTC date, to check the solar position
+  /// against published times.  The dusk can be on the next UTC day, or the
+  /// dawn on the previous one, depending on the longitude.
+  ///
+  fn civil_twilight(date: Date<Utc>, latitude: f64, longitude: f64) -> Twilight {
+    // The sun position at the local solar noon is close enough for the day
+    let approx_noon = date.and_hms(12, 0, 0) - Duration::minutes((4.0 * longitude) as i64);
+    let (declination, equation_of_time) = solar_position(&approx_noon);
+    let noon_minutes = 720.0 - 4.0 * longitude - equation_of_time;
+
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = (CIVIL_TWILIGHT.to_radians().sin() - latitude.sin() * declination.sin())
+      / (latitude.cos() * declination.cos());
+    if cos_hour_angle > 1.0 {
+      return Twilight::PolarNight;
+    }
+    if cos_hour_angle < -1.0 {
+      return Twilight::PolarDay;
+    }
+
+    let half_day = 4.0 * cos_hour_angle.acos().to_degrees();
+    let at =
+      |minutes: f64| date.and_hms(0, 0, 0) + Duration::seconds((minutes * 60.0).round() as i64);
+    Twilight::Times {
+      dawn: at(noon_minutes - half_day),
+      dusk: at(noon_minutes + half_day),
+    }
+  }
+
+  fn minutes_between(a: &DateTime<Utc>, b: &DateTime<Utc>) -> i64 {
+    (*a - *b).num_minutes().abs()
+  }
+
+  #[test]
+  fn test_elevation() {
+    // The equator at noon on the March equinox, the sun is nearly overhead
+    let noon = Utc.ymd(2020, 3, 20).and_hms(12, 7, 0);
+    assert!(elevation(&noon, 0.0, 0.0) > 89.0);
+
+    // Auckland, in the middle of the NZ day and night
+    let (lat, lon) = (-36.843292, 174.756864);
+    assert!(is_daylight(
+      &Utc.ymd(2020, 8, 30).and_hms(0, 0, 0),
+      lat,
+      lon
+    ));
+    assert!(!is_daylight(
+      &Utc.ymd(2020, 8, 30).and_hms(12, 0, 0),
+      lat,
+      lon
+    ));
+  }
+
+  #[test]
+  fn test_civil_twilight() {
+    // London on the June solstice, civil dawn 02:56 and dusk 21:09 UTC
+    match civil_twilight(Utc.ymd(2020, 6, 21), 51.5074, -0.1278) {
+      Twilight::Times { dawn, dusk } => {
+        assert!(minutes_between(&dawn, &Utc.ymd(2020, 6, 21).and_hms(2, 56, 0)) <= 2);
+        assert!(minutes_between(&dusk, &Utc.ymd(2020, 6, 21).and_hms(21, 9, 0)) <= 2);
+
+        // The elevation agrees with the times
+        assert!((elevation(&dawn, 51.5074, -0.1278) - CIVIL_TWILIGHT).abs() < 0.2);
+        assert!(is_daylight(
+          &(dawn + Duration::minutes(5)),
+          51.5074,
+          -0.1278
+        ));
+        assert!(!is_daylight(
+          &(dusk + Duration::minutes(5)),
+          51.5074,
+          -0.1278
+        ));
+      }
+      t => panic!("{:?}", t),
+    }
+
+    assert_eq!(
+      Twilight::PolarDay,
+      civil_twilight(Utc.ymd(2020, 6, 21), 80.0, 15.0)
+    );
+    assert_eq!(
+      Twilight::PolarNight,
+      civil_twilight(Utc.ymd(2020, 12, 21), 80.0, 15.0)
+    );
+  }
+}
@@ -0,0 +1,326 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The supervisor owns every long-running task of the buoy.  Each task runs
+/// in its own thread, and a task that returns an error or panics is restarted
+/// after a backoff, which doubles with each failure from `backoff_min_secs`
+/// to `backoff_max_secs`:
+///
+///    fail -> wait 5 s -> restart -> fail -> wait 10 s -> restart ...
+///
+/// A task that fails more than `max_failures` times within
+/// `failure_window_secs` is not going to get better by itself, so the
+/// supervisor reboots the FX30, and records it as the end of the session,
+/// see state_store.rs.  A task that returns `Ok(())` has finished and is not
+/// restarted.
+///
+/// The restart counts are sent to the server with every upload, see
+/// buoy_code::task_restarts.
+///
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use buoy_code::errors::GiftError;
+use buoy_code::session::SessionEnd;
+use buoy_code::task_restarts::TaskRestarts;
+
+use crate::platform::Platform;
+use crate::state_store::StateStore;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct SupervisorConfig {
+  pub backoff_min_secs: u64,    // The wait before the first restart
+  pub backoff_max_secs: u64,    // The longest wait before a restart
+  pub max_failures: u32,        // Failures in the window before rebooting
+  pub failure_window_secs: u64, // How long failures are remembered
+}
+
+impl Default for SupervisorConfig {
+  fn default() -> Self {
+    SupervisorConfig {
+      backoff_min_secs: 5,
+      backoff_max_secs: 10 * 60,
+      max_failures: 8,
+      failure_window_secs: 60 * 60,
+    }
+  }
+}
+
+impl SupervisorConfig {
+  pub fn validate(&self) -> Result<(), GiftError> {
+    if self.backoff_min_secs == 0 || self.backoff_max_secs < self.backoff_min_secs {
+      return Err(GiftError::InvalidConfig(String::from(
+        "supervisor: backoff_min_secs must be above 0 and not above backoff_max_secs",
+      )));
+    }
+    if self.failure_window_secs == 0 {
+      return Err(GiftError::InvalidConfig(String::from(
+        "supervisor: failure_window_secs must be above 0",
+      )));
+    }
+    Ok(())
+  }
+}
+
+/// What to do after a task failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recovery {
+  Restart(Duration), // Restart the task after waiting
+  Reboot,            // Too many failures, reboot the FX30
+}
+
+///
+/// Decides how to recover from the failures of one task.
+///
+pub struct RestartPolicy {
+  config: SupervisorConfig,
+  failures: VecDeque<Instant>, // Failures within the window
+}
+
+impl RestartPolicy {
+  pub fn new(config: &SupervisorConfig) -> Self {
+    RestartPolicy {
+      config: config.clone(),
+      failures: VecDeque::new(),
+    }
+  }
+
+  /// The task failed at `now`.
+  pub fn failed(&mut self, now: Instant) -> Recovery {
+    let window = Duration::from_secs(self.config.failure_window_secs);
+    while let Some(&first) = self.failures.front() {
+      if now.duration_since(first) < window {
+        break;
+      }
+      self.failures.pop_front();
+    }
+    self.failures.push_back(now);
+
+    if self.failures.len() > self.config.max_failures as usize {
+      return Recovery::Reboot;
+    }
+
+    // Doubles with each recent failure, without overflowing
+    let doublings = (self.failures.len() - 1).min(32) as u32;
+    let backoff = self
+      .config
+      .backoff_min_secs
+      .saturating_mul(1 << doublings)
+      .min(self.config.backoff_max_secs);
+    Recovery::Restart(Duration::from_secs(backoff))
+  }
+}
+
+///
+/// Lock a mutex that's shared with the tasks.  A task that panics while
+/// holding the lock poisons it, but it's restarted and the data is still
+/// usable, so carry on rather than panic in every other thread too.
+///
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The message of a caught panic.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+  if let Some(s) = panic.downcast_ref::<&str>() {
+    String::from(*s)
+  } else if let Some(s) = panic.downcast_ref::<String>() {
+    s.clone()
+  } else {
+    String::from("unknown panic")
+  }
+}
+
+#[derive(Clone)]
+pub struct Supervisor {
+  config: SupervisorConfig,
+  platform: Arc<dyn Platform>,
+  store: Arc<StateStore>, // Records the reboot as the end of the session
+  restarts: Arc<Mutex<TaskRestarts>>,
+}
+
+impl Supervisor {
+  pub fn new(
+    config: &SupervisorConfig,
+    platform: &Arc<dyn Platform>,
+    store: &Arc<StateStore>,
+  ) -> Self {
+    Supervisor {
+      config: config.clone(),
+      platform: Arc::clone(platform),
+      store: Arc::clone(store),
+      restarts: Arc::new(Mutex::new(TaskRestarts::default())),
+    }
+  }
+
+  /// The restart count of every task.
+  pub fn restarts(&self) -> TaskRestarts {
+    lock(&self.restarts).clone()
+  }
+
+  ///
+  /// Run `task` in a new thread, and keep it running.  `task` is called
+  /// again to restart it, so it should set itself up each time it's called.
+  ///
+  pub fn spawn<F>(&self, name: &str, mut task: F) -> thread::JoinHandle<()>
+  where
+    F: FnMut() -> Result<(), GiftError> + Send + 'static,
+  {
+    let name = String::from(name);
+    let platform = Arc::clone(&self.platform);
+    let store = Arc::clone(&self.store);
+    let restarts = Arc::clone(&self.restarts);
+    let mut policy = RestartPolicy::new(&self.config);
+    lock(&restarts).counts.insert(name.clone(), 0);
+
+    thread::spawn(move || loop {
+      let failure = match panic::catch_unwind(AssertUnwindSafe(&mut task)) {
+        Ok(Ok(())) => {
+          info!("supervisor: task {} finished", name);
+          return;
+        }
+        Ok(Err(e)) => format!("{:?}", e),
+        Err(panic) => format!("panic: {}", panic_message(&*panic)),
+      };
+
+      match policy.failed(Instant::now()) {
+        Recovery::Restart(backoff) => {
+          error!(
+            "supervisor: task {} failed ({}), restarting in {:?}",
+            name, failure, backoff
+          );
+          // Counted now, so the uploads during the backoff show the failure
+          *lock(&restarts).counts.entry(name.clone()).or_insert(0) += 1;
+          thread::sleep(backoff);
+        }
+        Recovery::Reboot => {
+          error!(
+            "supervisor: task {} failed ({}), too many failures, rebooting",
+            name, failure
+          );
+          if let Err(e) = store.end_session(SessionEnd::Watchdog) {
+            error!("supervisor: could not save the session end: {:?}", e);
+          }
+          if let Err(e) = platform.reboot() {
+            error!("supervisor: reboot failed: {:?}", e);
+          }
+          return;
+        }
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::supervisor::*;
+
+  #[test]
+  fn test_backoff() {
+    let config = SupervisorConfig {
+      backoff_min_secs: 5,
+      backoff_max_secs: 30,
+      max_failures: 5,
+      failure_window_secs: 3600,
+    };
+    let mut policy = RestartPolicy::new(&config);
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+
+    let restart = |secs| Recovery::Restart(Duration::from_secs(secs));
+    assert_eq!(restart(5), policy.failed(at(0)));
+    assert_eq!(restart(10), policy.failed(at(10)));
+    assert_eq!(restart(20), policy.failed(at(30)));
+    assert_eq!(restart(30), policy.failed(at(60)));
+    assert_eq!(restart(30), policy.failed(at(100)));
+    assert_eq!(Recovery::Reboot, policy.failed(at(200)));
+
+    // Old failures are forgotten
+    let mut policy = RestartPolicy::new(&config);
+    for i in 0..5 {
+      policy.failed(at(i * 2000));
+    }
+    assert_eq!(restart(10), policy.failed(at(10_000)));
+  }
+
+  #[test]
+  fn test_supervisor() {
+    use crate::platform::sim::{SimPlatform, SimScript};
+    use crate::voltage::Calibration;
+
+    let sim = Arc::new(SimPlatform::manual(
+      &SimScript::default(),
+      &Calibration::default(),
+    ));
+    let platform: Arc<dyn Platform> = sim.clone();
+    let config = SupervisorConfig {
+      max_failures: 0,
+      ..SupervisorConfig::default()
+    };
+    let store = Arc::new(StateStore::memory());
+    store.start().unwrap();
+    let supervisor = Supervisor::new(&config, &platform, &store);
+
+    // Finishing is not a failure
+    let mut runs = 0;
+    supervisor
+      .spawn("done", move || {
+        runs += 1;
+        assert_eq!(1, runs);
+        Ok(())
+      })
+      .join()
+      .unwrap();
+    assert!(sim.reboot_log().is_empty());
+
+    // No failures are allowed, so a panic reboots
+    supervisor
+      .spawn("panics", || panic!("test panic"))
+      .join()
+      .unwrap();
+    assert_eq!(1, sim.reboot_log().len());
+    assert_eq!(SessionEnd::Watchdog, store.state().session_end);
+    assert_eq!("done=0,panics=0", supervisor.restarts().encode());
+  }
+
+  #[test]
+  fn test_poisoned_lock() {
+    let shared = Arc::new(Mutex::new(1));
+    let task_shared = Arc::clone(&shared);
+    assert!(thread::spawn(move || {
+      let _guard = task_shared.lock().unwrap();
+      panic!("test panic");
+    })
+    .join()
+    .is_err());
+
+    assert!(shared.is_poisoned());
+    *lock(&shared) += 1;
+    assert_eq!(2, *lock(&shared));
+  }
+}
@@ -0,0 +1,235 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// How the buoy talks to the server.  The controller and the heartbeat only
+/// use the `Transport` trait, so the tests can run them without a network.
+/// `QuicTransport` sends each upload in its own thread over QUIC, see
+/// data_send.rs.
+///
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use buoy_code::commands::FX30Command;
+use buoy_code::errors::GiftError;
+use buoy_code::heartbeat::Heartbeat;
+use buoy_code::link_stats::LinkStats;
+use buoy_code::modem::ModemStatus;
+use buoy_code::session::Session;
+use buoy_code::task_restarts::TaskRestarts;
+use buoy_code::BuoyData;
+
+use crate::data_send::{build_http_post, Uplink};
+use crate::power::PowerPolicy;
+use crate::spool::{InFlight, Spool};
+use crate::state_store::StateStore;
+use crate::supervisor::lock;
+
+pub trait Transport: Send + Sync {
+  /// Start sending the data, `flush()` spools it if it's not sent in time.
+  fn upload(&self, data: BuoyData);
+
+  /// The number of uploads in progress.
+  fn in_flight(&self) -> usize;
+
+  /// Wait up to `timeout` for the uploads in progress, and spool the rest.
+  fn flush(&self, timeout: Duration);
+
+  fn send_heartbeat(
+    &self,
+    heartbeat: &Heartbeat,
+    restarts: &TaskRestarts,
+    modem: Option<&ModemStatus>,
+  ) -> Result<(), GiftError>;
+
+  /// Tell the server we are going to sleep, so it knows when to expect us.
+  fn send_sleep_notice(&self, sleep: Duration) -> Result<(), GiftError>;
+
+  /// The link statistics that have not been sent yet.
+  fn link_stats(&self) -> LinkStats;
+
+  /// Add link statistics saved before a sleep.
+  fn restore_link_stats(&self, stats: LinkStats);
+}
+
+pub struct QuicTransport {
+  uplink: Uplink,
+  power: Arc<Mutex<PowerPolicy>>, // The power transitions are put back if an upload fails
+  session: Arc<Mutex<Option<Session>>>, // Cleared once an upload reports it
+  spool: Arc<Spool>,              // Uploads left over from before a sleep
+  in_flight: Arc<InFlight>,       // Uploads in progress
+  store: Arc<StateStore>,         // Counts the uploads over reboots
+}
+
+impl QuicTransport {
+  pub fn new(
+    uplink: Uplink,
+    power: &Arc<Mutex<PowerPolicy>>,
+    session: &Arc<Mutex<Option<Session>>>,
+    spool_dir: &Path,
+    store: &Arc<StateStore>,
+  ) -> Self {
+    QuicTransport {
+      uplink,
+      power: Arc::clone(power),
+      session: Arc::clone(session),
+      spool: Arc::new(Spool::new(spool_dir)),
+      in_flight: Arc::new(InFlight::default()),
+      store: Arc::clone(store),
+    }
+  }
+}
+
+///
+/// Connect to the server and send the data, in the upload thread.  Once the
+/// link works anything spooled before a sleep is sent too.  Returns true if
+/// the data was sent.
+///
+fn send_data(uplink: &Uplink, power: &Mutex<PowerPolicy>, spool: &Spool, data: &BuoyData) -> bool {
+  // Connect to the server
+  let mut conn = match uplink.connect() {
+    Ok(conn) => conn,
+    Err(e) => {
+      error!("Transmit::new() failed: {:?}", e);
+      let mut stats = lock(&uplink.link_stats);
+      stats.record_failure(buoy_code::link_stats::FAIL_CONNECT);
+      let mut power = lock(power);
+      power.restore_transitions(data.power_transitions.clone());
+      return false;
+    }
+  };
+
+  // Send the data to the cloud
+  match conn.send(data) {
+    Ok(_) => {
+      if let Err(e) = spool.send(uplink) {
+        error!("spool.send() failed: {:?}", e);
+      }
+      true
+    }
+    Err(GiftError::HttpStatus(FX30Command::Retry(e))) => {
+      // The server could not process it now, keep it for the next upload
+      error!(
+        "conn.send(): server error, spooling {}: {}",
+        data.start_time, e
+      );
+      let request = build_http_post(data, &LinkStats::default());
+      if let Err(e) = request.and_then(|request| spool.save(&request)) {
+        error!("conn.send(): could not spool {}: {:?}", data.start_time, e);
+      }
+      false
+    }
+    Err(e) => {
+      // Errors are handled and counted in `conn.send()`, requests the server
+      // dropped or alerted on are not worth sending again
+      error!("conn.send() failed: {:?}", e);
+      let mut power = lock(power);
+      power.restore_transitions(data.power_transitions.clone());
+      false
+    }
+  }
+}
+
+impl Transport for QuicTransport {
+  fn upload(&self, data: BuoyData) {
+    let data = Arc::new(data);
+    let id = self.in_flight.add(Arc::clone(&data));
+
+    let uplink = self.uplink.clone();
+    let power = Arc::clone(&self.power);
+    let session = Arc::clone(&self.session);
+    let spool = Arc::clone(&self.spool);
+    let in_flight = Arc::clone(&self.in_flight);
+    let store = Arc::clone(&self.store);
+    thread::spawn(move || {
+      let sent = send_data(&uplink, &power, &spool, &data);
+      if sent && data.session.is_some() {
+        let mut session = lock(&session);
+        if *session == data.session {
+          *session = None;
+        }
+      }
+      // It was spooled while it was running, don't send it twice
+      if let Some(path) = in_flight.done(id) {
+        if sent {
+          info!(
+            "upload(): {} got through, unspooling {:?}",
+            data.start_time, path
+          );
+          if let Err(e) = spool.remove(&path) {
+            error!("upload(): could not remove {:?}: {:?}", path, e);
+          }
+        }
+      }
+      let counted = store.update(|state| {
+        if sent {
+          state.uploads += 1;
+          state.bytes += data.hydrophone.len() as u64;
+        } else {
+          state.failures += 1;
+        }
+      });
+      if let Err(e) = counted {
+        error!("upload(): could not save the upload counts: {:?}", e);
+      }
+    });
+  }
+
+  fn in_flight(&self) -> usize {
+    self.in_flight.len()
+  }
+
+  fn flush(&self, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while !self.in_flight.is_empty() && Instant::now() < deadline {
+      thread::sleep(Duration::from_secs(1));
+    }
+    let spool = &self.spool;
+    self.in_flight.spool(|data| {
+      build_http_post(data, &LinkStats::default()).and_then(|request| spool.save(&request))
+    });
+  }
+
+  fn send_heartbeat(
+    &self,
+    heartbeat: &Heartbeat,
+    restarts: &TaskRestarts,
+    modem: Option<&ModemStatus>,
+  ) -> Result<(), GiftError> {
+    self
+      .uplink
+      .connect()?
+      .send_heartbeat(heartbeat, restarts, modem)
+  }
+
+  fn send_sleep_notice(&self, sleep: Duration) -> Result<(), GiftError> {
+    self.uplink.connect()?.send_sleep_notice(sleep)
+  }
+
+  fn link_stats(&self) -> LinkStats {
+    lock(&self.uplink.link_stats).clone()
+  }
+
+  fn restore_link_stats(&self, stats: LinkStats) {
+    lock(&self.uplink.link_stats).merge(stats);
+  }
+}
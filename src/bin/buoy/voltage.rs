@@ -1,6 +1,3 @@
-#![allow(unused_imports)]
-#![allow(dead_code)]
-
 ///****************************************************************************
 ///
 ///  Smart-Buoy - connects marine sounds to the cloud.
@@ -20,77 +17,289 @@
 ///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 ///
 ///****************************************************************************
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
 use regex::Regex;
-use std::fs::File;
-use std::io::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use buoy_code::errors::GiftError;
-use buoy_code::GPIO_ADC_PATH;
 
-// Calibration
+use crate::platform::{Adc, Platform};
+
+// Default calibration, used when the config file has none.
 // These two values are calculated using least squares approximation of
 // the equation y = CAL_A * x + CAL_B.  Where x is the raw voltage
 // and y is the actual voltage.
 //
-// The following link works well to calculate these values:
-//    http://neoprogrammics.com/linear_least_squares_regression/index.php
+// Every FX30 ADC is different, use `buoy calibrate` to create the
+// calibration for a device.
 //
-// These are the some example values used - the "Raw"
+// These are the example values the default is fitted to - the "Raw"
 // 0.0V => Result:10499 Raw:24812
 // 1.280V => Result:453493 Raw:29327
 // 5.19V => Result:1796016 Raw:43010
-const CAL_A: f32 = 0.001_029_309;
-const CAL_B: f32 = -26.655_219;
+const CAL_A: f32 = 0.000_285_326_5;
+const CAL_B: f32 = -7.083_062;
 
-fn parse_voltage(buffer: String) -> Result<f32, GiftError> {
-  lazy_static! {
-    static ref RE: Regex = Regex::new(r"^Result:\d+ Raw:(?P<raw_v>\d+)").unwrap();
+///
+/// Convert the raw ADC value to the battery voltage.
+///
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "fit", rename_all = "lowercase")]
+pub enum Calibration {
+  // volts = a * raw + b
+  Linear { a: f32, b: f32 },
+  // Linear interpolation between (raw, volts) points, sorted by raw.  Values
+  // outside of the points are extrapolated from the first or last segment.
+  Piecewise { points: Vec<(f32, f32)> },
+}
+
+impl Default for Calibration {
+  fn default() -> Self {
+    Calibration::Linear { a: CAL_A, b: CAL_B }
+  }
+}
+
+impl Calibration {
+  pub fn apply(&self, raw: f32) -> f32 {
+    match self {
+      Calibration::Linear { a, b } => a * raw + b,
+      Calibration::Piecewise { points } => {
+        let i = match points.iter().position(|&(x, _)| raw < x) {
+          Some(0) => 1,
+          Some(i) => i,
+          None => points.len() - 1,
+        };
+        let (x0, y0) = points[i - 1];
+        let (x1, y1) = points[i];
+        y0 + (raw - x0) * (y1 - y0) / (x1 - x0)
+      }
+    }
   }
 
-  let re = RE.captures(&buffer);
-  if re.is_none() {
-    return Err(GiftError::ParseVoltage);
+  ///
+  /// The raw ADC value for a voltage, the simulator uses this.  Piecewise
+  /// points must increase in voltage.
+  ///
+  pub fn invert(&self, volts: f32) -> f32 {
+    match self {
+      Calibration::Linear { a, b } => (volts - b) / a,
+      Calibration::Piecewise { points } => {
+        let i = match points.iter().position(|&(_, y)| volts < y) {
+          Some(0) => 1,
+          Some(i) => i,
+          None => points.len() - 1,
+        };
+        let (x0, y0) = points[i - 1];
+        let (x1, y1) = points[i];
+        x0 + (volts - y0) * (x1 - x0) / (y1 - y0)
+      }
+    }
+  }
+
+  ///
+  /// Load the calibration written by `buoy calibrate`, None if there isn't
+  /// one.
+  ///
+  pub fn load(path: &Path) -> Result<Option<Calibration>, GiftError> {
+    if !path.exists() {
+      return Ok(None);
+    }
+    let cal: Calibration = toml::from_str(&fs::read_to_string(path)?)?;
+    cal.validate()?;
+    Ok(Some(cal))
+  }
+
+  ///
+  /// Save the calibration.  It's written and synced to a temporary file first
+  /// so a power failure does not leave us with half a calibration.
+  ///
+  pub fn save(&self, path: &Path) -> Result<(), GiftError> {
+    self.validate()?;
+    let tmp_path = path.with_extension("toml.tmp");
+    let mut file = File::create(&tmp_path)?;
+    writeln!(
+      file,
+      "# Written by `buoy calibrate`, it replaces [voltage] in buoy.toml"
+    )?;
+    file.write_all(toml::to_string_pretty(self)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+  }
+
+  pub fn validate(&self) -> Result<(), GiftError> {
+    match self {
+      Calibration::Linear { a, b } => {
+        if !a.is_finite() || !b.is_finite() {
+          return Err(GiftError::InvalidConfig(String::from(
+            "voltage: a and b must be numbers",
+          )));
+        }
+      }
+      Calibration::Piecewise { points } => {
+        if points.len() < 2 {
+          return Err(GiftError::InvalidConfig(String::from(
+            "voltage: piecewise needs at least two points",
+          )));
+        }
+        if points
+          .iter()
+          .any(|&(x, y)| !x.is_finite() || !y.is_finite())
+        {
+          return Err(GiftError::InvalidConfig(String::from(
+            "voltage: piecewise points must be numbers",
+          )));
+        }
+        if points.windows(2).any(|w| w[0].0 >= w[1].0) {
+          return Err(GiftError::InvalidConfig(String::from(
+            "voltage: piecewise points must be sorted by raw value",
+          )));
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+fn check_finite(points: &[(f32, f32)]) -> Result<(), GiftError> {
+  if points.iter().all(|&(x, y)| x.is_finite() && y.is_finite()) {
+    Ok(())
+  } else {
+    Err(GiftError::Calibration)
+  }
+}
+
+///
+/// Least squares fit of volts = a * raw + b, where points are (raw, volts).
+///
+pub fn fit_linear(points: &[(f32, f32)]) -> Result<Calibration, GiftError> {
+  check_finite(points)?;
+  let n = points.len() as f64;
+  let sum_x: f64 = points.iter().map(|&(x, _)| f64::from(x)).sum();
+  let sum_y: f64 = points.iter().map(|&(_, y)| f64::from(y)).sum();
+  let sum_xx: f64 = points.iter().map(|&(x, _)| f64::from(x).powi(2)).sum();
+  let sum_xy: f64 = points
+    .iter()
+    .map(|&(x, y)| f64::from(x) * f64::from(y))
+    .sum();
+
+  let denominator = n * sum_xx - sum_x * sum_x;
+  if points.len() < 2 || denominator.abs() < std::f64::EPSILON {
+    return Err(GiftError::Calibration);
   }
-  let caps = re.unwrap();
 
-  let raw_v_str = &caps["raw_v"];
-  let raw_v: f32 = raw_v_str.parse::<f32>()?;
-  let v = CAL_A * raw_v + CAL_B;
+  let a = (n * sum_xy - sum_x * sum_y) / denominator;
+  let b = (sum_y - a * sum_x) / n;
 
-  Ok(v)
+  Ok(Calibration::Linear {
+    a: a as f32,
+    b: b as f32,
+  })
+}
+
+///
+/// Create a piecewise calibration from (raw, volts) points.
+///
+pub fn fit_piecewise(points: &[(f32, f32)]) -> Result<Calibration, GiftError> {
+  check_finite(points)?;
+  let mut points = points.to_vec();
+  // Can't fail, the points are finite
+  points.sort_by(|p1, p2| p1.0.partial_cmp(&p2.0).unwrap());
+  let cal = Calibration::Piecewise { points };
+  cal.validate().map_err(|_| GiftError::Calibration)?;
+  Ok(cal)
 }
 
-/// Get the voltage of the ADC covertor.
-#[cfg(feature = "fx30")]
-pub fn get_voltage() -> Result<f32, GiftError> {
-  let mut f = File::open(GPIO_ADC_PATH)?;
-  let mut buffer = String::new();
-  f.read_to_string(&mut buffer)?;
+pub fn parse_raw(buffer: &str) -> Result<f32, GiftError> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r"^Result:\d+ Raw:(?P<raw_v>\d+)").unwrap();
+  }
+
+  let caps = RE.captures(buffer).ok_or(GiftError::ParseVoltage)?;
+  let raw_v: f32 = caps["raw_v"].parse::<f32>()?;
 
-  parse_voltage(buffer)
+  Ok(raw_v)
 }
 
-// We just use this for testing
-#[cfg(not(feature = "fx30"))]
-pub fn get_voltage() -> Result<f32, GiftError> {
-  Ok(1.0)
+/// Get the battery voltage.
+pub fn get_voltage(platform: &dyn Platform, cal: &Calibration) -> Result<f32, GiftError> {
+  Ok(cal.apply(platform.read_adc(Adc::Battery)?))
 }
 
 #[cfg(test)]
 mod tests {
-  use crate::voltage::parse_voltage;
+  use crate::voltage::*;
+
+  // The example values from the calibration comment above
+  const POINTS: [(f32, f32); 3] = [(24812.0, 0.0), (29327.0, 1.28), (43010.0, 5.19)];
 
   #[test]
-  fn test_parse_voltage() {
-    let v = parse_voltage(String::from("Result:10499 Raw:24812")).unwrap();
+  fn test_parse_raw() {
+    assert_eq!(24812.0, parse_raw("Result:10499 Raw:24812").unwrap());
+    assert!(parse_raw("Result:10499 Raw:asdf").is_err());
+    assert!(parse_raw("Raw:24812").is_err());
+  }
+
+  #[test]
+  fn test_default_calibration() {
+    // 0 V on the bench
+    assert!(Calibration::default().apply(24812.0).abs() < 0.01);
+    for &(raw, volts) in POINTS.iter() {
+      assert!((Calibration::default().apply(raw) - volts).abs() < 0.01);
+    }
+  }
+
+  #[test]
+  fn test_fit_linear() {
+    let cal = fit_linear(&POINTS).unwrap();
+    for &(raw, volts) in POINTS.iter() {
+      assert!((cal.apply(raw) - volts).abs() < 0.01);
+    }
+
+    assert!((cal.invert(cal.apply(30000.0)) - 30000.0).abs() < 0.5);
+
+    assert!(fit_linear(&POINTS[..1]).is_err());
+    assert!(fit_linear(&[(100.0, 1.0), (100.0, 2.0)]).is_err());
+    assert!(fit_linear(&[POINTS[0], POINTS[1], (30000.0, std::f32::NAN)]).is_err());
+  }
+
+  #[test]
+  fn test_save_load() {
+    let path = std::env::temp_dir().join(format!("buoy-calibration-{}.toml", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(None, Calibration::load(&path).unwrap());
+
+    for cal in &[
+      fit_linear(&POINTS).unwrap(),
+      fit_piecewise(&POINTS).unwrap(),
+    ] {
+      cal.save(&path).unwrap();
+      assert_eq!(Some(cal), Calibration::load(&path).unwrap().as_ref());
+    }
 
-    assert_eq!(-31.96656, v);
+    std::fs::write(&path, "fit = \"piecewise\"\npoints = [[1.0, 2.0]]\n").unwrap();
+    assert!(Calibration::load(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
   }
 
   #[test]
-  fn test_parse_invalid_voltage() {
-    let v = parse_voltage(String::from("Result:10499 Raw:asdf"));
+  fn test_fit_piecewise() {
+    let cal = fit_piecewise(&[POINTS[2], POINTS[0], POINTS[1]]).unwrap();
+    assert_eq!(0.0, cal.apply(24812.0));
+    assert_eq!(1.28, cal.apply(29327.0));
+    assert_eq!(0.64, cal.apply((24812.0 + 29327.0) / 2.0));
+    assert_eq!(5.19, cal.apply(43010.0));
+    assert!(cal.apply(20000.0) < 0.0);
+    assert!(cal.apply(50000.0) > 5.19);
+    assert_eq!(29327.0, cal.invert(1.28));
+    assert!((cal.invert(cal.apply(50000.0)) - 50000.0).abs() < 0.5);
 
-    assert!(v.is_err());
+    assert!(fit_piecewise(&POINTS[..1]).is_err());
+    assert!(fit_piecewise(&[(100.0, 1.0), (100.0, 2.0)]).is_err());
+    assert!(fit_piecewise(&[POINTS[0], (std::f32::NAN, 1.0), POINTS[1]]).is_err());
+    assert!(fit_piecewise(&[POINTS[0], POINTS[1], (50000.0, std::f32::INFINITY)]).is_err());
   }
 }
@@ -0,0 +1,678 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// A read-only REST API over `data/`, so notebooks and dashboards don't have
+/// to read the directory:
+///
+///    GET /                                      - the dashboard, dashboard.html
+///    GET /buoys                                 - each buoy and when it was last seen
+///    GET /id/{buoy_id}/status                   - last seen, heartbeat and position
+///    GET /id/{buoy_id}/recordings?from=TIME&to=TIME&offset=0&limit=100
+///    GET /id/{buoy_id}/recordings/{date}.{bin|wav|png|json}
+///    GET /id/{buoy_id}/battery?from=TIME&to=TIME - from the recordings' metadata
+///    GET /id/{buoy_id}/alerts?from=TIME&to=TIME  - data/{buoy_id}.alerts.csv
+///    GET /id/{buoy_id}/track.{geojson|gpx}?from=TIME&to=TIME, see track.rs
+///
+/// TIME is as for the track.  The recordings are listed oldest first, a page
+/// at a time, `next_offset` is null on the last page.  Errors have the JSON
+/// body in buoy_code::commands.  The answers come from the index, index.rs.
+/// It's served to the buoys' QUIC connections, and over plain HTTP for
+/// browsers, see web.rs.  A recording is sent from its file a chunk at a
+/// time, it's not read into memory.
+///
+use std::fs::File;
+use std::io::{self, Read};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Serialize, Serializer};
+
+use buoy_code::clock::parse_date;
+use buoy_code::commands::{json_string, ServerError, ERR_BAD_REQUEST, ERR_INTERNAL, ERR_NOT_FOUND};
+use buoy_code::errors::GiftError;
+
+use crate::heartbeat::parse_last_seen;
+use crate::index::{self, Index, Recording};
+use crate::save_post::SERVER_SAVE_PATH;
+use crate::track::{parse_time, rfc3339, serialize_rfc3339, TrackPoint, TrackQuery};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+const CHUNK_LEN: usize = 64 * 1024;
+
+// Self-contained, it only uses the API
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+#[derive(Debug)]
+pub enum Body {
+  Bytes(Vec<u8>),
+  File(File, u64), // With its length
+}
+
+///
+/// The body of a successful GET.
+///
+#[derive(Debug)]
+pub struct Reply {
+  pub content_type: &'static str,
+  pub body: Body,
+}
+
+impl Reply {
+  fn json(body: String) -> Self {
+    Reply {
+      content_type: "application/json",
+      body: Body::Bytes(body.into_bytes()),
+    }
+  }
+
+  fn file(content_type: &'static str, filename: &str) -> Result<Self, GiftError> {
+    let file = File::open(filename)?;
+    let len = file.metadata()?.len();
+    Ok(Reply {
+      content_type,
+      body: Body::File(file, len),
+    })
+  }
+
+  pub fn response(self) -> Chunks {
+    let len = match &self.body {
+      Body::Bytes(bytes) => bytes.len() as u64,
+      Body::File(_, len) => *len,
+    };
+    let mut head = format!(
+      "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+      self.content_type, len
+    )
+    .into_bytes();
+    match self.body {
+      Body::Bytes(bytes) => {
+        head.extend(bytes);
+        Chunks::from(head)
+      }
+      Body::File(file, len) => Chunks {
+        head: Some(head),
+        file: Some(file.take(len)),
+      },
+    }
+  }
+}
+
+///
+/// A response, the head and then the file, if there is one, `CHUNK_LEN`
+/// bytes at a time.
+///
+pub struct Chunks {
+  head: Option<Vec<u8>>,
+  file: Option<io::Take<File>>,
+}
+
+impl From<Vec<u8>> for Chunks {
+  fn from(resp: Vec<u8>) -> Self {
+    Chunks {
+      head: Some(resp),
+      file: None,
+    }
+  }
+}
+
+impl Iterator for Chunks {
+  type Item = io::Result<Vec<u8>>;
+
+  fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+    if let Some(head) = self.head.take() {
+      return Some(Ok(head));
+    }
+    let file = self.file.as_mut()?;
+    let mut chunk = vec![0; CHUNK_LEN];
+    match file.read(&mut chunk) {
+      Ok(0) => {
+        self.file = None;
+        None
+      }
+      Ok(n) => {
+        chunk.truncate(n);
+        Some(Ok(chunk))
+      }
+      Err(e) => {
+        self.file = None;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+fn content_type(ext: &str) -> &'static str {
+  match ext {
+    "wav" => "audio/wav",
+    "png" => "image/png",
+    "json" => "application/json",
+    _ => "application/octet-stream",
+  }
+}
+
+// The error is logged, the message doesn't give away the server's internals
+fn api_error(e: GiftError) -> ServerError {
+  match e {
+    GiftError::HttpInvalidPath => ServerError::new(404, ERR_NOT_FOUND, "nothing at the path"),
+    GiftError::Io(ref io) if io.kind() == io::ErrorKind::NotFound => {
+      ServerError::new(404, ERR_NOT_FOUND, "not found")
+    }
+    GiftError::HttpInvalidRequest | GiftError::ParseTelemetry | GiftError::ParseInt(_) => {
+      ServerError::new(400, ERR_BAD_REQUEST, "invalid query")
+    }
+    _ => {
+      error!("api_error(): {:?}", e);
+      ServerError::new(500, ERR_INTERNAL, "the request could not be processed")
+    }
+  }
+}
+
+///
+/// Answer a GET for `path`, which includes the query.
+///
+pub fn get(path: &str) -> Result<Reply, ServerError> {
+  route(path).map_err(api_error)
+}
+
+///
+/// The response to a GET, an error response if it failed.
+///
+pub fn response(path: &str) -> Chunks {
+  match get(path) {
+    Ok(reply) => reply.response(),
+    Err(error) => {
+      info!("GET {}: {}", path, error);
+      Chunks::from(error.response().into_bytes())
+    }
+  }
+}
+
+fn route(path: &str) -> Result<Reply, GiftError> {
+  lazy_static! {
+    static ref BUOY: Regex =
+      Regex::new(r"^/id/([0-9a-fA-F\-]{1,40})/(status|recordings|battery|alerts)$").unwrap();
+    static ref RECORDING: Regex = Regex::new(
+      r"^/id/([0-9a-fA-F\-]{1,40})/recordings/(\d{8}T\d{6}\.\d{3}Z)\.(bin|wav|png|json)$"
+    )
+    .unwrap();
+  }
+  let mut parts = path.splitn(2, '?');
+  let resource = parts.next().unwrap_or("");
+  let query = parts.next().unwrap_or("");
+
+  if resource == "/" || resource == "/dashboard" {
+    return Ok(Reply {
+      content_type: "text/html; charset=utf-8",
+      body: Body::Bytes(DASHBOARD.as_bytes().to_vec()),
+    });
+  }
+  if resource == "/buoys" {
+    params(query, &[])?;
+    return buoys();
+  }
+  if let Some(caps) = BUOY.captures(resource) {
+    return match &caps[2] {
+      "status" => {
+        params(query, &[])?;
+        status(&caps[1])
+      }
+      "recordings" => list_recordings(&caps[1], &Page::from_query(query)?),
+      "battery" => battery(&caps[1], &Page::from_query(query)?),
+      _ => alerts(&caps[1], &Page::from_query(query)?),
+    };
+  }
+  if let Some(caps) = RECORDING.captures(resource) {
+    params(query, &[])?;
+    let filename = format!(
+      "{}/{}.{}.{}",
+      SERVER_SAVE_PATH, &caps[1], &caps[2], &caps[3]
+    );
+    return Reply::file(content_type(&caps[3]), &filename);
+  }
+
+  let query = TrackQuery::from_path(path)?;
+  Ok(Reply {
+    content_type: query.format.content_type(),
+    body: Body::Bytes(query.export()?.into_bytes()),
+  })
+}
+
+///
+/// The query parameters, only the names in `known` are allowed.
+///
+fn params<'a>(query: &'a str, known: &[&str]) -> Result<Vec<(&'a str, &'a str)>, GiftError> {
+  query
+    .split('&')
+    .filter(|p| !p.is_empty())
+    .map(|pair| {
+      let mut kv = pair.splitn(2, '=');
+      match (kv.next(), kv.next()) {
+        (Some(name), Some(value)) if known.contains(&name) => Ok((name, value)),
+        _ => Err(GiftError::HttpInvalidRequest),
+      }
+    })
+    .collect()
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, GiftError> {
+  serde_json::to_string(value).map_err(|e| GiftError::StdFailure(e.into()))
+}
+
+///
+/// When the buoy was last seen, and when it's due back if it's asleep.
+///
+#[derive(Serialize)]
+struct LastSeen {
+  last_seen: String,
+  last_seen_by: String,
+  due_back: Option<String>,
+}
+
+impl LastSeen {
+  fn parse(last_seen: &str) -> LastSeen {
+    let (received, what, sleep_secs) = parse_last_seen(last_seen);
+    let due_back = match (parse_date(received), sleep_secs) {
+      (Some(time), Some(secs)) => Some(rfc3339(&(time + chrono::Duration::seconds(secs)))),
+      _ => None,
+    };
+    LastSeen {
+      last_seen: String::from(received),
+      last_seen_by: String::from(what),
+      due_back,
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct BuoyJson<'a> {
+  buoy_id: &'a str,
+  #[serde(flatten)]
+  last_seen: LastSeen,
+}
+
+fn buoys() -> Result<Reply, GiftError> {
+  let buoys = index::with(|index| index.buoys())?;
+  let buoys: Vec<BuoyJson> = buoys
+    .iter()
+    .map(|(buoy_id, last_seen, by)| BuoyJson {
+      buoy_id,
+      last_seen: LastSeen::parse(&format!("{},{}", last_seen, by)),
+    })
+    .collect();
+  Ok(Reply::json(to_json(&buoys)?))
+}
+
+/// When the buoy was last seen, an `HttpInvalidPath` error for an unknown buoy.
+fn last_seen(index: &Index, buoy_id: &str) -> Result<String, GiftError> {
+  index.last_seen(buoy_id)?.ok_or(GiftError::HttpInvalidPath)
+}
+
+#[derive(Serialize)]
+struct StatusJson<'a> {
+  buoy_id: &'a str,
+  #[serde(flatten)]
+  last_seen: LastSeen,
+  heartbeat: Option<serde_json::Value>, // As the heartbeat file
+  position: Option<TrackPoint>,
+}
+
+fn status(buoy_id: &str) -> Result<Reply, GiftError> {
+  let (last_seen, heartbeat, position) = index::with(|index| {
+    Ok((
+      last_seen(index, buoy_id)?,
+      index.heartbeat(buoy_id)?,
+      index.last_telemetry(buoy_id, "track")?,
+    ))
+  })?;
+  let status = StatusJson {
+    buoy_id,
+    last_seen: LastSeen::parse(&last_seen),
+    heartbeat: heartbeat.and_then(|json| serde_json::from_str(&json).ok()),
+    position: position.and_then(|line| TrackPoint::from_csv(&line)),
+  };
+  Ok(Reply::json(to_json(&status)?))
+}
+
+///
+/// Which recordings to list.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+  pub from: Option<DateTime<Utc>>,
+  pub to: Option<DateTime<Utc>>,
+  pub offset: usize,
+  pub limit: usize,
+}
+
+impl Page {
+  pub fn from_query(query: &str) -> Result<Page, GiftError> {
+    let mut page = Page {
+      from: None,
+      to: None,
+      offset: 0,
+      limit: DEFAULT_LIMIT,
+    };
+    for (name, value) in params(query, &["from", "to", "offset", "limit"])? {
+      match name {
+        "from" => page.from = Some(parse_time(value)?),
+        "to" => page.to = Some(parse_time(value)?),
+        "offset" => page.offset = value.parse()?,
+        _ => page.limit = value.parse()?,
+      }
+    }
+    if page.limit == 0 || page.limit > MAX_LIMIT {
+      return Err(GiftError::HttpInvalidRequest);
+    }
+    Ok(page)
+  }
+
+  /// The time is in the range, `from` is included, `to` is not.
+  pub fn contains(&self, time: &DateTime<Utc>) -> bool {
+    self.from.map_or(true, |from| *time >= from) && self.to.map_or(true, |to| *time < to)
+  }
+
+  ///
+  /// A page of recordings as JSON, each with the paths to download its
+  /// files, `total` is the number in the range.
+  ///
+  pub fn to_json(
+    &self,
+    buoy_id: &str,
+    total: usize,
+    recordings: &[Recording],
+  ) -> Result<String, GiftError> {
+    let end = total.min(self.offset.saturating_add(self.limit));
+    to_json(&PageJson {
+      buoy_id,
+      total,
+      offset: self.offset,
+      limit: self.limit,
+      next_offset: if end < total { Some(end) } else { None },
+      recordings: recordings
+        .iter()
+        .map(|recording| RecordingJson {
+          date: &recording.date,
+          time: recording.time,
+          status: &recording.status,
+          files: Files { buoy_id, recording },
+        })
+        .collect(),
+    })
+  }
+}
+
+#[derive(Serialize)]
+struct PageJson<'a> {
+  buoy_id: &'a str,
+  total: usize,
+  offset: usize,
+  limit: usize,
+  next_offset: Option<usize>,
+  recordings: Vec<RecordingJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct RecordingJson<'a> {
+  date: &'a str,
+  #[serde(serialize_with = "serialize_rfc3339")]
+  time: DateTime<Utc>,
+  status: &'a str,
+  files: Files<'a>,
+}
+
+/// The path to download each of a recording's files, by extension.
+struct Files<'a> {
+  buoy_id: &'a str,
+  recording: &'a Recording,
+}
+
+impl<'a> Serialize for Files<'a> {
+  fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    let Files { buoy_id, recording } = self;
+    s.collect_map(recording.files.iter().map(|ext| {
+      let path = format!("/id/{}/recordings/{}.{}", buoy_id, recording.date, ext);
+      (ext, path)
+    }))
+  }
+}
+
+fn list_recordings(buoy_id: &str, page: &Page) -> Result<Reply, GiftError> {
+  // An unknown buoy is a 404, not an empty list
+  let (total, recordings) = index::with(|index| {
+    last_seen(index, buoy_id)?;
+    index.recordings(buoy_id, page.from, page.to, page.offset, page.limit)
+  })?;
+  Ok(Reply::json(page.to_json(buoy_id, total, &recordings)?))
+}
+
+#[derive(Serialize)]
+struct BatteryJson {
+  #[serde(serialize_with = "serialize_rfc3339")]
+  time: DateTime<Utc>,
+  voltage: Option<f64>,
+  soc: Option<f64>,
+}
+
+///
+/// The battery voltage and state of charge of each recording in the range,
+/// oldest first.  Only the range is used from the page, it's not paged.
+///
+fn battery(buoy_id: &str, page: &Page) -> Result<Reply, GiftError> {
+  let points: Vec<BatteryJson> = index::with(|index| {
+    last_seen(index, buoy_id)?;
+    index.battery(buoy_id, page.from, page.to)
+  })?
+  .into_iter()
+  .map(|point| BatteryJson {
+    time: point.time,
+    voltage: point.voltage,
+    soc: point.soc,
+  })
+  .collect();
+  Ok(Reply::json(to_json(&points)?))
+}
+
+///
+/// The rows of a CSV file as JSON objects, with the names in the first line.
+/// Numbers are written as numbers, and empty fields as null.
+///
+pub fn csv_json<F: Fn(&[&str]) -> bool>(csv: &str, keep: F) -> String {
+  let mut lines = csv.lines();
+  let names: Vec<&str> = lines.next().unwrap_or("").split(',').collect();
+  let rows: Vec<String> = lines
+    .map(|line| line.split(',').collect::<Vec<&str>>())
+    .filter(|fields| keep(fields))
+    .map(|fields| {
+      let values: Vec<String> = names
+        .iter()
+        .zip(fields.iter())
+        .map(|(name, value)| {
+          let value = if value.is_empty() {
+            String::from("null")
+          } else if value.parse::<f64>().map_or(false, f64::is_finite) {
+            String::from(*value)
+          } else {
+            json_string(value)
+          };
+          format!("{}:{}", json_string(name), value)
+        })
+        .collect();
+      format!("{{{}}}", values.join(","))
+    })
+    .collect();
+  format!("[{}]", rows.join(","))
+}
+
+///
+/// The drift and light alerts received in the range, oldest first.
+///
+fn alerts(buoy_id: &str, page: &Page) -> Result<Reply, GiftError> {
+  let (header, lines) = index::with(|index| {
+    last_seen(index, buoy_id)?;
+    index.telemetry(buoy_id, "alerts", page.from, page.to)
+  })?;
+  let mut csv = header;
+  for line in lines {
+    csv.push('\n');
+    csv.push_str(&line);
+  }
+  Ok(Reply::json(csv_json(&csv, |_| true)))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::api::*;
+
+  fn status(path: &str) -> u16 {
+    get(path).unwrap_err().status
+  }
+
+  #[test]
+  fn test_route() {
+    assert_eq!(404, status("/nothing"));
+    assert_eq!(404, status("/id/1/recordings/../../etc/passwd.json"));
+    assert_eq!(404, status("/id/1/recordings/20200830T023517.000Z.exe"));
+    assert_eq!(400, status("/buoys?sort=asc"));
+    assert_eq!(400, status("/id/1/recordings?sort=asc"));
+    assert_eq!(400, status("/id/1/recordings?limit=abc"));
+    assert_eq!(400, status("/id/1/recordings?limit=0"));
+    assert_eq!(400, status("/id/1/recordings?from=yesterday"));
+
+    assert_eq!(400, status("/id/1/battery?sort=asc"));
+    assert_eq!("text/html; charset=utf-8", get("/").unwrap().content_type);
+
+    let reply = Reply::json(String::from("[]"));
+    let chunks: Vec<Vec<u8>> = reply.response().map(Result::unwrap).collect();
+    assert_eq!(
+      vec![
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]"
+          .to_vec()
+      ],
+      chunks
+    );
+  }
+
+  #[test]
+  fn test_file_reply() {
+    use std::fs;
+    let filename = std::env::temp_dir().join(format!("buoy-api-{}.bin", std::process::id()));
+    let filename = filename.to_str().unwrap();
+    fs::write(filename, vec![7; CHUNK_LEN * 2 + 1]).unwrap();
+
+    let chunks: Vec<Vec<u8>> = Reply::file("audio/wav", filename)
+      .unwrap()
+      .response()
+      .map(Result::unwrap)
+      .collect();
+    assert_eq!(4, chunks.len());
+    assert_eq!(
+      format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\nContent-Length: {}\r\n\r\n",
+        CHUNK_LEN * 2 + 1
+      )
+      .into_bytes(),
+      chunks[0]
+    );
+    assert_eq!(
+      vec![CHUNK_LEN, CHUNK_LEN, 1],
+      chunks[1..].iter().map(Vec::len).collect::<Vec<_>>()
+    );
+
+    fs::remove_file(filename).unwrap();
+  }
+
+  #[test]
+  fn test_recordings() {
+    let names: Vec<String> = [
+      "1.20200830T023517.000Z.json",
+      "1.20200830T023517.000Z.bin",
+      "1.20200830T023517.000Z.wav",
+      "1.20200830T013517.000Z.bin",
+    ]
+    .iter()
+    .map(|name| String::from(*name))
+    .collect();
+    let recordings = index::find_recordings(&names);
+
+    let page = Page::from_query("from=20200830T020000.000Z&limit=1").unwrap();
+    assert_eq!(
+      "{\"buoy_id\":\"1\",\"total\":1,\"offset\":0,\"limit\":1,\"next_offset\":null,\
+       \"recordings\":[{\"date\":\"20200830T023517.000Z\",\"time\":\"2020-08-30T02:35:17.000Z\",\
+       \"status\":\"saved\",\"files\":{\"bin\":\"/id/1/recordings/20200830T023517.000Z.bin\",\
+       \"wav\":\"/id/1/recordings/20200830T023517.000Z.wav\",\
+       \"json\":\"/id/1/recordings/20200830T023517.000Z.json\"}}]}",
+      page.to_json("1", 1, &recordings[1..]).unwrap()
+    );
+
+    let page = Page::from_query("limit=1").unwrap();
+    let json = page.to_json("1", 2, &recordings[..1]).unwrap();
+    assert!(json
+      .starts_with("{\"buoy_id\":\"1\",\"total\":2,\"offset\":0,\"limit\":1,\"next_offset\":1,"));
+    assert!(json.contains("\"status\":\"incomplete\""));
+    let page = Page::from_query("offset=1&limit=1").unwrap();
+    assert!(page
+      .to_json("1", 2, &recordings[1..])
+      .unwrap()
+      .contains("\"next_offset\":null"));
+  }
+
+  #[test]
+  fn test_last_seen() {
+    let buoy = BuoyJson {
+      buoy_id: "1\"",
+      last_seen: LastSeen::parse("20200830T023517.000Z,sleep:60\n"),
+    };
+    assert_eq!(
+      "{\"buoy_id\":\"1\\\"\",\"last_seen\":\"20200830T023517.000Z\",\
+       \"last_seen_by\":\"sleep:60\",\"due_back\":\"2020-08-30T02:36:17.000Z\"}",
+      to_json(&buoy).unwrap()
+    );
+
+    let status = StatusJson {
+      buoy_id: "1",
+      last_seen: LastSeen::parse("20200830T023517.000Z,upload"),
+      heartbeat: serde_json::from_str("{\"buoy_id\":\"1\"}").ok(),
+      position: None,
+    };
+    assert_eq!(
+      "{\"buoy_id\":\"1\",\"last_seen\":\"20200830T023517.000Z\",\"last_seen_by\":\"upload\",\
+       \"due_back\":null,\"heartbeat\":{\"buoy_id\":\"1\"},\"position\":null}",
+      to_json(&status).unwrap()
+    );
+  }
+
+  #[test]
+  fn test_csv_json() {
+    let csv = "received,alert,latitude,longitude,distance_m\n\
+               20200830T023517.000Z,drift,-36.843292,174.756864,512.5\n\
+               20200831T023517.000Z,light:open,,,\n";
+    assert_eq!(
+      "[{\"received\":\"20200830T023517.000Z\",\"alert\":\"drift\",\
+       \"latitude\":-36.843292,\"longitude\":174.756864,\"distance_m\":512.5},\
+       {\"received\":\"20200831T023517.000Z\",\"alert\":\"light:open\",\
+       \"latitude\":null,\"longitude\":null,\"distance_m\":null}]",
+      csv_json(csv, |_| true)
+    );
+    assert_eq!("[]", csv_json(csv, |fields| fields[1] == "none"));
+    assert_eq!("[]", csv_json("", |_| true));
+  }
+}
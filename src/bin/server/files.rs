@@ -0,0 +1,90 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Writing the files in `data/` that are replaced rather than appended to,
+/// the heartbeat, the last seen time, the metadata and the end of the
+/// track.  Each is written to a `.tmp` file that is renamed over the old
+/// one, and the `.tmp` files left by a crash are removed when the server
+/// starts.
+///
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::Path;
+
+use buoy_code::errors::GiftError;
+
+const TMP_EXT: &str = ".tmp";
+
+///
+/// Write a file by replacing it, so a reader never sees half a file.  The
+/// contents are on the disk before the rename, so a crash doesn't leave an
+/// empty file.
+///
+pub fn replace_file(filename: &str, contents: &str) -> Result<(), GiftError> {
+  let tmp_filename = format!("{}{}", filename, TMP_EXT);
+  let mut file = File::create(&tmp_filename)?;
+  file.write_all(contents.as_bytes())?;
+  file.sync_all()?;
+  fs::rename(&tmp_filename, filename)?;
+  Ok(())
+}
+
+///
+/// Remove the files `replace_file()` was writing when the server stopped,
+/// before it starts writing again.  Returns the number removed.
+///
+pub fn remove_tmp_files(dir: &Path) -> Result<usize, GiftError> {
+  let mut removed = 0;
+  if !dir.exists() {
+    return Ok(removed);
+  }
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.to_str().map_or(false, |name| name.ends_with(TMP_EXT)) {
+      warn!("remove_tmp_files(): removing {:?}", path);
+      fs::remove_file(&path)?;
+      removed += 1;
+    }
+  }
+  Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::files::*;
+
+  #[test]
+  fn test_remove_tmp_files() {
+    let dir = std::env::temp_dir().join(format!("buoy-tmp-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let filename = dir.join("1.20200830T023517.000Z.json");
+    let filename = filename.to_str().unwrap();
+    replace_file(filename, "{}").unwrap();
+    fs::write(format!("{}{}", filename, TMP_EXT), "{").unwrap();
+
+    assert_eq!(1, remove_tmp_files(&dir).unwrap());
+    assert_eq!(0, remove_tmp_files(&dir).unwrap());
+    assert_eq!("{}", fs::read_to_string(filename).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(0, remove_tmp_files(&dir).unwrap());
+  }
+}
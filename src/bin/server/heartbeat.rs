@@ -0,0 +1,347 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+///
+/// The buoy heartbeats, and when each buoy was last seen.  The buoys send a
+/// heartbeat to `POST /id/{buoy_id}/heartbeat` every few minutes, see
+/// buoy_code::heartbeat, and `POST /id/{buoy_id}/sleep` before going to sleep
+/// for `Sleep-Secs`.  For each buoy we keep:
+///
+///    data/{buoy_id}.heartbeat.json - the latest heartbeat
+///    data/{buoy_id}.last_seen      - "{received},{heartbeat|upload|sleep:{secs}}"
+///
+/// Both are replaced, not appended to, and are also kept in the index, see
+/// index.rs.  `server status` lists the buoys, and
+/// `GET /buoys` has them as JSON, see api.rs.
+///
+use std::fs;
+use std::io;
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+
+use buoy_code::clock::{format_date, parse_date};
+use buoy_code::date_now;
+use buoy_code::errors::GiftError;
+use buoy_code::heartbeat::{Heartbeat, HEADER_HEARTBEAT};
+use buoy_code::modem::HEADER_MODEM;
+use buoy_code::task_restarts::HEADER_TASK_RESTARTS;
+use buoy_code::HEADER_SLEEP;
+
+use crate::files::replace_file;
+use crate::index;
+use crate::save_post::{header_value, SERVER_SAVE_PATH};
+
+pub const LAST_SEEN_EXT: &str = ".last_seen";
+pub const HEARTBEAT_EXT: &str = ".heartbeat.json";
+const SLEEP_PREFIX: &str = "sleep:";
+
+///
+/// The buoy id and the notice of a heartbeat or sleep path, e.g.
+/// "/id/1/heartbeat" is ("1", "heartbeat").
+///
+pub fn notice_path(path: &str) -> Option<(&str, &str)> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r"^/id/([0-9a-fA-F\-]{1,40})/(heartbeat|sleep)$").unwrap();
+  }
+  let caps = RE.captures(path)?;
+  Some((caps.get(1)?.as_str(), caps.get(2)?.as_str()))
+}
+
+///
+/// The heartbeat file, the Task-Restarts and Modem headers are as the buoy
+/// sent them.
+///
+#[derive(Serialize)]
+struct HeartbeatJson<'a> {
+  buoy_id: &'a str,
+  received: &'a str,
+  hydrophone: String,
+  gps: String,
+  light: String,
+  power_state: String,
+  upload_queue: u32,
+  mem_free_kb: Option<u64>,
+  flash_used_pct: Option<f32>,
+  load_avg: Option<f32>,
+  uptime_secs: u64,
+  task_restarts: &'a str,
+  modem: &'a str,
+}
+
+/// Round for the JSON, so it has 41.3 rather than 41.29999923706055.
+fn round_to(value: f32, places: i32) -> f32 {
+  let scale = 10_f32.powi(places);
+  (value * scale).round() / scale
+}
+
+///
+/// The heartbeat as JSON.
+///
+pub fn heartbeat_json(
+  buoy_id: &str,
+  received: &str,
+  heartbeat: &Heartbeat,
+  restarts: Option<&str>,
+  modem: Option<&str>,
+) -> Result<String, GiftError> {
+  let json = HeartbeatJson {
+    buoy_id,
+    received,
+    hydrophone: heartbeat.hydrophone.to_string(),
+    gps: heartbeat.gps.to_string(),
+    light: heartbeat.light.to_string(),
+    power_state: heartbeat.power_state.to_string(),
+    upload_queue: heartbeat.upload_queue,
+    mem_free_kb: heartbeat.mem_free_kb,
+    flash_used_pct: heartbeat.flash_used_pct.map(|p| round_to(p, 1)),
+    load_avg: heartbeat.load_avg.map(|l| round_to(l, 2)),
+    uptime_secs: heartbeat.uptime_secs,
+    task_restarts: restarts.unwrap_or(""),
+    modem: modem.unwrap_or(""),
+  };
+  serde_json::to_string(&json).map_err(|e| GiftError::StdFailure(e.into()))
+}
+
+///
+/// Record that we heard from the buoy, `what` is "heartbeat", "upload" or
+/// "sleep:{secs}".
+///
+pub fn save_last_seen(buoy_id: &str, what: &str) -> Result<(), GiftError> {
+  let received = date_now();
+  replace_file(
+    &last_seen_filename(buoy_id),
+    &format!("{},{}\n", received, what),
+  )?;
+  index::with(|index| index.save_last_seen(buoy_id, &received, what))
+}
+
+fn last_seen_filename(buoy_id: &str) -> String {
+  format!("{}/{}{}", SERVER_SAVE_PATH, buoy_id, LAST_SEEN_EXT)
+}
+
+fn heartbeat_filename(buoy_id: &str) -> String {
+  format!("{}/{}{}", SERVER_SAVE_PATH, buoy_id, HEARTBEAT_EXT)
+}
+
+/// The contents of the last seen file, an `Io` NotFound error for an unknown
+/// buoy.
+pub fn read_last_seen(buoy_id: &str) -> Result<String, GiftError> {
+  Ok(fs::read_to_string(last_seen_filename(buoy_id))?)
+}
+
+/// The latest heartbeat JSON, if the buoy has sent one.
+pub fn read_heartbeat(buoy_id: &str) -> Result<Option<String>, GiftError> {
+  match fs::read_to_string(heartbeat_filename(buoy_id)) {
+    Ok(json) => Ok(Some(json)),
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(GiftError::Io(e)),
+  }
+}
+
+///
+/// The buoys we have heard from, sorted.
+///
+pub fn buoy_ids() -> Result<Vec<String>, GiftError> {
+  let mut buoy_ids: Vec<String> = fs::read_dir(SERVER_SAVE_PATH)?
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| entry.file_name().into_string().ok())
+    .filter(|name| name.ends_with(LAST_SEEN_EXT))
+    .map(|name| String::from(&name[..name.len() - LAST_SEEN_EXT.len()]))
+    .collect();
+  buoy_ids.sort();
+  Ok(buoy_ids)
+}
+
+///
+/// Save the heartbeat to `data/{buoy_id}.heartbeat.json`.
+///
+pub fn save_heartbeat(req: &httparse::Request, buoy_id: &str) -> Result<(), GiftError> {
+  save_last_seen(buoy_id, "heartbeat")?;
+
+  let value = header_value(req, HEADER_HEARTBEAT).ok_or(GiftError::HttpInvalidRequest)?;
+  let heartbeat = match Heartbeat::decode(value) {
+    Ok(heartbeat) => heartbeat,
+    Err(e) => {
+      error!("save_heartbeat(): invalid Heartbeat '{}': {:?}", value, e);
+      return Ok(());
+    }
+  };
+  info!("Buoy {}: heartbeat {}", buoy_id, value);
+
+  let json = heartbeat_json(
+    buoy_id,
+    &date_now(),
+    &heartbeat,
+    header_value(req, HEADER_TASK_RESTARTS),
+    header_value(req, HEADER_MODEM),
+  )?;
+  replace_file(&heartbeat_filename(buoy_id), &json)?;
+  index::with(|index| index.save_heartbeat(buoy_id, &json))
+}
+
+///
+/// The buoy is going to sleep, remember when it should be back.
+///
+pub fn save_sleep_notice(req: &httparse::Request, buoy_id: &str) -> Result<(), GiftError> {
+  let value = header_value(req, HEADER_SLEEP).ok_or(GiftError::HttpInvalidRequest)?;
+  let secs: u64 = value.trim().parse()?;
+  info!("Buoy {}: going to sleep for {} s", buoy_id, secs);
+  save_last_seen(buoy_id, &format!("{}{}", SLEEP_PREFIX, secs))
+}
+
+///
+/// The time and what was seen in a last seen file, and for how long the buoy
+/// said it would sleep.
+///
+pub fn parse_last_seen(last_seen: &str) -> (&str, &str, Option<i64>) {
+  let mut fields = last_seen.trim().splitn(2, ',');
+  let received = fields.next().unwrap_or("");
+  let what = fields.next().unwrap_or("unknown");
+  let sleep_secs = if what.starts_with(SLEEP_PREFIX) {
+    what[SLEEP_PREFIX.len()..].parse::<i64>().ok()
+  } else {
+    None
+  };
+  (received, what, sleep_secs)
+}
+
+///
+/// A line of `server status`, `last_seen` is the contents of the last seen
+/// file.
+///
+pub fn status_line(buoy_id: &str, last_seen: &str, now: DateTime<Utc>) -> String {
+  let (received, what, sleep_secs) = parse_last_seen(last_seen);
+  match (parse_date(received), sleep_secs) {
+    (Some(time), Some(secs)) => {
+      let wake = time + chrono::Duration::seconds(secs);
+      let late = if now > wake {
+        format!(", {} minutes late", (now - wake).num_minutes())
+      } else {
+        String::new()
+      };
+      format!(
+        "buoy {}: asleep since {} for {} s, due back {}{}",
+        buoy_id,
+        received,
+        secs,
+        format_date(&wake),
+        late
+      )
+    }
+    (Some(time), None) => format!(
+      "buoy {}: last seen {} by {}, {} minutes ago",
+      buoy_id,
+      received,
+      what,
+      (now - time).num_minutes()
+    ),
+    (None, _) => format!("buoy {}: invalid last seen '{}'", buoy_id, last_seen.trim()),
+  }
+}
+
+///
+/// When each buoy was last seen, and its latest heartbeat.
+///
+pub fn status() -> Result<String, GiftError> {
+  let now = Utc::now();
+  let mut out = String::new();
+  for buoy_id in buoy_ids()? {
+    out.push_str(&status_line(&buoy_id, &read_last_seen(&buoy_id)?, now));
+    out.push('\n');
+    if let Some(json) = read_heartbeat(&buoy_id)? {
+      out.push_str(&format!("  {}\n", json));
+    }
+  }
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::heartbeat::*;
+  use buoy_code::heartbeat::Health;
+  use buoy_code::power_state::PowerState;
+  use chrono::TimeZone;
+
+  #[test]
+  fn test_notice_path() {
+    assert_eq!(Some(("1", "heartbeat")), notice_path("/id/1/heartbeat"));
+    assert_eq!(
+      Some(("ab-12", "heartbeat")),
+      notice_path("/id/ab-12/heartbeat")
+    );
+    assert_eq!(Some(("1", "sleep")), notice_path("/id/1/sleep"));
+    assert_eq!(None, notice_path("/id/1"));
+    assert_eq!(None, notice_path("/id/1/heartbeat/x"));
+  }
+
+  #[test]
+  fn test_heartbeat_json() {
+    let heartbeat = Heartbeat {
+      hydrophone: Health::Ok,
+      gps: Health::Failed,
+      light: Health::Off,
+      power_state: PowerState::Low,
+      upload_queue: 2,
+      mem_free_kb: Some(20480),
+      flash_used_pct: None,
+      load_avg: Some(0.351),
+      uptime_secs: 600,
+    };
+    assert_eq!(
+      "{\"buoy_id\":\"1\",\"received\":\"20200830T023517.000Z\",\"hydrophone\":\"ok\",\
+       \"gps\":\"failed\",\"light\":\"off\",\"power_state\":\"low\",\"upload_queue\":2,\
+       \"mem_free_kb\":20480,\"flash_used_pct\":null,\"load_avg\":0.35,\
+       \"uptime_secs\":600,\"task_restarts\":\"gps=1\",\"modem\":\"rat=LTE,rssi=-65\"}",
+      heartbeat_json(
+        "1",
+        "20200830T023517.000Z",
+        &heartbeat,
+        Some("gps=1"),
+        Some("rat=LTE,rssi=-65")
+      )
+      .unwrap()
+    );
+
+    // The headers are the buoy's, they could have anything in them
+    let json = heartbeat_json("1", "", &heartbeat, Some("a\"b\\c"), Some("\n")).unwrap();
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!("a\"b\\c", value["task_restarts"]);
+    assert_eq!("\n", value["modem"]);
+  }
+
+  #[test]
+  fn test_status_line() {
+    let now = Utc.ymd(2020, 8, 30).and_hms(3, 35, 17);
+    assert_eq!(
+      "buoy 1: last seen 20200830T023517.000Z by heartbeat, 60 minutes ago",
+      status_line("1", "20200830T023517.000Z,heartbeat\n", now)
+    );
+    assert_eq!(
+      "buoy 1: asleep since 20200830T023517.000Z for 1800 s, due back 20200830T030517.000Z, \
+       30 minutes late",
+      status_line("1", "20200830T023517.000Z,sleep:1800", now)
+    );
+    assert_eq!(
+      "buoy 1: invalid last seen 'garbage'",
+      status_line("1", "garbage", now)
+    );
+  }
+}
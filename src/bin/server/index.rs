@@ -0,0 +1,980 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// An SQLite index of the buoys, recordings and telemetry in `data/`, so the
+/// API and the dashboard don't scan the directory:
+///
+///    data/index.sqlite
+///
+/// The files are the record, the index is rebuilt from them with
+/// `server reindex`, or when the server starts without one or with one from
+/// an older server.  A recording's row is written in one transaction with the
+/// buoy's last seen time and the telemetry lines appended to the CSV files,
+/// once `save_http_post` is done with it.  Its status is:
+///
+///    saved      - all the files were written
+///    failed     - saving failed, `error` says why
+///    incomplete - found by a reindex without its metadata JSON
+///
+/// A reindex can't tell failed from incomplete, the error is not in the files.
+/// If the index can't be updated the upload fails, and the buoy sends it
+/// again.  The connections are opened once and shared by the threads, see
+/// `with()`.
+///
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior, NO_PARAMS};
+
+use buoy_code::clock::{format_date, parse_date};
+use buoy_code::date_now;
+use buoy_code::errors::GiftError;
+use buoy_code::position::{HEADER_LATITUDE, HEADER_LONGITUDE};
+use buoy_code::HEADER_BATTERY_SOC;
+
+use crate::heartbeat::{parse_last_seen, HEARTBEAT_EXT, LAST_SEEN_EXT};
+use crate::metadata::Metadata;
+use crate::save_post::SERVER_SAVE_PATH;
+
+const SCHEMA_VERSION: i32 = 2;
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS buoys (
+    buoy_id       TEXT PRIMARY KEY,
+    last_seen     TEXT NOT NULL, -- date_now() format
+    last_seen_by  TEXT NOT NULL, -- heartbeat, upload or sleep:{secs}
+    heartbeat     TEXT           -- The latest heartbeat JSON
+  );
+  CREATE TABLE IF NOT EXISTS recordings (
+    buoy_id       TEXT NOT NULL,
+    date          TEXT NOT NULL, -- The start time the files are saved under
+    files         TEXT NOT NULL, -- The extensions of the files, e.g. 'bin,wav,png,json'
+    status        TEXT NOT NULL, -- saved, failed or incomplete
+    error         TEXT,
+    body_bytes    INTEGER,
+    body_sha256   TEXT,
+    decode_errors INTEGER,
+    voltage       REAL,
+    soc           REAL,
+    latitude      REAL,
+    longitude     REAL,
+    indexed       TEXT NOT NULL, -- When the row was written
+    PRIMARY KEY (buoy_id, date)
+  );
+  CREATE TABLE IF NOT EXISTS telemetry (
+    buoy_id       TEXT NOT NULL,
+    kind          TEXT NOT NULL, -- The CSV file, e.g. alerts for data/{buoy_id}.alerts.csv
+    line          INTEGER NOT NULL, -- In the file, the CSV header is line 0
+    time          TEXT NOT NULL, -- The first field
+    fields        TEXT NOT NULL, -- The CSV line
+    PRIMARY KEY (buoy_id, kind, line)
+  );
+  CREATE TABLE IF NOT EXISTS csv_files (
+    buoy_id       TEXT NOT NULL,
+    kind          TEXT NOT NULL,
+    header        TEXT NOT NULL,
+    bytes         INTEGER NOT NULL, -- How much of the file is indexed
+    lines         INTEGER NOT NULL,
+    PRIMARY KEY (buoy_id, kind)
+  );
+";
+const DROP_SCHEMA: &str = "
+  DROP TABLE IF EXISTS buoys;
+  DROP TABLE IF EXISTS recordings;
+  DROP TABLE IF EXISTS telemetry;
+  DROP TABLE IF EXISTS csv_files;
+";
+
+// The other uploads are waited for, they are saved in their own threads
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Sorts after any date, for a range with no end
+const MAX_DATE: &str = "~";
+
+// The files saved for each upload, see save_post.rs
+pub const RECORDING_EXTS: [&str; 4] = ["bin", "wav", "png", "json"];
+
+// The telemetry CSV files, `data/{buoy_id}.{kind}.csv`, see telemetry.rs and track.rs
+pub const TELEMETRY_KINDS: [&str; 7] = [
+  "link", "power", "light", "modem", "sessions", "alerts", "track",
+];
+
+// The connections kept for reuse, there is one per thread using the index
+const MAX_IDLE: usize = 8;
+
+lazy_static! {
+  static ref IDLE: Mutex<Vec<Index>> = Mutex::new(Vec::new());
+}
+
+pub const STATUS_SAVED: &str = "saved";
+pub const STATUS_FAILED: &str = "failed";
+pub const STATUS_INCOMPLETE: &str = "incomplete";
+
+fn sql_error(e: rusqlite::Error) -> GiftError {
+  GiftError::StdFailure(e.into())
+}
+
+pub fn index_path() -> PathBuf {
+  Path::new(SERVER_SAVE_PATH).join("index.sqlite")
+}
+
+///
+/// Run `f` with a connection to the server's index, `data/index.sqlite`.  A
+/// connection another thread is done with is reused, one is only opened when
+/// they are all in use.
+///
+pub fn with<T, F>(f: F) -> Result<T, GiftError>
+where
+  F: FnOnce(&mut Index) -> Result<T, GiftError>,
+{
+  let idle = IDLE.lock().unwrap_or_else(|e| e.into_inner()).pop();
+  let mut index = match idle {
+    Some(index) => index,
+    None => Index::open(&index_path())?,
+  };
+  let result = f(&mut index);
+
+  let mut idle = IDLE.lock().unwrap_or_else(|e| e.into_inner());
+  if idle.len() < MAX_IDLE {
+    idle.push(index);
+  }
+  result
+}
+
+///
+/// Rebuild the server's index from `data/`, returns the number of buoys and
+/// recordings.
+///
+pub fn reindex() -> Result<(usize, usize), GiftError> {
+  with(|index| index.rebuild(Path::new(SERVER_SAVE_PATH)))
+}
+
+///
+/// Build the index if there isn't one yet, or it's from an older server,
+/// e.g. on the first start after an upgrade.
+///
+pub fn ensure() -> Result<(), GiftError> {
+  fs::create_dir_all(SERVER_SAVE_PATH)?;
+  if !with(|index| Ok(index.stale))? {
+    return Ok(());
+  }
+  let (buoys, recordings) = reindex()?;
+  info!("Indexed {} buoys and {} recordings", buoys, recordings);
+  Ok(())
+}
+
+///
+/// An upload, `date` is the start time it's saved under, and `files` are the
+/// extensions of the files that were saved.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+  pub buoy_id: String,
+  pub date: String,
+  pub time: DateTime<Utc>,
+  pub files: Vec<&'static str>,
+  pub status: String,
+}
+
+///
+/// The recordings in the file names from `data/`, by buoy and oldest first.
+///
+pub fn find_recordings(names: &[String]) -> Vec<Recording> {
+  lazy_static! {
+    static ref RE: Regex =
+      Regex::new(r"^([0-9a-fA-F\-]{1,40})\.(\d{8}T\d{6}\.\d{3}Z)\.([a-z]+)$").unwrap();
+  }
+  let mut files: BTreeMap<(&str, &str), Vec<&'static str>> = BTreeMap::new();
+  for caps in names.iter().filter_map(|name| RE.captures(name)) {
+    if let Some(ext) = RECORDING_EXTS.iter().find(|ext| **ext == &caps[3]) {
+      let key = (caps.get(1).unwrap().as_str(), caps.get(2).unwrap().as_str());
+      files.entry(key).or_default().push(*ext);
+    }
+  }
+
+  files
+    .into_iter()
+    .filter_map(|((buoy_id, date), mut exts)| {
+      exts.sort_by_key(|ext| RECORDING_EXTS.iter().position(|e| e == ext));
+      let status = if exts.contains(&"json") {
+        STATUS_SAVED
+      } else {
+        STATUS_INCOMPLETE
+      };
+      Some(Recording {
+        buoy_id: String::from(buoy_id),
+        date: String::from(date),
+        time: parse_date(date)?,
+        files: exts,
+        status: String::from(status),
+      })
+    })
+    .collect()
+}
+
+///
+/// The value of a field in the metadata JSON written before it had a
+/// `schema_version`, where the headers are strings and the position fields
+/// numbers.
+///
+pub fn legacy_field<'a>(json: &'a str, name: &str) -> Option<&'a str> {
+  lazy_static! {
+    static ref FIELD: Regex = Regex::new(r#""([^"]+)":\s*"?([^",}]*)"?"#).unwrap();
+  }
+  FIELD
+    .captures_iter(json)
+    .find(|caps| caps[1].eq_ignore_ascii_case(name))
+    .map(|caps| caps.get(2).unwrap().as_str())
+}
+
+///
+/// What is indexed about a recording, read from its metadata JSON.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+  pub body_bytes: Option<i64>,
+  pub body_sha256: Option<String>,
+  pub decode_errors: Option<i64>,
+  pub voltage: Option<f64>,
+  pub soc: Option<f64>,
+  pub latitude: Option<f64>,
+  pub longitude: Option<f64>,
+}
+
+impl From<Metadata> for Summary {
+  fn from(metadata: Metadata) -> Summary {
+    Summary {
+      body_bytes: Some(metadata.body_bytes as i64),
+      body_sha256: Some(metadata.body_sha256),
+      decode_errors: Some(metadata.decode_errors as i64),
+      voltage: metadata.battery.voltage,
+      soc: metadata.battery.soc,
+      latitude: metadata.gps.as_ref().map(|gps| gps.latitude),
+      longitude: metadata.gps.as_ref().map(|gps| gps.longitude),
+    }
+  }
+}
+
+impl Summary {
+  pub fn from_json(json: &str) -> Summary {
+    if let Ok(metadata) = Metadata::from_json(json) {
+      return Summary::from(metadata);
+    }
+    let number = |name| legacy_field(json, name).and_then(|v| v.trim().parse::<f64>().ok());
+    Summary {
+      body_bytes: number("body_bytes").map(|n| n as i64),
+      body_sha256: legacy_field(json, "body_sha256").map(String::from),
+      decode_errors: number("decode_errors").map(|n| n as i64),
+      voltage: number("Battery-Voltage"),
+      soc: number(HEADER_BATTERY_SOC),
+      latitude: number(HEADER_LATITUDE),
+      longitude: number(HEADER_LONGITUDE),
+    }
+  }
+
+  /// The summary of a recording in `dir`, the default if it has no metadata.
+  fn read(dir: &Path, recording: &Recording) -> Summary {
+    if !recording.files.contains(&"json") {
+      return Summary::default();
+    }
+    let filename = dir.join(format!("{}.{}.json", recording.buoy_id, recording.date));
+    match fs::read_to_string(&filename) {
+      Ok(json) => Summary::from_json(&json),
+      Err(e) => {
+        warn!("Summary::read(): {:?}: {}", filename, e);
+        Summary::default()
+      }
+    }
+  }
+}
+
+/// A recording's battery readings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Battery {
+  pub time: DateTime<Utc>,
+  pub voltage: Option<f64>,
+  pub soc: Option<f64>,
+}
+
+pub struct Index {
+  conn: Connection,
+  pub stale: bool, // New or from an older server, it needs a rebuild
+}
+
+impl Index {
+  pub fn open(path: &Path) -> Result<Index, GiftError> {
+    let conn = Connection::open(path).map_err(sql_error)?;
+    conn.busy_timeout(BUSY_TIMEOUT).map_err(sql_error)?;
+    let version: i32 = conn
+      .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+      .map_err(sql_error)?;
+    // The version is set by the rebuild
+    let stale = version != SCHEMA_VERSION;
+    if stale {
+      conn.execute_batch(DROP_SCHEMA).map_err(sql_error)?;
+    }
+    conn.execute_batch(SCHEMA).map_err(sql_error)?;
+    Ok(Index { conn, stale })
+  }
+
+  pub fn save_last_seen(&self, buoy_id: &str, received: &str, what: &str) -> Result<(), GiftError> {
+    upsert_buoy(&self.conn, buoy_id, received, what)
+  }
+
+  /// The buoy's latest heartbeat, after its last seen time is saved.
+  pub fn save_heartbeat(&self, buoy_id: &str, json: &str) -> Result<(), GiftError> {
+    self
+      .conn
+      .execute(
+        "UPDATE buoys SET heartbeat = ?2 WHERE buoy_id = ?1",
+        params![buoy_id, json],
+      )
+      .map_err(sql_error)?;
+    Ok(())
+  }
+
+  ///
+  /// Index an upload that `save_http_post` is done with, and the telemetry
+  /// it appended, the files are in `dir`.  `error` is why saving failed, if
+  /// it did.
+  ///
+  pub fn save_recording(
+    &mut self,
+    dir: &Path,
+    buoy_id: &str,
+    date: &str,
+    error: Option<&str>,
+  ) -> Result<(), GiftError> {
+    let time = parse_date(date).ok_or(GiftError::ParseTelemetry)?;
+    let files = RECORDING_EXTS
+      .iter()
+      .filter(|ext| dir.join(format!("{}.{}.{}", buoy_id, date, ext)).exists())
+      .cloned()
+      .collect();
+    let status = if error.is_some() {
+      STATUS_FAILED
+    } else {
+      STATUS_SAVED
+    };
+    let recording = Recording {
+      buoy_id: String::from(buoy_id),
+      date: String::from(date),
+      time,
+      files,
+      status: String::from(status),
+    };
+    let summary = Summary::read(dir, &recording);
+
+    // Taking the write lock up front, the uploads are saved in parallel
+    let tx = self
+      .conn
+      .transaction_with_behavior(TransactionBehavior::Immediate)
+      .map_err(sql_error)?;
+    insert_recording(&tx, &recording, &summary, error)?;
+    upsert_buoy(&tx, buoy_id, &date_now(), "upload")?;
+    for kind in TELEMETRY_KINDS.iter() {
+      sync_csv(&tx, dir, buoy_id, kind)?;
+    }
+    tx.commit().map_err(sql_error)
+  }
+
+  ///
+  /// Replace the index with what is in `dir`, returns the number of buoys
+  /// and recordings.
+  ///
+  pub fn rebuild(&mut self, dir: &Path) -> Result<(usize, usize), GiftError> {
+    let names: Vec<String> = fs::read_dir(dir)?
+      .filter_map(|entry| entry.ok())
+      .filter_map(|entry| entry.file_name().into_string().ok())
+      .collect();
+    let recordings = find_recordings(&names);
+
+    let buoys: Vec<&str> = names
+      .iter()
+      .filter(|name| name.ends_with(LAST_SEEN_EXT))
+      .map(|name| &name[..name.len() - LAST_SEEN_EXT.len()])
+      .collect();
+
+    let tx = self
+      .conn
+      .transaction_with_behavior(TransactionBehavior::Immediate)
+      .map_err(sql_error)?;
+    tx.execute_batch(
+      "DELETE FROM buoys; DELETE FROM recordings; DELETE FROM telemetry; DELETE FROM csv_files;",
+    )
+    .map_err(sql_error)?;
+    for buoy_id in &buoys {
+      let last_seen = fs::read_to_string(dir.join(format!("{}{}", buoy_id, LAST_SEEN_EXT)))?;
+      let (received, what, _) = parse_last_seen(&last_seen);
+      let heartbeat = match fs::read_to_string(dir.join(format!("{}{}", buoy_id, HEARTBEAT_EXT))) {
+        Ok(json) => Some(json),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(GiftError::Io(e)),
+      };
+      tx.execute(
+        "INSERT INTO buoys (buoy_id, last_seen, last_seen_by, heartbeat) VALUES (?1, ?2, ?3, ?4)",
+        params![buoy_id, received, what, heartbeat],
+      )
+      .map_err(sql_error)?;
+      for kind in TELEMETRY_KINDS.iter() {
+        sync_csv(&tx, dir, buoy_id, kind)?;
+      }
+    }
+    for recording in &recordings {
+      insert_recording(&tx, recording, &Summary::read(dir, recording), None)?;
+    }
+    tx.pragma_update(None, "user_version", &SCHEMA_VERSION)
+      .map_err(sql_error)?;
+    tx.commit().map_err(sql_error)?;
+    self.stale = false;
+    Ok((buoys.len(), recordings.len()))
+  }
+
+  /// When the buoy was last seen, and by what, as in its last seen file.
+  pub fn last_seen(&self, buoy_id: &str) -> Result<Option<String>, GiftError> {
+    let mut stmt = self
+      .conn
+      .prepare("SELECT last_seen, last_seen_by FROM buoys WHERE buoy_id = ?1")
+      .map_err(sql_error)?;
+    let mut rows = stmt
+      .query_map(params![buoy_id], |row| {
+        let received: String = row.get(0)?;
+        let what: String = row.get(1)?;
+        Ok(format!("{},{}", received, what))
+      })
+      .map_err(sql_error)?;
+    rows.next().transpose().map_err(sql_error)
+  }
+
+  /// The date a recording with this body was saved under, if it was.
+  pub fn saved_date(&self, buoy_id: &str, body_sha256: &str) -> Result<Option<String>, GiftError> {
+    self
+      .conn
+      .query_row(
+        "SELECT date FROM recordings WHERE buoy_id = ?1 AND body_sha256 = ?2 AND status = ?3 \
+         ORDER BY date LIMIT 1",
+        params![buoy_id, body_sha256, STATUS_SAVED],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(sql_error)
+  }
+
+  /// The buoy's latest heartbeat JSON, if it has sent one.
+  pub fn heartbeat(&self, buoy_id: &str) -> Result<Option<String>, GiftError> {
+    let heartbeat: Option<Option<String>> = self
+      .conn
+      .query_row(
+        "SELECT heartbeat FROM buoys WHERE buoy_id = ?1",
+        params![buoy_id],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(sql_error)?;
+    Ok(heartbeat.and_then(|heartbeat| heartbeat))
+  }
+
+  /// The last line of the buoy's telemetry CSV file.
+  pub fn last_telemetry(&self, buoy_id: &str, kind: &str) -> Result<Option<String>, GiftError> {
+    self
+      .conn
+      .query_row(
+        "SELECT fields FROM telemetry WHERE buoy_id = ?1 AND kind = ?2 \
+         ORDER BY line DESC LIMIT 1",
+        params![buoy_id, kind],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(sql_error)
+  }
+
+  ///
+  /// The CSV header and lines of the buoy's telemetry in the range, oldest
+  /// first.  The range is on the first field, a time.
+  ///
+  pub fn telemetry(
+    &self,
+    buoy_id: &str,
+    kind: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+  ) -> Result<(String, Vec<String>), GiftError> {
+    let header: Option<String> = self
+      .conn
+      .query_row(
+        "SELECT header FROM csv_files WHERE buoy_id = ?1 AND kind = ?2",
+        params![buoy_id, kind],
+        |row| row.get(0),
+      )
+      .optional()
+      .map_err(sql_error)?;
+
+    let (from, to) = date_range(from, to);
+    let mut stmt = self
+      .conn
+      .prepare(
+        "SELECT fields FROM telemetry \
+         WHERE buoy_id = ?1 AND kind = ?2 AND time >= ?3 AND time < ?4 ORDER BY line",
+      )
+      .map_err(sql_error)?;
+    let rows = stmt
+      .query_map(params![buoy_id, kind, from, to], |row| row.get(0))
+      .map_err(sql_error)?;
+    let lines = rows.collect::<Result<_, _>>().map_err(sql_error)?;
+    Ok((header.unwrap_or_default(), lines))
+  }
+
+  /// Each buoy, when it was last seen, and by what.
+  pub fn buoys(&self) -> Result<Vec<(String, String, String)>, GiftError> {
+    let mut stmt = self
+      .conn
+      .prepare("SELECT buoy_id, last_seen, last_seen_by FROM buoys ORDER BY buoy_id")
+      .map_err(sql_error)?;
+    let rows = stmt
+      .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+      .map_err(sql_error)?;
+    rows.collect::<Result<_, _>>().map_err(sql_error)
+  }
+
+  ///
+  /// The number of recordings in the range, and `limit` of them from
+  /// `offset`, oldest first.
+  ///
+  pub fn recordings(
+    &self,
+    buoy_id: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    offset: usize,
+    limit: usize,
+  ) -> Result<(usize, Vec<Recording>), GiftError> {
+    let (from, to) = date_range(from, to);
+    let total: i64 = self
+      .conn
+      .query_row(
+        "SELECT COUNT(*) FROM recordings WHERE buoy_id = ?1 AND date >= ?2 AND date < ?3",
+        params![buoy_id, from, to],
+        |row| row.get(0),
+      )
+      .map_err(sql_error)?;
+
+    let mut stmt = self
+      .conn
+      .prepare(
+        "SELECT date, files, status FROM recordings \
+         WHERE buoy_id = ?1 AND date >= ?2 AND date < ?3 ORDER BY date LIMIT ?4 OFFSET ?5",
+      )
+      .map_err(sql_error)?;
+    let rows = stmt
+      .query_map(
+        params![buoy_id, from, to, limit as i64, offset as i64],
+        |row| {
+          let date: String = row.get(0)?;
+          let files: String = row.get(1)?;
+          let status: String = row.get(2)?;
+          Ok((date, files, status))
+        },
+      )
+      .map_err(sql_error)?;
+
+    let mut recordings = Vec::new();
+    for row in rows {
+      let (date, files, status) = row.map_err(sql_error)?;
+      let time = match parse_date(&date) {
+        Some(time) => time,
+        None => continue,
+      };
+      recordings.push(Recording {
+        buoy_id: String::from(buoy_id),
+        files: RECORDING_EXTS
+          .iter()
+          .filter(|ext| files.split(',').any(|f| f == **ext))
+          .cloned()
+          .collect(),
+        date,
+        time,
+        status,
+      });
+    }
+    Ok((total as usize, recordings))
+  }
+
+  ///
+  /// The battery voltage and state of charge of each recording in the
+  /// range, oldest first.
+  ///
+  pub fn battery(
+    &self,
+    buoy_id: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+  ) -> Result<Vec<Battery>, GiftError> {
+    let (from, to) = date_range(from, to);
+    let mut stmt = self
+      .conn
+      .prepare(
+        "SELECT date, voltage, soc FROM recordings \
+         WHERE buoy_id = ?1 AND date >= ?2 AND date < ?3 \
+         AND (voltage IS NOT NULL OR soc IS NOT NULL) ORDER BY date",
+      )
+      .map_err(sql_error)?;
+    let rows = stmt
+      .query_map(params![buoy_id, from, to], |row| {
+        let date: String = row.get(0)?;
+        Ok((date, row.get(1)?, row.get(2)?))
+      })
+      .map_err(sql_error)?;
+
+    let mut points = Vec::new();
+    for row in rows {
+      let (date, voltage, soc) = row.map_err(sql_error)?;
+      if let Some(time) = parse_date(&date) {
+        points.push(Battery { time, voltage, soc });
+      }
+    }
+    Ok(points)
+  }
+}
+
+// The dates sort in time order, the range includes `from` but not `to`
+fn date_range(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> (String, String) {
+  (
+    from.map_or_else(String::new, |from| format_date(&from)),
+    to.map_or_else(|| String::from(MAX_DATE), |to| format_date(&to)),
+  )
+}
+
+fn upsert_buoy(
+  conn: &Connection,
+  buoy_id: &str,
+  received: &str,
+  what: &str,
+) -> Result<(), GiftError> {
+  // Keeping its heartbeat
+  conn
+    .execute(
+      "INSERT OR IGNORE INTO buoys (buoy_id, last_seen, last_seen_by) VALUES (?1, ?2, ?3)",
+      params![buoy_id, received, what],
+    )
+    .map_err(sql_error)?;
+  conn
+    .execute(
+      "UPDATE buoys SET last_seen = ?2, last_seen_by = ?3 WHERE buoy_id = ?1",
+      params![buoy_id, received, what],
+    )
+    .map_err(sql_error)?;
+  Ok(())
+}
+
+///
+/// Index the lines appended to `{buoy_id}.{kind}.csv` in `dir` since it was
+/// last indexed.  Only whole lines are, the last may still be being written.
+///
+fn sync_csv(conn: &Connection, dir: &Path, buoy_id: &str, kind: &str) -> Result<(), GiftError> {
+  let mut file = match File::open(dir.join(format!("{}.{}.csv", buoy_id, kind))) {
+    Ok(file) => file,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(GiftError::Io(e)),
+  };
+  let indexed: Option<(i64, i64)> = conn
+    .query_row(
+      "SELECT bytes, lines FROM csv_files WHERE buoy_id = ?1 AND kind = ?2",
+      params![buoy_id, kind],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .map_err(sql_error)?;
+  let (mut bytes, mut lines) = indexed.unwrap_or((0, 0));
+
+  file.seek(SeekFrom::Start(bytes as u64))?;
+  let mut appended = Vec::new();
+  file.read_to_end(&mut appended)?;
+  let end = match appended.iter().rposition(|b| *b == b'\n') {
+    Some(end) => end + 1,
+    None => return Ok(()),
+  };
+
+  for line in String::from_utf8_lossy(&appended[..end]).lines() {
+    if lines == 0 {
+      conn
+        .execute(
+          "INSERT OR REPLACE INTO csv_files (buoy_id, kind, header, bytes, lines) \
+           VALUES (?1, ?2, ?3, 0, 0)",
+          params![buoy_id, kind, line],
+        )
+        .map_err(sql_error)?;
+    } else {
+      let time = line.split(',').next().unwrap_or("");
+      conn
+        .execute(
+          "INSERT OR REPLACE INTO telemetry (buoy_id, kind, line, time, fields) \
+           VALUES (?1, ?2, ?3, ?4, ?5)",
+          params![buoy_id, kind, lines, time, line],
+        )
+        .map_err(sql_error)?;
+    }
+    lines += 1;
+  }
+  bytes += end as i64;
+  conn
+    .execute(
+      "UPDATE csv_files SET bytes = ?3, lines = ?4 WHERE buoy_id = ?1 AND kind = ?2",
+      params![buoy_id, kind, bytes, lines],
+    )
+    .map_err(sql_error)?;
+  Ok(())
+}
+
+fn insert_recording(
+  conn: &Connection,
+  recording: &Recording,
+  summary: &Summary,
+  error: Option<&str>,
+) -> Result<(), GiftError> {
+  conn
+    .execute(
+      "INSERT OR REPLACE INTO recordings (buoy_id, date, files, status, error, body_bytes, \
+       body_sha256, decode_errors, voltage, soc, latitude, longitude, indexed) \
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+      params![
+        recording.buoy_id,
+        recording.date,
+        recording.files.join(","),
+        recording.status,
+        error,
+        summary.body_bytes,
+        summary.body_sha256,
+        summary.decode_errors,
+        summary.voltage,
+        summary.soc,
+        summary.latitude,
+        summary.longitude,
+        date_now(),
+      ],
+    )
+    .map_err(sql_error)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::index::*;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("buoy-index-{}-{}", name, std::process::id()))
+  }
+
+  #[test]
+  fn test_find_recordings() {
+    let names: Vec<String> = [
+      "1.20200830T023517.000Z.json",
+      "1.20200830T023517.000Z.bin",
+      "1.20200830T023517.000Z.wav",
+      "1.20200830T013517.000Z.bin",
+      "1.heartbeat.json",
+      "1.track.csv",
+      "2.20200830T023517.000Z.bin",
+      "1.20200830T033517.000Z.tmp",
+      "1.20200830T043517.000Z.json.tmp",
+    ]
+    .iter()
+    .map(|name| String::from(*name))
+    .collect();
+    let recordings = find_recordings(&names);
+    assert_eq!(3, recordings.len());
+    assert_eq!("1", recordings[0].buoy_id);
+    assert_eq!("20200830T013517.000Z", recordings[0].date);
+    assert_eq!(vec!["bin"], recordings[0].files);
+    assert_eq!(STATUS_INCOMPLETE, recordings[0].status);
+    assert_eq!(vec!["bin", "wav", "json"], recordings[1].files);
+    assert_eq!(STATUS_SAVED, recordings[1].status);
+    assert_eq!("2", recordings[2].buoy_id);
+  }
+
+  #[test]
+  fn test_summary() {
+    let json = "{\"Battery-Voltage\": \"12.61\",\"GPS-Latitude\": -36.843292,\
+                \"GPS-Stale\": false,\"decode_errors\": \"2\",\"body_bytes\": 4096,\"buoy_id\": \"1\"}";
+    assert_eq!(Some("12.61"), legacy_field(json, "battery-voltage"));
+    assert_eq!(Some("-36.843292"), legacy_field(json, "GPS-Latitude"));
+    assert_eq!(Some("false"), legacy_field(json, "GPS-Stale"));
+    assert_eq!(None, legacy_field(json, "Battery-SoC"));
+
+    let summary = Summary::from_json(json);
+    assert_eq!(Some(12.61), summary.voltage);
+    assert_eq!(None, summary.soc);
+    assert_eq!(Some(-36.843292), summary.latitude);
+    assert_eq!(Some(2), summary.decode_errors);
+    assert_eq!(Some(4096), summary.body_bytes);
+    assert_eq!(None, summary.body_sha256);
+
+    let json = "{\"schema_version\": 2,\"buoy_id\": \"1\",\"date\": \"20200830T023517.000Z\",\
+                \"start_time\": \"2020-08-30T02:35:17.000Z\",\"start_time_source\": \"gnss\",\
+                \"received\": \"2020-08-30T02:36:00.000Z\",\"sw_version\": null,\"uptime_secs\": 60,\
+                \"power_state\": null,\"dropped_blocks\": 0,\
+                \"battery\": {\"voltage\": 12.5,\"soc\": 80.0,\"trend\": null},\
+                \"gps\": {\"latitude\": -36.8,\"longitude\": 174.7,\"h_accuracy\": null,\
+                \"fix_time\": null,\"time_source\": \"system\",\"fix_type\": \"fix3d\",\
+                \"satellites\": null,\"stale\": false},\
+                \"body_bytes\": 4096,\"body_sha256\": \"abc\",\"decode_errors\": 0,\"headers\": {}}";
+    let summary = Summary::from_json(json);
+    assert_eq!((Some(12.5), Some(80.0)), (summary.voltage, summary.soc));
+    assert_eq!(
+      (Some(-36.8), Some(174.7)),
+      (summary.latitude, summary.longitude)
+    );
+    assert_eq!(Some(String::from("abc")), summary.body_sha256);
+  }
+
+  #[test]
+  fn test_index() {
+    let dir = temp_dir("rebuild");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, contents: &str| fs::write(dir.join(name), contents).unwrap();
+    write("1.last_seen", "20200830T023600.000Z,upload\n");
+    write("1.heartbeat.json", "{\"buoy_id\":\"1\"}");
+    write(
+      "1.alerts.csv",
+      "received,alert\n20200830T023600.000Z,drift\n20200831T023600.000Z,light\n",
+    );
+    write("1.20200830T013517.000Z.bin", "x3");
+    write("1.20200830T023517.000Z.bin", "x3");
+    write(
+      "1.20200830T023517.000Z.json",
+      "{\"Battery-Voltage\": \"12.61\",\"Battery-SoC\": \"87.5\",\"buoy_id\": \"1\",\
+       \"body_sha256\": \"abc\"}",
+    );
+
+    let mut index = Index::open(&dir.join("index.sqlite")).unwrap();
+    assert!(index.stale);
+    assert_eq!((1, 2), index.rebuild(&dir).unwrap());
+    assert!(!Index::open(&dir.join("index.sqlite")).unwrap().stale);
+    assert_eq!(
+      vec![(
+        String::from("1"),
+        String::from("20200830T023600.000Z"),
+        String::from("upload")
+      )],
+      index.buoys().unwrap()
+    );
+    assert_eq!(None, index.last_seen("2").unwrap());
+    assert_eq!(
+      Some(String::from("{\"buoy_id\":\"1\"}")),
+      index.heartbeat("1").unwrap()
+    );
+    assert_eq!(
+      (
+        String::from("received,alert"),
+        vec![String::from("20200831T023600.000Z,light")]
+      ),
+      index
+        .telemetry("1", "alerts", parse_date("20200831T000000.000Z"), None)
+        .unwrap()
+    );
+    assert_eq!(
+      Some(String::from("20200831T023600.000Z,light")),
+      index.last_telemetry("1", "alerts").unwrap()
+    );
+
+    let (total, recordings) = index.recordings("1", None, None, 1, 10).unwrap();
+    assert_eq!(2, total);
+    assert_eq!(1, recordings.len());
+    assert_eq!("20200830T023517.000Z", recordings[0].date);
+    assert_eq!(vec!["bin", "json"], recordings[0].files);
+    assert_eq!(STATUS_SAVED, recordings[0].status);
+
+    assert_eq!(
+      Some(String::from("20200830T023517.000Z")),
+      index.saved_date("1", "abc").unwrap()
+    );
+    assert_eq!(None, index.saved_date("1", "def").unwrap());
+    assert_eq!(None, index.saved_date("2", "abc").unwrap());
+
+    let from = parse_date("20200830T020000.000Z");
+    let (total, recordings) = index.recordings("1", None, from, 0, 10).unwrap();
+    assert_eq!(
+      (1, STATUS_INCOMPLETE),
+      (total, recordings[0].status.as_str())
+    );
+    let battery = index.battery("1", from, None).unwrap();
+    assert_eq!(1, battery.len());
+    assert_eq!(
+      (Some(12.61), Some(87.5)),
+      (battery[0].voltage, battery[0].soc)
+    );
+
+    // A failed upload, and a new buoy, the last line is still being written
+    write("2.20200831T023517.000Z.bin", "x3");
+    write(
+      "2.alerts.csv",
+      "received,alert\n20200831T023600.000Z,drift\n20200831T033600",
+    );
+    index
+      .save_recording(&dir, "2", "20200831T023517.000Z", Some("X3SaveIssue"))
+      .unwrap();
+    let (_, lines) = index.telemetry("2", "alerts", None, None).unwrap();
+    assert_eq!(vec![String::from("20200831T023600.000Z,drift")], lines);
+    let mut file = fs::OpenOptions::new()
+      .append(true)
+      .open(dir.join("2.alerts.csv"))
+      .unwrap();
+    writeln!(file, ".000Z,light").unwrap();
+    for _ in 0..2 {
+      index
+        .save_recording(&dir, "2", "20200831T023517.000Z", Some("X3SaveIssue"))
+        .unwrap();
+    }
+    let (_, lines) = index.telemetry("2", "alerts", None, None).unwrap();
+    assert_eq!(
+      vec![
+        String::from("20200831T023600.000Z,drift"),
+        String::from("20200831T033600.000Z,light")
+      ],
+      lines
+    );
+    let (total, recordings) = index.recordings("2", None, None, 0, 10).unwrap();
+    assert_eq!(1, total);
+    assert_eq!(STATUS_FAILED, recordings[0].status);
+    assert_eq!(2, index.buoys().unwrap().len());
+    assert!(index.last_seen("2").unwrap().unwrap().ends_with(",upload"));
+    index.save_heartbeat("2", "{}").unwrap();
+    index
+      .save_last_seen("2", "20200831T040000.000Z", "heartbeat")
+      .unwrap();
+    assert_eq!(Some(String::from("{}")), index.heartbeat("2").unwrap());
+
+    // The files are the record, the failure is not in them
+    write("2.last_seen", "20200831T040000.000Z,heartbeat\n");
+    assert_eq!((2, 3), index.rebuild(&dir).unwrap());
+    let (_, recordings) = index.recordings("2", None, None, 0, 10).unwrap();
+    assert_eq!(STATUS_INCOMPLETE, recordings[0].status);
+    let (_, lines) = index.telemetry("2", "alerts", None, None).unwrap();
+    assert_eq!(2, lines.len());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
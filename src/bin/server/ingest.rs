@@ -0,0 +1,465 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Reads a request as it arrives on a stream, so the server's memory use does
+/// not depend on the size or number of the uploads.
+///
+/// The head is parsed as soon as the blank line after it arrives, and the
+/// request is rejected straight away if it's malformed, for an unknown path,
+/// or says it's bigger than `MAX_BODY_LEN`.  The body of a POST is written to
+/// a file in `data/incoming` and hashed on the way.  The last few bytes are
+/// held back, they must be the END_BOUNDARY, which is not part of the body.
+///
+/// `Ingest` does no IO on the stream, it's fed what arrives with `push()`,
+/// and `finish()` is called when the stream ends.
+///
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ring::digest;
+
+use buoy_code::commands::{
+  ServerError, ERR_BAD_REQUEST, ERR_INTERNAL, ERR_INVALID_BUOY_ID, ERR_NOT_IMPLEMENTED,
+  ERR_TOO_LARGE,
+};
+use buoy_code::END_BOUNDARY;
+
+use crate::heartbeat::notice_path;
+use crate::save_post::{path_to_buoy_id, SERVER_SAVE_PATH};
+
+pub const MAX_HEAD_LEN: usize = 16 * 1024; // The request line and headers
+pub const MAX_BODY_LEN: u64 = 50 * 1024 * 1024; // The hydrophone data
+const MAX_HEADERS: usize = 64;
+
+// Makes the names of the incoming files unique
+static INCOMING_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the bodies are written while they arrive.
+pub fn incoming_dir() -> PathBuf {
+  Path::new(SERVER_SAVE_PATH).join("incoming")
+}
+
+///
+/// Why a request was rejected, each is answered with an HTTP status and a
+/// JSON error body.
+///
+#[derive(Debug, PartialEq)]
+pub enum Reject {
+  BadRequest(&'static str), // Malformed, the reason is logged
+  Forbidden,                // Not a path we accept uploads on
+  HeadTooLarge,             // No end of the head in MAX_HEAD_LEN
+  BodyTooLarge,             // Over MAX_BODY_LEN
+  NotImplemented,           // Not a GET or POST
+  Failed(String),           // Reading the stream or writing the body failed
+}
+
+impl Reject {
+  /// The error sent back to the buoy, see commands.rs.
+  pub fn error(&self) -> ServerError {
+    let message = self.to_string();
+    match self {
+      Reject::BadRequest(_) => ServerError::new(400, ERR_BAD_REQUEST, &message),
+      Reject::Forbidden => ServerError::new(403, ERR_INVALID_BUOY_ID, &message),
+      Reject::HeadTooLarge => ServerError::new(431, ERR_TOO_LARGE, &message),
+      Reject::BodyTooLarge => ServerError::new(413, ERR_TOO_LARGE, &message),
+      Reject::NotImplemented => ServerError::new(501, ERR_NOT_IMPLEMENTED, &message),
+      // The reason is logged
+      Reject::Failed(_) => ServerError::new(500, ERR_INTERNAL, "the request could not be read"),
+    }
+  }
+}
+
+impl fmt::Display for Reject {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Reject::BadRequest(reason) => write!(f, "bad request: {}", reason),
+      Reject::Forbidden => f.write_str("not a path uploads are accepted on"),
+      Reject::HeadTooLarge => write!(f, "the head is over {} bytes", MAX_HEAD_LEN),
+      Reject::BodyTooLarge => write!(f, "the body is over {} bytes", MAX_BODY_LEN),
+      Reject::NotImplemented => f.write_str("only GET and POST are supported"),
+      Reject::Failed(reason) => write!(f, "failed: {}", reason),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+  Get,
+  Post,
+}
+
+impl fmt::Display for Method {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Method::Get => f.write_str("GET"),
+      Method::Post => f.write_str("POST"),
+    }
+  }
+}
+
+///
+/// The body of a request.  The file is removed when the body is dropped,
+/// unless it has been saved with `persist()`.
+///
+#[derive(Debug)]
+pub struct Body {
+  file: Option<PathBuf>, // None when the body is empty
+  pub len: u64,
+  pub sha256: String, // Hex
+}
+
+impl Body {
+  /// Move the body to `path`, an empty body creates an empty file.
+  pub fn persist(&mut self, path: &Path) -> std::io::Result<()> {
+    match self.file.take() {
+      Some(file) => fs::rename(file, path),
+      None => File::create(path).map(|_| ()),
+    }
+  }
+}
+
+impl Drop for Body {
+  fn drop(&mut self) {
+    if let Some(file) = &self.file {
+      let _ = fs::remove_file(file);
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Request {
+  pub method: Method,
+  pub path: String,
+  pub head: Vec<u8>, // The request line and headers, with the blank line
+  pub body: Body,
+}
+
+pub struct Ingest {
+  dir: PathBuf,                             // Where the body file is created
+  head: Vec<u8>,                            // The head, until it's complete
+  parsed: Option<(Method, String)>,         // Set once the head is complete
+  file: Option<(PathBuf, BufWriter<File>)>, // Created with the first body byte
+  tail: Vec<u8>,                            // Held back, it may be the END_BOUNDARY
+  len: u64,                                 // Body bytes written
+  digest: digest::Context,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack
+    .windows(needle.len())
+    .position(|window| window == needle)
+}
+
+impl Ingest {
+  pub fn new(dir: &Path) -> Self {
+    Ingest {
+      dir: PathBuf::from(dir),
+      head: Vec::new(),
+      parsed: None,
+      file: None,
+      tail: Vec::new(),
+      len: 0,
+      digest: digest::Context::new(&digest::SHA256),
+    }
+  }
+
+  /// The method, once the head has arrived.  A GET is complete then.
+  pub fn method(&self) -> Option<Method> {
+    self.parsed.as_ref().map(|(method, _)| *method)
+  }
+
+  /// Take the next part of the stream.
+  pub fn push(&mut self, data: &[u8]) -> Result<(), Reject> {
+    if self.parsed.is_some() {
+      return self.push_body(data);
+    }
+
+    // The blank line may be split over two parts
+    let from = self.head.len().saturating_sub(3);
+    self.head.extend_from_slice(data);
+    let end = match find(&self.head[from..], b"\r\n\r\n") {
+      Some(i) => from + i + 4,
+      None if self.head.len() > MAX_HEAD_LEN => return Err(Reject::HeadTooLarge),
+      None => return Ok(()),
+    };
+    if end > MAX_HEAD_LEN {
+      return Err(Reject::HeadTooLarge);
+    }
+
+    let rest = self.head.split_off(end);
+    self.parsed = Some(self.parse_head()?);
+    self.push_body(&rest)
+  }
+
+  fn parse_head(&self) -> Result<(Method, String), Reject> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(&self.head) {
+      Ok(httparse::Status::Complete(_)) => (),
+      Ok(httparse::Status::Partial) => return Err(Reject::BadRequest("incomplete head")),
+      Err(_) => return Err(Reject::BadRequest("malformed head")),
+    }
+
+    let method = match req.method {
+      Some("GET") => Method::Get,
+      Some("POST") => Method::Post,
+      _ => return Err(Reject::NotImplemented),
+    };
+    let path = req.path.ok_or(Reject::BadRequest("no path"))?;
+    if method == Method::Post && notice_path(path).is_none() && path_to_buoy_id(path).is_err() {
+      return Err(Reject::Forbidden);
+    }
+
+    // The buoy sends the hydrophone data length, reject it before it arrives
+    for header in req.headers.iter() {
+      if header.name.eq_ignore_ascii_case("length")
+        || header.name.eq_ignore_ascii_case("Content-Length")
+      {
+        let len = std::str::from_utf8(header.value)
+          .ok()
+          .and_then(|v| v.trim().parse::<u64>().ok())
+          .ok_or(Reject::BadRequest("invalid length"))?;
+        if len > MAX_BODY_LEN {
+          return Err(Reject::BodyTooLarge);
+        }
+      }
+    }
+
+    Ok((method, String::from(path)))
+  }
+
+  fn push_body(&mut self, data: &[u8]) -> Result<(), Reject> {
+    if let Some((Method::Get, _)) = self.parsed {
+      // Nothing is done with the body of a GET
+      self.len += data.len() as u64;
+      if self.len > MAX_HEAD_LEN as u64 {
+        return Err(Reject::BadRequest("GET with a body"));
+      }
+      return Ok(());
+    }
+
+    self.tail.extend_from_slice(data);
+    if self.tail.len() <= END_BOUNDARY.len() {
+      return Ok(());
+    }
+    let n = self.tail.len() - END_BOUNDARY.len();
+    if self.len + n as u64 > MAX_BODY_LEN {
+      return Err(Reject::BodyTooLarge);
+    }
+
+    if self.file.is_none() {
+      self.file = Some(
+        self
+          .create_file()
+          .map_err(|e| Reject::Failed(e.to_string()))?,
+      );
+    }
+    let (_, file) = self.file.as_mut().unwrap();
+    file
+      .write_all(&self.tail[..n])
+      .map_err(|e| Reject::Failed(e.to_string()))?;
+    self.digest.update(&self.tail[..n]);
+    self.len += n as u64;
+    self.tail.drain(..n);
+    Ok(())
+  }
+
+  fn create_file(&self) -> std::io::Result<(PathBuf, BufWriter<File>)> {
+    fs::create_dir_all(&self.dir)?;
+    let count = INCOMING_COUNT.fetch_add(1, Ordering::SeqCst);
+    let path = self
+      .dir
+      .join(format!("{}.{}.part", std::process::id(), count));
+    let file = File::create(&path)?;
+    Ok((path, BufWriter::new(file)))
+  }
+
+  /// The stream has ended, the request is complete.
+  pub fn finish(mut self) -> Result<Request, Reject> {
+    let (method, path) = match self.parsed.take() {
+      Some(parsed) => parsed,
+      None if self.head.is_empty() => return Err(Reject::BadRequest("empty request")),
+      None => return Err(Reject::BadRequest("incomplete head")),
+    };
+    if method == Method::Post && self.tail != END_BOUNDARY.as_bytes() {
+      return Err(Reject::BadRequest("missing END_BOUNDARY"));
+    }
+
+    let file = match self.file.take() {
+      Some((path, file)) => {
+        if let Err(e) = file.into_inner() {
+          let _ = fs::remove_file(&path);
+          return Err(Reject::Failed(e.to_string()));
+        }
+        Some(path)
+      }
+      None => None,
+    };
+    let sha256 = self
+      .digest
+      .clone()
+      .finish()
+      .as_ref()
+      .iter()
+      .map(|b| format!("{:02x}", b))
+      .collect();
+
+    Ok(Request {
+      method,
+      path,
+      head: std::mem::replace(&mut self.head, Vec::new()),
+      body: Body {
+        file,
+        len: self.len,
+        sha256,
+      },
+    })
+  }
+}
+
+impl Drop for Ingest {
+  fn drop(&mut self) {
+    // The request was rejected, or did not finish
+    if let Some((path, _)) = &self.file {
+      let _ = fs::remove_file(path);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::ingest::*;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("buoy-ingest-{}-{}", name, std::process::id()))
+  }
+
+  fn post(path: &str, body: &[u8]) -> Vec<u8> {
+    let mut request = format!(
+      "POST {} HTTP/1.1\r\nHost: {}\r\nlength: {}\r\n\r\n",
+      path,
+      path,
+      body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    request.extend_from_slice(END_BOUNDARY.as_bytes());
+    request
+  }
+
+  #[test]
+  fn test_streaming() {
+    let dir = temp_dir("streaming");
+    let body = b"0123456789".repeat(100);
+    let request = post("/id/1", &body);
+
+    // However the stream is split up
+    for size in &[1, 7, 4096] {
+      let mut ingest = Ingest::new(&dir);
+      for part in request.chunks(*size) {
+        ingest.push(part).unwrap();
+      }
+      let mut request = ingest.finish().unwrap();
+      assert_eq!(
+        (Method::Post, "/id/1"),
+        (request.method, request.path.as_str())
+      );
+      assert!(request.head.ends_with(b"\r\n\r\n"));
+      assert_eq!(1000, request.body.len);
+      assert_eq!(
+        "ab6c5f3237f551d208fc2ca5225a4cca20b3fd638794a804f0ed5549d5041734",
+        request.body.sha256
+      );
+
+      let saved = dir.join("saved.bin");
+      request.body.persist(&saved).unwrap();
+      assert_eq!(body, fs::read(&saved).unwrap());
+    }
+
+    // A heartbeat has no body, and no file
+    let mut ingest = Ingest::new(&dir);
+    ingest.push(&post("/id/1/heartbeat", b"")).unwrap();
+    assert_eq!(0, ingest.finish().unwrap().body.len);
+
+    let mut ingest = Ingest::new(&dir);
+    ingest
+      .push(b"GET /id/1/track.gpx HTTP/1.1\r\n\r\n")
+      .unwrap();
+    assert_eq!(Some(Method::Get), ingest.method());
+    assert_eq!(Method::Get, ingest.finish().unwrap().method);
+
+    fs::remove_file(dir.join("saved.bin")).unwrap();
+    assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+  }
+
+  #[test]
+  fn test_reject() {
+    let dir = temp_dir("reject");
+    let push = |request: &[u8]| {
+      let mut ingest = Ingest::new(&dir);
+      ingest
+        .push(request)
+        .and_then(|_| ingest.finish().map(|_| ()))
+    };
+
+    assert_eq!(Err(Reject::Forbidden), push(&post("/etc/passwd", b"")));
+    assert_eq!(403, Reject::Forbidden.error().status);
+    assert_eq!(413, Reject::BodyTooLarge.error().status);
+    assert_eq!(
+      Err(Reject::NotImplemented),
+      push(b"DELETE /id/1 HTTP/1.1\r\n\r\n")
+    );
+    assert_eq!(
+      Err(Reject::BadRequest("malformed head")),
+      push(b"POST /id/1 HTTP/1.1\r\nno colon\r\n\r\n")
+    );
+    assert_eq!(
+      Err(Reject::BadRequest("missing END_BOUNDARY")),
+      push(b"POST /id/1 HTTP/1.1\r\n\r\nsome data")
+    );
+    assert_eq!(
+      Err(Reject::BadRequest("incomplete head")),
+      push(b"POST /id/1 HTTP/1.1\r\n")
+    );
+
+    // Rejected as soon as the head arrives
+    let too_big = format!(
+      "POST /id/1 HTTP/1.1\r\nlength: {}\r\n\r\n",
+      MAX_BODY_LEN + 1
+    );
+    assert_eq!(Err(Reject::BodyTooLarge), push(too_big.as_bytes()));
+    let mut ingest = Ingest::new(&dir);
+    assert_eq!(
+      Err(Reject::HeadTooLarge),
+      ingest.push(&vec![b'a'; MAX_HEAD_LEN + 1])
+    );
+
+    // The body file is removed when a request does not finish
+    let mut ingest = Ingest::new(&dir);
+    ingest.push(&post("/id/1", &[0; 100])).unwrap();
+    assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+    drop(ingest);
+    assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+  }
+}
@@ -25,7 +25,10 @@ extern crate tokio;
 extern crate failure;
 extern crate buoy_code;
 extern crate futures;
+extern crate ring;
+extern crate rusqlite;
 extern crate rustls;
+extern crate serde_json;
 extern crate tokio_current_thread;
 
 #[macro_use]
@@ -34,21 +37,33 @@ extern crate env_logger;
 
 use std::thread;
 
-use std::ascii;
 use std::fmt;
 use std::fs;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::str;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use failure::{Fail, ResultExt};
-use futures::{Future, Stream};
+use futures::sync::oneshot;
+use futures::{future, Async, Future, Poll, Stream};
 use tokio::runtime::current_thread::Runtime;
 
 use failure::Error;
 
+pub mod api;
+pub mod files;
+pub mod heartbeat;
+pub mod index;
+pub mod ingest;
+pub mod metadata;
 pub mod save_post;
-use save_post::save_http_post;
+pub mod telemetry;
+pub mod track;
+pub mod web;
+use buoy_code::commands::{response_ok, ServerError, ERR_BUSY, ERR_INTERNAL};
+use ingest::{incoming_dir, Ingest, Method, Reject, Request};
+use save_post::{save_http_post, server_error};
+use track::TrackQuery;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -82,7 +97,69 @@ struct Opt {
   listen: SocketAddr,
 }
 
+///
+/// Print a buoy's track, see track.rs.
+///
+fn export_track(args: &[String]) -> Result<()> {
+  let usage = "usage: server track <buoy_id> [geojson|gpx] [--from TIME] [--to TIME]";
+  let mut query = TrackQuery {
+    buoy_id: args.get(0).ok_or_else(|| format_err!("{}", usage))?.clone(),
+    format: track::TrackFormat::GeoJson,
+    from: None,
+    to: None,
+  };
+
+  let mut args = args[1..].iter();
+  while let Some(arg) = args.next() {
+    let mut value = || {
+      args
+        .next()
+        .ok_or_else(|| format_err!("{}", usage))
+        .and_then(|v| track::parse_time(v).map_err(|e| format_err!("{}: {:?}", v, e)))
+    };
+    match arg.as_str() {
+      "--from" => query.from = Some(value()?),
+      "--to" => query.to = Some(value()?),
+      format => query.format = format.parse().map_err(|_| format_err!("{}", usage))?,
+    }
+  }
+
+  let track = query.export().map_err(|e| format_err!("{:?}", e))?;
+  println!("{}", track);
+  Ok(())
+}
+
 fn main() {
+  let args: Vec<String> = std::env::args().collect();
+  if args.len() > 1 && args[1] == "track" {
+    env_logger::init();
+    if let Err(e) = export_track(&args[2..]) {
+      eprintln!("ERROR: {}", e.pretty());
+      ::std::process::exit(1);
+    }
+    return;
+  }
+  if args.len() > 1 && args[1] == "status" {
+    match heartbeat::status() {
+      Ok(status) => print!("{}", status),
+      Err(e) => {
+        eprintln!("ERROR: {:?}", e);
+        ::std::process::exit(1);
+      }
+    }
+    return;
+  }
+  if args.len() > 1 && args[1] == "reindex" {
+    match index::reindex() {
+      Ok((buoys, recordings)) => println!("Indexed {} buoys and {} recordings", buoys, recordings),
+      Err(e) => {
+        eprintln!("ERROR: {:?}", e);
+        ::std::process::exit(1);
+      }
+    }
+    return;
+  }
+
   println!("Running");
   let opt = Opt {
     key_path: PathBuf::from(buoy_code::CA_SERVER_RSA_PATH),
@@ -128,11 +205,18 @@ fn run(options: Opt) -> Result<()> {
     (driver, incoming)
   };
 
+  // Left by a crash, the index is rebuilt without them
+  files::remove_tmp_files(Path::new(save_post::SERVER_SAVE_PATH))
+    .map_err(|e| format_err!("failed to remove the temporary files: {:?}", e))?;
+  index::ensure().map_err(|e| format_err!("failed to build the index: {:?}", e))?;
+
   let mut runtime = Runtime::new()?;
   runtime.spawn(incoming.for_each(move |conn| {
     handle_connection(conn);
     Ok(())
   }));
+  let web_listen = web::listen_addr().context("invalid SERVER_WEB_ADDR")?;
+  runtime.spawn(web::serve(&web_listen).context("failed to listen for the dashboard")?);
   runtime.block_on(endpoint_driver)?;
 
   Ok(())
@@ -179,45 +263,88 @@ fn handle_connection(conn: quinn::Connecting) {
   );
 }
 
-const MAX_STREAM_SIZE: usize = 50 * 1024 * 1024;
+///
+/// Feeds a request stream to `Ingest` as it arrives, see ingest.rs.  If the
+/// request is rejected the stream is dropped, which tells the buoy to stop
+/// sending.
+///
+struct ReadRequest {
+  recv: quinn::RecvStream,
+  ingest: Option<Ingest>,
+  buf: Vec<u8>,
+}
 
-fn handle_request(stream: quinn::NewStream) {
-  let (send, recv) = stream.unwrap_bi();
+const READ_BUF_LEN: usize = 64 * 1024;
 
-  tokio_current_thread::spawn(
-    recv
-      .read_to_end(MAX_STREAM_SIZE) // Read the request, which must be at most 64KiB
-      .map_err(|e| format_err!("failed reading request: {}", e))
-      .and_then(move |req| {
-        let mut escaped = String::new();
-        for &x in &req[..] {
-          let part = ascii::escape_default(x).collect::<Vec<_>>();
-          escaped.push_str(str::from_utf8(&part).unwrap());
-        }
-        info!("got request: content {}", &escaped[0..20]);
+impl ReadRequest {
+  fn new(recv: quinn::RecvStream) -> Self {
+    ReadRequest {
+      recv,
+      ingest: Some(Ingest::new(&incoming_dir())),
+      buf: vec![0; READ_BUF_LEN],
+    }
+  }
+}
 
-        let http_type = request_type(&req);
-        if http_type.is_err() {
-          error!("Invalid HTTP Type");
+impl Future for ReadRequest {
+  type Item = Request;
+  type Error = Reject;
+
+  fn poll(&mut self) -> Poll<Request, Reject> {
+    loop {
+      let ingest = self.ingest.as_mut().expect("polled after completion");
+      match self.recv.poll_read(&mut self.buf) {
+        Ok(Async::Ready(Some(n))) => ingest.push(&self.buf[..n])?,
+        Ok(Async::Ready(None)) => {
+          let ingest = self.ingest.take().unwrap();
+          return ingest.finish().map(Async::Ready);
         }
+        Ok(Async::NotReady) => return Ok(Async::NotReady),
+        Err(e) => return Err(Reject::Failed(format!("failed reading request: {}", e))),
+      }
+    }
+  }
+}
 
-        // Execute the request
-        let process = match http_type.unwrap() {
-          HTTPRequestType::POST => process_post,
-          _ => process_error,
+fn handle_request(stream: quinn::NewStream) {
+  let (send, recv) = stream.unwrap_bi();
+
+  tokio_current_thread::spawn(
+    ReadRequest::new(recv)
+      .then(move |request| {
+        let resp: Response = match request {
+          Ok(request) => {
+            info!(
+              "got request: {} {}, body {} bytes",
+              request.method, request.path, request.body.len
+            );
+
+            // Execute the request
+            match request.method {
+              Method::Post => process_post(request),
+              Method::Get => process_get(request),
+            }
+          }
+          Err(reject) => {
+            error!("rejected request: {}", reject);
+            respond(reject.error().response())
+          }
         };
-        let resp = process(req).unwrap_or_else(move |e| {
-          error!("failed to process request: reason: {}", e.pretty());
-          format!("failed to process request: {}\n", e.pretty())
-            .into_bytes()
-            .into()
-        });
-
-        // Write the response
-        tokio::io::write_all(send, resp).map_err(|e| format_err!("failed to send response: {}", e))
+
+        resp
+          .or_else(|e| -> Result<web::Response> {
+            error!("failed to process request: reason: {}", e.pretty());
+            let error = ServerError::new(500, ERR_INTERNAL, "the request could not be processed");
+            Ok(web::bytes(error.response().into_bytes()))
+          })
+          // Write the response
+          .and_then(move |resp: web::Response| {
+            web::write_response(send, resp)
+              .map_err(|e| format_err!("failed to send response: {}", e))
+          })
       })
       // Gracefully terminate the stream
-      .and_then(|(send, _)| {
+      .and_then(|send| {
         send
           .finish()
           .map_err(|e| format_err!("failed to shutdown stream: {}", e))
@@ -226,60 +353,76 @@ fn handle_request(stream: quinn::NewStream) {
   )
 }
 
-#[allow(dead_code)]
-enum HTTPRequestType {
-  GET,
-  HEAD,
-  POST,
-  PUT,
-  DELETE,
-  CONNECT,
-  OPTIONS,
-  TRACE,
-  PATCH,
+// A response that may not be ready yet
+type Response = Box<dyn Future<Item = web::Response, Error = Error>>;
+
+fn respond(resp: String) -> Response {
+  Box::new(future::ok::<_, Error>(web::bytes(resp.into_bytes())))
 }
 
-fn first_token(buf: &[u8]) -> &[u8] {
-  for (i, &item) in buf.iter().enumerate() {
-    if item == b' ' {
-      return &buf[0..=i];
-    }
-  }
+// The uploads being saved, each decodes the hydrophone data in its own thread
+static PROCESSING: AtomicUsize = AtomicUsize::new(0);
+const MAX_PROCESSING: usize = 4;
 
-  &buf
-}
+// Counts an upload while it's saved
+struct Processing;
 
-fn request_type(buf: &[u8]) -> Result<HTTPRequestType> {
-  match first_token(buf) {
-    b"GET " => Ok(HTTPRequestType::GET),
-    b"POST " => Ok(HTTPRequestType::POST),
-    _ => {
-      error!("'{}'", str::from_utf8(first_token(buf)).unwrap());
-      bail!("unhandled http request type");
+impl Processing {
+  fn start() -> Option<Self> {
+    if PROCESSING.fetch_add(1, Ordering::SeqCst) < MAX_PROCESSING {
+      Some(Processing)
+    } else {
+      PROCESSING.fetch_sub(1, Ordering::SeqCst);
+      None
     }
   }
 }
 
-fn process_post(buf: Vec<u8>) -> Result<Box<[u8]>> {
-  if buf.len() < 5 || &buf[0..5] != b"POST " {
-    bail!("missing POST");
-  }
-  if !buf.ends_with(buoy_code::END_BOUNDARY.as_bytes()) {
-    bail!("missing END_BOUNDARY");
+impl Drop for Processing {
+  fn drop(&mut self) {
+    PROCESSING.fetch_sub(1, Ordering::SeqCst);
   }
+}
+
+///
+/// Save the POST in a thread, the response waits for it so the buoy knows
+/// whether the upload was saved, see commands.rs.  When too many uploads are
+/// being saved the buoy is told to try again later.
+///
+fn process_post(request: Request) -> Response {
+  let processing = match Processing::start() {
+    Some(processing) => processing,
+    None => {
+      let error = ServerError::new(503, ERR_BUSY, "too many uploads are being processed");
+      return respond(error.response());
+    }
+  };
 
-  // All good.  Let's move the heavy processing to a thread
+  let (tx, rx) = oneshot::channel();
   thread::spawn(move || {
-    let payload_len = buf.len() - buoy_code::END_BOUNDARY.len();
-    let payload = &buf[0..payload_len];
-    save_http_post(payload).unwrap();
+    let _processing = processing;
+    // The receiver is gone if the stream was closed, there's no one to tell
+    let _ = tx.send(save_http_post(request));
   });
 
-  // TODO: Add specific FX30 command response here
-  Ok(Box::new(*b"HTTP/1.1 200 OK\r\n\r\n"))
+  Box::new(rx.then(|result| -> Result<web::Response> {
+    let resp = match result {
+      Ok(Ok(())) => response_ok(),
+      Ok(Err(e)) => {
+        let error = server_error(&e);
+        error!("process_post(): {}: {:?}", error, e);
+        error.response()
+      }
+      // The thread panicked
+      Err(_) => ServerError::new(500, ERR_INTERNAL, "saving the upload failed").response(),
+    };
+    Ok(web::bytes(resp.into_bytes()))
+  }))
 }
 
-fn process_error(_buf: Vec<u8>) -> Result<Box<[u8]>> {
-  error!("Unhandled request");
-  Ok(Box::new(*b"HTTP/1.1 501 Not Implemented\r\n\r\n"))
+///
+/// The GETs are the read-only API, see api.rs, answered off the runtime.
+///
+fn process_get(request: Request) -> Response {
+  Box::new(future::ok::<_, Error>(web::api_response(request.path)))
 }
@@ -0,0 +1,240 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The metadata saved with each upload, next to its recording:
+///
+///    data/{buoy_id}.{date}.json
+///
+/// It's written with serde_json and replaces the file in one step.  Numbers
+/// are numbers, times are RFC 3339, and the position is the `gps` object,
+/// null when the upload has none.  Every header is also kept as sent, in
+/// `headers` by its lower case name, with repeated headers joined by ", ".
+/// Files from before `schema_version` have every value as a string, see
+/// index.rs.
+///
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use buoy_code::clock::{parse_date, TimeSource, HEADER_START_TIME_SOURCE};
+use buoy_code::errors::GiftError;
+use buoy_code::position::{FixType, Position};
+use buoy_code::{HEADER_BATTERY_SOC, HEADER_BATTERY_TREND};
+
+use crate::files::replace_file;
+use crate::save_post::{header_value, SERVER_SAVE_PATH};
+use crate::track::rfc3339;
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Battery {
+  pub voltage: Option<f64>,
+  pub soc: Option<f64>,   // Percent
+  pub trend: Option<f64>, // Volts per hour, see buoy_code::power_state
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Gps {
+  pub latitude: f64,
+  pub longitude: f64,
+  pub h_accuracy: Option<f32>,  // Metres
+  pub fix_time: Option<String>, // RFC 3339
+  pub time_source: TimeSource,
+  pub fix_type: FixType,
+  pub satellites: Option<u32>,
+  pub stale: bool,
+}
+
+impl From<Position> for Gps {
+  fn from(position: Position) -> Gps {
+    Gps {
+      latitude: position.latitude,
+      longitude: position.longitude,
+      h_accuracy: position.h_accuracy,
+      fix_time: parse_date(&position.fix_time).map(|time| rfc3339(&time)),
+      time_source: position.time_source,
+      fix_type: position.fix_type,
+      satellites: position.satellites,
+      stale: position.stale,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Metadata {
+  pub schema_version: u32,
+  pub buoy_id: String,
+  pub date: String,               // Start time in the file names, see save_post.rs
+  pub start_time: Option<String>, // RFC 3339
+  pub start_time_source: Option<TimeSource>,
+  pub received: String, // RFC 3339
+  pub sw_version: Option<String>,
+  pub uptime_secs: Option<u64>,
+  pub power_state: Option<String>,
+  pub dropped_blocks: Option<u64>,
+  pub battery: Battery,
+  pub gps: Option<Gps>,
+  pub body_bytes: u64,
+  pub body_sha256: String,
+  pub decode_errors: usize,
+  pub headers: BTreeMap<String, String>,
+}
+
+///
+/// The value of a number header, None if it's missing or not a number.
+///
+fn number<T: FromStr>(req: &httparse::Request, name: &str) -> Option<T> {
+  let value = header_value(req, name)?;
+  match value.trim().parse() {
+    Ok(n) => Some(n),
+    Err(_) => {
+      warn!("Metadata: invalid {} '{}'", name, value);
+      None
+    }
+  }
+}
+
+impl Metadata {
+  ///
+  /// The metadata of an upload from `buoy_id`, saved under `date`, with
+  /// `decode_errors` from the X3 decoder and the size and SHA-256 of the
+  /// body.
+  ///
+  pub fn new(
+    req: &httparse::Request,
+    buoy_id: &str,
+    date: &str,
+    decode_errors: usize,
+    body_bytes: u64,
+    body_sha256: &str,
+  ) -> Metadata {
+    let gps = match Position::from_headers(|name| header_value(req, name)) {
+      Ok(position) => position.map(Gps::from),
+      Err(e) => {
+        warn!("Metadata: invalid position: {:?}", e);
+        None
+      }
+    };
+
+    let mut headers: BTreeMap<String, String> = BTreeMap::new();
+    for header in req.headers.iter() {
+      let value = String::from_utf8_lossy(header.value);
+      headers
+        .entry(header.name.to_ascii_lowercase())
+        .and_modify(|values| {
+          values.push_str(", ");
+          values.push_str(&value);
+        })
+        .or_insert_with(|| value.into_owned());
+    }
+
+    Metadata {
+      schema_version: SCHEMA_VERSION,
+      buoy_id: String::from(buoy_id),
+      date: String::from(date),
+      start_time: parse_date(date).map(|time| rfc3339(&time)),
+      start_time_source: header_value(req, HEADER_START_TIME_SOURCE)
+        .and_then(|source| source.trim().parse().ok()),
+      received: rfc3339(&Utc::now()),
+      sw_version: header_value(req, "sw-version").map(|v| String::from(v.trim())),
+      uptime_secs: number(req, "Uptime"),
+      power_state: header_value(req, "Power-State").map(|v| String::from(v.trim())),
+      dropped_blocks: number(req, "Dropped-Blocks"),
+      battery: Battery {
+        voltage: number(req, "Battery-Voltage"),
+        soc: number(req, HEADER_BATTERY_SOC),
+        trend: number(req, HEADER_BATTERY_TREND),
+      },
+      gps,
+      body_bytes,
+      body_sha256: String::from(body_sha256),
+      decode_errors,
+      headers,
+    }
+  }
+
+  pub fn from_json(json: &str) -> Result<Metadata, GiftError> {
+    serde_json::from_str(json).map_err(|e| GiftError::StdFailure(e.into()))
+  }
+
+  pub fn to_json(&self) -> Result<String, GiftError> {
+    serde_json::to_string_pretty(self).map_err(|e| GiftError::StdFailure(e.into()))
+  }
+
+  /// Write `data/{buoy_id}.{date}.json`.
+  pub fn save(&self) -> Result<(), GiftError> {
+    let filename = format!("{}/{}.{}.json", SERVER_SAVE_PATH, self.buoy_id, self.date);
+    replace_file(&filename, &self.to_json()?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::metadata::*;
+
+  #[test]
+  fn test_metadata() {
+    let head = b"POST /id/1 HTTP/1.1\r\n\
+                 Battery-Voltage: 12.61\r\n\
+                 Battery-SoC: 87\r\n\
+                 Power-State: normal\r\n\
+                 Uptime: not-a-number\r\n\
+                 Start-Time-Source: gnss\r\n\
+                 GPS-Latitude: -36.843292\r\n\
+                 GPS-Longitude: 174.756864\r\n\
+                 GPS-Fix-Time: 20200830T023500.000Z\r\n\
+                 GPS-Satellites: 7\r\n\
+                 sw-version: 0.5.32 \"beta\\1\"\r\n\
+                 X-Note: one\r\n\
+                 x-note: two\r\n\r\n";
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(head).unwrap();
+
+    let metadata = Metadata::new(&req, "1", "20200830T023517.000Z", 2, 4096, "abc");
+    assert_eq!(SCHEMA_VERSION, metadata.schema_version);
+    assert_eq!(
+      Some("2020-08-30T02:35:17.000Z"),
+      metadata.start_time.as_deref()
+    );
+    assert_eq!(Some(TimeSource::Gnss), metadata.start_time_source);
+    assert_eq!(Some(12.61), metadata.battery.voltage);
+    assert_eq!(Some(87.0), metadata.battery.soc);
+    assert_eq!(None, metadata.uptime_secs);
+    assert_eq!(Some("normal"), metadata.power_state.as_deref());
+    let gps = metadata.gps.as_ref().unwrap();
+    assert_eq!((-36.843292, 174.756864), (gps.latitude, gps.longitude));
+    assert_eq!(Some("2020-08-30T02:35:00.000Z"), gps.fix_time.as_deref());
+    assert_eq!(Some(7), gps.satellites);
+    assert_eq!("one, two", metadata.headers["x-note"]);
+
+    let json = metadata.to_json().unwrap();
+    assert!(json.contains("\"voltage\": 12.61"));
+    assert!(json.contains("\"sw-version\": \"0.5.32 \\\"beta\\\\1\\\"\""));
+    assert_eq!(1, json.matches("\"x-note\"").count());
+    assert_eq!(metadata, Metadata::from_json(&json).unwrap());
+
+    assert!(Metadata::from_json("{\"Battery-Voltage\": \"12.61\"}").is_err());
+  }
+}
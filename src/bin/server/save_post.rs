@@ -22,8 +22,9 @@ extern crate regex;
 extern crate sonogram;
 extern crate x3;
 
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::thread;
 use std::time::Duration;
@@ -31,24 +32,30 @@ use std::time::Duration;
 use regex::Regex;
 use sonogram::{blackman_harris, SpecOptionsBuilder};
 
+use buoy_code::clock::{
+  format_date, is_valid_date, parse_date, TimeSource, HEADER_START_TIME_SOURCE,
+};
+use buoy_code::commands::{
+  ServerError, ERR_BAD_REQUEST, ERR_INTERNAL, ERR_INVALID_BUOY_ID, ERR_STORAGE, ERR_X3_DECODE,
+};
 use buoy_code::date_now;
 use buoy_code::errors::GiftError;
 
-const MAX_HTTP_HEADER_LEN: usize = 1024;
-const SERVER_SAVE_PATH: &str = "data";
-
-fn find(haystack: &[u8], needle: &[u8]) -> Result<usize, GiftError> {
-  haystack
-    .windows(needle.len())
-    .position(|window| window == needle)
-    .ok_or(GiftError::HttpErrorOnFind)
-}
+use crate::heartbeat::{notice_path, save_heartbeat, save_last_seen, save_sleep_notice};
+use crate::index;
+use crate::ingest::{incoming_dir, Body, Request};
+use crate::metadata::Metadata;
+use crate::telemetry::{
+  save_drift_alert, save_light_status, save_link_stats, save_modem_status, save_power_transitions,
+  save_session,
+};
+use crate::track::save_track_point;
 
-fn to_str(buf: &[u8]) -> Result<&str, GiftError> {
-  str::from_utf8(buf).map_err(GiftError::Str)
-}
+const MAX_HTTP_HEADER_LEN: usize = 1024;
+const ENOSPC: i32 = 28; // No space left on device
+pub const SERVER_SAVE_PATH: &str = "data";
 
-fn path_to_buoy_id(path: &str) -> Result<&str, GiftError> {
+pub fn path_to_buoy_id(path: &str) -> Result<&str, GiftError> {
   lazy_static! {
     static ref RE: Regex = Regex::new(r"^/id/[0-9a-fA-F\-]{1,40}$").unwrap();
   }
@@ -59,59 +66,10 @@ fn path_to_buoy_id(path: &str) -> Result<&str, GiftError> {
   }
 }
 
-fn json_start(mut file: &File) -> Result<(), GiftError> {
-  write!(file, "{{").map_err(GiftError::Io)
-}
-
-fn json_out(mut file: &File, property: &str, value: &str) -> Result<(), GiftError> {
-  write!(file, "\"{}\": \"{}\"", property, value).map_err(GiftError::Io)
-}
-
-fn json_sep(mut file: &File) -> Result<(), GiftError> {
-  write!(file, ",").map_err(GiftError::Io)
-}
-
-fn json_end(mut file: &File) -> Result<(), GiftError> {
-  write!(file, "}}").map_err(GiftError::Io)
-}
-
-fn write_headers_to_file(
-  req: &httparse::Request,
-  buoy_id: &str,
-  date: &str,
-  num_errors: usize,
-) -> Result<(), GiftError> {
-  let filename = format!("{}/{}.{}.json", SERVER_SAVE_PATH, buoy_id, date);
-  let meta_file = File::create(filename).map_err(GiftError::Io)?;
-  json_start(&meta_file)?;
-
-  for header in req.headers.iter() {
-    // print!("{}:{}; ", header.name, to_str(header.value)?);
-    json_out(&meta_file, header.name, to_str(header.value)?)?;
-    json_sep(&meta_file)?;
-  }
-  // print out the number of errors
-  json_out(&meta_file, "decode_errors", &format!("{}", num_errors))?;
-  json_sep(&meta_file)?;
-
-  // println!();
-
-  json_out(&meta_file, "buoy_id", buoy_id)?;
-  json_end(&meta_file)?;
-
-  Ok(())
-}
-
-// Write the data to an .x3 file
-fn write_raw_data_to_file(buf: &[u8], buoy_id: &str, date: &str) -> Result<(), GiftError> {
+// Move the body, which has already been written to data/incoming, to an .x3 file
+fn write_raw_data_to_file(body: &mut Body, buoy_id: &str, date: &str) -> Result<(), GiftError> {
   let filename = format!("{}/{}.{}.bin", SERVER_SAVE_PATH, buoy_id, date);
-  let mut meta_file = File::create(filename).map_err(GiftError::Io)?;
-
-  // Find the body
-  let idx = find(buf, buoy_code::HTTP_HEADER_END.as_bytes())?;
-  meta_file
-    .write_all(&buf[(idx + buoy_code::HTTP_HEADER_END.len())..])
-    .map_err(GiftError::Io)
+  body.persist(Path::new(&filename)).map_err(GiftError::Io)
 }
 
 // Convert the .bin file that has already been saved to a .wav file
@@ -129,27 +87,75 @@ fn write_wav_data_to_file(buoy_id: &str, date: &str) -> Result<usize, GiftError>
 }
 
 ///
-/// Get the date from the header, if it's not found, use the current date/time
+/// Get the value of a header, if it exists and is valid UTF-8.
+///
+pub fn header_value<'a>(req: &httparse::Request<'_, 'a>, name: &str) -> Option<&'a str> {
+  req
+    .headers
+    .iter()
+    .find(|h| h.name.eq_ignore_ascii_case(name))
+    .and_then(|h| str::from_utf8(h.value).ok())
+}
+
 ///
-fn date_from_header(req: &httparse::Request) -> String {
-  for header in req.headers.iter() {
-    if header.name == "Start-Time" {
-      let date = to_str(header.value);
-      if date.is_err() {
-        // There was an error
-        error!("date_from_header(): Error parsing date");
-        break;
-      }
-      let date = date.unwrap();
-      if &date[..4] == "1970" {
-        // The FX30 has recently booted and we need to wait till it's got the Unix time
-        break;
-      }
-      return String::from(date);
+/// Get the date from the header, None if it's not found or not valid, or the
+/// buoy's clock was not set.  The date is part of the file names, so it's
+/// written back in the one format.
+///
+fn date_from_header(req: &httparse::Request) -> Option<String> {
+  let source = header_value(req, HEADER_START_TIME_SOURCE).and_then(|s| s.trim().parse().ok());
+  if source == Some(TimeSource::Unsynced) {
+    return None;
+  }
+
+  let date = header_value(req, "Start-Time")?.trim();
+  match parse_date(date).filter(is_valid_date) {
+    Some(date) => Some(format_date(&date)),
+    None => {
+      // E.g. the FX30 has recently booted and doesn't have the Unix time yet
+      warn!("date_from_header(): invalid Start-Time '{}'", date);
+      None
     }
   }
+}
 
-  date_now()
+fn unsynced_date_path(dir: &Path, buoy_id: &str, sha256: &str) -> PathBuf {
+  dir.join(format!("{}.{}.date", buoy_id, sha256))
+}
+
+///
+/// The date an upload without a start time is saved under, the time it was
+/// first received.  The buoy sends the upload again if saving it failed, so
+/// the date is kept in `dir/{buoy_id}.{sha256}.date` until it's saved, and
+/// `saved` looks up the date of an upload that was saved before.
+///
+fn unsynced_date<F>(dir: &Path, buoy_id: &str, sha256: &str, saved: F) -> Result<String, GiftError>
+where
+  F: FnOnce() -> Result<Option<String>, GiftError>,
+{
+  let path = unsynced_date_path(dir, buoy_id, sha256);
+  if let Ok(date) = fs::read_to_string(&path) {
+    if parse_date(date.trim()).is_some() {
+      return Ok(String::from(date.trim()));
+    }
+  }
+  if let Some(date) = saved()? {
+    return Ok(date);
+  }
+
+  let date = date_now();
+  fs::create_dir_all(dir)?;
+  let mut file = File::create(&path)?;
+  writeln!(file, "{}", date)?;
+  file.sync_data()?;
+  Ok(date)
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), GiftError> {
+  match fs::remove_file(path) {
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    result => Ok(result?),
+  }
 }
 
 pub fn save_spectrogram_png(buoy_id: &str, date: &str) -> Result<(), GiftError> {
@@ -169,35 +175,239 @@ pub fn save_spectrogram_png(buoy_id: &str, date: &str) -> Result<(), GiftError>
   Ok(())
 }
 
-pub fn save_http_post(buf: &[u8]) -> Result<(), GiftError> {
+///
+/// The error sent back to the buoy when saving a POST failed, see
+/// commands.rs.  A full disk is worth retrying later, the rest is not.  The
+/// message doesn't give away the server's internals, the error is logged.
+///
+pub fn server_error(e: &GiftError) -> ServerError {
+  match e {
+    GiftError::HttpInvalidPath => {
+      ServerError::new(403, ERR_INVALID_BUOY_ID, "the buoy id is not valid")
+    }
+    GiftError::Parse(_)
+    | GiftError::Str(_)
+    | GiftError::HttpInvalidRequest
+    | GiftError::HttpErrorOnFind => {
+      ServerError::new(400, ERR_BAD_REQUEST, "the request could not be read")
+    }
+    GiftError::X3SaveIssue | GiftError::Sonogram(_) => {
+      ServerError::new(422, ERR_X3_DECODE, "the x3 data could not be decoded")
+    }
+    GiftError::Io(io) if io.raw_os_error() == Some(ENOSPC) => {
+      ServerError::new(503, ERR_STORAGE, "the server is out of space")
+    }
+    GiftError::Io(_) => ServerError::new(500, ERR_STORAGE, "the upload could not be saved"),
+    _ => ServerError::new(500, ERR_INTERNAL, "the upload could not be processed"),
+  }
+}
+
+///
+/// The steps of saving an upload that are done.  The buoy sends an upload
+/// again if saving it failed, and the telemetry must not be appended to the
+/// CSV files twice.  They are kept in `data/incoming/{buoy_id}.{date}.steps`
+/// until the upload is saved.
+///
+struct Steps {
+  path: PathBuf,
+  done: Vec<String>,
+}
+
+impl Steps {
+  fn load(buoy_id: &str, date: &str) -> Steps {
+    let path = incoming_dir().join(format!("{}.{}.steps", buoy_id, date));
+    let done = fs::read_to_string(&path)
+      .map(|steps| steps.lines().map(String::from).collect())
+      .unwrap_or_default();
+    Steps { path, done }
+  }
+
+  /// Run `step`, unless it was done when the upload was sent before.
+  fn run<F>(&mut self, name: &str, step: F) -> Result<(), GiftError>
+  where
+    F: FnOnce() -> Result<(), GiftError>,
+  {
+    if self.done.iter().any(|done| done == name) {
+      info!(
+        "Steps::run(): {} was already done for {:?}",
+        name, self.path
+      );
+      return Ok(());
+    }
+    step()?;
+
+    fs::create_dir_all(incoming_dir())?;
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)?;
+    writeln!(file, "{}", name)?;
+    file.sync_data()?;
+    self.done.push(String::from(name));
+    Ok(())
+  }
+
+  /// The upload is saved.
+  fn finish(self) -> Result<(), GiftError> {
+    remove_if_exists(&self.path)
+  }
+}
+
+///
+/// Whether the upload was saved before, and the response didn't get back to
+/// the buoy.
+///
+fn already_saved(body: &Body, buoy_id: &str, date: &str) -> bool {
+  let filename = format!("{}/{}.{}.json", SERVER_SAVE_PATH, buoy_id, date);
+  fs::read_to_string(&filename)
+    .ok()
+    .and_then(|json| Metadata::from_json(&json).ok())
+    .map_or(false, |metadata| {
+      metadata.body_sha256 == body.sha256 && metadata.body_bytes == body.len
+    })
+}
+
+///
+/// Save the recording and telemetry of an upload under `date`.
+///
+fn save_upload(
+  req: &httparse::Request,
+  body: &mut Body,
+  buoy_id: &str,
+  date: &str,
+) -> Result<(), GiftError> {
+  if already_saved(body, buoy_id, date) {
+    info!("save_upload(): {}.{} was already saved", buoy_id, date);
+    return Ok(());
+  }
+  write_raw_data_to_file(body, buoy_id, date)?;
+
+  let num_errors;
+  if body.len > (buoy_code::MIN_X3_FILE_SIZE * 2) as u64 {
+    num_errors = write_wav_data_to_file(buoy_id, date)?;
+
+    // Sleep a bit, because we need to read the wav file
+    thread::sleep(Duration::from_millis(500));
+    save_spectrogram_png(buoy_id, date)?;
+  } else {
+    num_errors = 0;
+  }
+
+  let mut steps = Steps::load(buoy_id, date);
+  steps.run("link_stats", || save_link_stats(req, buoy_id, date))?;
+  steps.run("modem", || save_modem_status(req, buoy_id, date))?;
+  steps.run("session", || save_session(req, buoy_id, date))?;
+  steps.run("power", || save_power_transitions(req, buoy_id))?;
+  steps.run("drift_alert", || save_drift_alert(req, buoy_id))?;
+  steps.run("light", || save_light_status(req, buoy_id, date))?;
+  steps.run("track", || save_track_point(req, buoy_id))?;
+
+  // Needs to happen last, we will trigger changes
+  Metadata::new(req, buoy_id, date, num_errors, body.len, &body.sha256).save()?;
+
+  steps.finish()
+}
+
+///
+/// Save a POST that has been read by `Ingest`.
+///
+pub fn save_http_post(mut request: Request) -> Result<(), GiftError> {
   let mut headers = [httparse::EMPTY_HEADER; MAX_HTTP_HEADER_LEN];
   let mut req = httparse::Request::new(&mut headers);
 
-  let parsed_req = req.parse(buf).map_err(GiftError::Parse)?;
+  let parsed_req = req.parse(&request.head).map_err(GiftError::Parse)?;
 
   if parsed_req.is_complete() {
     let http_path = req.path.ok_or(GiftError::HttpInvalidPath)?;
+    match notice_path(http_path) {
+      Some((buoy_id, "sleep")) => return save_sleep_notice(&req, buoy_id),
+      Some((buoy_id, _)) => return save_heartbeat(&req, buoy_id),
+      None => (),
+    }
     let buoy_id = path_to_buoy_id(http_path)?;
-    let dt_str = date_from_header(&req);
+    save_last_seen(buoy_id, "upload")?;
+    let sha256 = request.body.sha256.clone();
+    let dt_str = match date_from_header(&req) {
+      Some(date) => date,
+      None => unsynced_date(&incoming_dir(), buoy_id, &sha256, || {
+        index::with(|index| index.saved_date(buoy_id, &sha256))
+      })?,
+    };
+
+    let saved = save_upload(&req, &mut request.body, buoy_id, &dt_str);
+    let error = saved.as_ref().err().map(|e| format!("{:?}", e));
+    // The upload isn't saved until it's indexed, the buoy sends it again
+    let indexed = index::with(|index| {
+      let dir = Path::new(SERVER_SAVE_PATH);
+      index.save_recording(dir, buoy_id, &dt_str, error.as_ref().map(String::as_str))
+    });
+    if let (Err(_), Err(e)) = (&saved, &indexed) {
+      error!(
+        "save_http_post(): {}.{} not indexed: {:?}",
+        buoy_id, dt_str, e
+      );
+    }
+    saved.and(indexed)?;
+    remove_if_exists(&unsynced_date_path(&incoming_dir(), buoy_id, &sha256))
+  } else {
+    Err(GiftError::HttpInvalidRequest)
+  }
+}
 
-    write_raw_data_to_file(buf, buoy_id, &dt_str)?;
+#[cfg(test)]
+mod tests {
+  use crate::save_post::*;
 
-    let num_errors;
-    if buf.len() > buoy_code::MIN_X3_FILE_SIZE * 2 {
-      num_errors = write_wav_data_to_file(buoy_id, &dt_str)?;
+  fn date_of(head: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 8];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(head).unwrap();
+    date_from_header(&req)
+  }
 
-      // Sleep a bit, because we need to read the wav file
-      thread::sleep(Duration::from_millis(500));
-      save_spectrogram_png(buoy_id, &dt_str)?;
-    } else {
-      num_errors = 0;
+  #[test]
+  fn test_date_from_header() {
+    let date = date_of(b"POST /id/1 HTTP/1.1\r\nStart-Time: 20200830T023517.250Z\r\n\r\n");
+    assert_eq!(Some(String::from("20200830T023517.250Z")), date);
+
+    // The time it was received is used instead
+    for head in &[
+      &b"POST /id/1 HTTP/1.1\r\n\r\n"[..],
+      b"POST /id/1 HTTP/1.1\r\nStart-Time: 19700101T000142.000Z\r\n\r\n",
+      b"POST /id/1 HTTP/1.1\r\nStart-Time: 197\r\n\r\n",
+      b"POST /id/1 HTTP/1.1\r\nStart-Time: \xc3\xa9\xc3\xa9\r\n\r\n",
+      b"POST /id/1 HTTP/1.1\r\nStart-Time: ../../x\r\n\r\n",
+      b"POST /id/1 HTTP/1.1\r\nStart-Time: 20200830T023517.250Z/../../x\r\n\r\n",
+      b"POST /id/1 HTTP/1.1\r\nStart-Time: 20200830T023517.250Z\r\n\
+        Start-Time-Source: unsynced\r\n\r\n",
+    ] {
+      assert_eq!(None, date_of(head));
     }
+  }
 
-    // Needs to happen last, we will trigger changes
-    write_headers_to_file(&req, buoy_id, &dt_str, num_errors)?;
-
-    Ok(())
-  } else {
-    Err(GiftError::HttpInvalidRequest)
+  #[test]
+  fn test_unsynced_date() {
+    let dir = std::env::temp_dir().join(format!("buoy-unsynced-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let not_saved = || Ok(None);
+
+    // Sent again after saving it failed
+    let date = unsynced_date(&dir, "1", "abc", not_saved).unwrap();
+    assert!(parse_date(&date).map_or(false, |d| is_valid_date(&d)));
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(date, unsynced_date(&dir, "1", "abc", not_saved).unwrap());
+    assert_ne!(date, unsynced_date(&dir, "1", "def", not_saved).unwrap());
+    assert_ne!(date, unsynced_date(&dir, "2", "abc", not_saved).unwrap());
+
+    // Sent again after it was saved, and the response was lost
+    remove_if_exists(&unsynced_date_path(&dir, "1", "abc")).unwrap();
+    let saved = || Ok(Some(String::from("20200830T023517.250Z")));
+    assert_eq!(
+      "20200830T023517.250Z",
+      unsynced_date(&dir, "1", "abc", saved).unwrap()
+    );
+    assert!(!unsynced_date_path(&dir, "1", "abc").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
   }
 }
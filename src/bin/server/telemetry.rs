@@ -0,0 +1,417 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Telemetry sent by the buoys in the upload headers.  Each type of telemetry
+/// is appended to a per buoy CSV file in the data directory, so it can be
+/// correlated with the time of day and the buoy position.
+///
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::Path;
+
+use buoy_code::date_now;
+use buoy_code::drift_alert::{DriftAlert, HEADER_DRIFT_ALERT};
+use buoy_code::errors::GiftError;
+use buoy_code::light_status::{LightStatus, HEADER_LIGHT_STATUS};
+use buoy_code::link_stats::{LinkStats, HEADER_LINK_STATS};
+use buoy_code::modem::{ModemStatus, HEADER_MODEM};
+use buoy_code::position::Position;
+use buoy_code::power_state::{decode_transitions, HEADER_POWER_TRANSITIONS};
+use buoy_code::session::{Session, SessionEnd, HEADER_SESSION};
+
+use crate::save_post::{header_value, SERVER_SAVE_PATH};
+
+const LINK_STATS_CSV_HEADER: &str = "start_time,received,latitude,longitude,uploads,failures,\
+                                     bytes,handshake_ms,rtt_ms,kbps,failures_by_cause,\
+                                     close_codes,retransmits";
+
+const POWER_CSV_HEADER: &str = "time,received,from,to,voltage,latitude,longitude";
+
+const LIGHT_CSV_HEADER: &str = "start_time,received,latitude,longitude,active,flashes,\
+                                write_failures,readback_failures,current_failures,fault";
+
+const MODEM_CSV_HEADER: &str = "start_time,received,latitude,longitude,rat,registration,\
+                                rssi_dbm,rsrp_dbm,rsrq_db,cell_id,operator,data_connected";
+
+const SESSIONS_CSV_HEADER: &str = "start_time,received,boots,previous_end,uploads,failures,\
+                                   bytes,last_command,fix_latitude,fix_longitude,fix_time";
+
+const ALERTS_CSV_HEADER: &str =
+  "received,alert,latitude,longitude,distance_m,radius_m,fixes_outside";
+
+///
+/// Quote a CSV field if required.
+///
+fn csv_field(value: &str) -> String {
+  if value.contains(',') || value.contains('"') || value.contains('\n') {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    String::from(value)
+  }
+}
+
+fn csv_opt(value: Option<f32>) -> String {
+  value.map_or_else(String::new, |v| format!("{:0.1}", v))
+}
+
+fn csv_counts(counts: &std::collections::BTreeMap<String, u32>) -> String {
+  counts
+    .iter()
+    .map(|(k, v)| format!("{}:{}", k, v))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+///
+/// The latitude and longitude fields, empty if the upload has no position.
+///
+fn csv_position(req: &httparse::Request) -> String {
+  match Position::from_headers(|name| header_value(req, name)) {
+    Ok(Some(pos)) => format!("{:.6},{:.6}", pos.latitude, pos.longitude),
+    Ok(None) => String::from(","),
+    Err(e) => {
+      error!("csv_position(): invalid position: {:?}", e);
+      String::from(",")
+    }
+  }
+}
+
+///
+/// Append a line to a CSV file, the CSV header is written if the file is new.
+///
+pub fn append_csv(filename: &str, csv_header: &str, line: &str) -> Result<(), GiftError> {
+  let is_new = !Path::new(filename).exists();
+  let mut file = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(filename)
+    .map_err(GiftError::Io)?;
+
+  if is_new {
+    writeln!(file, "{}", csv_header).map_err(GiftError::Io)?;
+  }
+  writeln!(file, "{}", line).map_err(GiftError::Io)
+}
+
+///
+/// Save the link statistics to `data/{buoy_id}.link.csv`, if the upload had
+/// any.
+///
+pub fn save_link_stats(
+  req: &httparse::Request,
+  buoy_id: &str,
+  date: &str,
+) -> Result<(), GiftError> {
+  let value = match header_value(req, HEADER_LINK_STATS) {
+    Some(v) => v,
+    None => return Ok(()),
+  };
+
+  let stats = match LinkStats::decode(value) {
+    Ok(stats) => stats,
+    Err(e) => {
+      // Don't lose the recording because of bad statistics
+      error!("save_link_stats(): invalid Link-Stats '{}': {:?}", value, e);
+      return Ok(());
+    }
+  };
+
+  let line = [
+    csv_field(date),
+    date_now(),
+    csv_position(req),
+    stats.uploads.to_string(),
+    stats.failures.to_string(),
+    stats.bytes.to_string(),
+    csv_opt(stats.avg_handshake_ms()),
+    csv_opt(stats.avg_rtt_ms()),
+    csv_opt(stats.avg_kbps()),
+    csv_counts(&stats.failures_by_cause),
+    csv_counts(&stats.close_codes),
+    stats.retransmits.to_string(),
+  ]
+  .join(",");
+
+  let filename = format!("{}/{}.link.csv", SERVER_SAVE_PATH, buoy_id);
+  append_csv(&filename, LINK_STATS_CSV_HEADER, &line)
+}
+
+///
+/// Save the power state transitions to `data/{buoy_id}.power.csv`, if the
+/// upload had any.
+///
+pub fn save_power_transitions(req: &httparse::Request, buoy_id: &str) -> Result<(), GiftError> {
+  let value = match header_value(req, HEADER_POWER_TRANSITIONS) {
+    Some(v) => v,
+    None => return Ok(()),
+  };
+
+  let transitions = match decode_transitions(value) {
+    Ok(transitions) => transitions,
+    Err(e) => {
+      error!(
+        "save_power_transitions(): invalid Power-Transitions '{}': {:?}",
+        value, e
+      );
+      return Ok(());
+    }
+  };
+
+  let filename = format!("{}/{}.power.csv", SERVER_SAVE_PATH, buoy_id);
+  let position = csv_position(req);
+  for t in transitions {
+    info!(
+      "Buoy {}: power state {} -> {} at {:.2} V",
+      buoy_id, t.from, t.to, t.voltage
+    );
+    let line = [
+      csv_field(&t.time),
+      date_now(),
+      t.from.to_string(),
+      t.to.to_string(),
+      format!("{:.2}", t.voltage),
+      position.clone(),
+    ]
+    .join(",");
+    append_csv(&filename, POWER_CSV_HEADER, &line)?;
+  }
+
+  Ok(())
+}
+
+///
+/// Raise a drift alert if the buoy sent one, it's logged and saved to
+/// `data/{buoy_id}.alerts.csv`.
+///
+pub fn save_drift_alert(req: &httparse::Request, buoy_id: &str) -> Result<(), GiftError> {
+  let value = match header_value(req, HEADER_DRIFT_ALERT) {
+    Some(v) => v,
+    None => return Ok(()),
+  };
+
+  let alert = match DriftAlert::decode(value) {
+    Ok(alert) => alert,
+    Err(e) => {
+      error!(
+        "save_drift_alert(): invalid Drift-Alert '{}': {:?}",
+        value, e
+      );
+      return Ok(());
+    }
+  };
+
+  let position = csv_position(req);
+  error!(
+    "DRIFT ALERT: buoy {} is {:.0} m from its deployment position (watch circle {:.0} m), at {}",
+    buoy_id, alert.distance_m, alert.radius_m, position
+  );
+
+  let line = [
+    date_now(),
+    String::from("drift"),
+    position,
+    format!("{:.1}", alert.distance_m),
+    format!("{:.1}", alert.radius_m),
+    alert.fixes_outside.to_string(),
+  ]
+  .join(",");
+
+  let filename = format!("{}/{}.alerts.csv", SERVER_SAVE_PATH, buoy_id);
+  append_csv(&filename, ALERTS_CSV_HEADER, &line)
+}
+
+///
+/// The last field of the last line of a CSV file, if there is one.
+///
+fn last_csv_field(filename: &str) -> Option<String> {
+  let contents = fs::read_to_string(filename).ok()?;
+  let line = contents.lines().last()?;
+  line.rsplit(',').next().map(String::from)
+}
+
+///
+/// Save the navigation light status to `data/{buoy_id}.light.csv`.  A new
+/// light fault, one the previous upload did not have, is logged and saved to
+/// `data/{buoy_id}.alerts.csv`.
+///
+pub fn save_light_status(
+  req: &httparse::Request,
+  buoy_id: &str,
+  date: &str,
+) -> Result<(), GiftError> {
+  let value = match header_value(req, HEADER_LIGHT_STATUS) {
+    Some(v) => v,
+    None => return Ok(()),
+  };
+
+  let status = match LightStatus::decode(value) {
+    Ok(status) => status,
+    Err(e) => {
+      error!(
+        "save_light_status(): invalid Light-Status '{}': {:?}",
+        value, e
+      );
+      return Ok(());
+    }
+  };
+
+  let filename = format!("{}/{}.light.csv", SERVER_SAVE_PATH, buoy_id);
+  let was_faulty = last_csv_field(&filename).map_or(false, |f| !f.is_empty() && f != "fault");
+  let fault = status.fault.map_or_else(String::new, |f| f.to_string());
+  let position = csv_position(req);
+
+  let line = [
+    csv_field(date),
+    date_now(),
+    position.clone(),
+    status.active.to_string(),
+    status.flashes.to_string(),
+    status.write_failures.to_string(),
+    status.readback_failures.to_string(),
+    status.current_failures.to_string(),
+    fault.clone(),
+  ]
+  .join(",");
+  append_csv(&filename, LIGHT_CSV_HEADER, &line)?;
+
+  if fault.is_empty() || was_faulty {
+    return Ok(());
+  }
+
+  error!(
+    "LIGHT FAULT: buoy {} navigation light fault '{}', at {}",
+    buoy_id, fault, position
+  );
+  let line = [
+    date_now(),
+    format!("light:{}", fault),
+    position,
+    String::new(),
+    String::new(),
+    String::new(),
+  ]
+  .join(",");
+
+  let filename = format!("{}/{}.alerts.csv", SERVER_SAVE_PATH, buoy_id);
+  append_csv(&filename, ALERTS_CSV_HEADER, &line)
+}
+
+///
+/// Save the modem state to `data/{buoy_id}.modem.csv`, if the upload had it.
+///
+pub fn save_modem_status(
+  req: &httparse::Request,
+  buoy_id: &str,
+  date: &str,
+) -> Result<(), GiftError> {
+  let value = match header_value(req, HEADER_MODEM) {
+    Some(v) => v,
+    None => return Ok(()),
+  };
+
+  let modem = match ModemStatus::decode(value) {
+    Ok(modem) => modem,
+    Err(e) => {
+      error!("save_modem_status(): invalid Modem '{}': {:?}", value, e);
+      return Ok(());
+    }
+  };
+
+  let text = |value: &Option<String>| value.as_ref().map_or_else(String::new, |v| csv_field(v));
+  let number = |value: Option<i32>| value.map_or_else(String::new, |v| v.to_string());
+  let line = [
+    csv_field(date),
+    date_now(),
+    csv_position(req),
+    text(&modem.rat),
+    text(&modem.registration),
+    number(modem.rssi_dbm),
+    number(modem.rsrp_dbm),
+    csv_opt(modem.rsrq_db),
+    text(&modem.cell_id),
+    text(&modem.operator),
+    modem
+      .data_connected
+      .map_or_else(String::new, |c| c.to_string()),
+  ]
+  .join(",");
+
+  let filename = format!("{}/{}.modem.csv", SERVER_SAVE_PATH, buoy_id);
+  append_csv(&filename, MODEM_CSV_HEADER, &line)
+}
+
+///
+/// Save the session report to `data/{buoy_id}.sessions.csv`, it's sent with
+/// the first upload after the buoy starts.
+///
+pub fn save_session(req: &httparse::Request, buoy_id: &str, date: &str) -> Result<(), GiftError> {
+  let value = match header_value(req, HEADER_SESSION) {
+    Some(v) => v,
+    None => return Ok(()),
+  };
+
+  let session = match Session::decode(value) {
+    Ok(session) => session,
+    Err(e) => {
+      error!("save_session(): invalid Session '{}': {:?}", value, e);
+      return Ok(());
+    }
+  };
+
+  match session.previous_end {
+    SessionEnd::Crash | SessionEnd::Watchdog => warn!(
+      "save_session(): buoy {} restarted (boot {}), the last session ended with {}",
+      buoy_id, session.boots, session.previous_end
+    ),
+    _ => info!(
+      "save_session(): buoy {} started (boot {}), the last session ended with {}",
+      buoy_id, session.boots, session.previous_end
+    ),
+  }
+
+  let fix = match &session.last_fix {
+    Some(fix) => format!(
+      "{:.6},{:.6},{}",
+      fix.latitude,
+      fix.longitude,
+      csv_field(&fix.fix_time)
+    ),
+    None => String::from(",,"),
+  };
+  let line = [
+    csv_field(date),
+    date_now(),
+    session.boots.to_string(),
+    session.previous_end.to_string(),
+    session.uploads.to_string(),
+    session.failures.to_string(),
+    session.bytes.to_string(),
+    session
+      .last_command
+      .as_ref()
+      .map_or_else(String::new, |c| csv_field(c)),
+    fix,
+  ]
+  .join(",");
+
+  let filename = format!("{}/{}.sessions.csv", SERVER_SAVE_PATH, buoy_id);
+  append_csv(&filename, SESSIONS_CSV_HEADER, &line)
+}
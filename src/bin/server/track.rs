@@ -0,0 +1,681 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The GPS track of each buoy.  Each new fix that is uploaded is appended to
+/// `data/{buoy_id}.track.csv`, unless it's a repeat of the last fix or an
+/// outlier.  The last fix is kept in `data/{buoy_id}.track.last` so the track
+/// isn't read on every upload.  The track for a time range can be exported as
+/// GeoJSON or GPX:
+///
+///    server track {buoy_id} [geojson|gpx] [--from TIME] [--to TIME]
+///    GET /id/{buoy_id}/track.geojson?from=TIME&to=TIME
+///
+/// TIME is either a date, "20200830", or a time like `date_now()`,
+/// "20200830T023517.000Z".
+///
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, TimeZone, Utc};
+use regex::Regex;
+use serde::{Serialize, Serializer};
+
+use buoy_code::clock::{format_date, is_valid_date, parse_date};
+use buoy_code::date_now;
+use buoy_code::errors::GiftError;
+use buoy_code::position::{distance_m, FixType, Position};
+
+use crate::files::replace_file;
+use crate::save_post::{header_value, SERVER_SAVE_PATH};
+use crate::telemetry::append_csv;
+
+const TRACK_CSV_HEADER: &str = "fix_time,received,latitude,longitude,accuracy_m,satellites";
+
+// Fixes less accurate than this are not added to the track
+const MAX_ACCURACY_M: f32 = 250.0;
+
+// Faster than this is a bad fix, a drifting buoy does not do 10 knots
+const MAX_SPEED_MPS: f64 = 5.0;
+
+// After this long without a fix the buoy may have been moved, so a jump is
+// accepted
+const MAX_GAP_SECS: i64 = 12 * 60 * 60;
+
+const REJECT_TOO_FAST: &str = "too fast";
+
+lazy_static! {
+  // Uploads from the same buoy may be saved at the same time
+  static ref TRACK_LOCK: Mutex<()> = Mutex::new(());
+}
+
+///
+/// A fix on the track.  It's serialized as in `GET /id/{buoy_id}/status`.
+///
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackPoint {
+  #[serde(serialize_with = "serialize_rfc3339")]
+  pub time: DateTime<Utc>,
+  pub latitude: f64,
+  pub longitude: f64,
+  #[serde(rename = "accuracy_m")]
+  pub h_accuracy: Option<f32>,
+  pub satellites: Option<u32>,
+}
+
+impl TrackPoint {
+  /// A point for the track, None if there is no fix.
+  pub fn from_position(position: &Position) -> Option<TrackPoint> {
+    if position.stale || position.fix_type == FixType::NoFix {
+      return None;
+    }
+    Some(TrackPoint {
+      time: parse_date(&position.fix_time)?,
+      latitude: position.latitude,
+      longitude: position.longitude,
+      h_accuracy: position.h_accuracy,
+      satellites: position.satellites,
+    })
+  }
+
+  fn to_csv(&self, received: &str) -> String {
+    [
+      format_date(&self.time),
+      String::from(received),
+      format!("{:.6}", self.latitude),
+      format!("{:.6}", self.longitude),
+      self
+        .h_accuracy
+        .map_or_else(String::new, |a| format!("{:.1}", a)),
+      self.satellites.map_or_else(String::new, |s| s.to_string()),
+    ]
+    .join(",")
+  }
+
+  pub fn from_csv(line: &str) -> Option<TrackPoint> {
+    let fields: Vec<&str> = line.split(',').collect();
+    if fields.len() < 6 {
+      return None;
+    }
+    Some(TrackPoint {
+      time: parse_date(fields[0])?,
+      latitude: fields[2].parse().ok()?,
+      longitude: fields[3].parse().ok()?,
+      h_accuracy: fields[4].parse().ok(),
+      satellites: fields[5].parse().ok(),
+    })
+  }
+}
+
+///
+/// Why `point` does not belong on the track after `last`, or None if it
+/// does.
+///
+pub fn reject_reason(
+  last: Option<&TrackPoint>,
+  point: &TrackPoint,
+  now: DateTime<Utc>,
+) -> Option<&'static str> {
+  if !is_valid_date(&point.time) || point.time > now + chrono::Duration::minutes(5) {
+    return Some("invalid time");
+  }
+  if point.latitude.abs() > 90.0 || point.longitude.abs() > 180.0 {
+    return Some("invalid position");
+  }
+  if point.h_accuracy.map_or(false, |a| a > MAX_ACCURACY_M) {
+    return Some("inaccurate");
+  }
+
+  let last = last?;
+  let secs = (point.time - last.time).num_milliseconds() as f64 / 1000.0;
+  if secs <= 0.0 {
+    return Some("not newer than the last fix");
+  }
+  if secs > MAX_GAP_SECS as f64 {
+    return None;
+  }
+
+  // The fixes could be out by their accuracy
+  let error_m = f64::from(point.h_accuracy.unwrap_or(0.0) + last.h_accuracy.unwrap_or(0.0));
+  let distance = distance_m(
+    last.latitude,
+    last.longitude,
+    point.latitude,
+    point.longitude,
+  );
+  if (distance - error_m) / secs > MAX_SPEED_MPS {
+    return Some(REJECT_TOO_FAST);
+  }
+
+  None
+}
+
+fn track_filename(buoy_id: &str) -> String {
+  format!("{}/{}.track.csv", SERVER_SAVE_PATH, buoy_id)
+}
+
+fn last_filename(buoy_id: &str) -> String {
+  format!("{}/{}.track.last", SERVER_SAVE_PATH, buoy_id)
+}
+
+///
+/// The end of the track, the CSV lines of the last point and of a "too fast"
+/// fix that may show the last point was bad.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+struct TrackEnd {
+  last: Option<String>,
+  candidate: Option<String>,
+}
+
+impl TrackEnd {
+  fn load(buoy_id: &str) -> Result<TrackEnd, GiftError> {
+    let filename = last_filename(buoy_id);
+    if !Path::new(&filename).exists() {
+      // Tracks from before the .track.last file
+      let track = track_filename(buoy_id);
+      if !Path::new(&track).exists() {
+        return Ok(TrackEnd::default());
+      }
+      return Ok(TrackEnd {
+        last: fs::read_to_string(track)?
+          .lines()
+          .skip(1)
+          .last()
+          .map(String::from),
+        candidate: None,
+      });
+    }
+
+    let contents = fs::read_to_string(filename)?;
+    let mut lines = contents
+      .lines()
+      .map(|l| Some(String::from(l)).filter(|l| !l.is_empty()));
+    Ok(TrackEnd {
+      last: lines.next().unwrap_or(None),
+      candidate: lines.next().unwrap_or(None),
+    })
+  }
+
+  fn save(&self, buoy_id: &str) -> Result<(), GiftError> {
+    replace_file(
+      &last_filename(buoy_id),
+      &format!(
+        "{}\n{}\n",
+        self.last.as_deref().unwrap_or(""),
+        self.candidate.as_deref().unwrap_or("")
+      ),
+    )
+  }
+
+  fn last_point(&self) -> Option<TrackPoint> {
+    self.last.as_deref().and_then(TrackPoint::from_csv)
+  }
+
+  ///
+  /// Add `point`, with its CSV `line`.  Returns the lines to append to the
+  /// track, or why it was rejected.
+  ///
+  /// A bad fix could reject every good one after it for MAX_GAP_SECS, so two
+  /// "too fast" fixes that agree with each other replace the last point.
+  ///
+  fn add(
+    &mut self,
+    point: &TrackPoint,
+    line: String,
+    now: DateTime<Utc>,
+  ) -> Result<Vec<String>, &'static str> {
+    let reason = match reject_reason(self.last_point().as_ref(), point, now) {
+      None => {
+        self.last = Some(line.clone());
+        self.candidate = None;
+        return Ok(vec![line]);
+      }
+      Some(reason) => reason,
+    };
+    if reason != REJECT_TOO_FAST {
+      return Err(reason);
+    }
+
+    let candidate = self.candidate.as_deref().and_then(TrackPoint::from_csv);
+    match (candidate, self.candidate.take()) {
+      (Some(candidate), Some(candidate_line))
+        if reject_reason(Some(&candidate), point, now).is_none() =>
+      {
+        self.last = Some(line.clone());
+        Ok(vec![candidate_line, line])
+      }
+      _ => {
+        self.candidate = Some(line);
+        Err(reason)
+      }
+    }
+  }
+}
+
+fn read_track(filename: &str) -> Result<Vec<TrackPoint>, GiftError> {
+  if !Path::new(filename).exists() {
+    return Ok(Vec::new());
+  }
+  Ok(
+    fs::read_to_string(filename)?
+      .lines()
+      .skip(1)
+      .filter_map(TrackPoint::from_csv)
+      .collect(),
+  )
+}
+
+///
+/// Add the position in the upload to `data/{buoy_id}.track.csv`, if it's a
+/// new fix.
+///
+pub fn save_track_point(req: &httparse::Request, buoy_id: &str) -> Result<(), GiftError> {
+  let position = match Position::from_headers(|name| header_value(req, name)) {
+    Ok(Some(position)) => position,
+    Ok(None) => return Ok(()),
+    Err(e) => {
+      error!("save_track_point(): invalid position: {:?}", e);
+      return Ok(());
+    }
+  };
+  let point = match TrackPoint::from_position(&position) {
+    Some(point) => point,
+    None => return Ok(()),
+  };
+
+  // The lock covers reading the end of the track through to appending
+  let _lock = TRACK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+  let mut end = TrackEnd::load(buoy_id)?;
+  if end.last_point() == Some(point.clone()) {
+    return Ok(()); // The same fix as the last upload
+  }
+
+  let lines = end.add(&point, point.to_csv(&date_now()), Utc::now());
+  // Save the candidate too, even if the fix is rejected
+  end.save(buoy_id)?;
+  match lines {
+    Ok(lines) => {
+      if lines.len() > 1 {
+        warn!(
+          "Buoy {}: the fixes before {} show the last fix on the track was bad",
+          buoy_id, position.fix_time
+        );
+      }
+      let filename = track_filename(buoy_id);
+      for line in lines {
+        append_csv(&filename, TRACK_CSV_HEADER, &line)?;
+      }
+      Ok(())
+    }
+    Err(reason) => {
+      warn!(
+        "Buoy {}: fix at {} ({:.6}, {:.6}) not added to the track: {}",
+        buoy_id, position.fix_time, point.latitude, point.longitude, reason
+      );
+      Ok(())
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackFormat {
+  GeoJson,
+  Gpx,
+}
+
+impl TrackFormat {
+  pub fn content_type(self) -> &'static str {
+    match self {
+      TrackFormat::GeoJson => "application/geo+json",
+      TrackFormat::Gpx => "application/gpx+xml",
+    }
+  }
+}
+
+impl FromStr for TrackFormat {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "geojson" => Ok(TrackFormat::GeoJson),
+      "gpx" => Ok(TrackFormat::Gpx),
+      _ => Err(GiftError::HttpInvalidPath),
+    }
+  }
+}
+
+///
+/// A date, "20200830", or a time like `date_now()`.
+///
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, GiftError> {
+  if s.len() == 8 {
+    return Utc
+      .datetime_from_str(&format!("{}T000000", s), "%Y%m%dT%H%M%S")
+      .map_err(|_| GiftError::ParseTelemetry);
+  }
+  parse_date(s).ok_or(GiftError::ParseTelemetry)
+}
+
+///
+/// What to export.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackQuery {
+  pub buoy_id: String,
+  pub format: TrackFormat,
+  pub from: Option<DateTime<Utc>>,
+  pub to: Option<DateTime<Utc>>,
+}
+
+impl TrackQuery {
+  ///
+  /// Parse a request path, e.g. "/id/1/track.gpx?from=20200830".  Returns
+  /// `HttpInvalidPath` if it's not a track request.
+  ///
+  pub fn from_path(path: &str) -> Result<TrackQuery, GiftError> {
+    lazy_static! {
+      static ref RE: Regex =
+        Regex::new(r"^/id/([0-9a-fA-F\-]{1,40})/track\.([a-z]+)(?:\?(.*))?$").unwrap();
+    }
+    let caps = RE.captures(path).ok_or(GiftError::HttpInvalidPath)?;
+
+    let mut query = TrackQuery {
+      buoy_id: String::from(&caps[1]),
+      format: caps[2].parse()?,
+      from: None,
+      to: None,
+    };
+    let params = caps.get(3).map_or("", |m| m.as_str());
+    for pair in params.split('&').filter(|p| !p.is_empty()) {
+      let mut kv = pair.splitn(2, '=');
+      match (kv.next(), kv.next()) {
+        (Some("from"), Some(value)) => query.from = Some(parse_time(value)?),
+        (Some("to"), Some(value)) => query.to = Some(parse_time(value)?),
+        _ => return Err(GiftError::HttpInvalidRequest),
+      }
+    }
+    Ok(query)
+  }
+
+  /// The track in the requested format.
+  pub fn export(&self) -> Result<String, GiftError> {
+    let points: Vec<TrackPoint> = read_track(&track_filename(&self.buoy_id))?
+      .into_iter()
+      .filter(|p| self.from.map_or(true, |from| p.time >= from))
+      .filter(|p| self.to.map_or(true, |to| p.time < to))
+      .collect();
+
+    Ok(match self.format {
+      TrackFormat::GeoJson => to_geojson(&self.buoy_id, &points),
+      TrackFormat::Gpx => to_gpx(&self.buoy_id, &points),
+    })
+  }
+}
+
+pub fn rfc3339(time: &DateTime<Utc>) -> String {
+  time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+pub fn serialize_rfc3339<S: Serializer>(time: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+  s.serialize_str(&rfc3339(time))
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum GeoJson<'a> {
+  FeatureCollection {
+    features: Vec<GeoJson<'a>>,
+  },
+  Feature {
+    properties: Properties<'a>,
+    geometry: Geometry,
+  },
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Properties<'a> {
+  Track {
+    buoy_id: &'a str,
+  },
+  Fix {
+    #[serde(serialize_with = "serialize_rfc3339")]
+    time: DateTime<Utc>,
+    accuracy_m: Option<f32>,
+  },
+}
+
+// GeoJSON coordinates are [longitude, latitude]
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Geometry {
+  LineString { coordinates: Vec<[f64; 2]> },
+  Point { coordinates: [f64; 2] },
+}
+
+///
+/// A FeatureCollection with the track as a LineString, and each fix as a
+/// Point with its time.
+///
+pub fn to_geojson(buoy_id: &str, points: &[TrackPoint]) -> String {
+  let coordinates: Vec<[f64; 2]> = points.iter().map(|p| [p.longitude, p.latitude]).collect();
+
+  let mut features = vec![GeoJson::Feature {
+    properties: Properties::Track { buoy_id },
+    geometry: Geometry::LineString {
+      coordinates: coordinates.clone(),
+    },
+  }];
+  for (p, &coordinates) in points.iter().zip(coordinates.iter()) {
+    features.push(GeoJson::Feature {
+      properties: Properties::Fix {
+        time: p.time,
+        accuracy_m: p.h_accuracy,
+      },
+      geometry: Geometry::Point { coordinates },
+    });
+  }
+
+  // Can't fail, the fields are plain values
+  serde_json::to_string(&GeoJson::FeatureCollection { features }).unwrap()
+}
+
+pub fn to_gpx(buoy_id: &str, points: &[TrackPoint]) -> String {
+  let mut gpx = format!(
+    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+     <gpx version=\"1.1\" creator=\"smart-buoy\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n\
+     <trk><name>Buoy {}</name><trkseg>\n",
+    buoy_id
+  );
+  for p in points {
+    gpx.push_str(&format!(
+      "<trkpt lat=\"{:.6}\" lon=\"{:.6}\"><time>{}</time>",
+      p.latitude,
+      p.longitude,
+      rfc3339(&p.time)
+    ));
+    if let Some(sat) = p.satellites {
+      gpx.push_str(&format!("<sat>{}</sat>", sat));
+    }
+    gpx.push_str("</trkpt>\n");
+  }
+  gpx.push_str("</trkseg></trk>\n</gpx>\n");
+  gpx
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::track::*;
+
+  fn point(time: &str, latitude: f64, longitude: f64) -> TrackPoint {
+    TrackPoint {
+      time: parse_date(time).unwrap(),
+      latitude,
+      longitude,
+      h_accuracy: Some(5.0),
+      satellites: Some(7),
+    }
+  }
+
+  #[test]
+  fn test_reject_reason() {
+    let now = parse_date("20200830T120000.000Z").unwrap();
+    let first = point("20200830T000000.000Z", -36.843292, 174.756864);
+    assert_eq!(None, reject_reason(None, &first, now));
+
+    // About 110 m in 15 minutes
+    let next = point("20200830T001500.000Z", -36.844292, 174.756864);
+    assert_eq!(None, reject_reason(Some(&first), &next, now));
+
+    // About 11 km in 15 minutes
+    let jump = point("20200830T001500.000Z", -36.943292, 174.756864);
+    assert_eq!(Some("too fast"), reject_reason(Some(&first), &jump, now));
+
+    // But fine after a long gap
+    let moved = point("20200830T130000.000Z", -36.943292, 174.756864);
+    assert_eq!(
+      None,
+      reject_reason(Some(&first), &moved, now + chrono::Duration::hours(2))
+    );
+
+    assert_eq!(
+      Some("not newer than the last fix"),
+      reject_reason(Some(&next), &first, now)
+    );
+    assert_eq!(
+      Some("invalid time"),
+      reject_reason(None, &point("19700101T000142.000Z", -36.8, 174.7), now)
+    );
+    assert_eq!(
+      Some("invalid time"),
+      reject_reason(None, &point("20200831T000000.000Z", -36.8, 174.7), now)
+    );
+
+    let mut inaccurate = next.clone();
+    inaccurate.h_accuracy = Some(1000.0);
+    assert_eq!(
+      Some("inaccurate"),
+      reject_reason(Some(&first), &inaccurate, now)
+    );
+  }
+
+  #[test]
+  fn test_track_end() {
+    let now = parse_date("20200830T120000.000Z").unwrap();
+    let line = |p: &TrackPoint| p.to_csv("20200830T120000.000Z");
+    let mut end = TrackEnd::default();
+
+    // A bad first fix, 11 km away
+    let bad = point("20200830T000000.000Z", -36.943292, 174.756864);
+    assert_eq!(Ok(vec![line(&bad)]), end.add(&bad, line(&bad), now));
+    assert_eq!(Some(bad), end.last_point());
+
+    // The good fixes are too fast, until two of them agree
+    let good = point("20200830T001500.000Z", -36.843292, 174.756864);
+    assert_eq!(Err("too fast"), end.add(&good, line(&good), now));
+    assert_eq!(Some(line(&good)), end.candidate);
+    let next = point("20200830T003000.000Z", -36.844292, 174.756864);
+    assert_eq!(
+      Ok(vec![line(&good), line(&next)]),
+      end.add(&next, line(&next), now)
+    );
+    assert_eq!(Some(next.clone()), end.last_point());
+    assert_eq!(None, end.candidate);
+
+    // A single bad fix is left out
+    let jump = point("20200830T004500.000Z", -36.943292, 174.756864);
+    assert_eq!(Err("too fast"), end.add(&jump, line(&jump), now));
+    let after = point("20200830T010000.000Z", -36.845292, 174.756864);
+    assert_eq!(Ok(vec![line(&after)]), end.add(&after, line(&after), now));
+    assert_eq!(None, end.candidate);
+
+    assert_eq!(
+      Err("not newer than the last fix"),
+      end.add(&next, line(&next), now)
+    );
+  }
+
+  #[test]
+  fn test_csv() {
+    let p = point("20200830T001500.000Z", -36.844292, 174.756864);
+    let line = p.to_csv("20200830T001730.000Z");
+    assert_eq!(
+      "20200830T001500.000Z,20200830T001730.000Z,-36.844292,174.756864,5.0,7",
+      line
+    );
+    assert_eq!(
+      "{\"time\":\"2020-08-30T00:15:00.000Z\",\"latitude\":-36.844292,\
+       \"longitude\":174.756864,\"accuracy_m\":5.0,\"satellites\":7}",
+      serde_json::to_string(&p).unwrap()
+    );
+    assert_eq!(Some(p.clone()), TrackPoint::from_csv(&line));
+
+    let no_accuracy = TrackPoint {
+      h_accuracy: None,
+      satellites: None,
+      ..p
+    };
+    assert!(serde_json::to_string(&no_accuracy)
+      .unwrap()
+      .ends_with("\"accuracy_m\":null,\"satellites\":null}"));
+    assert_eq!(None, TrackPoint::from_csv(TRACK_CSV_HEADER));
+  }
+
+  #[test]
+  fn test_query() {
+    let query =
+      TrackQuery::from_path("/id/1/track.gpx?from=20200830&to=20200831T120000.000Z").unwrap();
+    assert_eq!("1", query.buoy_id);
+    assert_eq!(TrackFormat::Gpx, query.format);
+    assert_eq!("20200830T000000.000Z", format_date(&query.from.unwrap()));
+    assert_eq!("20200831T120000.000Z", format_date(&query.to.unwrap()));
+
+    let query = TrackQuery::from_path("/id/1/track.geojson").unwrap();
+    assert_eq!(TrackFormat::GeoJson, query.format);
+    assert_eq!(None, query.from);
+
+    assert!(TrackQuery::from_path("/id/1").is_err());
+    assert!(TrackQuery::from_path("/id/1/track.kml").is_err());
+    assert!(TrackQuery::from_path("/id/1/track.gpx?from=yesterday").is_err());
+  }
+
+  #[test]
+  fn test_export() {
+    let points = [
+      point("20200830T000000.000Z", -36.843292, 174.756864),
+      point("20200830T001500.000Z", -36.844292, 174.756864),
+    ];
+
+    let geojson = to_geojson("1\"", &points);
+    assert!(geojson.starts_with("{\"type\":\"FeatureCollection\""));
+    assert!(geojson.contains("\"properties\":{\"buoy_id\":\"1\\\"\"}"));
+    assert!(geojson.contains("\"coordinates\":[[174.756864,-36.843292],[174.756864,-36.844292]]"));
+    assert!(geojson.contains("\"time\":\"2020-08-30T00:15:00.000Z\",\"accuracy_m\":5.0"));
+
+    let gpx = to_gpx("1", &points);
+    assert!(gpx.contains(
+      "<trkpt lat=\"-36.844292\" lon=\"174.756864\"><time>2020-08-30T00:15:00.000Z</time><sat>7</sat></trkpt>"
+    ));
+    assert!(gpx.ends_with("</gpx>\n"));
+  }
+}
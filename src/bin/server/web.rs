@@ -0,0 +1,235 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The dashboard and the API in api.rs over plain HTTP, for browsers, which
+/// can't talk to the QUIC listener the buoys use.  Only GETs are answered,
+/// one per connection.  It listens on localhost unless `SERVER_WEB_ADDR` is
+/// set, so by default it's reached over an SSH tunnel:
+///
+///    ssh -L 8080:localhost:8080 {server}
+///    http://localhost:8080/
+///
+/// The API reads files and the index, so each request is answered in a
+/// thread of its own and the chunks are passed back to the runtime, which
+/// also serves the buoys.
+///
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use futures::sync::mpsc;
+use futures::{stream, Async, Future, Poll, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+use buoy_code::commands::{ServerError, ERR_BUSY};
+
+use crate::api;
+use crate::ingest::{incoming_dir, Ingest, Method, Reject, Request};
+
+const READ_BUF_LEN: usize = 4 * 1024;
+
+// The chunks read ahead of the connection
+const CHUNKS_QUEUED: usize = 4;
+
+// The API requests being answered, each in its own thread
+static ANSWERING: AtomicUsize = AtomicUsize::new(0);
+const MAX_ANSWERING: usize = 8;
+
+///
+/// A response, written as it's read.
+///
+pub type Response = Box<dyn Stream<Item = Vec<u8>, Error = io::Error>>;
+
+/// A response that is ready.
+pub fn bytes(resp: Vec<u8>) -> Response {
+  Box::new(stream::once(Ok(resp)))
+}
+
+// Counts an API request while it's answered
+struct Answering;
+
+impl Answering {
+  fn start() -> Option<Self> {
+    if ANSWERING.fetch_add(1, Ordering::SeqCst) < MAX_ANSWERING {
+      Some(Answering)
+    } else {
+      ANSWERING.fetch_sub(1, Ordering::SeqCst);
+      None
+    }
+  }
+}
+
+impl Drop for Answering {
+  fn drop(&mut self) {
+    ANSWERING.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+///
+/// Answer a GET from the API in a thread, see api.rs.  When too many are
+/// being answered the client is told to try again later.
+///
+pub fn api_response(path: String) -> Response {
+  let answering = match Answering::start() {
+    Some(answering) => answering,
+    None => {
+      let error = ServerError::new(503, ERR_BUSY, "too many requests are being answered");
+      return bytes(error.response().into_bytes());
+    }
+  };
+
+  let (tx, rx) = mpsc::channel(CHUNKS_QUEUED);
+  thread::spawn(move || {
+    let _answering = answering;
+    let mut tx = tx;
+    for chunk in api::response(&path) {
+      tx = match tx.send(chunk).wait() {
+        Ok(tx) => tx,
+        Err(_) => return, // The connection was closed
+      };
+    }
+  });
+
+  Box::new(rx.then(|chunk| match chunk {
+    Ok(chunk) => chunk,
+    Err(()) => Err(io::Error::new(
+      io::ErrorKind::Other,
+      "the API thread failed",
+    )),
+  }))
+}
+
+///
+/// Where to listen, `SERVER_WEB_ADDR` or localhost on `WEB_PORT`.
+///
+pub fn listen_addr() -> Result<SocketAddr, std::net::AddrParseError> {
+  match std::env::var("SERVER_WEB_ADDR") {
+    Ok(addr) => addr.parse(),
+    Err(_) => Ok(SocketAddr::from(([127, 0, 0, 1], buoy_code::WEB_PORT))),
+  }
+}
+
+///
+/// Reads a GET, it's complete once the head has arrived.
+///
+struct ReadGet {
+  stream: Option<TcpStream>,
+  ingest: Option<Ingest>,
+  buf: Vec<u8>,
+}
+
+impl ReadGet {
+  fn new(stream: TcpStream) -> Self {
+    ReadGet {
+      stream: Some(stream),
+      ingest: Some(Ingest::new(&incoming_dir())),
+      buf: vec![0; READ_BUF_LEN],
+    }
+  }
+}
+
+impl Future for ReadGet {
+  type Item = (TcpStream, Result<Request, Reject>);
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+    loop {
+      let stream = self.stream.as_mut().expect("polled after completion");
+      let n = match stream.poll_read(&mut self.buf)? {
+        Async::Ready(n) => n,
+        Async::NotReady => return Ok(Async::NotReady),
+      };
+      let ingest = self.ingest.as_mut().unwrap();
+      let done = if n == 0 {
+        true
+      } else if let Err(reject) = ingest.push(&self.buf[..n]) {
+        return Ok(Async::Ready((self.stream.take().unwrap(), Err(reject))));
+      } else {
+        ingest.method().is_some()
+      };
+      if !done {
+        continue;
+      }
+
+      let ingest = self.ingest.take().unwrap();
+      let request = match ingest.method() {
+        Some(Method::Post) => Err(Reject::NotImplemented),
+        _ => ingest.finish(),
+      };
+      return Ok(Async::Ready((self.stream.take().unwrap(), request)));
+    }
+  }
+}
+
+///
+/// Write a response a chunk at a time, for the buoys' QUIC streams too.
+///
+pub fn write_response<W: AsyncWrite>(
+  writer: W,
+  resp: Response,
+) -> impl Future<Item = W, Error = io::Error> {
+  resp.fold(writer, |writer, chunk| {
+    tokio::io::write_all(writer, chunk).map(|(writer, _)| writer)
+  })
+}
+
+fn handle_connection(stream: TcpStream) -> impl Future<Item = (), Error = ()> {
+  ReadGet::new(stream)
+    .and_then(|(stream, request)| {
+      let resp = match request {
+        Ok(request) => api_response(request.path),
+        Err(reject) => {
+          info!("web: rejected request: {}", reject);
+          bytes(reject.error().response().into_bytes())
+        }
+      };
+      write_response(stream, resp)
+    })
+    .and_then(tokio::io::shutdown)
+    .map(|_| ())
+    .map_err(|e| info!("web: request failed: {}", e))
+}
+
+///
+/// Serve the dashboard on `listen`, the future runs until the server stops.
+///
+pub fn serve(listen: &SocketAddr) -> io::Result<impl Future<Item = (), Error = ()>> {
+  let listener = TcpListener::bind(listen)?;
+  info!("dashboard on http://{}/", listener.local_addr()?);
+  Ok(
+    listener
+      .incoming()
+      .then(|stream| match stream {
+        Ok(stream) => Ok::<_, ()>(Some(stream)),
+        Err(e) => {
+          error!("web: accept failed: {}", e);
+          Ok(None)
+        }
+      })
+      .filter_map(|stream| stream)
+      .for_each(|stream| {
+        tokio_current_thread::spawn(handle_connection(stream));
+        Ok(())
+      }),
+  )
+}
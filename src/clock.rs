@@ -0,0 +1,179 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Where the buoy's timestamps come from.  The FX30 often boots before it has
+/// network time, so the buoy disciplines its clock from the GNSS time and the
+/// server's `Date` response header.  Each timestamp is sent with its source,
+/// and the state of the clock is sent in the `Clock` header, e.g.:
+///
+/// ```text
+/// source=gnss,offset_ms=-1598832000123,drift_ppm=3.20
+/// ```
+///
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::GiftError;
+use crate::key_values;
+
+pub const HEADER_CLOCK: &str = "Clock";
+pub const HEADER_START_TIME_SOURCE: &str = "Start-Time-Source";
+
+// A system clock before this year has not been set
+pub const MIN_VALID_YEAR: i32 = 2020;
+
+const DATE_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeSource {
+  Gnss,     // Corrected from the GNSS time
+  Server,   // Corrected from the server's Date header
+  System,   // The system clock, it looks valid but nothing has confirmed it
+  Unsynced, // The system clock has not been set, e.g. 1970
+}
+
+impl fmt::Display for TimeSource {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      TimeSource::Gnss => "gnss",
+      TimeSource::Server => "server",
+      TimeSource::System => "system",
+      TimeSource::Unsynced => "unsynced",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for TimeSource {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "gnss" => Ok(TimeSource::Gnss),
+      "server" => Ok(TimeSource::Server),
+      "system" => Ok(TimeSource::System),
+      "unsynced" => Ok(TimeSource::Unsynced),
+      _ => Err(GiftError::ParseTelemetry),
+    }
+  }
+}
+
+/// Format a time the same as `date_now()`, e.g. "20200830T023517.000Z".
+pub fn format_date(date: &DateTime<Utc>) -> String {
+  date.format(DATE_FORMAT).to_string()
+}
+
+/// Parse a time created by `format_date()` or `date_now()`.
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+  Utc.datetime_from_str(date, DATE_FORMAT).ok()
+}
+
+/// The system clock has been set.
+pub fn is_valid_date(date: &DateTime<Utc>) -> bool {
+  date.year() >= MIN_VALID_YEAR
+}
+
+/// The source of a time read from the system clock, e.g. from `date_now()`.
+pub fn system_source(date: &str) -> TimeSource {
+  if parse_date(date).map_or(false, |date| is_valid_date(&date)) {
+    TimeSource::System
+  } else {
+    TimeSource::Unsynced
+  }
+}
+
+///
+/// The state of the buoy's clock.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockStatus {
+  pub source: TimeSource,     // Where the current time comes from
+  pub offset_ms: i64,         // UTC minus the system clock
+  pub drift_ppm: Option<f64>, // How fast the monotonic clock runs, compared to UTC
+}
+
+impl ClockStatus {
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    let mut out = format!("source={},offset_ms={}", self.source, self.offset_ms);
+    if let Some(drift) = self.drift_ppm {
+      out.push_str(&format!(",drift_ppm={:.2}", drift));
+    }
+    out
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<ClockStatus, GiftError> {
+    let mut source = None;
+    let mut offset_ms = None;
+    let mut drift_ppm = None;
+
+    for (key, value) in key_values(value)? {
+      match key {
+        "source" => source = Some(value.parse()?),
+        "offset_ms" => offset_ms = Some(value.parse()?),
+        "drift_ppm" => drift_ppm = Some(value.parse()?),
+        _ => debug!("ClockStatus::decode(): ignoring '{}'", key),
+      }
+    }
+
+    Ok(ClockStatus {
+      source: source.ok_or(GiftError::ParseTelemetry)?,
+      offset_ms: offset_ms.ok_or(GiftError::ParseTelemetry)?,
+      drift_ppm,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::clock::*;
+
+  #[test]
+  fn test_dates() {
+    let date = parse_date("20200830T023517.250Z").unwrap();
+    assert_eq!("20200830T023517.250Z", format_date(&date));
+    assert!(is_valid_date(&date));
+    assert!(!is_valid_date(&parse_date("19700101T000142.000Z").unwrap()));
+    assert_eq!(None, parse_date("2020-08-30 02:35:17"));
+    assert_eq!(TimeSource::System, system_source("20200830T023517.250Z"));
+    assert_eq!(TimeSource::Unsynced, system_source("19700101T000142.000Z"));
+  }
+
+  #[test]
+  fn test_encode_decode() {
+    let status = ClockStatus {
+      source: TimeSource::Gnss,
+      offset_ms: -1_598_832_000_123,
+      drift_ppm: Some(3.2),
+    };
+    assert_eq!(
+      "source=gnss,offset_ms=-1598832000123,drift_ppm=3.20",
+      status.encode()
+    );
+    assert_eq!(status, ClockStatus::decode(&status.encode()).unwrap());
+    assert!(ClockStatus::decode("source=ntp,offset_ms=0").is_err());
+  }
+}
@@ -21,42 +21,317 @@
 ///
 /// Commands that are sent from the server to FX30.
 ///
+/// The HTTP status of a response says what the buoy should do with the
+/// request:
 ///
+/// ```text
+/// 2xx           - it was accepted
+/// 500, 503, ... - the server can't take it now, send it again later
+/// 403           - the server refuses the buoy, e.g. an unknown buoy id
+/// 400, 413, 422 - the request is bad, sending it again won't help
+/// ```
+///
+/// An error response has a JSON body, e.g.:
+///
+/// ```text
+/// {"status":422,"error":"x3_decode","message":"the x3 data could not be decoded"}
+/// ```
+///
+use std::fmt;
 use std::sync::mpsc::Sender;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::errors::GiftError;
-use crate::ControllerAction::{self, CtrlServerCmd};
+use crate::ControllerAction::{self, CtrlServerCmd, CtrlServerTime};
+
+pub const RESPONSE_OK_STATUS: &str = "HTTP/1.1 200 OK";
 
-pub const RESPONSE_OK: &str = "HTTP/1.1 200 OK\r\n\r\n";
+// The `error` of an error response
+pub const ERR_BAD_REQUEST: &str = "bad_request"; // Malformed request
+pub const ERR_INVALID_BUOY_ID: &str = "invalid_buoy_id"; // Not a buoy we accept uploads from
+pub const ERR_NOT_FOUND: &str = "not_found"; // Nothing at the path
+pub const ERR_TOO_LARGE: &str = "too_large"; // The head or body is too big
+pub const ERR_X3_DECODE: &str = "x3_decode"; // The hydrophone data could not be decoded
+pub const ERR_NOT_IMPLEMENTED: &str = "not_implemented"; // Not a GET or POST
+pub const ERR_BUSY: &str = "busy"; // Too many uploads are being processed
+pub const ERR_STORAGE: &str = "storage"; // Saving failed, e.g. the disk is full
+pub const ERR_INTERNAL: &str = "internal"; // Anything else
+pub const ERR_INVALID_RESPONSE: &str = "invalid_response"; // The buoy could not read the response
 
-#[derive(Clone)]
+fn http_date() -> String {
+  Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+///
+/// The response to an upload.  The Date header lets the buoy set its clock.
+///
+pub fn response_ok() -> String {
+  format!("{}\r\nDate: {}\r\n\r\n", RESPONSE_OK_STATUS, http_date())
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    400 => "Bad Request",
+    403 => "Forbidden",
+    404 => "Not Found",
+    413 => "Payload Too Large",
+    422 => "Unprocessable Entity",
+    431 => "Request Header Fields Too Large",
+    500 => "Internal Server Error",
+    501 => "Not Implemented",
+    503 => "Service Unavailable",
+    _ => "Unknown",
+  }
+}
+
+/// A string as a quoted JSON string value.
+pub fn json_string(s: &str) -> String {
+  serde_json::Value::from(s).to_string()
+}
+
+///
+/// Why the server did not accept a request.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerError {
+  pub status: u16,     // The HTTP status, 0 if the response could not be read
+  pub error: String,   // One of the ERR_* values
+  pub message: String, // For people
+}
+
+// The JSON body of an error response, the fields may be missing
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ErrorBody {
+  error: Option<String>,
+  message: Option<String>,
+}
+
+impl ServerError {
+  pub fn new(status: u16, error: &str, message: &str) -> Self {
+    ServerError {
+      status,
+      error: String::from(error),
+      message: String::from(message),
+    }
+  }
+
+  /// The server is busy, or the disk is full, so the buoy should try later.
+  pub fn is_retryable(&self) -> bool {
+    self.status == 0 || self.status >= 500
+  }
+
+  /// The response, with the error as JSON.
+  pub fn response(&self) -> String {
+    let body = serde_json::to_string(self).unwrap_or_default();
+    let retry_after = if self.status == 503 {
+      "Retry-After: 60\r\n"
+    } else {
+      ""
+    };
+    format!(
+      "HTTP/1.1 {} {}\r\nDate: {}\r\n{}Content-Type: application/json\r\n\
+       Content-Length: {}\r\n\r\n{}",
+      self.status,
+      reason_phrase(self.status),
+      http_date(),
+      retry_after,
+      body.len(),
+      body
+    )
+  }
+
+  ///
+  /// Read an error response.  The status line is enough, the JSON body is
+  /// used if there is one.
+  ///
+  pub fn from_response(resp: &str) -> Option<ServerError> {
+    lazy_static! {
+      static ref STATUS: Regex = Regex::new(r"^HTTP/1\.[01] (\d{3})").unwrap();
+    }
+    let status = STATUS.captures(resp)?.get(1)?.as_str().parse().ok()?;
+    let body = resp.splitn(2, "\r\n\r\n").nth(1).unwrap_or("");
+    let body: ErrorBody = serde_json::from_str(body).unwrap_or_default();
+    Some(ServerError {
+      status,
+      error: body.error.unwrap_or_default(),
+      message: body
+        .message
+        .unwrap_or_else(|| String::from(reason_phrase(status))),
+    })
+  }
+}
+
+impl fmt::Display for ServerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {}: {}", self.status, self.error, self.message)
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum FX30Command {
-  Normal, // Normal operation.
+  Normal,             // Normal operation.
+  Retry(ServerError), // The server can't take the request now, send it again later
+  Drop(ServerError),  // The request is bad, don't send it again
+  Alert(ServerError), // The server refuses the buoy, it needs fixing by hand
+}
+
+impl FX30Command {
+  /// The error, if the request was not accepted.
+  pub fn error(&self) -> Option<&ServerError> {
+    match self {
+      FX30Command::Normal => None,
+      FX30Command::Retry(e) | FX30Command::Drop(e) | FX30Command::Alert(e) => Some(e),
+    }
+  }
 }
 
+impl fmt::Display for FX30Command {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FX30Command::Normal => f.write_str("normal"),
+      FX30Command::Retry(e) => write!(f, "retry:{}", e.status),
+      FX30Command::Drop(e) => write!(f, "drop:{}", e.status),
+      FX30Command::Alert(e) => write!(f, "alert:{}", e.status),
+    }
+  }
+}
+
+///
+/// Retries and drops are handled by the upload itself, see the buoy's
+/// transport.rs.
+///
 pub fn handle_fx30_command(cmd: FX30Command) -> Result<(), GiftError> {
   match cmd {
-    FX30Command::Normal => Ok(()),
+    FX30Command::Normal => (),
+    FX30Command::Retry(e) => warn!("the server will take it later: {}", e),
+    FX30Command::Drop(e) => error!("the server rejected the request: {}", e),
+    FX30Command::Alert(e) => error!("ALERT: the server refuses this buoy: {}", e),
   }
+  Ok(())
 }
 
-fn parse_server_response(resp: &str) -> Result<ControllerAction, GiftError> {
+fn parse_server_response(resp: &str) -> FX30Command {
   debug!("parse_server_response(): {:?}", resp);
 
-  if resp == RESPONSE_OK {
-    Ok(CtrlServerCmd(FX30Command::Normal))
-  } else {
-    error!("handle_server_response(): error in response: {}", resp);
-    Ok(CtrlServerCmd(FX30Command::Normal))
+  if resp.starts_with(RESPONSE_OK_STATUS) {
+    return FX30Command::Normal;
+  }
+  let error = match ServerError::from_response(resp) {
+    Some(error) => error,
+    None => {
+      error!("parse_server_response(): invalid response: {:?}", resp);
+      return FX30Command::Retry(ServerError::new(
+        0,
+        ERR_INVALID_RESPONSE,
+        "the response could not be read",
+      ));
+    }
+  };
+
+  match error.status {
+    200..=299 => FX30Command::Normal,
+    403 => FX30Command::Alert(error),
+    _ if error.is_retryable() => FX30Command::Retry(error),
+    _ => FX30Command::Drop(error),
   }
 }
 
+///
+/// The time in the Date header of the response, e.g.
+/// "Date: Sun, 30 Aug 2020 02:35:17 GMT".
+///
+fn parse_response_date(resp: &str) -> Option<DateTime<Utc>> {
+  resp
+    .lines()
+    .take_while(|line| !line.is_empty())
+    .filter_map(|line| {
+      let mut parts = line.splitn(2, ':');
+      match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) if name.eq_ignore_ascii_case("Date") => Some(value.trim()),
+        _ => None,
+      }
+    })
+    .next()
+    .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+    .map(|date| date.with_timezone(&Utc))
+}
+
+///
+/// Pass the server's time and command to the controller.  Returns the
+/// command, so the upload can act on it.
+///
 pub fn handle_server_response(
   action_tx: Sender<ControllerAction>,
   resp: &[u8],
-) -> Result<(), GiftError> {
-  let s = String::from_utf8(resp.to_vec()).unwrap();
-  let action = crate::commands::parse_server_response(&s).unwrap();
-  action_tx.send(action).unwrap();
-  Ok(())
+) -> Result<FX30Command, GiftError> {
+  let received = Instant::now();
+  let s = String::from_utf8_lossy(resp);
+  if let Some(date) = parse_response_date(&s) {
+    action_tx.send(CtrlServerTime(date, received))?;
+  }
+  let command = parse_server_response(&s);
+  action_tx.send(CtrlServerCmd(command.clone()))?;
+  Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::commands::*;
+
+  #[test]
+  fn test_response_date() {
+    let resp = "HTTP/1.1 200 OK\r\nDate: Sun, 30 Aug 2020 02:35:17 GMT\r\n\r\n";
+    let date = parse_response_date(resp).unwrap();
+    assert_eq!("2020-08-30T02:35:17+00:00", date.to_rfc3339());
+
+    let date = parse_response_date(&response_ok()).unwrap();
+    assert!((Utc::now() - date).num_seconds().abs() <= 1);
+
+    assert_eq!(None, parse_response_date("HTTP/1.1 200 OK\r\n\r\n"));
+  }
+
+  #[test]
+  fn test_server_response() {
+    assert_eq!(FX30Command::Normal, parse_server_response(&response_ok()));
+
+    let error = ServerError::new(422, ERR_X3_DECODE, "bad \"x3\" data\n");
+    let resp = error.response();
+    assert!(resp.starts_with("HTTP/1.1 422 Unprocessable Entity\r\n"));
+    assert!(resp.ends_with(
+      "{\"status\":422,\"error\":\"x3_decode\",\"message\":\"bad \\\"x3\\\" data\\n\"}"
+    ));
+    assert_eq!(Some(error.clone()), ServerError::from_response(&resp));
+    assert_eq!(FX30Command::Drop(error), parse_server_response(&resp));
+
+    let busy = ServerError::new(503, ERR_BUSY, "try later");
+    assert!(busy.response().contains("Retry-After: 60\r\n"));
+    assert_eq!(
+      FX30Command::Retry(busy.clone()),
+      parse_server_response(&busy.response())
+    );
+
+    let refused = ServerError::new(403, ERR_INVALID_BUOY_ID, "unknown buoy");
+    assert_eq!(
+      FX30Command::Alert(refused.clone()),
+      parse_server_response(&refused.response())
+    );
+
+    // No JSON, e.g. from an older server
+    assert_eq!(
+      Some(ServerError::new(500, "", "Internal Server Error")),
+      ServerError::from_response("HTTP/1.1 500 Internal Server Error\r\n\r\n")
+    );
+    assert_eq!("\"a \\\"b\\\"\\n\"", json_string("a \"b\"\n"));
+    match parse_server_response("garbage") {
+      FX30Command::Retry(e) => assert_eq!(ERR_INVALID_RESPONSE, e.error),
+      cmd => panic!("unexpected {:?}", cmd),
+    }
+  }
 }
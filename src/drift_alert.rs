@@ -0,0 +1,93 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// A drift alert, sent by a buoy whose GPS fixes are outside of its watch
+/// circle, i.e. it has probably broken its mooring.  It's sent in the
+/// `Drift-Alert` header as `key=value` pairs, e.g.:
+///
+/// ```text
+/// distance_m=523.4,radius_m=100.0,fixes_outside=3
+/// ```
+///
+use crate::errors::GiftError;
+use crate::key_values;
+
+pub const HEADER_DRIFT_ALERT: &str = "Drift-Alert";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DriftAlert {
+  pub distance_m: f64,    // Distance from the deployment position
+  pub radius_m: f64,      // The watch circle radius
+  pub fixes_outside: u32, // Consecutive fixes outside of the watch circle
+}
+
+impl DriftAlert {
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    format!(
+      "distance_m={:.1},radius_m={:.1},fixes_outside={}",
+      self.distance_m, self.radius_m, self.fixes_outside
+    )
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<DriftAlert, GiftError> {
+    let mut distance_m = None;
+    let mut radius_m = None;
+    let mut fixes_outside = None;
+
+    for (key, value) in key_values(value)? {
+      match key {
+        "distance_m" => distance_m = Some(value.parse()?),
+        "radius_m" => radius_m = Some(value.parse()?),
+        "fixes_outside" => fixes_outside = Some(value.parse()?),
+        _ => debug!("DriftAlert::decode(): ignoring '{}'", key),
+      }
+    }
+
+    Ok(DriftAlert {
+      distance_m: distance_m.ok_or(GiftError::ParseTelemetry)?,
+      radius_m: radius_m.ok_or(GiftError::ParseTelemetry)?,
+      fixes_outside: fixes_outside.unwrap_or(0),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::drift_alert::*;
+
+  #[test]
+  fn test_encode_decode() {
+    let alert = DriftAlert {
+      distance_m: 523.4,
+      radius_m: 100.0,
+      fixes_outside: 3,
+    };
+    assert_eq!(
+      "distance_m=523.4,radius_m=100.0,fixes_outside=3",
+      alert.encode()
+    );
+    assert_eq!(alert, DriftAlert::decode(&alert.encode()).unwrap());
+    assert!(DriftAlert::decode("radius_m=100.0").is_err());
+    assert!(DriftAlert::decode("distance_m=far,radius_m=100.0").is_err());
+  }
+}
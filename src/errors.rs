@@ -46,12 +46,15 @@ pub enum GiftError {
   QuinnParse(quinn::crypto::rustls::ParseError),
   StdFailure(failure::Error),
   MPSC(mpsc::SendError<ControllerAction>),
+  TomlDe(toml::de::Error),
+  TomlSer(toml::ser::Error),
 
   // Custom Http Errors
   HttpInvalidRequest,
   HttpInvalidPath,
   HttpErrorOnFind,
   HttpInvalidMethod,
+  HttpStatus(crate::commands::FX30Command), // The server did not accept the request
 
   // Custom FX30 Errors
   DataConnection,        // Issue with the data connection, or it's process
@@ -61,6 +64,10 @@ pub enum GiftError {
   RemoteUrlError,        // Bad remote URL
   X3SaveIssue,           // x3bin to wav save error
   ParseVoltage,          // Error parsing voltage
+  ParseTelemetry,        // Error parsing a telemetry header
+  ParseNmea,             // Invalid NMEA sentence, or the checksum is wrong
+  InvalidConfig(String), // The configuration file has an invalid value
+  Calibration,           // Not enough calibration points for a fit
 }
 
 impl From<io::Error> for GiftError {
@@ -158,3 +165,15 @@ impl From<sonogram::SonogramError> for GiftError {
     GiftError::Sonogram(err)
   }
 }
+
+impl From<toml::de::Error> for GiftError {
+  fn from(err: toml::de::Error) -> GiftError {
+    GiftError::TomlDe(err)
+  }
+}
+
+impl From<toml::ser::Error> for GiftError {
+  fn from(err: toml::ser::Error) -> GiftError {
+    GiftError::TomlSer(err)
+  }
+}
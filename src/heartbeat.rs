@@ -0,0 +1,186 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+///
+/// The buoy's health heartbeat.  It's sent on its own schedule, independent of
+/// the hydrophone recordings, to `POST /id/{buoy_id}/heartbeat` with the
+/// `Heartbeat` header as `key=value` pairs, e.g.:
+///
+/// ```text
+/// hydrophone=ok,gps=degraded,light=off,power=normal,queue=1,mem_free_kb=20480,
+/// flash_used=61.2,load=0.35,uptime=86400
+/// ```
+///
+/// (on one line).  The system values are left out if they could not be read.
+///
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::GiftError;
+use crate::key_values;
+use crate::power_state::PowerState;
+
+pub const HEADER_HEARTBEAT: &str = "Heartbeat";
+
+/// The health of a subsystem.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Health {
+  Ok,       // Working
+  Degraded, // Working, but not well, e.g. the last GPS fix is stale
+  Failed,   // Not working
+  Off,      // Switched off, e.g. by the power policy or during the day
+}
+
+impl fmt::Display for Health {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      Health::Ok => "ok",
+      Health::Degraded => "degraded",
+      Health::Failed => "failed",
+      Health::Off => "off",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for Health {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "ok" => Ok(Health::Ok),
+      "degraded" => Ok(Health::Degraded),
+      "failed" => Ok(Health::Failed),
+      "off" => Ok(Health::Off),
+      _ => Err(GiftError::ParseTelemetry),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heartbeat {
+  pub hydrophone: Health,          // The hydrophone reader
+  pub gps: Health,                 // GPS fixes
+  pub light: Health,               // The navigation light
+  pub power_state: PowerState,     // The power policy state
+  pub upload_queue: u32,           // Uploads waiting or in progress
+  pub mem_free_kb: Option<u64>,    // Available memory
+  pub flash_used_pct: Option<f32>, // How full the flash is
+  pub load_avg: Option<f32>,       // The 1 minute load average
+  pub uptime_secs: u64,            // How long the buoy process has been running
+}
+
+impl Heartbeat {
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    let mut out = format!(
+      "hydrophone={},gps={},light={},power={},queue={}",
+      self.hydrophone, self.gps, self.light, self.power_state, self.upload_queue
+    );
+    if let Some(mem_free_kb) = self.mem_free_kb {
+      out.push_str(&format!(",mem_free_kb={}", mem_free_kb));
+    }
+    if let Some(flash_used_pct) = self.flash_used_pct {
+      out.push_str(&format!(",flash_used={:.1}", flash_used_pct));
+    }
+    if let Some(load_avg) = self.load_avg {
+      out.push_str(&format!(",load={:.2}", load_avg));
+    }
+    out.push_str(&format!(",uptime={}", self.uptime_secs));
+    out
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<Heartbeat, GiftError> {
+    let mut heartbeat = Heartbeat {
+      hydrophone: Health::Off,
+      gps: Health::Off,
+      light: Health::Off,
+      power_state: PowerState::Normal,
+      upload_queue: 0,
+      mem_free_kb: None,
+      flash_used_pct: None,
+      load_avg: None,
+      uptime_secs: 0,
+    };
+    let mut has_power = false;
+
+    for (key, value) in key_values(value)? {
+      match key {
+        "hydrophone" => heartbeat.hydrophone = value.parse()?,
+        "gps" => heartbeat.gps = value.parse()?,
+        "light" => heartbeat.light = value.parse()?,
+        "power" => {
+          heartbeat.power_state = value.parse()?;
+          has_power = true;
+        }
+        "queue" => heartbeat.upload_queue = value.parse()?,
+        "mem_free_kb" => heartbeat.mem_free_kb = Some(value.parse()?),
+        "flash_used" => heartbeat.flash_used_pct = Some(value.parse()?),
+        "load" => heartbeat.load_avg = Some(value.parse()?),
+        "uptime" => heartbeat.uptime_secs = value.parse()?,
+        _ => debug!("Heartbeat::decode(): ignoring '{}'", key),
+      }
+    }
+
+    if has_power {
+      Ok(heartbeat)
+    } else {
+      Err(GiftError::ParseTelemetry)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::heartbeat::*;
+
+  #[test]
+  fn test_encode_decode() {
+    let heartbeat = Heartbeat {
+      hydrophone: Health::Ok,
+      gps: Health::Degraded,
+      light: Health::Off,
+      power_state: PowerState::Normal,
+      upload_queue: 1,
+      mem_free_kb: Some(20480),
+      flash_used_pct: Some(61.2),
+      load_avg: Some(0.35),
+      uptime_secs: 86400,
+    };
+    let encoded = heartbeat.encode();
+    assert_eq!(
+      "hydrophone=ok,gps=degraded,light=off,power=normal,queue=1,mem_free_kb=20480,\
+       flash_used=61.2,load=0.35,uptime=86400",
+      encoded
+    );
+    assert_eq!(heartbeat, Heartbeat::decode(&encoded).unwrap());
+
+    let heartbeat = Heartbeat {
+      mem_free_kb: None,
+      flash_used_pct: None,
+      load_avg: None,
+      ..heartbeat
+    };
+    assert_eq!(heartbeat, Heartbeat::decode(&heartbeat.encode()).unwrap());
+
+    assert!(Heartbeat::decode("hydrophone=ok").is_err());
+    assert!(Heartbeat::decode("hydrophone=fine,power=normal").is_err());
+  }
+}
@@ -31,11 +31,34 @@ extern crate url;
 extern crate log;
 extern crate env_logger;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use core::time::Duration;
+use std::time::Instant;
 
+use crate::clock::{ClockStatus, TimeSource};
+
+use crate::drift_alert::DriftAlert;
+use crate::errors::GiftError;
+use crate::light_status::LightStatus;
+use crate::modem::ModemStatus;
+use crate::position::Position;
+use crate::power_state::{PowerState, Transition};
+use crate::session::Session;
+use crate::task_restarts::TaskRestarts;
+
+pub mod clock;
 pub mod commands;
+pub mod drift_alert;
 pub mod errors;
+pub mod heartbeat;
+pub mod light_status;
+pub mod link_stats;
+pub mod modem;
+pub mod nmea;
+pub mod position;
+pub mod power_state;
+pub mod session;
+pub mod task_restarts;
 
 //
 //                     ####### #     #  #####    ###
@@ -49,18 +72,20 @@ pub mod errors;
 
 pub const BUOY_ID: &str = "1";
 
+// The per-device configuration, relative to the working directory (/home/root)
+pub const BUOY_CONFIG_PATH: &str = "./buoy.toml";
+// The voltage calibration written by `buoy calibrate`, it replaces [voltage] in buoy.toml
+pub const BUOY_CALIBRATION_PATH: &str = "./buoy.calibration.toml";
+// Uploads that could not be sent before going to sleep, they are sent on waking
+pub const BUOY_SPOOL_PATH: &str = "./spool";
+// The state kept over reboots and sleeps, see state_store.rs
+pub const BUOY_STATE_PATH: &str = "./buoy.state";
+
 const SEND_INT: u64 = 60 * 5;
 pub const FX30_SEND_INTERVAL: Duration = Duration::from_secs(SEND_INT);
 pub const FX30_RECORD_LEN: u64 = SEND_INT / 8; // How long to record for. We will have this many simultanous upload connections.
 pub const FX30_NO_DATA_WAIT: Duration = Duration::from_secs(1800); // How long to wait for hydrophone to send data before we ignore it.
 
-// Power management stuff
-pub const FX30_PM_TIME_AWAKE: Duration = Duration::from_secs(SEND_INT * 2 - 60);
-pub const FX30_PM_POWER_MEDIUM_THRESH: f32 = 12.0;
-pub const FX30_PM_POWER_LOW_THRESH: f32 = 11.0;
-pub const FX30_PM_POWER_MEDIUM_SLEEP_TIME_SEC: usize = 30 * 60;
-pub const FX30_PM_POWER_LOW_SLEEP_TIME_SEC: usize = 3 * 60 * 60;
-
 // How long to transmit data before we timeout
 pub const FX30_UPLOAD_SEND_TIMEOUT: Duration = Duration::from_secs(180);
 
@@ -73,20 +98,22 @@ pub const SW_VERSION: &str = env!("CARGO_PKG_VERSION"); // The version number
 
 // This is the ADC GPIO on the FX30.  It's the Green wire.
 pub const GPIO_ADC_PATH: &str = "/sys/class/hwmon/hwmon0/device/mpp_05";
+// The navigation lamp current sense ADC, if one is wired up.  See [light.current_sense]
+pub const LAMP_CURRENT_ADC_PATH: &str = "/sys/class/hwmon/hwmon0/device/mpp_04";
+// The state of the cellular data interface, see fx30/etc/iptables.rules
+pub const MODEM_DATA_OPERSTATE: &str = "/sys/class/net/rmnet0/operstate";
 pub const SERIAL_PATH: &str = "/dev/ttyUSB0";
 pub const SERIAL_BAUD: u32 = 230_400; // or 460_800 (original 288_000)
 pub const SERIAL_BUF_SIZE: usize = 16384;
 
 pub const GPS_ACQUISITION_PERIOD: Duration = Duration::from_secs(15 * 60); // How often we should probe the GPS, in seconds
 pub const GPS_SCRIPT: &str = "/home/root/gps.sh";
+pub const GPS_SCRIPT_TIMEOUT: Duration = Duration::from_secs(60); // gps.sh gives up after 50 seconds
 pub const ULPM_SCRIPT: &str = "/home/root/sms_scripts/ulpm.sh";
 pub const FX30_BIN_NAME: &str = "buoy";
 
 pub const BUOY_NAV_LIGHT_GPIO: &str = "/sys/class/gpio/gpio56/value";
-pub const BUOY_NAV_LIGHT_LONG_INT: Duration = Duration::from_millis(16 * 1000); // 18 seconds
-pub const BUOY_NAV_LIGHT_NUM_SHORT: u64 = 5;
-pub const BUOY_NAV_LIGHT_BLINK_OFF: Duration = Duration::from_millis(400); // 4 seconds worth of flashing
-pub const BUOY_NAV_LIGHT_BLINK_ON: Duration = Duration::from_millis(100);
+pub const BUOY_NAV_LIGHT_CHARACTER: &str = "Fl(5)Y.20s"; // The default light character, see light.rs
 
 pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-20", b"hq-22"];
 
@@ -120,26 +147,80 @@ pub const HOME_SERVER_URL: &str = concat!("https://", env!("DOMAIN"), ":4433");
 
 // Protocol specific
 pub const QUIC_PORT: u16 = 4433;
+pub const WEB_PORT: u16 = 8080; // The dashboard, over plain HTTP
 pub const END_BOUNDARY: &str = "------END!!!";
 pub const HTTP_HEADER_END: &str = "\r\n\r\n";
+pub const HEADER_SLEEP: &str = "Sleep-Secs"; // How long the buoy is going to sleep for
+pub const HEADER_BATTERY_SOC: &str = "Battery-SoC"; // The battery state of charge, in %
+pub const HEADER_BATTERY_TREND: &str = "Battery-Trend"; // The battery voltage trend in V/hour
 
 #[derive(Clone)]
 pub struct BuoyData {
-  pub id: &'static str,      // The buoy id
-  pub hydrophone: Vec<u8>,   // The raw hydrophone data
-  pub voltage: f32,          // The voltage read from the battery voltage sensor
-  pub dropped_blocks: usize, // The number of dropped blocks
-  pub gps: String,           // The GPS location, if available
-  pub start_time: String,    // The start time of the recording
-  pub uptime: i64,           // The uptime of the buoy operating system
+  pub id: &'static str,                   // The buoy id
+  pub hydrophone: Vec<u8>,                // The raw hydrophone data
+  pub voltage: f32,                       // The filtered battery voltage
+  pub battery_soc: f32,                   // The estimated battery state of charge, in %
+  pub battery_trend: Option<f32>,         // The battery voltage trend in V/hour
+  pub dropped_blocks: usize,              // The number of dropped blocks
+  pub position: Option<Position>,         // The last GPS fix, if available
+  pub start_time: String,                 // The start time of the recording
+  pub start_instant: Instant,             // The start time on the monotonic clock
+  pub start_time_source: TimeSource,      // How `start_time` was derived
+  pub clock: Option<ClockStatus>,         // The state of the buoy's clock
+  pub uptime: i64,                        // The uptime of the buoy operating system
+  pub power_state: PowerState,            // The current power state
+  pub power_transitions: Vec<Transition>, // Power state changes since the last upload
+  pub drift_alert: Option<DriftAlert>,    // Set when the buoy is outside its watch circle
+  pub light: Option<LightStatus>,         // The navigation light status
+  pub restarts: TaskRestarts,             // Task restarts by the supervisor
+  pub modem: Option<ModemStatus>,         // The modem state when the upload started
+  pub session: Option<Session>,           // Set on the first upload after the buoy starts
 }
 
 #[derive(Clone)]
 pub enum ControllerAction {
   CtrlBuoyData(BuoyData),
   CtrlServerCmd(crate::commands::FX30Command),
+  CtrlDriftAlert(DriftAlert),             // Send the alert now
+  CtrlServerTime(DateTime<Utc>, Instant), // The server's Date, and when it was received
+  CtrlRecordingStopped,                   // The last recording before sleeping has been sent
 }
 
 pub fn date_now() -> String {
-  Utc::now().format("%Y%m%dT%H%M%S.%3fZ").to_string()
+  crate::clock::format_date(&Utc::now())
+}
+
+///
+/// Split a telemetry header value into its `key=value` pairs, e.g.
+/// `uploads=2,failures=1`.  Empty pairs are skipped.  The decoders ignore the
+/// keys they don't know so that newer buoys can add values.
+///
+pub fn key_values(value: &str) -> Result<Vec<(&str, &str)>, GiftError> {
+  value
+    .split(',')
+    .map(str::trim)
+    .filter(|pair| !pair.is_empty())
+    .map(|pair| {
+      let mut kv = pair.splitn(2, '=');
+      match (kv.next(), kv.next()) {
+        (Some(key), Some(value)) => Ok((key, value)),
+        _ => Err(GiftError::ParseTelemetry),
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::*;
+
+  #[test]
+  fn test_key_values() {
+    assert_eq!(
+      vec![("uploads", "2"), ("fail.connect", "1"), ("note", "a=b")],
+      key_values(" uploads=2,,fail.connect=1 ,note=a=b").unwrap()
+    );
+    assert!(key_values("").unwrap().is_empty());
+    assert!(key_values("uploads=2,failures").is_err());
+  }
 }
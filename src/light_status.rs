@@ -0,0 +1,139 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+///
+/// The state of the navigation light, a dead light is a maritime compliance
+/// problem.  It's sent in the `Light-Status` header of every upload as
+/// `key=value` pairs, e.g.:
+///
+/// ```text
+/// active=true,flashes=1520,write=0,readback=0,current=3,fault=no_current
+/// ```
+///
+/// The counts are since the buoy started.  `fault` is only there while the
+/// light has failed several times in a row.
+///
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::GiftError;
+use crate::key_values;
+
+pub const HEADER_LIGHT_STATUS: &str = "Light-Status";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightFault {
+  Write,     // Writing the GPIO failed
+  Readback,  // The GPIO did not read back what was written
+  NoCurrent, // The lamp did not draw current during a flash
+}
+
+impl fmt::Display for LightFault {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      LightFault::Write => "write",
+      LightFault::Readback => "readback",
+      LightFault::NoCurrent => "no_current",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for LightFault {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "write" => Ok(LightFault::Write),
+      "readback" => Ok(LightFault::Readback),
+      "no_current" => Ok(LightFault::NoCurrent),
+      _ => Err(GiftError::ParseTelemetry),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LightStatus {
+  pub active: bool,              // The light is flashing, i.e. it's night
+  pub flashes: u64,              // Flashes that were checked and good
+  pub write_failures: u32,       // GPIO writes that failed
+  pub readback_failures: u32,    // GPIO reads that did not match the write
+  pub current_failures: u32,     // Flashes without lamp current
+  pub fault: Option<LightFault>, // The last failure, while the light is faulty
+}
+
+impl LightStatus {
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    let mut out = format!(
+      "active={},flashes={},write={},readback={},current={}",
+      self.active, self.flashes, self.write_failures, self.readback_failures, self.current_failures
+    );
+    if let Some(fault) = self.fault {
+      out.push_str(&format!(",fault={}", fault));
+    }
+    out
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<LightStatus, GiftError> {
+    let mut status = LightStatus::default();
+
+    for (key, value) in key_values(value)? {
+      match key {
+        "active" => status.active = value.parse().map_err(|_| GiftError::ParseTelemetry)?,
+        "flashes" => status.flashes = value.parse()?,
+        "write" => status.write_failures = value.parse()?,
+        "readback" => status.readback_failures = value.parse()?,
+        "current" => status.current_failures = value.parse()?,
+        "fault" => status.fault = Some(value.parse()?),
+        _ => debug!("LightStatus::decode(): ignoring '{}'", key),
+      }
+    }
+
+    Ok(status)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::light_status::*;
+
+  #[test]
+  fn test_encode_decode() {
+    let status = LightStatus {
+      active: true,
+      flashes: 1520,
+      write_failures: 0,
+      readback_failures: 0,
+      current_failures: 3,
+      fault: Some(LightFault::NoCurrent),
+    };
+    assert_eq!(
+      "active=true,flashes=1520,write=0,readback=0,current=3,fault=no_current",
+      status.encode()
+    );
+    assert_eq!(status, LightStatus::decode(&status.encode()).unwrap());
+
+    let status = LightStatus::default();
+    assert_eq!(status, LightStatus::decode(&status.encode()).unwrap());
+    assert!(LightStatus::decode("active=maybe").is_err());
+    assert!(LightStatus::decode("fault=broken").is_err());
+  }
+}
@@ -0,0 +1,257 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// Statistics about the cellular link, collected by the buoy for each upload
+/// and sent to the server in the `Link-Stats` header of the next upload.
+///
+/// The header value is a list of `key=value` pairs separated by commas, e.g.:
+///
+/// ```text
+/// uploads=2,failures=1,bytes=81920,handshake_ms=1630,rtt_ms=702,upload_ms=9020,retransmits=4,fail.connect=1
+/// ```
+///
+/// Times are sums over all successful uploads so that reports can be merged,
+/// use the `avg_*` functions to get the per upload values.
+///
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::errors::GiftError;
+use crate::key_values;
+
+pub const HEADER_LINK_STATS: &str = "Link-Stats";
+
+// Why an upload failed
+pub const FAIL_CONNECT: &str = "connect"; // Could not create the endpoint or connect
+pub const FAIL_TIMEOUT: &str = "timeout"; // The handshake, send or response timed out
+pub const FAIL_STREAM: &str = "stream"; // Could not open the stream
+pub const FAIL_SEND: &str = "send"; // Sending the request failed
+pub const FAIL_RESPONSE: &str = "response"; // Reading the response failed
+pub const FAIL_SERVER: &str = "server"; // The server answered with an error status
+pub const FAIL_OTHER: &str = "other"; // Anything else
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkStats {
+  pub uploads: u32,                             // Number of successful uploads
+  pub failures: u32,                            // Number of failed uploads
+  pub bytes: u64,                               // Bytes sent by the successful uploads
+  pub handshake_ms: u64,                        // Sum of the QUIC handshake times
+  pub rtt_ms: u64,                              // Sum of the request finished to response times
+  pub upload_ms: u64,                           // Sum of the total upload times
+  pub retransmits: u64,                         // Packets QUIC declared lost and sent again
+  pub failures_by_cause: BTreeMap<String, u32>, // Failure count for each FAIL_* cause
+  pub close_codes: BTreeMap<String, u32>,       // QUIC close codes seen, e.g. "app.0"
+}
+
+fn millis(d: Duration) -> u64 {
+  d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+impl LinkStats {
+  pub fn is_empty(&self) -> bool {
+    *self == LinkStats::default()
+  }
+
+  /// Record a successful upload.
+  pub fn record_upload(
+    &mut self,
+    bytes: usize,
+    handshake: Duration,
+    rtt: Duration,
+    total: Duration,
+  ) {
+    self.uploads += 1;
+    self.bytes += bytes as u64;
+    self.handshake_ms += millis(handshake);
+    self.rtt_ms += millis(rtt);
+    self.upload_ms += millis(total);
+  }
+
+  /// Record the packets an upload had to retransmit, successful or not.
+  pub fn record_retransmits(&mut self, count: u64) {
+    self.retransmits += count;
+  }
+
+  /// Record a failed upload, `cause` should be one of the FAIL_* values.
+  pub fn record_failure(&mut self, cause: &str) {
+    self.failures += 1;
+    *self
+      .failures_by_cause
+      .entry(String::from(cause))
+      .or_insert(0) += 1;
+  }
+
+  /// Record the code a connection was closed with.
+  pub fn record_close_code(&mut self, code: &str) {
+    *self.close_codes.entry(String::from(code)).or_insert(0) += 1;
+  }
+
+  /// Take the statistics collected so far, leaving this empty.
+  pub fn take(&mut self) -> LinkStats {
+    std::mem::replace(self, LinkStats::default())
+  }
+
+  /// Add the statistics from `other`, used when a report could not be sent.
+  pub fn merge(&mut self, other: LinkStats) {
+    self.uploads += other.uploads;
+    self.failures += other.failures;
+    self.bytes += other.bytes;
+    self.handshake_ms += other.handshake_ms;
+    self.rtt_ms += other.rtt_ms;
+    self.upload_ms += other.upload_ms;
+    self.retransmits += other.retransmits;
+    for (cause, count) in other.failures_by_cause {
+      *self.failures_by_cause.entry(cause).or_insert(0) += count;
+    }
+    for (code, count) in other.close_codes {
+      *self.close_codes.entry(code).or_insert(0) += count;
+    }
+  }
+
+  fn avg(&self, sum: u64) -> Option<f32> {
+    if self.uploads == 0 {
+      None
+    } else {
+      Some(sum as f32 / self.uploads as f32)
+    }
+  }
+
+  pub fn avg_handshake_ms(&self) -> Option<f32> {
+    self.avg(self.handshake_ms)
+  }
+
+  pub fn avg_rtt_ms(&self) -> Option<f32> {
+    self.avg(self.rtt_ms)
+  }
+
+  /// The throughput of the successful uploads in kB/s.
+  pub fn avg_kbps(&self) -> Option<f32> {
+    if self.upload_ms == 0 {
+      None
+    } else {
+      Some((self.bytes as f32 / 1024.0) / (self.upload_ms as f32 / 1000.0))
+    }
+  }
+
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    let mut out = format!(
+      "uploads={},failures={},bytes={},handshake_ms={},rtt_ms={},upload_ms={},retransmits={}",
+      self.uploads,
+      self.failures,
+      self.bytes,
+      self.handshake_ms,
+      self.rtt_ms,
+      self.upload_ms,
+      self.retransmits
+    );
+    for (cause, count) in &self.failures_by_cause {
+      out.push_str(&format!(",fail.{}={}", cause, count));
+    }
+    for (code, count) in &self.close_codes {
+      out.push_str(&format!(",close.{}={}", code, count));
+    }
+    out
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<LinkStats, GiftError> {
+    let mut stats = LinkStats::default();
+
+    for (key, value) in key_values(value)? {
+      match key {
+        "uploads" => stats.uploads = value.parse()?,
+        "failures" => stats.failures = value.parse()?,
+        "bytes" => stats.bytes = value.parse()?,
+        "handshake_ms" => stats.handshake_ms = value.parse()?,
+        "rtt_ms" => stats.rtt_ms = value.parse()?,
+        "upload_ms" => stats.upload_ms = value.parse()?,
+        "retransmits" => stats.retransmits = value.parse()?,
+        _ if key.starts_with("fail.") => {
+          stats
+            .failures_by_cause
+            .insert(String::from(&key[5..]), value.parse()?);
+        }
+        _ if key.starts_with("close.") => {
+          stats
+            .close_codes
+            .insert(String::from(&key[6..]), value.parse()?);
+        }
+        _ => debug!("LinkStats::decode(): ignoring '{}'", key),
+      }
+    }
+
+    Ok(stats)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::link_stats::*;
+
+  #[test]
+  fn test_encode_decode() {
+    let mut stats = LinkStats::default();
+    stats.record_upload(
+      2048,
+      Duration::from_millis(800),
+      Duration::from_millis(350),
+      Duration::from_millis(2000),
+    );
+    stats.record_failure(FAIL_CONNECT);
+    stats.record_failure(FAIL_TIMEOUT);
+    stats.record_failure(FAIL_TIMEOUT);
+    stats.record_close_code("app.0");
+    stats.record_retransmits(3);
+
+    let encoded = stats.encode();
+    assert_eq!(
+      "uploads=1,failures=3,bytes=2048,handshake_ms=800,rtt_ms=350,upload_ms=2000,\
+       retransmits=3,fail.connect=1,fail.timeout=2,close.app.0=1",
+      encoded
+    );
+    assert_eq!(stats, LinkStats::decode(&encoded).unwrap());
+    assert_eq!(Some(1.0), stats.avg_kbps());
+  }
+
+  #[test]
+  fn test_decode_invalid() {
+    assert!(LinkStats::decode("uploads=x").is_err());
+    assert!(LinkStats::decode("uploads").is_err());
+    assert_eq!(
+      1,
+      LinkStats::decode("uploads=1,new_thing=3").unwrap().uploads
+    );
+  }
+
+  #[test]
+  fn test_take_and_merge() {
+    let mut stats = LinkStats::default();
+    stats.record_failure(FAIL_SEND);
+    let report = stats.take();
+    assert!(stats.is_empty());
+
+    stats.record_failure(FAIL_SEND);
+    stats.merge(report);
+    assert_eq!(2, stats.failures);
+    assert_eq!(Some(&2), stats.failures_by_cause.get(FAIL_SEND));
+  }
+}
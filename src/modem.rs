@@ -0,0 +1,254 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+///
+/// A snapshot of the cellular modem state, so failed uploads can be told apart
+/// from bad coverage.  On the FX30 it's parsed from the output of `cm radio`
+/// and the state of the data interface, which are `Name: value` lines, e.g.:
+///
+/// ```text
+/// Power:                         ON
+/// Current Network Operator:      Spark NZ
+/// Current RAT:                   LTE network (LE_MRC_RAT_LTE)
+/// Status:                        Registered, home network (LE_MRC_REG_HOME)
+/// Signal:                        Strong signal strength (4)
+/// Cell ID:                       0x0A1B2C3
+/// RSSI:                          -65 dBm
+/// RSRP:                          -95 dBm
+/// RSRQ:                          -10.5 dB
+/// Connected:                     yes
+/// ```
+///
+/// It's sent in the `Modem` header of every upload and heartbeat, e.g.:
+///
+/// ```text
+/// rat=LTE,reg=home,rssi=-65,rsrp=-95,rsrq=-10.5,cell=0A1B2C3,operator=Spark NZ,data=true
+/// ```
+///
+/// Values the modem did not report are left out.
+///
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::errors::GiftError;
+use crate::key_values;
+
+pub const HEADER_MODEM: &str = "Modem";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModemStatus {
+  pub rat: Option<String>,          // Radio access technology, e.g. "LTE"
+  pub registration: Option<String>, // Network registration, e.g. "home" or "roaming"
+  pub rssi_dbm: Option<i32>,        // Received signal strength
+  pub rsrp_dbm: Option<i32>,        // LTE reference signal received power
+  pub rsrq_db: Option<f32>,         // LTE reference signal received quality
+  pub cell_id: Option<String>,      // The serving cell, in hex
+  pub operator: Option<String>,     // The network operator name
+  pub data_connected: Option<bool>, // The data session is up
+}
+
+/// The value of a `Name: value` line, the name is not case sensitive.
+fn field<'a>(output: &'a str, name: &str) -> Option<&'a str> {
+  output
+    .lines()
+    .filter_map(|line| {
+      let mut parts = line.splitn(2, ':');
+      Some((parts.next()?.trim(), parts.next()?.trim()))
+    })
+    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+    .map(|(_, v)| v)
+    .filter(|v| !v.is_empty())
+}
+
+/// The first number in a value, e.g. -95 in "-95 dBm".
+fn number(value: &str) -> Option<&str> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(r"-?\d+(\.\d+)?").unwrap();
+  }
+  RE.find(value).map(|m| m.as_str())
+}
+
+/// The suffix of a Legato enum name, e.g. "LTE" from "... (LE_MRC_RAT_LTE)".
+fn legato_enum(value: &str, prefix: &str) -> Option<String> {
+  let start = value.find(prefix)? + prefix.len();
+  let rest = &value[start..];
+  let end = rest.find(')').unwrap_or(rest.len());
+  Some(rest[..end].to_lowercase())
+}
+
+/// Header values can't have commas or equals signs.
+fn clean(value: &str) -> String {
+  value
+    .replace(|c| c == ',' || c == '=', " ")
+    .trim()
+    .to_string()
+}
+
+impl ModemStatus {
+  ///
+  /// Parse the output of `cm radio` and the data session state, missing or
+  /// unknown values are left as None.
+  ///
+  pub fn parse(output: &str) -> ModemStatus {
+    let rat = field(output, "Current RAT").map(|v| {
+      legato_enum(v, "LE_MRC_RAT_")
+        .map(|rat| rat.to_uppercase())
+        .unwrap_or_else(|| clean(v.split_whitespace().next().unwrap_or(v)))
+    });
+    let cell_id = field(output, "Cell ID").map(|v| {
+      let v = v.trim_start_matches("0x").trim_start_matches("0X");
+      v.split_whitespace().next().unwrap_or(v).to_uppercase()
+    });
+
+    ModemStatus {
+      rat,
+      registration: field(output, "Status").and_then(|v| legato_enum(v, "LE_MRC_REG_")),
+      rssi_dbm: field(output, "RSSI")
+        .and_then(number)
+        .and_then(|n| n.parse().ok()),
+      rsrp_dbm: field(output, "RSRP")
+        .and_then(number)
+        .and_then(|n| n.parse().ok()),
+      rsrq_db: field(output, "RSRQ")
+        .and_then(number)
+        .and_then(|n| n.parse().ok()),
+      cell_id,
+      operator: field(output, "Current Network Operator").map(clean),
+      data_connected: field(output, "Connected").map(|v| v.eq_ignore_ascii_case("yes")),
+    }
+  }
+
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    let mut fields = Vec::new();
+    if let Some(rat) = &self.rat {
+      fields.push(format!("rat={}", rat));
+    }
+    if let Some(registration) = &self.registration {
+      fields.push(format!("reg={}", registration));
+    }
+    if let Some(rssi) = self.rssi_dbm {
+      fields.push(format!("rssi={}", rssi));
+    }
+    if let Some(rsrp) = self.rsrp_dbm {
+      fields.push(format!("rsrp={}", rsrp));
+    }
+    if let Some(rsrq) = self.rsrq_db {
+      fields.push(format!("rsrq={:.1}", rsrq));
+    }
+    if let Some(cell_id) = &self.cell_id {
+      fields.push(format!("cell={}", cell_id));
+    }
+    if let Some(operator) = &self.operator {
+      fields.push(format!("operator={}", operator));
+    }
+    if let Some(connected) = self.data_connected {
+      fields.push(format!("data={}", connected));
+    }
+    fields.join(",")
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<ModemStatus, GiftError> {
+    let mut status = ModemStatus::default();
+
+    for (key, value) in key_values(value)? {
+      match key {
+        "rat" => status.rat = Some(String::from(value)),
+        "reg" => status.registration = Some(String::from(value)),
+        "rssi" => status.rssi_dbm = Some(value.parse()?),
+        "rsrp" => status.rsrp_dbm = Some(value.parse()?),
+        "rsrq" => status.rsrq_db = Some(value.parse()?),
+        "cell" => status.cell_id = Some(String::from(value)),
+        "operator" => status.operator = Some(String::from(value)),
+        "data" => {
+          status.data_connected = Some(value.parse().map_err(|_| GiftError::ParseTelemetry)?)
+        }
+        _ => debug!("ModemStatus::decode(): ignoring '{}'", key),
+      }
+    }
+
+    Ok(status)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::modem::*;
+
+  const CM_OUTPUT: &str = "Power:                         ON\n\
+                           Current Network Operator:      Spark NZ\n\
+                           Current RAT:                   LTE network (LE_MRC_RAT_LTE)\n\
+                           Status:                        Registered, home network (LE_MRC_REG_HOME)\n\
+                           Signal:                        Strong signal strength (4)\n\
+                           Cell ID:                       0x0a1b2c3\n\
+                           RSSI:                          -65 dBm\n\
+                           RSRP:                          -95 dBm\n\
+                           RSRQ:                          -10.5 dB\n\
+                           \n\
+                           Index:                         1\n\
+                           APN:                           internet\n\
+                           Connected:                     yes\n";
+
+  #[test]
+  fn test_parse() {
+    let status = ModemStatus::parse(CM_OUTPUT);
+    assert_eq!(
+      ModemStatus {
+        rat: Some(String::from("LTE")),
+        registration: Some(String::from("home")),
+        rssi_dbm: Some(-65),
+        rsrp_dbm: Some(-95),
+        rsrq_db: Some(-10.5),
+        cell_id: Some(String::from("0A1B2C3")),
+        operator: Some(String::from("Spark NZ")),
+        data_connected: Some(true),
+      },
+      status
+    );
+
+    // No coverage
+    let status = ModemStatus::parse(
+      "Power: ON\nCurrent RAT: \nStatus: Not registered, searching (LE_MRC_REG_SEARCHING)\n\
+       Connected: no\n",
+    );
+    assert_eq!(None, status.rat);
+    assert_eq!(Some(String::from("searching")), status.registration);
+    assert_eq!(Some(false), status.data_connected);
+    assert_eq!(ModemStatus::default(), ModemStatus::parse(""));
+  }
+
+  #[test]
+  fn test_encode_decode() {
+    let status = ModemStatus::parse(CM_OUTPUT);
+    assert_eq!(
+      "rat=LTE,reg=home,rssi=-65,rsrp=-95,rsrq=-10.5,cell=0A1B2C3,operator=Spark NZ,data=true",
+      status.encode()
+    );
+    assert_eq!(status, ModemStatus::decode(&status.encode()).unwrap());
+
+    let status = ModemStatus {
+      operator: Some(clean("Vodafone, NZ")),
+      ..ModemStatus::default()
+    };
+    assert_eq!("operator=Vodafone  NZ", status.encode());
+    assert_eq!(status, ModemStatus::decode(&status.encode()).unwrap());
+    assert!(ModemStatus::decode("rssi=strong").is_err());
+  }
+}
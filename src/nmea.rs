@@ -0,0 +1,344 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// NMEA 0183 parser for external GPS receivers.  Only the sentences needed for
+/// a position fix are parsed, from any talker (GP, GN, GL, GA, BD):
+///
+///  - GGA: time, position, fix quality, satellites and HDOP
+///  - RMC: time, date, position and whether it's valid
+///  - GSA: 2D/3D fix mode
+///
+/// The sentences of one fix are added to `NmeaFix`, which then creates the
+/// `Position`.
+///
+use crate::clock::TimeSource;
+use crate::errors::GiftError;
+use crate::position::{FixType, Position};
+
+// The receiver's range error, used to estimate the accuracy from the HDOP
+const NMEA_UERE_M: f32 = 5.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gga {
+  pub time: String, // hhmmss.ss UTC
+  pub latitude: Option<f64>,
+  pub longitude: Option<f64>,
+  pub quality: u8, // 0 is no fix, 6 is estimated (dead reckoning)
+  pub satellites: Option<u32>,
+  pub hdop: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rmc {
+  pub time: String, // hhmmss.ss UTC
+  pub valid: bool,
+  pub latitude: Option<f64>,
+  pub longitude: Option<f64>,
+  pub date: String, // ddmmyy
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gsa {
+  pub mode: u8, // 1 is no fix, 2 is 2D, 3 is 3D
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sentence {
+  Gga(Gga),
+  Rmc(Rmc),
+  Gsa(Gsa),
+  Other(String), // Any other sentence, with its type, e.g. "GSV"
+}
+
+///
+/// Check the `*hh` checksum, it's the XOR of the bytes between `$` and `*`.
+///
+fn check_sentence(line: &str) -> Result<&str, GiftError> {
+  let line = line.trim();
+  // NMEA is ASCII, anything else is line noise
+  if !line.starts_with('$') || !line.is_ascii() {
+    return Err(GiftError::ParseNmea);
+  }
+
+  match line[1..].rfind('*') {
+    Some(idx) => {
+      let (body, checksum) = (&line[1..=idx], &line[idx + 2..]);
+      let expected = u8::from_str_radix(checksum, 16).map_err(|_| GiftError::ParseNmea)?;
+      let actual = body.bytes().fold(0, |acc, b| acc ^ b);
+      if actual != expected {
+        return Err(GiftError::ParseNmea);
+      }
+      Ok(body)
+    }
+    // The checksum is optional
+    None => Ok(&line[1..]),
+  }
+}
+
+///
+/// Parse a latitude or longitude, e.g. "3650.59752" and "S".
+///
+fn parse_coord(value: &str, hemisphere: &str) -> Result<Option<f64>, GiftError> {
+  if value.is_empty() {
+    return Ok(None);
+  }
+  let dot = value.find('.').unwrap_or_else(|| value.len());
+  if dot < 3 {
+    return Err(GiftError::ParseNmea);
+  }
+
+  let (degrees, minutes) = match (value.get(..dot - 2), value.get(dot - 2..)) {
+    (Some(degrees), Some(minutes)) => (degrees.parse::<f64>()?, minutes.parse::<f64>()?),
+    _ => return Err(GiftError::ParseNmea),
+  };
+  let coord = degrees + minutes / 60.0;
+  match hemisphere {
+    "N" | "E" => Ok(Some(coord)),
+    "S" | "W" => Ok(Some(-coord)),
+    _ => Err(GiftError::ParseNmea),
+  }
+}
+
+fn parse_opt<T: std::str::FromStr>(value: &str) -> Result<Option<T>, GiftError> {
+  if value.is_empty() {
+    Ok(None)
+  } else {
+    value.parse().map(Some).map_err(|_| GiftError::ParseNmea)
+  }
+}
+
+pub fn parse_sentence(line: &str) -> Result<Sentence, GiftError> {
+  let body = check_sentence(line)?;
+  let fields: Vec<&str> = body.split(',').collect();
+  // The talker and the sentence type, e.g. "GPGGA"
+  let kind = match fields[0].get(2..) {
+    Some(kind) if fields[0].len() == 5 => kind,
+    _ => return Err(GiftError::ParseNmea),
+  };
+  let field = |i: usize| fields.get(i).cloned().unwrap_or("");
+
+  match kind {
+    "GGA" => Ok(Sentence::Gga(Gga {
+      time: String::from(field(1)),
+      latitude: parse_coord(field(2), field(3))?,
+      longitude: parse_coord(field(4), field(5))?,
+      quality: parse_opt(field(6))?.unwrap_or(0),
+      satellites: parse_opt(field(7))?,
+      hdop: parse_opt(field(8))?,
+    })),
+    "RMC" => Ok(Sentence::Rmc(Rmc {
+      time: String::from(field(1)),
+      valid: field(2) == "A",
+      latitude: parse_coord(field(3), field(4))?,
+      longitude: parse_coord(field(5), field(6))?,
+      date: String::from(field(9)),
+    })),
+    "GSA" => Ok(Sentence::Gsa(Gsa {
+      mode: parse_opt(field(2))?.unwrap_or(1),
+    })),
+    other => Ok(Sentence::Other(String::from(other))),
+  }
+}
+
+///
+/// Collects the sentences of a fix.
+///
+#[derive(Clone, Debug, Default)]
+pub struct NmeaFix {
+  gga: Option<Gga>,
+  rmc: Option<Rmc>,
+  gsa: Option<Gsa>,
+}
+
+impl NmeaFix {
+  pub fn add(&mut self, sentence: Sentence) {
+    match sentence {
+      Sentence::Gga(gga) => self.gga = Some(gga),
+      Sentence::Rmc(rmc) => self.rmc = Some(rmc),
+      Sentence::Gsa(gsa) => self.gsa = Some(gsa),
+      Sentence::Other(_) => (),
+    }
+  }
+
+  /// There is a GGA with a fix, and the RMC for the same time.
+  pub fn is_complete(&self) -> bool {
+    match (&self.gga, &self.rmc) {
+      (Some(gga), Some(rmc)) => gga.quality > 0 && gga.time == rmc.time,
+      _ => false,
+    }
+  }
+
+  fn fix_time(&self, now: &str) -> (String, TimeSource) {
+    let system = (String::from(now), TimeSource::System);
+    let (time, date) = match (&self.gga, &self.rmc) {
+      (Some(gga), Some(rmc)) if gga.time == rmc.time => (&rmc.time, &rmc.date),
+      (None, Some(rmc)) => (&rmc.time, &rmc.date),
+      _ => return system,
+    };
+    let (hhmmss, yy, mm, dd) = match (
+      time.get(0..6),
+      date.get(4..6),
+      date.get(2..4),
+      date.get(0..2),
+    ) {
+      (Some(hhmmss), Some(yy), Some(mm), Some(dd)) if date.len() == 6 => (hhmmss, yy, mm, dd),
+      _ => return system,
+    };
+
+    // hhmmss.ss => hhmmss.sss
+    let millis = time
+      .get(6..)
+      .and_then(|frac| format!("0{}", frac).parse::<f64>().ok())
+      .map_or(0, |frac| (frac * 1000.0).round() as u32);
+    let fix_time = format!("20{}{}{}T{}.{:03}Z", yy, mm, dd, hhmmss, millis);
+    (fix_time, TimeSource::Gnss)
+  }
+
+  ///
+  /// The position, if there is a fix.  `now` is used as the fix time when
+  /// there's no RMC date.
+  ///
+  pub fn position(&self, now: &str) -> Option<Position> {
+    let (latitude, longitude) = match (&self.gga, &self.rmc) {
+      (Some(gga), _) if gga.quality > 0 => (gga.latitude?, gga.longitude?),
+      (None, Some(rmc)) if rmc.valid => (rmc.latitude?, rmc.longitude?),
+      _ => return None,
+    };
+
+    let gga = self.gga.as_ref();
+    let fix_type = match (self.gsa.as_ref().map(|g| g.mode), gga.map(|g| g.quality)) {
+      (_, Some(6)) => FixType::Estimated,
+      (Some(2), _) => FixType::Fix2d,
+      _ => FixType::Fix3d,
+    };
+
+    let (fix_time, time_source) = self.fix_time(now);
+    Some(Position {
+      latitude,
+      longitude,
+      h_accuracy: gga.and_then(|g| g.hdop).map(|hdop| hdop * NMEA_UERE_M),
+      fix_time,
+      time_source,
+      fix_type,
+      satellites: gga.and_then(|g| g.satellites),
+      stale: false,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::nmea::*;
+
+  const NOW: &str = "20200830T023600.000Z";
+
+  // From a u-blox receiver on the Waitemata harbour
+  const GGA: &str = "$GNGGA,023517.00,3650.59752,S,17445.41184,E,1,07,1.20,21.3,M,28.1,M,,*65";
+  const RMC: &str = "$GNRMC,023517.00,A,3650.59752,S,17445.41184,E,0.052,,300820,,,A*78";
+  const GSA: &str = "$GNGSA,A,3,10,12,24,25,32,,,,,,,,2.31,1.20,1.97*12";
+
+  #[test]
+  fn test_parse_sentence() {
+    // The examples from the NMEA 0183 standard
+    let gga = parse_sentence("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47");
+    match gga.unwrap() {
+      Sentence::Gga(gga) => {
+        assert_eq!("123519", gga.time);
+        assert!((gga.latitude.unwrap() - 48.1173).abs() < 1e-6);
+        assert!((gga.longitude.unwrap() - 11.516_666_7).abs() < 1e-6);
+        assert_eq!(1, gga.quality);
+        assert_eq!(Some(8), gga.satellites);
+        assert_eq!(Some(0.9), gga.hdop);
+      }
+      s => panic!("not a GGA: {:?}", s),
+    }
+
+    let rmc =
+      parse_sentence("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n");
+    match rmc.unwrap() {
+      Sentence::Rmc(rmc) => {
+        assert!(rmc.valid);
+        assert_eq!("230394", rmc.date);
+      }
+      s => panic!("not a RMC: {:?}", s),
+    }
+
+    let gsa = parse_sentence("$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39").unwrap();
+    assert_eq!(Sentence::Gsa(Gsa { mode: 3 }), gsa);
+
+    let gsv =
+      parse_sentence("$GPGSV,2,1,08,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*75");
+    assert_eq!(Sentence::Other(String::from("GSV")), gsv.unwrap());
+  }
+
+  #[test]
+  fn test_parse_invalid() {
+    // Bad checksum
+    assert!(parse_sentence("$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*38").is_err());
+    // Half a sentence, as read after the serial buffer is cleared
+    assert!(parse_sentence("17445.41184,E,1,07,1.20,21.3,M,28.1,M,,*65").is_err());
+    assert!(parse_sentence("$GNGGA,023517.00,3650.59752,X,17445.41184,E,1,07,1.20").is_err());
+    assert!(parse_sentence("").is_err());
+    // Short and non-ASCII fields
+    assert!(parse_sentence("$G€A,1").is_err());
+    assert!(parse_sentence("$GPGGA,123519,1€.5,N,01131.000,E,1").is_err());
+    assert!(parse_sentence("$GPGGA,123519,1.5,N,01131.000,E,1").is_err());
+    assert!(parse_sentence("$GPRMC,123519,A,4807.038,N,01131.000,E,,,2€45").is_err());
+  }
+
+  #[test]
+  fn test_fix() {
+    let mut fix = NmeaFix::default();
+    fix.add(parse_sentence(GGA).unwrap());
+    assert!(!fix.is_complete());
+    fix.add(parse_sentence(GSA).unwrap());
+    fix.add(parse_sentence(RMC).unwrap());
+    assert!(fix.is_complete());
+
+    let pos = fix.position(NOW).unwrap();
+    assert!((pos.latitude + 36.843292).abs() < 1e-6);
+    assert!((pos.longitude - 174.756864).abs() < 1e-6);
+    assert_eq!(Some(6.0), pos.h_accuracy);
+    assert_eq!("20200830T023517.000Z", pos.fix_time);
+    assert_eq!(TimeSource::Gnss, pos.time_source);
+    assert_eq!(FixType::Fix3d, pos.fix_type);
+    assert_eq!(Some(7), pos.satellites);
+  }
+
+  #[test]
+  fn test_no_fix() {
+    let mut fix = NmeaFix::default();
+    fix.add(parse_sentence("$GNGGA,023518.00,,,,,0,00,99.99,,,,,,*75").unwrap());
+    fix.add(parse_sentence("$GNRMC,023518.00,V,,,,,,,300820,,,N*67").unwrap());
+    assert!(!fix.is_complete());
+    assert_eq!(None, fix.position(NOW));
+  }
+
+  #[test]
+  fn test_short_time() {
+    // A short date or time uses the system time rather than panicking
+    let mut fix = NmeaFix::default();
+    fix.add(parse_sentence("$GPRMC,1235,A,4807.038,N,01131.000,E,,,2303").unwrap());
+    let pos = fix.position(NOW).unwrap();
+    assert_eq!(NOW, pos.fix_time);
+    assert_eq!(TimeSource::System, pos.time_source);
+  }
+}
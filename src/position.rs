@@ -0,0 +1,373 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// A GNSS position fix.  The buoy parses the output of the Legato `gnss` tool
+/// (see gps.sh) and sends the fix to the server in the `GPS-*` headers.
+///
+/// gnss doc: https://docs.legato.io/latest/toolsTarget_gnss.html
+///
+use std::fmt;
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::TimeSource;
+use crate::errors::GiftError;
+
+pub const HEADER_LATITUDE: &str = "GPS-Latitude";
+pub const HEADER_LONGITUDE: &str = "GPS-Longitude";
+pub const HEADER_ACCURACY: &str = "GPS-Accuracy";
+pub const HEADER_FIX_TIME: &str = "GPS-Fix-Time";
+pub const HEADER_TIME_SOURCE: &str = "GPS-Time-Source";
+pub const HEADER_FIX_TYPE: &str = "GPS-Fix-Type";
+pub const HEADER_SATELLITES: &str = "GPS-Satellites";
+pub const HEADER_STALE: &str = "GPS-Stale";
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+///
+/// The great circle distance in metres between two points, using the
+/// haversine formula.
+///
+pub fn distance_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+  let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+  let d_phi = (lat2 - lat1).to_radians();
+  let d_lambda = (lon2 - lon1).to_radians();
+
+  let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+  2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FixType {
+  NoFix,
+  Fix2d,
+  Fix3d,
+  Estimated,
+}
+
+impl fmt::Display for FixType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      FixType::NoFix => "none",
+      FixType::Fix2d => "2d",
+      FixType::Fix3d => "3d",
+      FixType::Estimated => "estimated",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for FixType {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(FixType::NoFix),
+      "2d" => Ok(FixType::Fix2d),
+      "3d" => Ok(FixType::Fix3d),
+      "estimated" => Ok(FixType::Estimated),
+      _ => Err(GiftError::ParseTelemetry),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Position {
+  pub latitude: f64,           // Degrees, positive is north
+  pub longitude: f64,          // Degrees, positive is east
+  pub h_accuracy: Option<f32>, // Horizontal accuracy, in metres
+  pub fix_time: String,        // UTC time of the fix, same format as `date_now()`
+  pub time_source: TimeSource, // How `fix_time` was derived
+  pub fix_type: FixType,
+  pub satellites: Option<u32>, // Satellites used in the fix
+  pub stale: bool,             // The fix is older than the buoy's max age
+}
+
+impl Position {
+  ///
+  /// Parse the output of the `gnss get` commands run by gps.sh, e.g.:
+  ///
+  /// ```text
+  /// Latitude(positive->north) : -36.843292
+  /// Longitude(positive->east) : 174.756864
+  /// hAccuracy                 : 10.0m
+  /// Position state: 3D Fix
+  /// Date(YYYY-MM-DD) 2020-08-30
+  /// Time(HH:MM:SS:ms) 02:35:17:000
+  /// Satellites in View: 12
+  /// Satellites Tracked: 9
+  /// Satellites Used: 7
+  /// ```
+  ///
+  /// Only the latitude and longitude are required.  If the GNSS date and time
+  /// are missing, `now` from the system clock is used as the fix time.
+  ///
+  pub fn parse_gnss(output: &str, now: &str) -> Result<Position, GiftError> {
+    lazy_static! {
+      static ref LAT: Regex =
+        Regex::new(r"Latitude\(positive->north\)\s*:\s*(-?\d+\.?\d*)").unwrap();
+      static ref LON: Regex =
+        Regex::new(r"Longitude\(positive->east\)\s*:\s*(-?\d+\.?\d*)").unwrap();
+      static ref ACC: Regex = Regex::new(r"hAccuracy\s*:\s*(\d+\.?\d*)m").unwrap();
+      static ref STATE: Regex = Regex::new(r"Position state\s*:\s*([\w ]+?)\s*$").unwrap();
+      static ref DATE: Regex = Regex::new(r"Date\(YYYY-MM-DD\)\s*(\d{4})-(\d{2})-(\d{2})").unwrap();
+      static ref TIME: Regex =
+        Regex::new(r"Time\(HH:MM:SS:ms\)\s*(\d{2}):(\d{2}):(\d{2}):(\d{3})").unwrap();
+      static ref SATS: Regex = Regex::new(r"Satellites Used\s*:\s*(\d+)").unwrap();
+    }
+
+    if output.contains("GPS FAILED") || output.contains("Location invalid") {
+      return Err(GiftError::GPSIssue);
+    }
+
+    let field = |re: &Regex| -> Option<String> {
+      output
+        .lines()
+        .filter_map(|line| re.captures(line))
+        .map(|caps| String::from(&caps[1]))
+        .next()
+    };
+
+    let latitude: f64 = field(&LAT).ok_or(GiftError::GPSIssue)?.parse()?;
+    let longitude: f64 = field(&LON).ok_or(GiftError::GPSIssue)?.parse()?;
+    if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+      return Err(GiftError::GPSIssue);
+    }
+
+    let h_accuracy = match field(&ACC) {
+      Some(acc) => Some(acc.parse()?),
+      None => None,
+    };
+    let satellites = match field(&SATS) {
+      Some(sats) => Some(sats.parse()?),
+      None => None,
+    };
+
+    // No state means an old gps.sh, it only prints the location once it has a fix
+    let fix_type = match field(&STATE).as_deref() {
+      Some("No Fix") => FixType::NoFix,
+      Some("2D Fix") => FixType::Fix2d,
+      Some("Estimated Fix") => FixType::Estimated,
+      _ => FixType::Fix3d,
+    };
+
+    let date = DATE.captures(output);
+    let time = TIME.captures(output);
+    let (fix_time, time_source) = match (date, time) {
+      (Some(d), Some(t)) => (
+        format!(
+          "{}{}{}T{}{}{}.{}Z",
+          &d[1], &d[2], &d[3], &t[1], &t[2], &t[3], &t[4]
+        ),
+        TimeSource::Gnss,
+      ),
+      _ => (String::from(now), TimeSource::System),
+    };
+
+    Ok(Position {
+      latitude,
+      longitude,
+      h_accuracy,
+      fix_time,
+      time_source,
+      fix_type,
+      satellites,
+      stale: false,
+    })
+  }
+
+  /// The distance in metres to a point.
+  pub fn distance_to(&self, latitude: f64, longitude: f64) -> f64 {
+    distance_m(self.latitude, self.longitude, latitude, longitude)
+  }
+
+  /// The upload headers, each ending in "\r\n".
+  pub fn to_headers(&self) -> String {
+    let mut headers = format!(
+      "{}: {:.6}\r\n{}: {:.6}\r\n{}: {}\r\n{}: {}\r\n{}: {}\r\n{}: {}\r\n",
+      HEADER_LATITUDE,
+      self.latitude,
+      HEADER_LONGITUDE,
+      self.longitude,
+      HEADER_FIX_TIME,
+      self.fix_time,
+      HEADER_TIME_SOURCE,
+      self.time_source,
+      HEADER_FIX_TYPE,
+      self.fix_type,
+      HEADER_STALE,
+      self.stale,
+    );
+    if let Some(acc) = self.h_accuracy {
+      headers.push_str(&format!("{}: {:.1}\r\n", HEADER_ACCURACY, acc));
+    }
+    if let Some(sats) = self.satellites {
+      headers.push_str(&format!("{}: {}\r\n", HEADER_SATELLITES, sats));
+    }
+    headers
+  }
+
+  ///
+  /// Read the position from the upload headers, `header` returns the value
+  /// of a header.  Returns None if the upload has no position.
+  ///
+  pub fn from_headers<'a, F>(header: F) -> Result<Option<Position>, GiftError>
+  where
+    F: Fn(&str) -> Option<&'a str>,
+  {
+    let (latitude, longitude) = match (header(HEADER_LATITUDE), header(HEADER_LONGITUDE)) {
+      (Some(lat), Some(lon)) => (lat.trim().parse()?, lon.trim().parse()?),
+      _ => return Ok(None),
+    };
+
+    let h_accuracy = match header(HEADER_ACCURACY) {
+      Some(acc) => Some(acc.trim().parse()?),
+      None => None,
+    };
+    let satellites = match header(HEADER_SATELLITES) {
+      Some(sats) => Some(sats.trim().parse()?),
+      None => None,
+    };
+    let time_source = match header(HEADER_TIME_SOURCE) {
+      Some(source) => source.trim().parse()?,
+      None => TimeSource::System,
+    };
+    let fix_type = match header(HEADER_FIX_TYPE) {
+      Some(fix_type) => fix_type.trim().parse()?,
+      None => FixType::Fix3d,
+    };
+
+    Ok(Some(Position {
+      latitude,
+      longitude,
+      h_accuracy,
+      fix_time: String::from(header(HEADER_FIX_TIME).unwrap_or("").trim()),
+      time_source,
+      fix_type,
+      satellites,
+      stale: header(HEADER_STALE).map_or(false, |s| s.trim() == "true"),
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::position::*;
+  use std::collections::HashMap;
+
+  const NOW: &str = "20200830T023600.000Z";
+
+  #[test]
+  fn test_parse_gnss() {
+    let output = "Latitude(positive->north) : -36.843292\n\
+                  Longitude(positive->east) : 174.756864\n\
+                  hAccuracy                 : 10.0m\n\
+                  Position state: 3D Fix\n\
+                  Date(YYYY-MM-DD) 2020-08-30\n\
+                  Time(HH:MM:SS:ms) 02:35:17:000\n\
+                  Satellites in View: 12\n\
+                  Satellites Tracked: 9\n\
+                  Satellites Used: 7\n";
+    let pos = Position::parse_gnss(output, NOW).unwrap();
+
+    assert_eq!(-36.843292, pos.latitude);
+    assert_eq!(174.756864, pos.longitude);
+    assert_eq!(Some(10.0), pos.h_accuracy);
+    assert_eq!(FixType::Fix3d, pos.fix_type);
+    assert_eq!("20200830T023517.000Z", pos.fix_time);
+    assert_eq!(TimeSource::Gnss, pos.time_source);
+    assert_eq!(Some(7), pos.satellites);
+    assert!(!pos.stale);
+  }
+
+  #[test]
+  fn test_parse_gnss_old_script() {
+    // The old gps.sh used `echo $(gnss get loc2d)`, which joins the lines
+    let output = "Latitude(positive->north) : -36.843292 Longitude(positive->east) : \
+                  174.756864 hAccuracy : 10.0m";
+    let pos = Position::parse_gnss(output, NOW).unwrap();
+
+    assert_eq!(-36.843292, pos.latitude);
+    assert_eq!(174.756864, pos.longitude);
+    assert_eq!(Some(10.0), pos.h_accuracy);
+    assert_eq!(NOW, pos.fix_time);
+    assert_eq!(TimeSource::System, pos.time_source);
+    assert_eq!(None, pos.satellites);
+  }
+
+  #[test]
+  fn test_parse_gnss_failed() {
+    let failed =
+      "GPS FAILED: command 'gnss fix' returned 'TTFF not calculated (Position not fixed) BUSY'";
+    assert!(Position::parse_gnss(failed, NOW).is_err());
+    assert!(Position::parse_gnss("Location invalid [1, 1, 1]", NOW).is_err());
+    assert!(Position::parse_gnss("", NOW).is_err());
+    assert!(Position::parse_gnss("Latitude(positive->north) : -36.8", NOW).is_err());
+  }
+
+  #[test]
+  fn test_distance() {
+    assert_eq!(
+      0.0,
+      distance_m(-36.843292, 174.756864, -36.843292, 174.756864)
+    );
+
+    // One minute of latitude is a nautical mile
+    let d = distance_m(-36.0, 174.0, -36.0 - 1.0 / 60.0, 174.0);
+    assert!((d - 1853.2).abs() < 1.0, "distance: {}", d);
+
+    // Auckland to Wellington is about 494 km
+    let d = distance_m(-36.8485, 174.7633, -41.2865, 174.7762);
+    assert!((d - 493_500.0).abs() < 1000.0, "distance: {}", d);
+  }
+
+  #[test]
+  fn test_headers() {
+    let pos = Position {
+      latitude: -36.843292,
+      longitude: 174.756864,
+      h_accuracy: Some(10.0),
+      fix_time: String::from(NOW),
+      time_source: TimeSource::Server,
+      fix_type: FixType::Fix2d,
+      satellites: Some(5),
+      stale: true,
+    };
+
+    let headers = pos.to_headers();
+    let map: HashMap<&str, &str> = headers
+      .split("\r\n")
+      .filter(|l| !l.is_empty())
+      .map(|l| {
+        let mut parts = l.splitn(2, ':');
+        (parts.next().unwrap(), parts.next().unwrap())
+      })
+      .collect();
+
+    let decoded = Position::from_headers(|name| map.get(name).cloned()).unwrap();
+    assert_eq!(Some(pos), decoded);
+    assert_eq!(None, Position::from_headers(|_| None).unwrap());
+  }
+}
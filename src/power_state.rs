@@ -0,0 +1,166 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// The buoy power states, and the transitions between them that are reported
+/// to the server in the `Power-Transitions` header.  The power policy itself
+/// lives in the buoy code.
+///
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::GiftError;
+
+pub const HEADER_POWER_TRANSITIONS: &str = "Power-Transitions";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+  Normal,
+  Conserve,
+  Low,
+  Critical,
+}
+
+pub const ALL_STATES: [PowerState; 4] = [
+  PowerState::Normal,
+  PowerState::Conserve,
+  PowerState::Low,
+  PowerState::Critical,
+];
+
+impl fmt::Display for PowerState {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      PowerState::Normal => "normal",
+      PowerState::Conserve => "conserve",
+      PowerState::Low => "low",
+      PowerState::Critical => "critical",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for PowerState {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    ALL_STATES
+      .iter()
+      .find(|state| state.to_string() == s)
+      .cloned()
+      .ok_or(GiftError::ParseTelemetry)
+  }
+}
+
+///
+/// A change of power state, reported to the server.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+  pub from: PowerState,
+  pub to: PowerState,
+  pub voltage: f32,
+  pub time: String,
+}
+
+impl Transition {
+  /// Encode as `from>to@voltage@time`, e.g. "normal>conserve@11.95@20200101T101010.000Z"
+  pub fn encode(&self) -> String {
+    format!(
+      "{}>{}@{:.2}@{}",
+      self.from, self.to, self.voltage, self.time
+    )
+  }
+
+  pub fn decode(s: &str) -> Result<Transition, GiftError> {
+    let mut parts = s.trim().splitn(3, '@');
+    let states = parts.next().ok_or(GiftError::ParseTelemetry)?;
+    let voltage = parts.next().ok_or(GiftError::ParseTelemetry)?.parse()?;
+    let time = String::from(parts.next().ok_or(GiftError::ParseTelemetry)?);
+
+    let mut states = states.splitn(2, '>');
+    let from = states.next().ok_or(GiftError::ParseTelemetry)?.parse()?;
+    let to = states.next().ok_or(GiftError::ParseTelemetry)?.parse()?;
+
+    Ok(Transition {
+      from,
+      to,
+      voltage,
+      time,
+    })
+  }
+}
+
+/// Encode a list of transitions as a header value.
+pub fn encode_transitions(transitions: &[Transition]) -> String {
+  transitions
+    .iter()
+    .map(Transition::encode)
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+pub fn decode_transitions(value: &str) -> Result<Vec<Transition>, GiftError> {
+  value
+    .split(',')
+    .filter(|s| !s.trim().is_empty())
+    .map(Transition::decode)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::power_state::*;
+
+  #[test]
+  fn test_encode_decode() {
+    let transitions = vec![
+      Transition {
+        from: PowerState::Normal,
+        to: PowerState::Low,
+        voltage: 10.95,
+        time: String::from("20200101T101010.000Z"),
+      },
+      Transition {
+        from: PowerState::Low,
+        to: PowerState::Conserve,
+        voltage: 11.5,
+        time: String::from("20200101T121010.000Z"),
+      },
+    ];
+
+    let encoded = encode_transitions(&transitions);
+    assert_eq!(
+      "normal>low@10.95@20200101T101010.000Z,low>conserve@11.50@20200101T121010.000Z",
+      encoded
+    );
+    assert_eq!(transitions, decode_transitions(&encoded).unwrap());
+  }
+
+  #[test]
+  fn test_decode_invalid() {
+    assert!(Transition::decode("normal>sleepy@11.0@20200101T101010.000Z").is_err());
+    assert!(Transition::decode("normal@11.0@20200101T101010.000Z").is_err());
+    assert!(Transition::decode("normal>low").is_err());
+  }
+}
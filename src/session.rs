@@ -0,0 +1,225 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+
+///
+/// What the buoy remembers from before it started, sent in the `Session`
+/// header of the first upload after the buoy program starts, e.g.:
+///
+/// ```text
+/// boots=12,end=ulpm,uploads=4311,failures=27,bytes=1768000512,command=normal,fix=-36.843292/174.765121/20200901T013000.000Z
+/// ```
+///
+/// The counters are totals since the state was first saved on the buoy.
+/// `end` is why the previous session ended, see `SessionEnd`.  The last
+/// command and fix are left out if there hasn't been one.
+///
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::GiftError;
+use crate::key_values;
+
+pub const HEADER_SESSION: &str = "Session";
+
+///
+/// Why a session of the buoy program ended.  It's saved as `Running` while
+/// the program runs, so a session still `Running` at the next start ended
+/// without saying so, i.e. the program crashed or the power was lost.  It's
+/// `New` the first time the buoy starts.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEnd {
+  New,      // There was no previous session
+  Running,  // Still running
+  Ulpm,     // Went to sleep in ultra low power mode
+  Watchdog, // The supervisor rebooted after too many task failures
+  Command,  // Stopped by a command, e.g. an SMS to sleep or upgrade
+  Crash,    // Ended without saying why
+}
+
+const ALL_ENDS: [SessionEnd; 6] = [
+  SessionEnd::New,
+  SessionEnd::Running,
+  SessionEnd::Ulpm,
+  SessionEnd::Watchdog,
+  SessionEnd::Command,
+  SessionEnd::Crash,
+];
+
+impl fmt::Display for SessionEnd {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      SessionEnd::New => "new",
+      SessionEnd::Running => "running",
+      SessionEnd::Ulpm => "ulpm",
+      SessionEnd::Watchdog => "watchdog",
+      SessionEnd::Command => "command",
+      SessionEnd::Crash => "crash",
+    };
+    f.write_str(s)
+  }
+}
+
+impl FromStr for SessionEnd {
+  type Err = GiftError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    ALL_ENDS
+      .iter()
+      .find(|end| end.to_string() == s)
+      .cloned()
+      .ok_or(GiftError::ParseTelemetry)
+  }
+}
+
+///
+/// Where the buoy was when it last had a GPS fix.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedFix {
+  pub latitude: f64,
+  pub longitude: f64,
+  pub fix_time: String, // UTC time of the fix, same format as `date_now()`
+}
+
+impl SavedFix {
+  /// Encode as `latitude/longitude/time`.
+  pub fn encode(&self) -> String {
+    format!(
+      "{:.6}/{:.6}/{}",
+      self.latitude, self.longitude, self.fix_time
+    )
+  }
+
+  pub fn decode(value: &str) -> Result<SavedFix, GiftError> {
+    let parts: Vec<&str> = value.split('/').map(str::trim).collect();
+    if parts.len() != 3 || parts[2].is_empty() {
+      return Err(GiftError::ParseTelemetry);
+    }
+    Ok(SavedFix {
+      latitude: parts[0].parse()?,
+      longitude: parts[1].parse()?,
+      fix_time: String::from(parts[2]),
+    })
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+  pub boots: u32,                   // How many times the buoy program has started
+  pub previous_end: SessionEnd,     // Why the previous session ended
+  pub uploads: u64,                 // Successful uploads
+  pub failures: u64,                // Failed uploads
+  pub bytes: u64,                   // Bytes sent by the successful uploads
+  pub last_command: Option<String>, // The last command from the server
+  pub last_fix: Option<SavedFix>,
+}
+
+impl Session {
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    let mut out = format!(
+      "boots={},end={},uploads={},failures={},bytes={}",
+      self.boots, self.previous_end, self.uploads, self.failures, self.bytes
+    );
+    if let Some(command) = &self.last_command {
+      out.push_str(&format!(",command={}", command));
+    }
+    if let Some(fix) = &self.last_fix {
+      out.push_str(&format!(",fix={}", fix.encode()));
+    }
+    out
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<Session, GiftError> {
+    let mut boots = None;
+    let mut previous_end = None;
+    let mut session = Session {
+      boots: 0,
+      previous_end: SessionEnd::Crash,
+      uploads: 0,
+      failures: 0,
+      bytes: 0,
+      last_command: None,
+      last_fix: None,
+    };
+
+    for (key, value) in key_values(value)? {
+      match key {
+        "boots" => boots = Some(value.parse()?),
+        "end" => previous_end = Some(value.parse()?),
+        "uploads" => session.uploads = value.parse()?,
+        "failures" => session.failures = value.parse()?,
+        "bytes" => session.bytes = value.parse()?,
+        "command" => session.last_command = Some(String::from(value)),
+        "fix" => session.last_fix = Some(SavedFix::decode(value)?),
+        _ => debug!("Session::decode(): ignoring '{}'", key),
+      }
+    }
+
+    session.boots = boots.ok_or(GiftError::ParseTelemetry)?;
+    session.previous_end = previous_end.ok_or(GiftError::ParseTelemetry)?;
+    Ok(session)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::session::*;
+
+  #[test]
+  fn test_encode_decode() {
+    let mut session = Session {
+      boots: 12,
+      previous_end: SessionEnd::Ulpm,
+      uploads: 4311,
+      failures: 27,
+      bytes: 1_768_000_512,
+      last_command: Some(String::from("normal")),
+      last_fix: Some(SavedFix {
+        latitude: -36.843292,
+        longitude: 174.765121,
+        fix_time: String::from("20200901T013000.000Z"),
+      }),
+    };
+    let encoded = session.encode();
+    assert_eq!(
+      "boots=12,end=ulpm,uploads=4311,failures=27,bytes=1768000512,command=normal,\
+       fix=-36.843292/174.765121/20200901T013000.000Z",
+      encoded
+    );
+    assert_eq!(session, Session::decode(&encoded).unwrap());
+
+    session.last_command = None;
+    session.last_fix = None;
+    assert_eq!(session, Session::decode(&session.encode()).unwrap());
+
+    assert!(Session::decode("uploads=1").is_err());
+    assert!(Session::decode("boots=1,end=sleeping").is_err());
+    assert!(Session::decode("boots=1,end=crash,fix=1/2").is_err());
+    assert_eq!(
+      SessionEnd::Watchdog,
+      Session::decode("boots=1,end=watchdog,new_thing=3")
+        .unwrap()
+        .previous_end
+    );
+  }
+}
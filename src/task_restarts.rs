@@ -0,0 +1,87 @@
+///****************************************************************************
+///
+///  Smart-Buoy - connects marine sounds to the cloud.
+///  Copyright (C) 2020  Simon M. Werner (Anemoi Robotics Ltd)
+///
+///  This program is free software: you can redistribute it and/or modify
+///  it under the terms of the GNU General Public License as published by
+///  the Free Software Foundation, either version 3 of the License, or
+///  (at your option) any later version.
+///
+///  This program is distributed in the hope that it will be useful,
+///  but WITHOUT ANY WARRANTY; without even the implied warranty of
+///  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+///  GNU General Public License for more details.
+///
+///  You should have received a copy of the GNU General Public License
+///  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+///
+///****************************************************************************
+///
+/// How many times each of the buoy's long-running tasks has been restarted by
+/// the supervisor, since the buoy started.  It's sent in the `Task-Restarts`
+/// header of every upload as `task=count` pairs, e.g.:
+///
+/// ```text
+/// battery=0,gps=2,light=0,power=0,sensor=5
+/// ```
+///
+use std::collections::BTreeMap;
+
+use crate::errors::GiftError;
+use crate::key_values;
+
+pub const HEADER_TASK_RESTARTS: &str = "Task-Restarts";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TaskRestarts {
+  pub counts: BTreeMap<String, u32>, // Restarts for each task name
+}
+
+impl TaskRestarts {
+  /// The total number of restarts.
+  pub fn total(&self) -> u32 {
+    self.counts.values().sum()
+  }
+
+  /// Encode as a header value.
+  pub fn encode(&self) -> String {
+    self
+      .counts
+      .iter()
+      .map(|(task, count)| format!("{}={}", task, count))
+      .collect::<Vec<_>>()
+      .join(",")
+  }
+
+  /// Decode a header value created by `encode()`.
+  pub fn decode(value: &str) -> Result<TaskRestarts, GiftError> {
+    let mut restarts = TaskRestarts::default();
+
+    for (task, count) in key_values(value)? {
+      restarts.counts.insert(String::from(task), count.parse()?);
+    }
+
+    Ok(restarts)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::task_restarts::*;
+
+  #[test]
+  fn test_encode_decode() {
+    let mut restarts = TaskRestarts::default();
+    restarts.counts.insert(String::from("sensor"), 5);
+    restarts.counts.insert(String::from("gps"), 2);
+    restarts.counts.insert(String::from("light"), 0);
+    assert_eq!("gps=2,light=0,sensor=5", restarts.encode());
+    assert_eq!(7, restarts.total());
+    assert_eq!(restarts, TaskRestarts::decode(&restarts.encode()).unwrap());
+
+    assert_eq!(TaskRestarts::default(), TaskRestarts::decode("").unwrap());
+    assert!(TaskRestarts::decode("gps").is_err());
+    assert!(TaskRestarts::decode("gps=-1").is_err());
+  }
+}